@@ -0,0 +1,24 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Embeds the git commit and the time of the build, served by /version
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string()); // e.g. built from a source archive
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", built_at);
+    // Otherwise a new commit without changes to the sources would keep the old hash
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=src");
+}
@@ -1,521 +1,15830 @@
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use axum::{
-    extract, extract::State, http::StatusCode, response, response::IntoResponse, routing, Router,
+    body,
+    body::{Body, Bytes},
+    extract,
+    extract::{
+        connect_info::IntoMakeServiceWithConnectInfo, rejection::JsonRejection, ConnectInfo,
+        FromRequest, FromRequestParts, State,
+    },
+    http::{
+        header, request::Parts, Extensions, HeaderMap, HeaderName, HeaderValue, Method, Request,
+        StatusCode, Uri,
+    },
+    middleware,
+    middleware::Next,
+    response,
+    response::sse::{Event, KeepAlive, Sse},
+    response::IntoResponse,
+    routing, Router,
 };
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
 use clap::Parser;
+use dashmap::DashMap;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use redis::AsyncCommands;
+use rusqlite::OptionalExtension;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::RwLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{
+    broadcast, mpsc, watch, Mutex, OwnedSemaphorePermit, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    Semaphore,
+};
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::set_header::SetResponseHeaderLayer;
+use tower_http::trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer};
 
 #[derive(Parser)]
 struct CmdArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(long, default_value = "127.0.0.1:8080")]
     address: String,
-    #[arg(long)]
+    // Listen on this Unix domain socket instead of --address, a stale socket file is replaced
+    #[arg(long, conflicts_with_all = ["address", "discovery_url"])]
+    unix_socket: Option<PathBuf>,
+    // Where the entries are stored, by default on disk if --cache-dir is given and in memory
+    // otherwise
+    #[arg(long, value_enum)]
+    backend: Option<Backend>,
+    #[arg(long, required_if_eq("backend", "disk"))]
     cache_dir: Option<String>,
+    // SQLite database file of the sqlite backend, created if it does not exist
+    #[arg(long, required_if_eq("backend", "sqlite"))]
+    db_path: Option<PathBuf>,
+    // Server of the redis backend e.g. redis://127.0.0.1:6379/
+    #[arg(long, required_if_eq("backend", "redis"))]
+    redis_url: Option<String>,
+    // Consul agent address e.g. http://127.0.0.1:8500, the server registers itself there
+    #[arg(long, requires = "service_name")]
+    discovery_url: Option<String>,
+    #[arg(long, requires = "discovery_url")]
+    service_name: Option<String>,
+    // Refuse to start if the registration fails (by default it is only logged)
+    #[arg(long, requires = "discovery_url")]
+    discovery_required: bool,
+    // Identical mutating requests from the same client within this window are executed only once
+    #[arg(long)]
+    dedup_window_ms: Option<u64>,
+    // While more than this many mutating requests are in progress, the responses carry Retry-After
+    // (without --write-queue-hard-limit the requests are rejected with 429 instead)
+    #[arg(long)]
+    write_queue_soft_limit: Option<usize>,
+    // Mutating requests are rejected with 429 while more than this many of them are in progress
+    #[arg(long)]
+    write_queue_hard_limit: Option<usize>,
+    // Each client IP may make this many requests per second (in bursts of up to a second's worth),
+    // the excess is rejected with 429
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_per_second: Option<u32>,
+    // Values larger than this are rejected with 413 (/add-from-url stops fetching them)
+    #[arg(long)]
+    max_value_bytes: Option<usize>,
+    // Bodies of the mutating requests larger than this are rejected with 413 before they are read
+    // in full (/restore, which reads its body record by record, is exempt)
+    #[arg(long, default_value_t = 1024 * 1024)]
+    max_body_bytes: usize,
+    // JSON Schema file, the values that are not JSON documents valid against it are rejected with
+    // 422 (the integers /incr writes are not checked)
+    #[arg(long)]
+    value_schema: Option<PathBuf>,
+    // New entries are rejected with 507 once the cache holds that many (the existing ones can still
+    // be overwritten). Concurrent adds of different keys may exceed it slightly. Makes every add
+    // count the entries, which reads the directory with DiskCache.
+    #[arg(long)]
+    max_entries: Option<usize>,
+    // What happens to new entries once there are --max-entries of them
+    #[arg(long, value_enum, default_value_t = Eviction::Reject)]
+    eviction: Eviction,
+    // How the mem backend synchronizes the access to the entries (dashmap if not given)
+    #[arg(long, value_enum)]
+    mem_impl: Option<MemImpl>,
+    // JSON file with the quotas of the namespaces e.g. {"ns": {"max_entries": 100, "max_bytes":
+    // 65536}} ("" is the default namespace). /add exceeding them is rejected with 507. Makes every
+    // /add to such a namespace read all its entries.
+    #[arg(long)]
+    quota_file: Option<PathBuf>,
+    // JSON object {"key": "value", ...} of the entries added to the cache before serving
+    #[arg(long)]
+    seed_file: Option<PathBuf>,
+    // What happens to the seeded keys that are already in the cache (e.g. in --cache-dir)
+    #[arg(long, value_enum, default_value_t = SeedMode::Overwrite)]
+    seed_mode: SeedMode,
+    // Response of /get for missing keys (without the default query parameter): "empty" (404 with no
+    // body), "json" (404 with {"error": "not found", "key": ...}) or "default:<VALUE>" (200 with the
+    // value)
+    #[arg(long, default_value = "empty", value_parser = parse_miss_body)]
+    miss_body: MissBody,
+    // Hosts that /add-from-url may fetch from, if none is given /add-from-url is disabled
+    #[arg(long)]
+    fetch_allow_host: Vec<String>,
+    // Every mutation is reported with a POST of {op, key, timestamp} to this URL
+    #[arg(long)]
+    webhook_url: Option<String>,
+    // Include the new value in the webhook events
+    #[arg(long, requires = "webhook_url")]
+    webhook_include_value: bool,
+    // Base URL of a peer server to which every add, modify and delete is forwarded in the background
+    // (the other mutations are not). The peer must not replicate back to this server.
+    #[arg(long)]
+    replica_url: Option<String>,
+    // Use only that many bytes of the key hash for the DiskCache filenames. Shorter filenames save
+    // space in the directory, but make collisions of different keys more likely.
+    #[arg(long, requires = "cache_dir", value_parser = clap::value_parser!(u8).range(DiskCache::MIN_HASH_LEN as i64..=blake3::OUT_LEN as i64))]
+    disk_hash_truncate: Option<u8>,
+    // Keep up to that many recently read entries of the disk, sqlite or redis backend in memory
+    #[arg(long)]
+    memory_layer_size: Option<std::num::NonZeroUsize>,
+    // Keys with this prefix are kept only in memory even with --cache-dir (they are lost on restart)
+    #[arg(long, requires = "cache_dir")]
+    ephemeral_prefix: Option<String>,
+    // Writes creating a namespace beyond this many are rejected with 403, the ones stored before
+    // (e.g. by a previous run) can still be used
+    #[arg(long)]
+    max_namespaces: Option<usize>,
+    // /list, /snapshot and /dump.csv read the whole cache, excess concurrent ones are rejected with
+    // 503
+    #[arg(long)]
+    max_concurrent_lists: Option<usize>,
+    // Requests beyond this many in progress at once are rejected with 503
+    #[arg(long)]
+    max_concurrent_requests: Option<usize>,
+    // Record every mutation in this file, to allow recovering the state from any moment
+    #[arg(long)]
+    journal: Option<PathBuf>,
+    // Read every DiskCache entry back right after writing it and fail the request if it differs
+    #[arg(long, requires = "cache_dir")]
+    verify_writes: bool,
+    // How DiskCache makes the writes durable, "interval" trades a short window of possible loss of
+    // the recent writes for throughput
+    #[arg(long, value_enum, default_value_t = FsyncMode::Always)]
+    fsync_mode: FsyncMode,
+    // Log every DiskCache mutation (without the values) to this file before performing it
+    #[arg(long, requires = "cache_dir")]
+    wal_path: Option<PathBuf>,
+    // 32-byte key (64 hex digits or base64) to encrypt the DiskCache values with. The keys and the
+    // rest of the metadata are not encrypted.
+    #[arg(long, requires = "cache_dir", value_parser = parse_encryption_key)]
+    encryption_key: Option<[u8; 32]>,
+    // Store the DiskCache values gzip-compressed (those that would not get smaller are stored as
+    // they are)
+    #[arg(long, requires = "cache_dir")]
+    compress_values: bool,
+    // DiskCache filesystem operations taking longer fail the request with 504, instead of hanging
+    // it on a stuck filesystem (e.g. a hung network mount)
+    #[arg(long, requires = "cache_dir")]
+    disk_timeout_ms: Option<u64>,
+    // Require the "Authorization: Bearer <API_KEY>" header in requests
+    #[arg(long, conflicts_with = "jwks_url")]
+    api_key: Option<String>,
+    // Require "Authorization: Bearer <JWT>" with a JWT signed by one of the keys from this JWKS
+    #[arg(long)]
+    jwks_url: Option<String>,
+    // Accept only JWTs issued for this audience
+    #[arg(long, requires = "jwks_url")]
+    jwt_audience: Option<String>,
+    // By default only the mutating routes require authentication
+    #[arg(long)]
+    require_auth_for_reads: bool,
+    // Entries not modified for longer than this (e.g. 30d, 12h, 90m, 45s) are periodically deleted
+    #[arg(long, requires = "cache_dir", value_parser = parse_duration)]
+    max_entry_age: Option<Duration>,
+    // Keep this many previous values of every key (replaced by /add or /modify), see /history
+    #[arg(long, default_value_t = 0)]
+    value_history: usize,
+    // "Name: Value" header added to every response e.g. "X-Content-Type-Options: nosniff"
+    #[arg(long, value_parser = parse_response_header)]
+    response_header: Vec<(HeaderName, HeaderValue)>,
+    // Origin e.g. https://example.com (or * for any) allowed to call the API from browsers, CORS is
+    // disabled unless given
+    #[arg(long, value_parser = HeaderValue::from_str)]
+    cors_allow_origin: Vec<HeaderValue>,
+    // Cache-Control of the successful /get and /list responses e.g. "max-age=60", the requests may
+    // override it with the cache_control query parameter. The mutations are always "no-store".
+    #[arg(long, value_parser = HeaderValue::from_str)]
+    default_cache_control: Option<HeaderValue>,
+    // Keys longer than this are rejected with 413
+    #[arg(long)]
+    max_key_bytes: Option<usize>,
+    // Keys not matching this regex as a whole (e.g. "[a-z0-9:_-]+") are rejected with 400
+    #[arg(long, value_parser = parse_key_pattern)]
+    key_pattern: Option<regex::Regex>,
+    // Don't store the keys in the DiskCache entries, only their hashes. Saves space with long keys,
+    // but makes /list unavailable.
+    #[arg(long, requires = "cache_dir")]
+    drop_key_in_storage: bool,
+    // Reject with 409 deleting entries that are targets of aliases (by default the aliases are left
+    // dangling). Makes deletions read all the entries of DiskCache.
+    #[arg(long)]
+    refuse_delete_aliased: bool,
+    // Serve the cache as it is: the requests that would change it are rejected with 403
+    #[arg(long)]
+    read_only: bool,
+    // Route e.g. /Add and /LIST like /add and /list (the keys and namespaces stay case-sensitive)
+    #[arg(long)]
+    case_insensitive_routes: bool,
+    // Pack up to that many DiskCache entries into a file, instead of a file per entry
+    #[arg(long, requires = "cache_dir", conflicts_with_all = ["disk_hash_truncate", "max_entry_age"], value_parser = clap::value_parser!(u64).range(1..))]
+    group_size: Option<u64>,
+    // How often expired entries are removed from the cache (until then they only take space)
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    sweep_interval_seconds: u64,
+    // Every request is logged (to stderr) at the info level
+    #[arg(long, default_value_t = tracing_subscriber::filter::LevelFilter::INFO)]
+    log_level: tracing_subscriber::filter::LevelFilter,
 }
 
-#[tokio::main]
-async fn main() {
-    let cmd_args = CmdArgs::parse();
-
-    let app_state = AppState {
-        cache: match cmd_args.cache_dir {
-            Some(path) => {
-                tokio::fs::create_dir_all(&path).await.unwrap();
-                Box::new(DiskCache::new(PathBuf::from(path)))
-            }
-            None => Box::new(MemCache::new()),
-        },
-    };
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Backend {
+    Mem,
+    Disk,
+    Sqlite,
+    Redis,
+}
 
-    println!("Starting to listen on http://{}", cmd_args.address);
-    axum::Server::bind(&cmd_args.address.parse().unwrap())
-        .serve(app(app_state))
-        .await
-        .unwrap();
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum Eviction {
+    // Fail the request with 507
+    Reject,
+    // Make room by removing the least recently used entry (only the mem backend)
+    Lru,
 }
 
-struct AppState {
-    cache: Box<dyn Cache + Send + Sync>,
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum MemImpl {
+    // Sharded read-write locks, see MemCache
+    #[value(alias = "rwlock")]
+    Dashmap,
+    // Lock-free reads of snapshots replaced by the writes, see SnapshotMemCache. Meant for
+    // read-mostly workloads, as every write copies the map of entries.
+    Arcswap,
 }
 
-// As a function to facilitate testing
-fn app(app_state: AppState) -> axum::routing::IntoMakeService<Router> {
-    Router::new()
-        .route("/add", routing::put(add))
-        .route("/delete", routing::delete(delete))
-        .route("/get", routing::get(get))
-        .route("/list", routing::get(list))
-        .route("/modify", routing::patch(modify))
-        .with_state(Arc::new(RwLock::new(app_state)))
-        .into_make_service()
+#[derive(Clone, Copy, PartialEq, Debug, clap::ValueEnum)]
+enum FsyncMode {
+    // Sync every written file and the directory before the write is acknowledged
+    Always,
+    // Sync the written files, but the directory only every PendingDirSyncs::INTERVAL, so the
+    // renames and deletions acknowledged in the meantime may be lost on a power failure
+    Interval,
+    // Leave it to the OS, a power failure may lose or tear recent writes
+    Never,
 }
 
-// Allow more than one implementation of the Cache
-#[async_trait]
-trait Cache {
-    async fn list(&self) -> Value;
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum SeedMode {
+    // Replace the value from the cache with the seeded one
+    Overwrite,
+    // Keep the value from the cache
+    Skip,
+}
 
-    async fn add(&mut self, key: String, value: String);
+#[derive(Clone, Debug, PartialEq)]
+enum MissBody {
+    Empty,
+    Json,
+    Default(String),
+}
 
-    // Returns true if the entry was deleted, false if there is no entry
-    async fn delete(&mut self, key: &String) -> bool;
+fn parse_miss_body(s: &str) -> Result<MissBody, String> {
+    match s {
+        "empty" => Ok(MissBody::Empty),
+        "json" => Ok(MissBody::Json),
+        _ => match s.strip_prefix("default:") {
+            Some(value) => Ok(MissBody::Default(value.to_string())),
+            None => Err(format!(
+                "expected \"empty\", \"json\" or \"default:<VALUE>\", got {:?}",
+                s
+            )),
+        },
+    }
+}
 
-    // Returns true if the entry was modified, false if there is no entry
-    async fn modify(&mut self, key: String, value: String) -> bool;
+fn parse_response_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Name: Value\", got {:?}", s))?;
+    let name = HeaderName::try_from(name.trim()).map_err(|err| err.to_string())?;
+    let value = HeaderValue::try_from(value.trim()).map_err(|err| err.to_string())?;
+    Ok((name, value))
+}
 
-    async fn get(&self, key: &String) -> Option<String>;
+// Accepts 64 hex digits or base64 of 32 bytes
+fn parse_encryption_key(s: &str) -> Result<[u8; 32], String> {
+    use base64::Engine;
+    let bytes = if s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    } else {
+        base64::engine::general_purpose::STANDARD
+            .decode(s)
+            .map_err(|_| "expected 64 hex digits or base64".to_string())?
+    };
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| format!("expected 32 bytes, got {}", bytes.len()))
 }
 
-struct MemCache {
-    cache: HashMap<String, String>,
+// Anchored, so that the pattern has to match the whole key
+fn parse_key_pattern(s: &str) -> Result<regex::Regex, String> {
+    regex::Regex::new(&format!("^(?:{})$", s)).map_err(|err| err.to_string())
 }
 
-// In memory cache - the simplest
-impl MemCache {
-    fn new() -> Self {
-        MemCache {
-            cache: HashMap::new(),
-        }
+// Parses a positive number of seconds, minutes, hours or days e.g. "30d"
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(unit_start);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {:?}", s))?;
+    let unit_secs = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid duration unit: {:?}", unit)),
+    };
+    if number == 0 {
+        return Err("the duration must be positive".to_string());
     }
+    let secs = number
+        .checked_mul(unit_secs)
+        .ok_or_else(|| format!("duration too long: {:?}", s))?;
+    Ok(Duration::from_secs(secs))
 }
 
-#[async_trait]
-impl Cache for MemCache {
-    async fn list(&self) -> Value {
-        let map = serde_json::Map::from_iter(
-            self.cache
-                .iter()
-                .map(|(k, v)| (k.clone(), Value::String(v.clone()))),
-        );
-        Value::Object(map)
-    }
+#[derive(clap::Subcommand)]
+enum Command {
+    // Reconstructs the cache from the journal
+    Replay {
+        #[arg(long)]
+        journal: PathBuf,
+        // Replay only the mutations up to this time (milliseconds since the UNIX epoch)
+        #[arg(long)]
+        until_ms: Option<u64>,
+        // Empty directory to restore the cache into, if not given the contents (of the default
+        // namespace only) are printed
+        #[arg(long)]
+        cache_dir: Option<PathBuf>,
+    },
+}
 
-    async fn add(&mut self, key: String, value: String) {
-        self.cache.insert(key, value);
+#[tokio::main]
+async fn main() {
+    let cmd_args = CmdArgs::parse();
+    tracing_subscriber::fmt()
+        .with_max_level(cmd_args.log_level)
+        .with_writer(std::io::stderr)
+        .init();
+    if let Some(Command::Replay {
+        journal,
+        until_ms,
+        cache_dir,
+    }) = cmd_args.command
+    {
+        return replay(journal, until_ms, cache_dir).await;
     }
 
-    async fn delete(&mut self, key: &String) -> bool {
-        self.cache.remove(key).is_some()
+    let backend = cmd_args.backend.unwrap_or(match cmd_args.cache_dir {
+        Some(_) => Backend::Disk,
+        None => Backend::Mem,
+    });
+    if backend != Backend::Disk && cmd_args.cache_dir.is_some() {
+        eprintln!("--cache-dir is used only by the disk backend");
+        std::process::exit(1);
     }
-
-    async fn modify(&mut self, key: String, value: String) -> bool {
-        let entry = self.cache.entry(key);
-        match entry {
-            std::collections::hash_map::Entry::Occupied(mut o) => {
-                o.insert(value);
-                true
-            }
-            std::collections::hash_map::Entry::Vacant(_) => false,
-        }
+    if backend != Backend::Sqlite && cmd_args.db_path.is_some() {
+        eprintln!("--db-path is used only by the sqlite backend");
+        std::process::exit(1);
     }
-
-    async fn get(&self, key: &String) -> Option<String> {
-        self.cache.get(key).cloned()
+    if backend != Backend::Redis && cmd_args.redis_url.is_some() {
+        eprintln!("--redis-url is used only by the redis backend");
+        std::process::exit(1);
     }
-}
-
-// On disk cache - a little trickier than in memory cache
-struct DiskCache {
-    cache_dir: PathBuf,
-}
-
-impl DiskCache {
-    fn new(cache_dir: PathBuf) -> Self {
-        DiskCache { cache_dir }
+    if backend == Backend::Mem && cmd_args.memory_layer_size.is_some() {
+        eprintln!("--memory-layer-size requires the disk, sqlite or redis backend");
+        std::process::exit(1);
     }
-
-    fn key_to_filename(key: &String) -> String {
-        blake3::hash(key.as_bytes()).to_hex().as_str().to_string()
+    if backend != Backend::Mem && cmd_args.mem_impl.is_some() {
+        eprintln!("--mem-impl is used only by the mem backend");
+        std::process::exit(1);
     }
-
-    fn key_to_path(&self, key: &String) -> PathBuf {
-        self.cache_dir.join(Self::key_to_filename(&key))
+    let mem_impl = cmd_args.mem_impl.unwrap_or(MemImpl::Dashmap);
+    let with_memory_layer = |cache: Box<dyn Cache + Send + Sync>| -> Box<dyn Cache + Send + Sync> {
+        match cmd_args.memory_layer_size {
+            Some(capacity) => Box::new(CachedDiskCache::new(cache, capacity)),
+            None => cache,
+        }
+    };
+    let lru_capacity = match (cmd_args.eviction, cmd_args.max_entries) {
+        (Eviction::Reject, _) => None,
+        (Eviction::Lru, _) if mem_impl == MemImpl::Arcswap => {
+            eprintln!("--eviction lru requires --mem-impl dashmap");
+            std::process::exit(1);
+        }
+        (Eviction::Lru, Some(max_entries)) if backend == Backend::Mem => {
+            match std::num::NonZeroUsize::new(max_entries) {
+                Some(capacity) => Some(capacity),
+                None => {
+                    eprintln!("--eviction lru requires --max-entries greater than 0");
+                    std::process::exit(1);
+                }
+            }
+        }
+        (Eviction::Lru, _) => {
+            eprintln!("--eviction lru requires the mem backend and --max-entries");
+            std::process::exit(1);
+        }
+    };
+    let mut app_state = AppState::new(
+        match (cmd_args.cache_dir, cmd_args.db_path, cmd_args.redis_url) {
+            (Some(path), _, _) => {
+                tokio::fs::create_dir_all(&path).await.unwrap();
+                let mut disk_cache = match cmd_args.disk_hash_truncate {
+                    Some(hash_len) => {
+                        DiskCache::with_hash_len(PathBuf::from(path), hash_len.into())
+                    }
+                    None => DiskCache::new(PathBuf::from(path)),
+                };
+                if let Err(err) = disk_cache.check_hash_len().await {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+                if disk_cache.hash_len != blake3::OUT_LEN {
+                    println!("Using {}-byte key hashes as filenames", disk_cache.hash_len);
+                }
+                disk_cache.verify_writes = cmd_args.verify_writes;
+                disk_cache.set_fsync_mode(cmd_args.fsync_mode);
+                disk_cache.drop_keys = cmd_args.drop_key_in_storage;
+                disk_cache.cipher = cmd_args
+                    .encryption_key
+                    .map(|key| ChaCha20Poly1305::new(&key.into()));
+                disk_cache.compress_values = cmd_args.compress_values;
+                disk_cache.io_timeout = cmd_args.disk_timeout_ms.map(Duration::from_millis);
+                if let Some(wal_path) = cmd_args.wal_path {
+                    match WriteAheadLog::open(&wal_path) {
+                        Ok(wal) => disk_cache.wal = Some(wal),
+                        Err(err) => {
+                            eprintln!("Failed to open {:?}: {}", wal_path, err);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                if let Some(group_size) = cmd_args.group_size {
+                    disk_cache.group_size = Some(group_size as usize);
+                    disk_cache.load_groups().await;
+                }
+                // The ephemeral keys are in memory anyway
+                let disk_cache = with_memory_layer(Box::new(disk_cache));
+                match cmd_args.ephemeral_prefix {
+                    Some(prefix) => Box::new(HybridCache::new(prefix, disk_cache)),
+                    None => disk_cache,
+                }
+            }
+            (None, Some(db_path), _) => match SqliteCache::open(&db_path) {
+                Ok(sqlite_cache) => with_memory_layer(Box::new(sqlite_cache)),
+                Err(err) => {
+                    eprintln!("Failed to open {:?}: {}", db_path, err);
+                    std::process::exit(1);
+                }
+            },
+            (None, None, Some(redis_url)) => match RedisCache::connect(&redis_url).await {
+                Ok(redis_cache) => with_memory_layer(Box::new(redis_cache)),
+                Err(err) => {
+                    eprintln!("Failed to connect to {}: {}", redis_url, err);
+                    std::process::exit(1);
+                }
+            },
+            (None, None, None) => match (mem_impl, lru_capacity) {
+                (MemImpl::Arcswap, _) => Box::new(SnapshotMemCache::new()),
+                (MemImpl::Dashmap, Some(capacity)) => Box::new(MemCache::with_lru(capacity)),
+                (MemImpl::Dashmap, None) => Box::new(MemCache::new()),
+            },
+        },
+    );
+    if let Some(path) = cmd_args.seed_file {
+        match seed_cache(app_state.cache.as_ref(), &path, cmd_args.seed_mode).await {
+            Ok(added) => println!("Seeded {} entries", added),
+            Err(err) => {
+                eprintln!("Failed to seed from {:?}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
     }
-
-    fn serialize(entry: &DiskCacheEntry) -> String {
-        serde_json::to_string(entry).unwrap()
+    app_state.dedup = cmd_args
+        .dedup_window_ms
+        .map(|ms| Arc::new(Deduplicator::new(Duration::from_millis(ms))));
+    app_state.write_queue = Arc::new(WriteQueue::new(
+        cmd_args.write_queue_soft_limit,
+        cmd_args.write_queue_hard_limit,
+    ));
+    app_state.rate_limiter = cmd_args
+        .rate_limit_per_second
+        .map(|rate| Arc::new(RateLimiter::new(rate)));
+    app_state.max_value_bytes = cmd_args.max_value_bytes;
+    app_state.max_body_bytes = Some(cmd_args.max_body_bytes);
+    if let Some(path) = cmd_args.value_schema {
+        match load_value_schema(&path) {
+            Ok(schema) => app_state.value_schema = Some(Arc::new(schema)),
+            Err(err) => {
+                eprintln!("Failed to load {:?}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
     }
-
-    fn deserialize(entry: &[u8]) -> DiskCacheEntry {
-        serde_json::from_slice(entry).unwrap()
+    if lru_capacity.is_none() {
+        app_state.max_entries = cmd_args.max_entries;
     }
-}
-
-#[derive(Serialize, Deserialize)]
-struct DiskCacheEntry {
-    key: String,
-    value: String,
-}
-
-#[async_trait]
-impl Cache for DiskCache {
-    async fn list(&self) -> Value {
-        let mut entries = tokio::fs::read_dir(&self.cache_dir).await.unwrap();
-        let mut vec = vec![];
-        while let Some(entry) = entries.next_entry().await.unwrap() {
-            let file_name = entry.file_name();
-            if file_name.len() == blake3::OUT_LEN * 2 {
-                let mut contents = vec![];
-                File::open(self.cache_dir.join(file_name))
-                    .await
-                    .unwrap()
-                    .read_to_end(&mut contents)
-                    .await
-                    .unwrap();
-                let entry = Self::deserialize(&contents);
-                vec.push((entry.key, Value::String(entry.value)));
+    if let Some(path) = cmd_args.quota_file {
+        match load_quotas(&path) {
+            Ok(quotas) => app_state.quotas = Arc::new(quotas),
+            Err(err) => {
+                eprintln!("Failed to load {:?}: {}", path, err);
+                std::process::exit(1);
             }
         }
-        let map = serde_json::Map::from_iter(vec.into_iter());
-        Value::Object(map)
     }
-
-    async fn add(&mut self, key: String, value: String) {
-        let filename = Self::key_to_filename(&key);
-        let file_path = self.cache_dir.join(&filename);
-        let tmp_filename = filename + ".new";
-        let tmp_file_path = self.cache_dir.join(tmp_filename);
-        let contents = Self::serialize(&DiskCacheEntry { key, value });
-        // Save data
-        let mut file = File::create(&tmp_file_path).await.unwrap();
-        file.write_all(contents.as_bytes()).await.unwrap();
-        // Make changes to disk durable
-        file.sync_all().await.unwrap();
-        tokio::fs::rename(tmp_file_path, file_path).await.unwrap();
-        File::open(&self.cache_dir)
-            .await
-            .unwrap()
-            .sync_data() // make rename durable
-            .await
-            .unwrap();
+    app_state.fetcher = Arc::new(Fetcher::new(cmd_args.fetch_allow_host));
+    app_state.webhook = cmd_args
+        .webhook_url
+        .map(|url| Webhook::start(url, cmd_args.webhook_include_value));
+    app_state.replica = cmd_args.replica_url.map(Replica::start);
+    app_state.list_limiter = cmd_args
+        .max_concurrent_lists
+        .map(|limit| Arc::new(ListLimiter::new(limit)));
+    app_state.request_limiter = cmd_args
+        .max_concurrent_requests
+        .map(|limit| Arc::new(Semaphore::new(limit)));
+    if let Some(path) = cmd_args.journal {
+        match Journal::open(&path) {
+            Ok(journal) => app_state.journal = Some(Arc::new(journal)),
+            Err(err) => {
+                eprintln!("Failed to open {:?}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
     }
+    app_state.authenticator = match (cmd_args.api_key, cmd_args.jwks_url) {
+        (Some(api_key), _) => Some(Arc::new(StaticTokenAuth::new(&api_key))),
+        (None, Some(jwks_url)) => Some(Arc::new(JwtAuth::new(jwks_url, cmd_args.jwt_audience))),
+        (None, None) => None,
+    };
+    app_state.require_auth_for_reads = cmd_args.require_auth_for_reads;
+    app_state.max_entry_age = cmd_args.max_entry_age;
+    app_state.value_history = cmd_args.value_history;
+    app_state.sweep_interval = Some(Duration::from_secs(cmd_args.sweep_interval_seconds));
+    app_state.response_headers = cmd_args.response_header;
+    app_state.cors_allow_origins = cmd_args.cors_allow_origin;
+    app_state.default_cache_control = cmd_args.default_cache_control;
+    app_state.max_key_bytes = cmd_args.max_key_bytes;
+    app_state.key_pattern = cmd_args.key_pattern;
+    app_state.miss_body = cmd_args.miss_body;
+    app_state.list_disabled = cmd_args.drop_key_in_storage;
+    app_state.refuse_delete_aliased = cmd_args.refuse_delete_aliased;
+    app_state.read_only = cmd_args.read_only;
+    app_state.namespaces.max_count = cmd_args.max_namespaces;
+    app_state.case_insensitive_routes = cmd_args.case_insensitive_routes;
 
-    async fn delete(&mut self, key: &String) -> bool {
-        match tokio::fs::remove_file(self.key_to_path(key)).await {
-            Ok(()) => {
-                File::open(&self.cache_dir)
-                    .await
-                    .unwrap()
-                    .sync_data() // make deletion durable
-                    .await
-                    .unwrap();
-                true
+    let address = cmd_args.address.parse().unwrap();
+    let discovery = match (cmd_args.discovery_url, cmd_args.service_name) {
+        (Some(url), Some(service_name)) => {
+            let discovery = Arc::new(Discovery::new(url, service_name, address));
+            match discovery.register().await {
+                Ok(()) => Some((discovery.clone(), tokio::spawn(discovery.heartbeat_loop()))),
+                Err(err) if cmd_args.discovery_required => {
+                    eprintln!("Failed to register in service discovery: {}", err);
+                    std::process::exit(1);
+                }
+                Err(err) => {
+                    eprintln!("Failed to register in service discovery: {}", err);
+                    None
+                }
             }
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => false,
-            Err(err) => panic!("{:?}", err),
         }
+        _ => None,
+    };
+
+    let changes = app_state.changes.clone();
+    let shutdown = async move {
+        shutdown_signal().await;
+        changes.close();
+    };
+    if let Some(path) = cmd_args.unix_socket {
+        let listener = match bind_unix_socket(&path).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                eprintln!("Failed to listen on {:?}: {}", path, err);
+                std::process::exit(1);
+            }
+        };
+        println!("Starting to listen on {:?}", path);
+        serve_unix(listener, with_middleware(routes(app_state)), shutdown).await;
+        let _ = std::fs::remove_file(&path);
+    } else {
+        println!("Starting to listen on http://{}", cmd_args.address);
+        let listener = std::net::TcpListener::bind(address).unwrap();
+        serve(listener, app(app_state), shutdown).await;
     }
 
-    async fn modify(&mut self, key: String, value: String) -> bool {
-        if tokio::fs::try_exists(self.key_to_path(&key)).await.unwrap() {
-            self.add(key, value).await;
-            true
-        } else {
-            false
+    if let Some((discovery, heartbeat)) = discovery {
+        heartbeat.abort();
+        if let Err(err) = discovery.deregister().await {
+            eprintln!("Failed to deregister from service discovery: {}", err);
         }
     }
+}
 
-    async fn get(&self, key: &String) -> Option<String> {
-        match File::open(self.key_to_path(key)).await {
-            Ok(mut file) => {
-                let mut contents = vec![];
-                file.read_to_end(&mut contents).await.unwrap();
-                let entry = Self::deserialize(&contents);
-                Some(entry.value)
+async fn replay(journal: PathBuf, until_ms: Option<u64>, cache_dir: Option<PathBuf>) {
+    let cache: Box<dyn Cache + Send + Sync> = match &cache_dir {
+        Some(path) => {
+            tokio::fs::create_dir_all(path).await.unwrap();
+            if tokio::fs::read_dir(path)
+                .await
+                .unwrap()
+                .next_entry()
+                .await
+                .unwrap()
+                .is_some()
+            {
+                eprintln!("{:?} is not empty", path);
+                std::process::exit(1);
+            }
+            Box::new(DiskCache::new(path.clone()))
+        }
+        None => Box::new(MemCache::new()),
+    };
+    match Journal::replay(&journal, until_ms, cache.as_ref()).await {
+        Ok(applied) => eprintln!("Replayed {} mutations", applied),
+        Err(err) => {
+            eprintln!("Failed to replay {:?}: {}", journal, err);
+            std::process::exit(1);
+        }
+    }
+    if cache_dir.is_none() {
+        match cache.list().await {
+            Ok(list) => println!("{}", list),
+            Err(err) => {
+                eprintln!("Failed to list the replayed entries: {}", err);
+                std::process::exit(1);
             }
-            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
-            Err(err) => panic!("{:?}", err),
         }
     }
 }
 
-async fn list(State(state): State<Arc<RwLock<AppState>>>) -> response::Json<Value> {
-    response::Json(state.read().await.cache.list().await)
+// Returns the number of the entries added
+async fn seed_cache(
+    cache: &(dyn Cache + Send + Sync),
+    path: &Path,
+    mode: SeedMode,
+) -> Result<usize, String> {
+    let contents = tokio::fs::read(path).await.map_err(|err| err.to_string())?;
+    let entries: BTreeMap<String, String> =
+        serde_json::from_slice(&contents).map_err(|err| err.to_string())?;
+    let mut added = 0;
+    for (key, value) in entries {
+        if mode == SeedMode::Skip
+            && cache
+                .get(&key)
+                .await
+                .map_err(|err| err.to_string())?
+                .is_some()
+        {
+            continue;
+        }
+        cache
+            .add(key, value.into_bytes(), None)
+            .await
+            .map_err(|err| err.to_string())?;
+        added += 1;
+    }
+    Ok(added)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct AddPayload {
-    key: String,
-    value: String,
+fn load_quotas(path: &Path) -> Result<HashMap<String, Quota>, String> {
+    let quotas = std::fs::read(path).map_err(|err| err.to_string())?;
+    serde_json::from_slice(&quotas).map_err(|err| err.to_string())
 }
 
-async fn add(
-    State(state): State<Arc<RwLock<AppState>>>,
-    extract::Json(payload): extract::Json<AddPayload>,
-) -> impl IntoResponse {
-    state
-        .write()
+fn load_value_schema(path: &Path) -> Result<jsonschema::JSONSchema, String> {
+    let schema = std::fs::read(path).map_err(|err| err.to_string())?;
+    let schema = serde_json::from_slice(&schema).map_err(|err| err.to_string())?;
+    jsonschema::JSONSchema::compile(&schema).map_err(|err| err.to_string())
+}
+
+// Once shutdown completes, no new connections are accepted, but the in-flight requests are
+// finished (e.g. so that no DiskCache write is interrupted halfway)
+async fn serve(
+    listener: std::net::TcpListener,
+    app: App,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app)
+        .with_graceful_shutdown(async {
+            shutdown.await;
+            println!("Shutting down, waiting for the in-flight requests to finish");
+        })
         .await
-        .cache
-        .add(payload.key, payload.value)
-        .await;
-    StatusCode::CREATED
+        .unwrap();
+    println!("Shut down");
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct DeletePayload {
-    key: String,
+// The requests get no ConnectInfo, so they are not told apart by the client address (e.g. by the
+// rate limiter)
+async fn serve_unix(
+    listener: tokio::net::UnixListener,
+    router: Router,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    axum::Server::builder(UnixAccept(listener))
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(async {
+            shutdown.await;
+            println!("Shutting down, waiting for the in-flight requests to finish");
+        })
+        .await
+        .unwrap();
+    println!("Shut down");
 }
 
-async fn delete(
-    State(state): State<Arc<RwLock<AppState>>>,
-    extract::Json(payload): extract::Json<DeletePayload>,
-) -> impl IntoResponse {
-    if state.write().await.cache.delete(&payload.key).await {
-        StatusCode::NO_CONTENT
-    } else {
-        StatusCode::NOT_FOUND
+struct UnixAccept(tokio::net::UnixListener);
+
+impl hyper::server::accept::Accept for UnixAccept {
+    type Conn = tokio::net::UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<std::io::Result<Self::Conn>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ModifyPayload {
-    key: String,
-    value: String,
+// A socket file left by a server that is gone is removed, a live one (or another file) is an error
+async fn bind_unix_socket(path: &Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = tokio::fs::symlink_metadata(path).await {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "the file exists and is not a socket",
+            ));
+        }
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "another server listens on it",
+            ));
+        }
+        tokio::fs::remove_file(path).await?;
+    }
+    tokio::net::UnixListener::bind(path)
 }
 
-async fn modify(
-    State(state): State<Arc<RwLock<AppState>>>,
-    extract::Json(payload): extract::Json<ModifyPayload>,
-) -> impl IntoResponse {
-    if state
-        .write()
-        .await
-        .cache
-        .modify(payload.key, payload.value)
-        .await
-    {
-        StatusCode::NO_CONTENT
-    } else {
-        StatusCode::NOT_FOUND
+async fn shutdown_signal() {
+    let mut sigterm =
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.unwrap(),
+        _ = sigterm.recv() => {}
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct GetPayload {
-    key: String,
+// Registration in Consul (https://developer.hashicorp.com/consul/api-docs/agent/service).
+// The service gets a TTL health check that is kept passing by periodic heartbeats, so Consul
+// notices a server that died without deregistering.
+struct Discovery {
+    client: reqwest::Client,
+    url: String,
+    service_name: String,
+    service_id: String,
+    address: SocketAddr,
 }
 
-async fn get(
-    State(state): State<Arc<RwLock<AppState>>>,
-    extract::Json(payload): extract::Json<GetPayload>,
-) -> impl IntoResponse {
-    match state.read().await.cache.get(&payload.key).await {
-        Some(val) => (StatusCode::OK, val.clone()),
-        None => (StatusCode::NOT_FOUND, String::new()),
+impl Discovery {
+    const CHECK_TTL: Duration = Duration::from_secs(30);
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+    fn new(url: String, service_name: String, address: SocketAddr) -> Self {
+        Discovery {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            service_id: format!("{}-{}", service_name, address),
+            service_name,
+            address,
+        }
     }
-}
 
-#[cfg(test)]
-mod app_tests {
-    use super::*;
-    use axum::http::StatusCode;
-    use axum_test::TestServer;
-    use tmpdir::TmpDir;
+    async fn register(&self) -> Result<(), reqwest::Error> {
+        let registration = serde_json::json!({
+            "ID": self.service_id,
+            "Name": self.service_name,
+            "Address": self.address.ip().to_string(),
+            "Port": self.address.port(),
+            "Check": {
+                "TTL": format!("{}s", Self::CHECK_TTL.as_secs()),
+            },
+        });
+        self.client
+            .put(format!("{}/v1/agent/service/register", self.url))
+            .json(&registration)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 
-    struct Apps {
-        _tmp_dir: TmpDir, // guards temporary directory and removes it after testing
-        apps: [axum::routing::IntoMakeService<Router>; 2],
+    async fn heartbeat(&self) -> Result<(), reqwest::Error> {
+        self.client
+            .put(format!(
+                "{}/v1/agent/check/pass/service:{}",
+                self.url, self.service_id
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
     }
 
-    impl Apps {
-        async fn new() -> Self {
-            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
-            let tmp_dir_path = tmp_dir.to_path_buf();
-            Self {
-                _tmp_dir: tmp_dir,
-                apps: [
-                    app(AppState {
-                        cache: Box::new(MemCache::new()),
-                    }),
-                    app(AppState {
-                        cache: Box::new(DiskCache::new(tmp_dir_path)),
-                    }),
-                ],
+    async fn heartbeat_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Self::HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.heartbeat().await {
+                eprintln!("Service discovery heartbeat failed: {}", err);
             }
         }
     }
 
-    #[tokio::test]
-    async fn empty_list() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
-
-            let response = server.get("/list").await;
-            assert_eq!(response.status_code(), StatusCode::OK);
-            assert_eq!(response.text(), "{}");
-        }
+    async fn deregister(&self) -> Result<(), reqwest::Error> {
+        self.client
+            .put(format!(
+                "{}/v1/agent/service/deregister/{}",
+                self.url, self.service_id
+            ))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
     }
+}
 
-    #[tokio::test]
-    async fn add() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+struct AppState {
+    cache: Box<dyn Cache + Send + Sync>,
+    dedup: Option<Arc<Deduplicator>>,
+    write_queue: Arc<WriteQueue>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    max_value_bytes: Option<usize>,
+    max_body_bytes: Option<usize>, // see limit_body_size()
+    // Values have to be JSON documents valid against it
+    value_schema: Option<Arc<jsonschema::JSONSchema>>,
+    max_entries: Option<usize>,
+    quotas: Arc<HashMap<String, Quota>>, // by the namespace, "" is the default one
+    fetcher: Arc<Fetcher>,
+    webhook: Option<Webhook>,
+    replica: Option<Replica>,
+    changes: ChangeFeed,
+    list_limiter: Option<Arc<ListLimiter>>,
+    request_limiter: Option<Arc<Semaphore>>,
+    journal: Option<Arc<Journal>>,
+    authenticator: Option<Arc<dyn Authenticator + Send + Sync>>,
+    require_auth_for_reads: bool,
+    max_entry_age: Option<Duration>,
+    value_history: usize,             // 0 disables keeping the previous values
+    sweep_interval: Option<Duration>, // None disables removing the expired entries in background
+    response_headers: Vec<(HeaderName, HeaderValue)>,
+    cors_allow_origins: Vec<HeaderValue>, // empty disables CORS
+    // Cache-Control of the reads of the values (see set_cache_control())
+    default_cache_control: Option<HeaderValue>,
+    max_key_bytes: Option<usize>,
+    key_pattern: Option<regex::Regex>, // anchored (see parse_key_pattern())
+    miss_body: MissBody,
+    // Set when the cache does not know its keys
+    list_disabled: bool,
+    counters: Arc<RequestCounters>,
+    throughput: Arc<Throughput>,
+    lookups: Arc<LookupCounters>,
+    refuse_delete_aliased: bool,
+    // The routes that change the cache are rejected with 403
+    read_only: bool,
+    case_insensitive_routes: bool, // see RouteNormalizer
+    key_locks: KeyLocks,
+    namespace: Option<String>, // None for the default namespace
+    namespaces: Namespaces,
+}
 
-            let request = server.put("/add").json(&AddPayload {
-                key: "some key".to_string(),
-                value: "a value".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+// Separate keyspaces served under /<namespace>/..., each with its own cache (see Cache::namespace)
+// and otherwise the configuration of the default namespace. They are created by the first write
+// (or read, if the storage has them from before) and live until the server stops.
+#[derive(Default)]
+struct Namespaces {
+    states: RwLock<HashMap<String, Arc<AppState>>>,
+    // Creating more of them is rejected, as each takes storage and background tasks
+    max_count: Option<usize>,
+    // The first segments of the routes, such namespaces would be ambiguous
+    reserved: BTreeSet<&'static str>,
+}
 
-            let response = server.get("/list").await;
-            assert_eq!(response.status_code(), StatusCode::OK);
-            assert_eq!(response.text(), r#"{"some key":"a value"}"#);
+impl AppState {
+    fn new(cache: Box<dyn Cache + Send + Sync>) -> Self {
+        AppState {
+            cache,
+            dedup: None,
+            write_queue: Arc::new(WriteQueue::new(None, None)),
+            rate_limiter: None,
+            max_value_bytes: None,
+            max_body_bytes: None,
+            value_schema: None,
+            max_entries: None,
+            quotas: Arc::default(),
+            fetcher: Arc::new(Fetcher::new(vec![])),
+            webhook: None,
+            replica: None,
+            changes: ChangeFeed::new(),
+            list_limiter: None,
+            request_limiter: None,
+            journal: None,
+            authenticator: None,
+            require_auth_for_reads: false,
+            max_entry_age: None,
+            value_history: 0,
+            sweep_interval: None,
+            response_headers: vec![],
+            cors_allow_origins: vec![],
+            default_cache_control: None,
+            max_key_bytes: None,
+            key_pattern: None,
+            miss_body: MissBody::Empty,
+            list_disabled: false,
+            counters: Arc::new(RequestCounters::new([])),
+            throughput: Arc::new(Throughput::new()),
+            lookups: Arc::default(),
+            refuse_delete_aliased: false,
+            read_only: false,
+            case_insensitive_routes: false,
+            key_locks: KeyLocks::new(),
+            namespace: None,
+            namespaces: Namespaces::default(),
         }
     }
 
-    #[tokio::test]
-    async fn add_two_values() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+    // Returns the state of the namespace, creating it if needed. Without create, a namespace that
+    // does not exist is served by an empty state that is not kept, so that e.g. reads don't leave
+    // anything behind.
+    async fn namespace(
+        &self,
+        name: &str,
+        create: bool,
+    ) -> Result<Arc<AppState>, response::Response> {
+        validate_namespace(name, &self.namespaces.reserved).map_err(|err| err.into_response())?;
+        if let Some(state) = self.namespaces.states.read().await.get(name) {
+            return Ok(state.clone());
+        }
+        let exists = self
+            .cache
+            .has_namespace(name)
+            .await
+            .map_err(|err| err.into_response())?;
+        if !create && !exists {
+            return Ok(Arc::new(self.child(name, Box::new(MemCache::new()))));
+        }
+        let mut states = self.namespaces.states.write().await;
+        if let Some(state) = states.get(name) {
+            return Ok(state.clone());
+        }
+        if let Some(max_count) = self.namespaces.max_count {
+            if !exists && states.len() >= max_count {
+                let error = format!("there are already {} namespaces", max_count);
+                return Err(error_response(StatusCode::FORBIDDEN, error));
+            }
+        }
+        let cache = self
+            .cache
+            .namespace(name)
+            .await
+            .map_err(|err| err.into_response())?;
+        let state = Arc::new(self.child(name, cache));
+        spawn_background_tasks(&state);
+        states.insert(name.to_string(), state.clone());
+        Ok(state)
+    }
 
-            let request = server.put("/add").json(&AddPayload {
-                key: "a".to_string(),
-                value: "x".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+    // The state of the namespace, with the configuration of this one
+    fn child(&self, name: &str, cache: Box<dyn Cache + Send + Sync>) -> AppState {
+        AppState {
+            cache,
+            dedup: self.dedup.clone(),
+            write_queue: self.write_queue.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            max_value_bytes: self.max_value_bytes,
+            max_body_bytes: self.max_body_bytes,
+            value_schema: self.value_schema.clone(),
+            max_entries: self.max_entries,
+            quotas: self.quotas.clone(),
+            fetcher: self.fetcher.clone(),
+            webhook: self.webhook.clone(),
+            replica: self.replica.clone(),
+            changes: self.changes.clone(),
+            list_limiter: self.list_limiter.clone(),
+            request_limiter: self.request_limiter.clone(),
+            journal: self.journal.clone(),
+            authenticator: self.authenticator.clone(),
+            require_auth_for_reads: self.require_auth_for_reads,
+            max_entry_age: self.max_entry_age,
+            value_history: self.value_history,
+            sweep_interval: self.sweep_interval,
+            response_headers: self.response_headers.clone(),
+            cors_allow_origins: self.cors_allow_origins.clone(),
+            default_cache_control: self.default_cache_control.clone(),
+            max_key_bytes: self.max_key_bytes,
+            key_pattern: self.key_pattern.clone(),
+            miss_body: self.miss_body.clone(),
+            list_disabled: self.list_disabled,
+            counters: self.counters.clone(),
+            throughput: self.throughput.clone(),
+            lookups: self.lookups.clone(),
+            refuse_delete_aliased: self.refuse_delete_aliased,
+            read_only: self.read_only,
+            case_insensitive_routes: self.case_insensitive_routes,
+            key_locks: KeyLocks::new(),
+            namespace: Some(name.to_string()),
+            namespaces: Namespaces::default(),
+        }
+    }
 
-            let request = server.put("/add").json(&AddPayload {
-                key: "b".to_string(),
-                value: "y".to_string(),
+    // Like Cache::add_with_content_type(), but with value_history the current value of the key goes
+    // to the history of the new one. Callers hold the lock of the key.
+    async fn add_entry(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        content_type: Option<String>,
+    ) -> Result<(), CacheError> {
+        self.add_entry_with_meta(key, value, expires_at, EntryMeta::new(content_type))
+            .await
+    }
+
+    // Like Cache::add_with_meta(), see add_entry()
+    async fn add_entry_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        mut meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        if self.value_history > 0 {
+            // An alias is replaced, its target has the history
+            if let Some(stored) = self.cache.get_with_meta(&key).await? {
+                if !stored.aliased {
+                    meta.history = self.history_with(stored);
+                }
+            }
+        }
+        self.cache.add_with_meta(key, value, expires_at, meta).await
+    }
+
+    // Like Cache::modify(), see add_entry()
+    async fn modify_entry(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        if self.value_history == 0 {
+            return self.cache.modify(key, value).await;
+        }
+        match self.cache.get_with_meta(&key).await? {
+            Some(stored) if !stored.aliased => {
+                let expires_at = stored.expires_at;
+                let mut meta = stored.meta.clone().updated();
+                meta.history = self.history_with(stored);
+                self.cache
+                    .add_with_meta(key, value, expires_at, meta)
+                    .await?;
+                Ok(true)
+            }
+            _ => self.cache.modify(key, value).await,
+        }
+    }
+
+    // Like Cache::append(), see add_entry()
+    async fn append_entry(&self, key: String, suffix: Vec<u8>) -> Result<bool, CacheError> {
+        if self.value_history == 0 {
+            return self.cache.append(key, suffix).await;
+        }
+        let Some(mut value) = self.cache.get(&key).await? else {
+            return Ok(false);
+        };
+        value.extend(suffix);
+        self.modify_entry(key, value).await
+    }
+
+    // Like Cache::compare_and_swap(), see add_entry()
+    async fn compare_and_swap_entry(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        if self.value_history == 0 {
+            return self.cache.compare_and_swap(key, expected, new).await;
+        }
+        match self.cache.get(&key).await? {
+            None => Ok(CasResult::NotFound),
+            Some(current) if current != expected => Ok(CasResult::Mismatch),
+            Some(_) => {
+                self.modify_entry(key, new).await?;
+                Ok(CasResult::Swapped)
+            }
+        }
+    }
+
+    // Like Cache::increment(), see add_entry()
+    async fn increment_entry(&self, key: String, delta: i64) -> Result<i64, IncrError> {
+        if self.value_history == 0 {
+            return self.cache.increment(key, delta).await;
+        }
+        let value = self.cache.get(&key).await?;
+        let new = incremented(value.as_deref(), delta)?;
+        if value.is_some() {
+            self.modify_entry(key, new.to_string().into_bytes()).await?;
+        } else {
+            self.add_entry(key, new.to_string().into_bytes(), None, None)
+                .await?;
+        }
+        Ok(new)
+    }
+
+    // The history of stored with its value at the front, without the versions over value_history
+    fn history_with(&self, stored: StoredValue) -> Vec<PastValue> {
+        let mut history = stored.meta.history;
+        history.insert(
+            0,
+            PastValue {
+                value: JsonBytes(stored.value),
+                updated_at_ms: stored.meta.updated_at_ms,
+            },
+        );
+        history.truncate(self.value_history);
+        history
+    }
+
+    // Returns false if creating the entries of the new ones among the keys would exceed
+    // max_entries. Callers hold the locks of the keys.
+    async fn has_room_for(&self, keys: &[&str]) -> Result<bool, CacheError> {
+        let Some(max_entries) = self.max_entries else {
+            return Ok(true);
+        };
+        let mut new_keys = BTreeSet::new();
+        for &key in keys {
+            if self.cache.get(key).await?.is_none() {
+                new_keys.insert(key);
+            }
+        }
+        Ok(new_keys.is_empty() || self.cache.len().await? + new_keys.len() <= max_entries)
+    }
+
+    fn quota(&self) -> Option<&Quota> {
+        self.quotas.get(self.namespace.as_deref().unwrap_or(""))
+    }
+
+    // Returns the error if setting the key to a value of value_bytes would exceed the quota of the
+    // namespace. Callers hold all the keys locked (if there is a quota, see lock_for_write()) until
+    // the value is written, otherwise concurrent writes could all pass the check and exceed the
+    // quota together.
+    async fn check_quota(
+        &self,
+        key: &str,
+        value_bytes: usize,
+    ) -> Result<Option<QuotaExceeded>, CacheError> {
+        self.check_quota_of_writes(&[(key, Some(value_bytes))])
+            .await
+    }
+
+    // Like check_quota(), for the writes applied in order, each setting the key to a value of the
+    // given size or deleting it (None)
+    async fn check_quota_of_writes(
+        &self,
+        writes: &[(&str, Option<usize>)],
+    ) -> Result<Option<QuotaExceeded>, CacheError> {
+        let namespace = self.namespace.as_deref().unwrap_or("");
+        let Some(quota) = self.quota() else {
+            return Ok(None);
+        };
+        // The last write of a key wins
+        let writes = writes.iter().copied().collect::<HashMap<_, _>>();
+        let stats = self.cache.stats().await?;
+        let (mut entry_count, mut total_bytes) = (stats.entry_count, stats.total_value_bytes);
+        for (key, value_bytes) in writes {
+            if let Some(current) = self.cache.get(key).await? {
+                entry_count = entry_count.saturating_sub(1);
+                total_bytes = total_bytes.saturating_sub(current.len());
+            }
+            if let Some(value_bytes) = value_bytes {
+                entry_count += 1;
+                total_bytes += value_bytes;
+            }
+        }
+        if quota.max_entries.is_some_and(|max| entry_count > max)
+            || quota.max_bytes.is_some_and(|max| total_bytes > max)
+        {
+            return Ok(Some(QuotaExceeded {
+                namespace: namespace.to_string(),
+            }));
+        }
+        Ok(None)
+    }
+
+    // Locks the key for a write, with a quota all the keys are locked (see check_quota())
+    async fn lock_for_write(&self, key: &str) -> WriteLock<'_> {
+        if self.quota().is_some() {
+            (Some(self.key_locks.write_all().await), None)
+        } else {
+            (None, Some(self.key_locks.write(key).await))
+        }
+    }
+
+    // Called after every successful mutation with the key locked, so the mutations of every key are
+    // recorded in the order they happened. Fails if the journal cannot be written, the mutation is
+    // not undone then.
+    async fn record_mutation(
+        &self,
+        op: MutationOp,
+        key: &str,
+        value: Option<&[u8]>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<(), CacheError> {
+        self.record_mutation_with_content_type(op, key, value, expires_at, None)
+            .await
+    }
+
+    // Like record_mutation(), but with the content type of the added entry
+    async fn record_mutation_with_content_type(
+        &self,
+        op: MutationOp,
+        key: &str,
+        value: Option<&[u8]>,
+        expires_at: Option<SystemTime>,
+        content_type: Option<&str>,
+    ) -> Result<(), CacheError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        if let Some(journal) = &self.journal {
+            journal
+                .append(JournalRecord {
+                    timestamp_ms: 0, // set by append()
+                    op,
+                    namespace: self.namespace.clone(),
+                    key: key.to_string(),
+                    value: value.map(|value| JsonBytes(value.to_vec())),
+                    expires_at_ms: expires_at.map(|expires_at| {
+                        expires_at
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap()
+                            .as_millis() as u64
+                    }),
+                    content_type: content_type.map(str::to_string),
+                })
+                .await?;
+        }
+        self.changes.publish(MutationEvent {
+            op,
+            namespace: self.namespace.clone(),
+            key: key.to_string(),
+            timestamp: now.as_secs(),
+            value: None,
+        });
+        if let Some(webhook) = &self.webhook {
+            webhook.send(MutationEvent {
+                op,
+                namespace: self.namespace.clone(),
+                key: key.to_string(),
+                timestamp: now.as_secs(),
+                value: value
+                    .filter(|_| webhook.include_value)
+                    .map(|value| JsonBytes(value.to_vec())),
+            });
+        }
+        if let Some(replica) = &self.replica {
+            let write = match (op, value) {
+                (MutationOp::Add, Some(value)) => ReplicatedOp::Add {
+                    value: value.to_vec(),
+                    expires_at,
+                },
+                (MutationOp::Modify, Some(value)) => ReplicatedOp::Modify {
+                    value: value.to_vec(),
+                },
+                (MutationOp::Delete, _) => ReplicatedOp::Delete,
+                _ => return Ok(()),
+            };
+            replica.send(ReplicatedWrite {
+                namespace: self.namespace.clone(),
+                key: key.to_string(),
+                op: write,
+            });
+        }
+        Ok(())
+    }
+}
+
+// Replaces a global lock around the cache, so that e.g. a slow DiskCache write does not block the
+// requests for other keys. Single-key operations hold the lock of their key (shared for reads) and
+// the global lock shared, operations touching many keys (transactions, aliases, the entry GC) hold
+// the global lock exclusively. See Cache for what the implementations may assume.
+struct KeyLocks {
+    all: RwLock<()>,
+    // Keys are assigned by the first byte of their hash, so keys that DiskCache maps to the same
+    // file (even with a truncated hash) always share the lock
+    keys: Vec<RwLock<()>>,
+}
+
+impl KeyLocks {
+    fn new() -> Self {
+        KeyLocks {
+            all: RwLock::new(()),
+            keys: (0..256).map(|_| RwLock::new(())).collect(),
+        }
+    }
+
+    fn key_lock(&self, key: &str) -> &RwLock<()> {
+        &self.keys[Self::index(key)]
+    }
+
+    fn index(key: &str) -> usize {
+        blake3::hash(key.as_bytes()).as_bytes()[0] as usize
+    }
+
+    // Locks all the keys whose hash starts with the byte, e.g. to delete a DiskCache file without
+    // knowing its key
+    async fn write_hash_byte(
+        &self,
+        hash_byte: u8,
+    ) -> (RwLockReadGuard<'_, ()>, RwLockWriteGuard<'_, ()>) {
+        (
+            self.all.read().await,
+            self.keys[hash_byte as usize].write().await,
+        )
+    }
+
+    async fn read(&self, key: &str) -> (RwLockReadGuard<'_, ()>, RwLockReadGuard<'_, ()>) {
+        (self.all.read().await, self.key_lock(key).read().await)
+    }
+
+    async fn write(&self, key: &str) -> (RwLockReadGuard<'_, ()>, RwLockWriteGuard<'_, ()>) {
+        (self.all.read().await, self.key_lock(key).write().await)
+    }
+
+    async fn write_all(&self) -> RwLockWriteGuard<'_, ()> {
+        self.all.write().await
+    }
+
+    // Locks the keys for reading together, e.g. to read them consistently. The locks are taken in
+    // the order of their index, so that two such callers cannot deadlock.
+    async fn read_many(
+        &self,
+        keys: &[String],
+    ) -> (RwLockReadGuard<'_, ()>, Vec<RwLockReadGuard<'_, ()>>) {
+        let all = self.all.read().await;
+        let indices = keys
+            .iter()
+            .map(|key| Self::index(key))
+            .collect::<BTreeSet<_>>();
+        let mut guards = Vec::with_capacity(indices.len());
+        for index in indices {
+            guards.push(self.keys[index].read().await);
+        }
+        (all, guards)
+    }
+}
+
+// See AppState::lock_for_write()
+type WriteLock<'a> = (
+    Option<RwLockWriteGuard<'a, ()>>,
+    Option<(RwLockReadGuard<'a, ()>, RwLockWriteGuard<'a, ()>)>,
+);
+
+type App = IntoMakeServiceWithConnectInfo<Router, SocketAddr>;
+
+// As a function to facilitate testing
+fn app(app_state: AppState) -> App {
+    with_middleware(routes(app_state)).into_make_service_with_connect_info::<SocketAddr>()
+}
+
+fn routes(mut app_state: AppState) -> Router {
+    let handlers = [
+        ("/add", routing::put(add)),
+        ("/add-from-url", routing::post(add_from_url)),
+        ("/add-if-absent", routing::put(add_if_absent)),
+        ("/alias", routing::post(alias)),
+        ("/append", routing::post(append)),
+        ("/bulk/add", routing::put(bulk_add)),
+        ("/bulk/delete", routing::delete(bulk_delete)),
+        ("/bulk/get", routing::post(bulk_get)),
+        ("/cas", routing::patch(cas)),
+        ("/compact", routing::post(compact)),
+        ("/counters", routing::get(counters)),
+        ("/counters/reset", routing::post(reset_counters)),
+        ("/delete", routing::delete(delete)),
+        ("/delete/*key", routing::delete(delete_by_path)),
+        ("/delete-prefix", routing::delete(delete_prefix)),
+        ("/dump.csv", routing::get(dump_csv)),
+        ("/exists/*key", routing::get(exists)),
+        ("/flush", routing::post(flush)),
+        ("/get", routing::get(get)),
+        ("/get/*key", routing::get(get_by_path)),
+        ("/health", routing::get(health)),
+        ("/history/*key", routing::get(history)),
+        ("/incr", routing::post(incr)),
+        ("/list", routing::get(list)),
+        ("/merge", routing::patch(merge)),
+        ("/metrics", routing::get(metrics)),
+        ("/mget", routing::get(mget)),
+        ("/modify", routing::patch(modify)),
+        ("/ready", routing::get(ready)),
+        ("/rename", routing::post(rename)),
+        ("/restore", routing::post(restore)),
+        ("/rpc", routing::post(rpc)),
+        ("/snapshot", routing::get(snapshot)),
+        ("/stats", routing::get(stats)),
+        ("/subscribe", routing::get(subscribe)),
+        ("/throughput", routing::get(throughput)),
+        ("/touch", routing::post(touch)),
+        ("/txn", routing::post(txn)),
+        ("/version", routing::get(version)),
+    ];
+    app_state.namespaces.reserved = handlers
+        .iter()
+        .filter_map(|(path, _)| path[1..].split('/').next())
+        .collect();
+    let namespaced = handlers
+        .iter()
+        .filter(|(path, _)| !GLOBAL_ROUTES.contains(path))
+        .map(|(path, handler)| (format!("/:namespace{}", path), handler.clone()))
+        .collect::<Vec<_>>();
+    let handlers = handlers
+        .into_iter()
+        .map(|(path, handler)| (path.to_string(), handler))
+        .chain(namespaced)
+        .collect::<Vec<_>>();
+    app_state.counters = Arc::new(RequestCounters::new(
+        handlers.iter().map(|(path, _)| path.clone()),
+    ));
+    let normalizer = RouteNormalizer {
+        routes: handlers.iter().map(|(path, _)| path.clone()).collect(),
+        case_insensitive: app_state.case_insensitive_routes,
+    };
+    let counters = app_state.counters.clone();
+    let throughput = app_state.throughput.clone();
+    let dedup = app_state.dedup.clone();
+    let max_body_bytes = app_state.max_body_bytes;
+    let write_queue = app_state.write_queue.clone();
+    let rate_limiter = app_state.rate_limiter.clone();
+    let request_limiter = app_state.request_limiter.clone();
+    let auth = app_state
+        .authenticator
+        .clone()
+        .map(|authenticator| AuthPolicy {
+            authenticator,
+            require_for_reads: app_state.require_auth_for_reads,
+        });
+    let response_headers = app_state.response_headers.clone();
+    let cors = cors_layer(&app_state.cors_allow_origins);
+    let read_only = app_state.read_only;
+    let default_cache_control = app_state.default_cache_control.clone();
+    let state = Arc::new(app_state);
+    spawn_background_tasks(&state);
+    let router = handlers
+        .into_iter()
+        .fold(Router::new(), |router, (path, handler)| {
+            router.route(&path, handler)
+        })
+        .with_state(state)
+        .layer(middleware::from_fn_with_state(
+            write_queue,
+            limit_write_queue,
+        ));
+    let router = match read_only {
+        true => router.layer(middleware::from_fn(reject_mutations)),
+        false => router,
+    };
+    let router = router.layer(middleware::from_fn_with_state(
+        default_cache_control,
+        set_cache_control,
+    ));
+    let router = match dedup {
+        Some(dedup) => router.layer(middleware::from_fn_with_state(dedup, deduplicate)),
+        None => router,
+    };
+    // Outside of deduplicate(), as it buffers the body too
+    let router = match max_body_bytes {
+        Some(max_bytes) => router.layer(middleware::from_fn_with_state(max_bytes, limit_body_size)),
+        None => router,
+    };
+    let router = match auth {
+        Some(auth) => router.layer(middleware::from_fn_with_state(Arc::new(auth), require_auth)),
+        None => router,
+    };
+    // Before authentication, so that it is not a way to flood the server either
+    let router = match rate_limiter {
+        Some(rate_limiter) => {
+            tokio::spawn(RateLimiter::cleanup_loop(Arc::downgrade(&rate_limiter)));
+            router.layer(middleware::from_fn_with_state(rate_limiter, limit_rate))
+        }
+        None => router,
+    };
+    // Inside of count_requests(), so that the rejected requests are counted too
+    let router = match request_limiter {
+        Some(request_limiter) => router.layer(middleware::from_fn_with_state(
+            request_limiter,
+            limit_concurrency,
+        )),
+        None => router,
+    };
+    let router = router
+        .layer(middleware::from_fn_with_state(counters, count_requests))
+        .layer(middleware::from_fn_with_state(
+            throughput,
+            measure_throughput,
+        ))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(tracing::Level::INFO))
+                .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
+        )
+        // gzip or brotli, whichever the client accepts (tiny responses are left as they are)
+        .layer(CompressionLayer::new());
+    // Answers the preflight requests itself, as they carry no credentials
+    let router = match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    };
+    // Outermost, so that also the rejections of the other layers get the headers
+    let router = response_headers
+        .into_iter()
+        .fold(router, |router, (name, value)| {
+            router.layer(SetResponseHeaderLayer::overriding(name, value))
+        });
+    // The layers of a router run after the routing, so the rewriting router forwards everything to
+    // the actual one
+    Router::new()
+        .fallback_service(router)
+        .layer(middleware::from_fn_with_state(
+            Arc::new(normalizer),
+            normalize_path,
+        ))
+}
+
+// Rewrites the paths that would not match any route only because of trailing slashes (e.g. /list/)
+// or, with case_insensitive, the case of the route (e.g. /Add), to the path of the route. The paths
+// that match a route are left as they are, e.g. keys ending with a slash in /get/<key>.
+struct RouteNormalizer {
+    routes: Vec<String>,
+    case_insensitive: bool,
+}
+
+impl RouteNormalizer {
+    fn normalize(&self, path: &str) -> Option<String> {
+        if self.find(path, false).is_some() {
+            return None;
+        }
+        let trimmed = path.trim_end_matches('/');
+        [path, trimmed]
+            .into_iter()
+            .filter(|path| !path.is_empty())
+            .find_map(|path| self.find(path, self.case_insensitive))
+    }
+
+    // Returns the path with the segments of the first matching route in its case
+    fn find(&self, path: &str, ignore_case: bool) -> Option<String> {
+        let segments = path.strip_prefix('/')?.split('/').collect::<Vec<_>>();
+        self.routes.iter().find_map(|route| {
+            let route_segments = route[1..].split('/').collect::<Vec<_>>();
+            let mut normalized = vec![];
+            for (i, &route_segment) in route_segments.iter().enumerate() {
+                if route_segment.starts_with('*') {
+                    let rest = segments.get(i..).filter(|rest| !rest.is_empty())?;
+                    normalized.extend_from_slice(rest);
+                    return Some(format!("/{}", normalized.join("/")));
+                }
+                let segment = *segments.get(i)?;
+                if route_segment.starts_with(':') && !segment.is_empty() {
+                    normalized.push(segment);
+                } else if segment == route_segment
+                    || (ignore_case && segment.eq_ignore_ascii_case(route_segment))
+                {
+                    normalized.push(route_segment);
+                } else {
+                    return None;
+                }
+            }
+            (route_segments.len() == segments.len()).then(|| format!("/{}", normalized.join("/")))
+        })
+    }
+}
+
+async fn normalize_path(
+    State(normalizer): State<Arc<RouteNormalizer>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    if let Some(path) = normalizer.normalize(request.uri().path()) {
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().unwrap());
+        *request.uri_mut() = Uri::from_parts(parts).unwrap();
+    }
+    next.run(request).await
+}
+
+fn cors_layer(allow_origins: &[HeaderValue]) -> Option<CorsLayer> {
+    if allow_origins.is_empty() {
+        return None;
+    }
+    let allow_origin = match allow_origins.iter().any(|origin| origin == "*") {
+        true => AllowOrigin::any(),
+        false => AllowOrigin::list(allow_origins.iter().cloned()),
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ])
+            .allow_headers([
+                header::ACCEPT,
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::IF_NONE_MATCH,
+            ])
+            .expose_headers([
+                header::ETAG,
+                header::LOCATION,
+                header::RETRY_AFTER,
+                HeaderName::from_static(TTL_HEADER),
+            ]),
+    )
+}
+
+// Routes that are not served per namespace
+const GLOBAL_ROUTES: [&str; 7] = [
+    "/counters",
+    "/counters/reset",
+    "/health",
+    "/metrics",
+    "/ready",
+    "/throughput",
+    "/version",
+];
+
+// Resolves the state of the namespace from the path of the namespaced routes
+struct NsState(Arc<AppState>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for NsState {
+    type Rejection = response::Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let params = extract::RawPathParams::from_request_parts(parts, state)
+            .await
+            .map_err(|rejection| rejection.into_response())?;
+        match params.iter().find(|(name, _)| *name == "namespace") {
+            Some((_, namespace)) => {
+                let create = !is_read(&parts.extensions, &parts.uri);
+                Ok(NsState(state.namespace(namespace, create).await?))
+            }
+            None => Ok(NsState(state.clone())),
+        }
+    }
+}
+
+// Like extract::Json, but the rejections have the {"error": ...} body of the other errors. A body that
+// is not JSON or does not match the payload is a 400, not axum's 422.
+struct JsonBody<T>(T);
+
+#[async_trait]
+impl<T: serde::de::DeserializeOwned, S: Send + Sync> FromRequest<S, Body> for JsonBody<T> {
+    type Rejection = response::Response;
+
+    async fn from_request(request: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        match extract::Json::<T>::from_request(request, state).await {
+            Ok(extract::Json(payload)) => Ok(JsonBody(payload)),
+            Err(rejection) => {
+                let status = match rejection {
+                    JsonRejection::JsonDataError(_) | JsonRejection::JsonSyntaxError(_) => {
+                        StatusCode::BAD_REQUEST
+                    }
+                    _ => rejection.status(),
+                };
+                Err(error_response(status, rejection.body_text()))
+            }
+        }
+    }
+}
+
+fn spawn_background_tasks(state: &Arc<AppState>) {
+    if let Some(max_age) = state.max_entry_age {
+        tokio::spawn(entry_gc_loop(state.clone(), max_age));
+    }
+    if let Some(interval) = state.sweep_interval {
+        tokio::spawn(sweep_expired_loop(state.clone(), interval));
+    }
+}
+
+// Operator-wide cleanup of stale entries, regardless of what the clients do
+async fn entry_gc_loop(state: Arc<AppState>, max_age: Duration) {
+    const MAX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    let mut interval = tokio::time::interval(max_age.min(MAX_INTERVAL));
+    loop {
+        interval.tick().await;
+        let removed = {
+            let _lock = state.key_locks.write_all().await;
+            state.cache.remove_older_than(max_age).await
+        };
+        let removed = match removed {
+            Ok(removed) => removed,
+            Err(err) => {
+                eprintln!("Failed to remove old entries: {}", err);
+                continue;
+            }
+        };
+        if removed > 0 {
+            println!(
+                "Removed {} entries not modified for {}s",
+                removed,
+                max_age.as_secs()
+            );
+        }
+    }
+}
+
+async fn sweep_expired_loop(state: Arc<AppState>, interval: Duration) {
+    // The lock is released between the batches, so the requests are not starved
+    const BATCH_SIZE: usize = 100;
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let mut removed = 0;
+        loop {
+            let batch_removed = state
+                .cache
+                .remove_expired(BATCH_SIZE, &state.key_locks)
+                .await;
+            let batch_removed = match batch_removed {
+                Ok(batch_removed) => batch_removed,
+                Err(err) => {
+                    eprintln!("Failed to remove expired entries: {}", err);
+                    break;
+                }
+            };
+            removed += batch_removed;
+            if batch_removed < BATCH_SIZE {
+                break;
+            }
+        }
+        if removed > 0 {
+            println!("Removed {} expired entries", removed);
+        }
+    }
+}
+
+// Layers applied to every route (separated from routes() so that tests can add their own routes)
+fn with_middleware(router: Router) -> Router {
+    router.layer(CatchPanicLayer::custom(handle_panic))
+}
+
+// A bug in a handler must not take the whole worker down - it is reported as 500 instead
+fn handle_panic(err: Box<dyn std::any::Any + Send + 'static>) -> response::Response {
+    let msg = if let Some(s) = err.downcast_ref::<String>() {
+        s.as_str()
+    } else if let Some(s) = err.downcast_ref::<&str>() {
+        s
+    } else {
+        "unknown panic payload"
+    };
+    tracing::error!(
+        "handler panicked: {}\n{}",
+        msg,
+        std::backtrace::Backtrace::force_capture()
+    );
+    error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal error")
+}
+
+// Who made the request, inserted into the request extensions by require_auth()
+#[derive(Debug, Clone, PartialEq)]
+struct Identity {
+    name: String,
+}
+
+#[derive(Debug)]
+enum AuthError {
+    MissingCredentials,
+    InvalidCredentials,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> response::Response {
+        let msg = match self {
+            AuthError::MissingCredentials => "missing bearer token",
+            AuthError::InvalidCredentials => "invalid bearer token",
+        };
+        let mut response = error_response(StatusCode::UNAUTHORIZED, msg);
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        response
+    }
+}
+
+// Decides who made the request. To support another scheme, implement this trait and set
+// AppState::authenticator to it - require_auth() works with any implementation.
+#[async_trait]
+trait Authenticator {
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<Identity, AuthError>;
+}
+
+fn bearer_token(headers: &HeaderMap) -> Result<&str, AuthError> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AuthError::MissingCredentials)
+}
+
+// A single shared secret
+struct StaticTokenAuth {
+    token_hash: blake3::Hash,
+}
+
+impl StaticTokenAuth {
+    fn new(token: &str) -> Self {
+        StaticTokenAuth {
+            token_hash: blake3::hash(token.as_bytes()),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticTokenAuth {
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<Identity, AuthError> {
+        // Comparing hashes (which is constant-time) doesn't leak the token through timing
+        if blake3::hash(bearer_token(headers)?.as_bytes()) == self.token_hash {
+            Ok(Identity {
+                name: "api-key".to_string(),
+            })
+        } else {
+            Err(AuthError::InvalidCredentials)
+        }
+    }
+}
+
+// JWTs signed by one of the keys published at the JWKS URL, the identity is the "sub" claim. The
+// keys are fetched on first use and refetched when a token refers to an unknown key (but not more
+// often than MIN_REFRESH_INTERVAL, so that bogus tokens can't make us hammer the JWKS endpoint).
+struct JwtAuth {
+    client: reqwest::Client,
+    jwks_url: String,
+    audience: Option<String>,
+    jwks: RwLock<(jsonwebtoken::jwk::JwkSet, Option<Instant>)>,
+}
+
+#[derive(Deserialize)]
+struct JwtClaims {
+    sub: String,
+}
+
+impl JwtAuth {
+    const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+    fn new(jwks_url: String, audience: Option<String>) -> Self {
+        JwtAuth {
+            client: reqwest::Client::new(),
+            jwks_url,
+            audience,
+            jwks: RwLock::new((jsonwebtoken::jwk::JwkSet { keys: vec![] }, None)),
+        }
+    }
+
+    async fn find_key(&self, kid: &str) -> Option<jsonwebtoken::jwk::Jwk> {
+        if let Some(jwk) = self.jwks.read().await.0.find(kid) {
+            return Some(jwk.clone());
+        }
+        let mut jwks = self.jwks.write().await;
+        if jwks
+            .1
+            .is_some_and(|fetched| fetched.elapsed() < Self::MIN_REFRESH_INTERVAL)
+        {
+            return jwks.0.find(kid).cloned();
+        }
+        jwks.1 = Some(Instant::now());
+        let res = async {
+            self.client
+                .get(&self.jwks_url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+        };
+        match res.await {
+            Ok(fetched) => jwks.0 = fetched,
+            Err(err) => eprintln!("Failed to fetch JWKS from {}: {}", self.jwks_url, err),
+        }
+        jwks.0.find(kid).cloned()
+    }
+}
+
+#[async_trait]
+impl Authenticator for JwtAuth {
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<Identity, AuthError> {
+        let token = bearer_token(headers)?;
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|_| AuthError::InvalidCredentials)?;
+        let kid = header.kid.ok_or(AuthError::InvalidCredentials)?;
+        let jwk = self
+            .find_key(&kid)
+            .await
+            .ok_or(AuthError::InvalidCredentials)?;
+        // The token must not be able to choose a different algorithm than the key is meant for
+        if jwk
+            .common
+            .key_algorithm
+            .is_some_and(|alg| alg.to_string() != format!("{:?}", header.alg))
+        {
+            return Err(AuthError::InvalidCredentials);
+        }
+        let key =
+            jsonwebtoken::DecodingKey::from_jwk(&jwk).map_err(|_| AuthError::InvalidCredentials)?;
+        let mut validation = jsonwebtoken::Validation::new(header.alg);
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<JwtClaims>(token, &key, &validation)
+            .map_err(|_| AuthError::InvalidCredentials)?
+            .claims;
+        Ok(Identity { name: claims.sub })
+    }
+}
+
+// Routes that don't change the cache (but see is_read())
+const READ_ROUTES: [&str; 13] = [
+    "/bulk/get",
+    "/counters",
+    "/dump.csv",
+    "/exists/*key",
+    "/get",
+    "/get/*key",
+    "/history/*key",
+    "/list",
+    "/metrics",
+    "/mget",
+    "/snapshot",
+    "/stats",
+    "/subscribe",
+];
+
+// Whether the request is to one of READ_ROUTES and does not write anyway: /get with the
+// default_and_set query parameter stores the value
+fn is_read(extensions: &Extensions, uri: &Uri) -> bool {
+    let Some(path) = extensions.get::<extract::MatchedPath>() else {
+        return false;
+    };
+    let path = path.as_str();
+    let path = path.strip_prefix("/:namespace").unwrap_or(path);
+    if !READ_ROUTES.contains(&path) {
+        return false;
+    }
+    if path != "/get" && path != "/get/*key" {
+        return true;
+    }
+    // Decoded like the handler decodes it, so that e.g. a percent-encoded name is caught too
+    let params = extract::Query::<Vec<(String, String)>>::try_from_uri(uri);
+    !params.is_ok_and(|extract::Query(params)| {
+        params.iter().any(|(name, _)| name == "default_and_set")
+    })
+}
+
+// The global routes don't touch the cache
+fn is_mutation<B>(request: &Request<B>) -> bool {
+    let touches_cache = request
+        .extensions()
+        .get::<extract::MatchedPath>()
+        .is_some_and(|path| !GLOBAL_ROUTES.contains(&path.as_str()));
+    touches_cache && !is_read(request.extensions(), request.uri())
+}
+
+// Used in the read-only mode
+async fn reject_mutations(request: Request<Body>, next: Next<Body>) -> response::Response {
+    if is_mutation(&request) {
+        return error_response(StatusCode::FORBIDDEN, "the server is read-only");
+    }
+    next.run(request).await
+}
+
+// The bodies of the mutations are buffered as a whole (by the extractors or deduplicate()), so a
+// large one could exhaust the memory. They are read here up to max_bytes, an announced
+// Content-Length over it is rejected without reading anything. /restore applies its body record by
+// record, it limits the size of a record instead.
+async fn limit_body_size(
+    State(max_bytes): State<usize>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    let is_restore = request
+        .extensions()
+        .get::<extract::MatchedPath>()
+        .is_some_and(|path| path.as_str().ends_with("/restore"));
+    if !is_mutation(&request) || is_restore {
+        return next.run(request).await;
+    }
+    let too_large = || {
+        let error = format!("the request body exceeds {} bytes", max_bytes);
+        error_response(StatusCode::PAYLOAD_TOO_LARGE, error)
+    };
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > max_bytes as u64) {
+        return too_large();
+    }
+    let (parts, mut body) = request.into_parts();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body::HttpBody::data(&mut body).await {
+        let Ok(chunk) = chunk else {
+            return error_response(StatusCode::BAD_REQUEST, "failed to read the request body");
+        };
+        if buf.len() + chunk.len() > max_bytes {
+            return too_large();
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    next.run(Request::from_parts(parts, Body::from(buf))).await
+}
+
+// Routes whose responses may be cached by the intermediaries
+const CACHEABLE_ROUTES: [&str; 4] = ["/get", "/get/*key", "/list", "/mget"];
+
+#[derive(Deserialize)]
+struct CacheControlQuery {
+    cache_control: Option<String>,
+}
+
+// The successful responses of CACHEABLE_ROUTES get the Cache-Control from the cache_control query
+// parameter or the default one, the responses of the mutations get "no-store"
+async fn set_cache_control(
+    State(default): State<Option<HeaderValue>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    if is_mutation(&request) {
+        let mut response = next.run(request).await;
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        return response;
+    }
+    let is_cacheable = request
+        .extensions()
+        .get::<extract::MatchedPath>()
+        .is_some_and(|path| {
+            let path = path.as_str();
+            CACHEABLE_ROUTES.contains(&path.strip_prefix("/:namespace").unwrap_or(path))
+        });
+    if !is_cacheable {
+        return next.run(request).await;
+    }
+    let cache_control = match extract::Query::<CacheControlQuery>::try_from_uri(request.uri()) {
+        Ok(extract::Query(CacheControlQuery {
+            cache_control: Some(cache_control),
+        })) => match HeaderValue::from_str(&cache_control) {
+            Ok(cache_control) => Some(cache_control),
+            Err(_) => {
+                return error_response(StatusCode::BAD_REQUEST, "invalid cache_control");
+            }
+        },
+        _ => default,
+    };
+    let mut response = next.run(request).await;
+    let status = response.status();
+    if let Some(cache_control) = cache_control {
+        if status.is_success() || status == StatusCode::NOT_MODIFIED {
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, cache_control);
+        }
+    }
+    response
+}
+
+// The orchestrator's probes carry no credentials
+const PROBE_ROUTES: [&str; 2] = ["/health", "/ready"];
+
+struct AuthPolicy {
+    authenticator: Arc<dyn Authenticator + Send + Sync>,
+    require_for_reads: bool, // otherwise READ_ROUTES are open to everyone
+}
+
+async fn require_auth(
+    State(auth): State<Arc<AuthPolicy>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    let is_read = is_read(request.extensions(), request.uri());
+    let is_probe = request
+        .extensions()
+        .get::<extract::MatchedPath>()
+        .is_some_and(|path| PROBE_ROUTES.contains(&path.as_str()));
+    if is_probe || (is_read && !auth.require_for_reads) {
+        return next.run(request).await;
+    }
+    match auth.authenticator.authenticate(request.headers()).await {
+        Ok(identity) => {
+            request.extensions_mut().insert(identity);
+            next.run(request).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+// Cumulative per-route request statistics, a lightweight alternative to a full metrics system
+#[derive(Default)]
+struct RouteCounters {
+    requests: AtomicU64,
+    client_errors: AtomicU64, // 4xx responses
+    server_errors: AtomicU64, // 5xx responses
+}
+
+struct RequestCounters {
+    routes: HashMap<String, RouteCounters>,
+}
+
+impl RequestCounters {
+    // The set of routes is fixed, so that the counters need no locking
+    fn new(routes: impl IntoIterator<Item = String>) -> Self {
+        RequestCounters {
+            routes: routes
+                .into_iter()
+                .map(|route| (route, RouteCounters::default()))
+                .collect(),
+        }
+    }
+
+    fn record(&self, route: &str, status: StatusCode) {
+        if let Some(counters) = self.routes.get(route) {
+            counters.requests.fetch_add(1, Ordering::Relaxed);
+            if status.is_client_error() {
+                counters.client_errors.fetch_add(1, Ordering::Relaxed);
+            } else if status.is_server_error() {
+                counters.server_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let map = serde_json::Map::from_iter(self.routes.iter().map(|(route, counters)| {
+            (
+                route.clone(),
+                serde_json::json!({
+                    "requests": counters.requests.load(Ordering::Relaxed),
+                    "client_errors": counters.client_errors.load(Ordering::Relaxed),
+                    "server_errors": counters.server_errors.load(Ordering::Relaxed),
+                }),
+            )
+        }));
+        Value::Object(map)
+    }
+
+    fn reset(&self) {
+        for counters in self.routes.values() {
+            counters.requests.store(0, Ordering::Relaxed);
+            counters.client_errors.store(0, Ordering::Relaxed);
+            counters.server_errors.store(0, Ordering::Relaxed);
+        }
+    }
+
+    // Appends the request counts in the Prometheus text format
+    fn to_prometheus(&self, out: &mut String) {
+        out.push_str("# TYPE rest_server_requests_total counter\n");
+        let mut routes = self.routes.iter().collect::<Vec<_>>();
+        routes.sort_unstable_by_key(|(route, _)| *route);
+        for (route, counters) in routes {
+            out.push_str(&format!(
+                "rest_server_requests_total{{route=\"{}\"}} {}\n",
+                route,
+                counters.requests.load(Ordering::Relaxed)
+            ));
+        }
+    }
+}
+
+// The requests (all of them, also the ones not matching any route) of the last WINDOW_SECS seconds
+// by method, counted in per-second buckets of a ring. A bucket is reused once the ring wraps around
+// to it.
+struct Throughput {
+    start: Instant,
+    buckets: std::sync::Mutex<Vec<ThroughputBucket>>,
+}
+
+#[derive(Clone, Default)]
+struct ThroughputBucket {
+    second: u64, // since Throughput::start
+    requests: BTreeMap<String, u64>,
+}
+
+impl Throughput {
+    const WINDOW_SECS: u64 = 60;
+
+    fn new() -> Self {
+        Throughput {
+            start: Instant::now(),
+            buckets: std::sync::Mutex::new(vec![
+                ThroughputBucket::default();
+                Self::WINDOW_SECS as usize
+            ]),
+        }
+    }
+
+    fn record(&self, method: &Method) {
+        let second = self.start.elapsed().as_secs();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = &mut buckets[(second % Self::WINDOW_SECS) as usize];
+        if bucket.second != second {
+            bucket.second = second;
+            bucket.requests.clear();
+        }
+        *bucket.requests.entry(method.to_string()).or_default() += 1;
+    }
+
+    // The rates are averaged over the whole window, or the time since the start if it is shorter
+    fn to_json(&self) -> Value {
+        let now = self.start.elapsed().as_secs();
+        let mut requests = BTreeMap::<String, u64>::new();
+        for bucket in self.buckets.lock().unwrap().iter() {
+            if now - bucket.second < Self::WINDOW_SECS {
+                for (method, count) in &bucket.requests {
+                    *requests.entry(method.clone()).or_default() += count;
+                }
+            }
+        }
+        let window_secs = Self::WINDOW_SECS.min(now + 1);
+        let per_second = |count: u64| count as f64 / window_secs as f64;
+        let by_method = requests.iter().map(|(method, count)| {
+            (
+                method.clone(),
+                serde_json::json!({
+                    "requests": count,
+                    "requests_per_second": per_second(*count),
+                }),
+            )
+        });
+        serde_json::json!({
+            "window_seconds": window_secs,
+            "requests_per_second": per_second(requests.values().sum()),
+            "by_method": Value::Object(by_method.collect()),
+        })
+    }
+}
+
+async fn measure_throughput(
+    State(throughput): State<Arc<Throughput>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    throughput.record(request.method());
+    next.run(request).await
+}
+
+// Whether /get and /bulk/get found the entries
+#[derive(Default)]
+struct LookupCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl LookupCounters {
+    fn record(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn count_requests(
+    State(counters): State<Arc<RequestCounters>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    // Requests not matching any route are not counted
+    let route = request
+        .extensions()
+        .get::<extract::MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        counters.record(&route, response.status());
+    }
+    response
+}
+
+// Remembers responses to mutating requests for a short time, so that a request repeated within the
+// window (e.g. retried by the client after a network failure) is answered from memory instead of
+// being executed again. Unlike explicit idempotency keys this needs no cooperation from clients,
+// but a legitimately repeated identical request (e.g. add, delete, add) within the window is
+// swallowed too, so the window should be kept short.
+struct Deduplicator {
+    window: Duration,
+    responses: std::sync::Mutex<HashMap<blake3::Hash, (Instant, CachedResponse)>>,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Deduplicator {
+    const MAX_ENTRIES: usize = 10_000;
+
+    fn new(window: Duration) -> Self {
+        Deduplicator {
+            window,
+            responses: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, request_hash: &blake3::Hash) -> Option<CachedResponse> {
+        match self.responses.lock().unwrap().get(request_hash) {
+            Some((time, response)) if time.elapsed() < self.window => Some(response.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, request_hash: blake3::Hash, response: CachedResponse) {
+        let mut responses = self.responses.lock().unwrap();
+        if responses.len() >= Self::MAX_ENTRIES {
+            responses.retain(|_, (time, _)| time.elapsed() < self.window);
+            if responses.len() >= Self::MAX_ENTRIES {
+                return; // better not to deduplicate than to grow unbounded
+            }
+        }
+        responses.insert(request_hash, (Instant::now(), response));
+    }
+}
+
+// The request is identified by its method, uri, body and client (its IP and credentials)
+async fn deduplicate(
+    State(dedup): State<Arc<Deduplicator>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => {
+            return error_response(StatusCode::BAD_REQUEST, "failed to read the request body")
+        }
+    };
+    let client_ip = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string());
+    let mut hasher = blake3::Hasher::new();
+    for field in [
+        parts.method.as_str().as_bytes(),
+        parts.uri.to_string().as_bytes(),
+        client_ip.unwrap_or_default().as_bytes(),
+        parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .map_or(&[][..], |value| value.as_bytes()),
+        &body,
+    ] {
+        hasher.update(&field.len().to_le_bytes());
+        hasher.update(field);
+    }
+    let request_hash = hasher.finalize();
+
+    if let Some(response) = dedup.get(&request_hash) {
+        return (response.status, response.headers, response.body).into_response();
+    }
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    // Failed requests are not remembered, so that retrying them works
+    if !response.status().is_success() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => {
+            let error = "failed to read the response body";
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, error);
+        }
+    };
+    dedup.insert(
+        request_hash,
+        CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        },
+    );
+    response::Response::from_parts(parts, body::boxed(body::Full::from(body)))
+}
+
+// Mutating requests in progress - they are mostly waiting for the key locks (or for the lock of all
+// keys, which e.g. /clear and the quota checks take). Past the soft limit the writes are still
+// executed, but the responses carry Retry-After, so that well-behaved clients slow down before the
+// latencies explode. Past the hard limit the writes are rejected with 429.
+struct WriteQueue {
+    depth: AtomicUsize,
+    soft_limit: Option<usize>,
+    hard_limit: Option<usize>,
+}
+
+impl WriteQueue {
+    const RETRY_AFTER_SECONDS: u64 = 1;
+
+    // Without a hard limit, the writes are rejected past the soft limit
+    fn new(soft_limit: Option<usize>, hard_limit: Option<usize>) -> Self {
+        WriteQueue {
+            depth: AtomicUsize::new(0),
+            soft_limit,
+            hard_limit: hard_limit.or(soft_limit),
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+async fn limit_write_queue(
+    State(write_queue): State<Arc<WriteQueue>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+    struct DepthGuard<'a>(&'a AtomicUsize);
+    impl Drop for DepthGuard<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    let depth = write_queue.depth.fetch_add(1, Ordering::Relaxed) + 1;
+    let _guard = DepthGuard(&write_queue.depth);
+    let retry_after = (header::RETRY_AFTER, WriteQueue::RETRY_AFTER_SECONDS);
+    if write_queue
+        .hard_limit
+        .is_some_and(|hard_limit| depth > hard_limit)
+    {
+        return (
+            [retry_after],
+            error_response(StatusCode::TOO_MANY_REQUESTS, "too many writes in progress"),
+        )
+            .into_response();
+    }
+    let response = next.run(request).await;
+    if write_queue
+        .soft_limit
+        .is_some_and(|soft_limit| depth > soft_limit)
+    {
+        return ([retry_after], response).into_response();
+    }
+    response
+}
+
+// Token buckets per client IP: a bucket holds up to a second's worth of tokens, refills at the rate
+// and every request takes a token
+struct RateLimiter {
+    per_second: f64,
+    buckets: DashMap<std::net::IpAddr, TokenBucket>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+    fn new(per_second: u32) -> Self {
+        RateLimiter {
+            per_second: per_second as f64,
+            buckets: DashMap::new(),
+        }
+    }
+
+    // Returns how long to wait for a token if there is none
+    fn acquire(&self, ip: std::net::IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(ip).or_insert(TokenBucket {
+            tokens: self.per_second,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(self.per_second);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+
+    // Buckets idle long enough to refill are the same as new ones, so they are dropped. Stops once
+    // the limiter is gone.
+    async fn cleanup_loop(rate_limiter: std::sync::Weak<RateLimiter>) {
+        let mut interval = tokio::time::interval(Self::CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(rate_limiter) = rate_limiter.upgrade() else {
+                return;
+            };
+            rate_limiter
+                .buckets
+                .retain(|_, bucket| bucket.updated.elapsed() < Duration::from_secs(1));
+        }
+    }
+}
+
+async fn limit_rate(
+    State(rate_limiter): State<Arc<RateLimiter>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() else {
+        return next.run(request).await;
+    };
+    match rate_limiter.acquire(addr.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => (
+            [(
+                header::RETRY_AFTER,
+                wait.as_secs_f64().ceil().max(1.0) as u64,
+            )],
+            error_response(StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded"),
+        )
+            .into_response(),
+    }
+}
+
+// The permit is held until the handler returns, so the streamed response bodies do not count
+async fn limit_concurrency(
+    State(request_limiter): State<Arc<Semaphore>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> response::Response {
+    let Ok(_permit) = request_limiter.try_acquire_owned() else {
+        return error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too many requests in progress",
+        );
+    };
+    next.run(request).await
+}
+
+// Fetches values for /add-from-url. Since the server fetches whatever the client asks for, only the
+// explicitly allowed hosts may be contacted (otherwise clients could reach internal services
+// through the server) and redirects are not followed as they could lead anywhere.
+struct Fetcher {
+    client: reqwest::Client,
+    allowed_hosts: Vec<String>,
+}
+
+enum FetchError {
+    Request(reqwest::Error),
+    TooLarge,
+    NotUtf8,
+}
+
+impl Fetcher {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn new(allowed_hosts: Vec<String>) -> Self {
+        Fetcher {
+            client: reqwest::Client::builder()
+                .timeout(Self::TIMEOUT)
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+            allowed_hosts,
+        }
+    }
+
+    fn is_allowed(&self, url: &reqwest::Url) -> bool {
+        matches!(url.scheme(), "http" | "https")
+            && url
+                .host_str()
+                .is_some_and(|host| self.allowed_hosts.iter().any(|allowed| allowed == host))
+    }
+
+    // Returns the body with the Content-Type of the response
+    async fn fetch(
+        &self,
+        url: reqwest::Url,
+        max_bytes: Option<usize>,
+    ) -> Result<(String, Option<String>), FetchError> {
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(FetchError::Request)?;
+        let max_bytes = max_bytes.unwrap_or(usize::MAX);
+        if response
+            .content_length()
+            .is_some_and(|len| len > max_bytes as u64)
+        {
+            return Err(FetchError::TooLarge);
+        }
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        // Content-Length may be absent or lie, so the limit is checked while receiving the body
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await.map_err(FetchError::Request)? {
+            if body.len() + chunk.len() > max_bytes {
+                return Err(FetchError::TooLarge);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let body = String::from_utf8(body).map_err(|_| FetchError::NotUtf8)?;
+        Ok((body, content_type))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum MutationOp {
+    Add,
+    Delete,
+    Modify,
+    Alias,  // the value is the target
+    Rename, // the value is the new key
+    Touch,  // only the expiration time changes
+    #[serde(rename = "delete_prefix")]
+    DeletePrefix, // the key is the prefix
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MutationEvent {
+    op: MutationOp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    key: String,
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JsonBytes>,
+}
+
+// Delivers the mutation events to the webhook URL in the background, so a slow endpoint never
+// delays the requests. A failed delivery is retried a few times, so an event may be delivered more
+// than once (if the response got lost), but if the endpoint is down long enough for the queue to
+// fill up, new events are dropped (with a log message) - the delivery is neither exactly nor at
+// least once.
+#[derive(Clone)]
+struct Webhook {
+    sender: mpsc::Sender<MutationEvent>,
+    include_value: bool,
+}
+
+impl Webhook {
+    const QUEUE_SIZE: usize = 1024;
+    const MAX_ATTEMPTS: u32 = 5;
+    const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn start(url: String, include_value: bool) -> Self {
+        let (sender, receiver) = mpsc::channel(Self::QUEUE_SIZE);
+        tokio::spawn(Self::deliver(url, receiver));
+        Webhook {
+            sender,
+            include_value,
+        }
+    }
+
+    fn send(&self, event: MutationEvent) {
+        if let Err(mpsc::error::TrySendError::Full(event)) = self.sender.try_send(event) {
+            eprintln!("Webhook queue is full, dropping event: {:?}", event);
+        }
+    }
+
+    async fn deliver(url: String, mut receiver: mpsc::Receiver<MutationEvent>) {
+        let client = reqwest::Client::builder()
+            .timeout(Self::TIMEOUT)
+            .build()
+            .unwrap();
+        while let Some(event) = receiver.recv().await {
+            let mut delay = Self::FIRST_RETRY_DELAY;
+            for attempt in 1..=Self::MAX_ATTEMPTS {
+                let res = client
+                    .post(&url)
+                    .json(&event)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                match res {
+                    Ok(_) => break,
+                    Err(err) if attempt == Self::MAX_ATTEMPTS => {
+                        eprintln!("Webhook delivery of {:?} failed: {}", event, err);
+                    }
+                    Err(_) => {
+                        tokio::time::sleep(delay).await;
+                        delay *= 2;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ReplicatedOp {
+    Add {
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    },
+    Modify {
+        value: Vec<u8>,
+    },
+    Delete,
+}
+
+#[derive(Debug)]
+struct ReplicatedWrite {
+    namespace: Option<String>,
+    key: String,
+    op: ReplicatedOp,
+}
+
+// Forwards the writes to the REST API of a peer server in the background, in the order they were
+// made here. It is fire-and-forget: a failed write is only logged and if the peer is too slow for
+// the queue, new writes are dropped (also with a log message), so the peer may drift apart.
+#[derive(Clone)]
+struct Replica {
+    sender: mpsc::Sender<ReplicatedWrite>,
+}
+
+impl Replica {
+    const QUEUE_SIZE: usize = 1024;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn start(url: String) -> Self {
+        let (sender, receiver) = mpsc::channel(Self::QUEUE_SIZE);
+        tokio::spawn(Self::forward(url, receiver));
+        Replica { sender }
+    }
+
+    fn send(&self, write: ReplicatedWrite) {
+        if let Err(mpsc::error::TrySendError::Full(write)) = self.sender.try_send(write) {
+            eprintln!("Replica queue is full, dropping write: {:?}", write);
+        }
+    }
+
+    async fn forward(url: String, mut receiver: mpsc::Receiver<ReplicatedWrite>) {
+        let client = reqwest::Client::builder()
+            .timeout(Self::TIMEOUT)
+            .build()
+            .unwrap();
+        let url = url.trim_end_matches('/');
+        while let Some(write) = receiver.recv().await {
+            let base = match &write.namespace {
+                Some(namespace) => format!("{}/{}", url, namespace),
+                None => url.to_string(),
+            };
+            let request =
+                match &write.op {
+                    // Raw, as the value does not have to be UTF-8
+                    ReplicatedOp::Add { value, expires_at } => client
+                        .put(format!("{}/add", base))
+                        .query(&AddQuery {
+                            key: Some(write.key.clone()),
+                            ttl_seconds: expires_at.map(|expires_at| {
+                                let ttl = expires_at
+                                    .duration_since(SystemTime::now())
+                                    .unwrap_or_default();
+                                ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0)
+                            }),
+                            content_type: None,
+                        })
+                        .header(header::CONTENT_TYPE, "application/octet-stream")
+                        .body(value.clone()),
+                    // Unlike an add, keeps the expiration time of the entry
+                    ReplicatedOp::Modify { value } => client
+                        .patch(format!("{}/modify", base))
+                        .json(&ModifyPayload {
+                            key: write.key.clone(),
+                            value: String::from_utf8_lossy(value).into_owned(),
+                        }),
+                    ReplicatedOp::Delete => {
+                        client
+                            .delete(format!("{}/delete", base))
+                            .json(&DeletePayload {
+                                key: write.key.clone(),
+                            })
+                    }
+                };
+            let res = request.send().await.and_then(|response| {
+                // The entry may have expired on the peer already
+                match (&write.op, response.status()) {
+                    (ReplicatedOp::Delete, reqwest::StatusCode::NOT_FOUND) => Ok(response),
+                    _ => response.error_for_status(),
+                }
+            });
+            if let Err(err) = res {
+                eprintln!("Replication of {:?} failed: {}", write, err);
+            }
+        }
+    }
+}
+
+// Fans the mutation events (without the values) out to the /subscribe streams. A subscriber gets
+// only the events published after it subscribed, and one that falls more than CAPACITY events
+// behind misses the oldest of them.
+#[derive(Clone)]
+struct ChangeFeed {
+    sender: broadcast::Sender<MutationEvent>,
+    // Set on shutdown, the streams end so that they don't hold up the graceful shutdown
+    closing: Arc<watch::Sender<bool>>,
+}
+
+impl ChangeFeed {
+    const CAPACITY: usize = 1024;
+
+    fn new() -> Self {
+        ChangeFeed {
+            sender: broadcast::channel(Self::CAPACITY).0,
+            closing: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    fn publish(&self, event: MutationEvent) {
+        let _ = self.sender.send(event); // fails only if there are no subscribers
+    }
+
+    fn close(&self) {
+        self.closing.send_replace(true);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalRecord {
+    timestamp_ms: u64,
+    op: MutationOp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<JsonBytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at_ms: Option<u64>,
+    // Of the added entry, the modifications keep the one it has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+// Append-only log of all the mutations (one JSON record per line), from which the state of the
+// cache at any moment can be reconstructed with the replay subcommand. Every record is synced to
+// disk, so the journal roughly doubles the write cost and takes at least as much space as all the
+// values ever written. It is never compacted - to rotate it, stop the server and move the file
+// away; replaying then requires concatenating the rotated journals in order.
+struct Journal {
+    file: Mutex<File>,
+}
+
+impl Journal {
+    fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Journal {
+            file: Mutex::new(File::from_std(file)),
+        })
+    }
+
+    // Sets the timestamp of the record under the lock, so that the records are ordered by time
+    async fn append(&self, mut record: JournalRecord) -> std::io::Result<()> {
+        let mut file = self.file.lock().await;
+        record.timestamp_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let mut line = serde_json::to_string(&record).unwrap();
+        line.push('\n');
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await
+    }
+
+    // Applies the records up to (inclusive) the given time to the cache (and its namespaces),
+    // returns the number of applied records
+    async fn replay(
+        path: &std::path::Path,
+        until_ms: Option<u64>,
+        default_cache: &(dyn Cache + Send + Sync),
+    ) -> std::io::Result<usize> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        let mut namespaces = HashMap::new();
+        let mut applied = 0;
+        for line in contents.lines() {
+            let record: JournalRecord = serde_json::from_str(line)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+            if until_ms.is_some_and(|until_ms| record.timestamp_ms > until_ms) {
+                break;
+            }
+            let cache = match record.namespace {
+                None => default_cache,
+                Some(namespace) => {
+                    if !namespaces.contains_key(&namespace) {
+                        let cache = default_cache
+                            .namespace(&namespace)
+                            .await
+                            .map_err(std::io::Error::other)?;
+                        namespaces.insert(namespace.clone(), cache);
+                    }
+                    namespaces[&namespace].as_ref()
+                }
+            };
+            match (record.op, record.value) {
+                (MutationOp::Add, Some(value)) => {
+                    let expires_at = record
+                        .expires_at_ms
+                        .map(|ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms));
+                    cache
+                        .add_with_content_type(record.key, value.0, expires_at, record.content_type)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                }
+                (MutationOp::Modify, Some(value)) => {
+                    cache
+                        .modify(record.key, value.0)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                }
+                (MutationOp::Delete, _) => {
+                    cache
+                        .delete(&record.key)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                }
+                (MutationOp::DeletePrefix, _) => {
+                    cache
+                        .delete_prefix(&record.key)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                }
+                (MutationOp::Touch, _) => {
+                    let expires_at_ms = record.expires_at_ms.ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("touch of {:?} without an expiration time", record.key),
+                        )
+                    })?;
+                    cache
+                        .touch(
+                            &record.key,
+                            SystemTime::UNIX_EPOCH + Duration::from_millis(expires_at_ms),
+                        )
+                        .await
+                        .map_err(std::io::Error::other)?;
+                }
+                (MutationOp::Rename, Some(to)) => {
+                    let to = String::from_utf8(to.0)
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                    cache
+                        .rename(record.key, to, true)
+                        .await
+                        .map_err(std::io::Error::other)?;
+                }
+                (MutationOp::Alias, Some(target)) => {
+                    let target = String::from_utf8(target.0)
+                        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                    // Succeeded originally, so it can fail again only because of the storage
+                    if let Err(AliasError::Cache(err)) = cache.alias(record.key, target).await {
+                        return Err(std::io::Error::other(err));
+                    }
+                }
+                (_, None) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("{:?} of {:?} without a value", record.op, record.key),
+                    ))
+                }
+            }
+            applied += 1;
+        }
+        Ok(applied)
+    }
+}
+
+// Allow more than one implementation of the Cache. The methods are called concurrently, but the
+// callers (see KeyLocks) guarantee that a mutation of a key does not overlap with any other call
+// for the same key, and that alias(), rename(), remove_older_than(), compact() and the bulk methods
+// run exclusively. remove_expired() takes the locks it needs itself.
+// The methods fail only if the storage does.
+#[async_trait]
+trait Cache {
+    async fn list(&self) -> Result<Value, CacheError>;
+
+    // Returns {"key": {"size": N}, ...} where N is the length of the value in bytes (not the size
+    // it takes in the storage)
+    async fn list_sizes(&self) -> Result<Value, CacheError>;
+
+    // Returns the entries with keys starting with the prefix, ordered by the key, without the first
+    // offset of them and at most limit of them
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError>;
+
+    // The entries of list() in no particular order. The default implementation collects them first,
+    // the caches that don't hold the entries in memory stream them.
+    fn iter(&self) -> BoxStream<'_, Result<(String, Vec<u8>), CacheError>> {
+        futures::stream::once(self.list_paged("", None, 0))
+            .map_ok(|page| futures::stream::iter(page.entries.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed()
+    }
+
+    // The entries of iter() with their metadata and expiration times
+    fn iter_with_meta(&self) -> BoxStream<'_, Result<(String, StoredValue), CacheError>>
+    where
+        Self: Sync,
+    {
+        self.iter()
+            .try_filter_map(move |(key, _)| async move {
+                // Unless deleted in the meantime
+                let stored = self.get_with_meta(&key).await?;
+                Ok(stored.map(|stored| (key, stored)))
+            })
+            .boxed()
+    }
+
+    // After expires_at the entry is treated as absent by all the methods
+    async fn add(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+    ) -> Result<(), CacheError> {
+        self.add_with_content_type(key, value, expires_at, None)
+            .await
+    }
+
+    // Like add(), but with the content type stored in the EntryMeta
+    async fn add_with_content_type(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        content_type: Option<String>,
+    ) -> Result<(), CacheError> {
+        self.add_with_meta(key, value, expires_at, EntryMeta::new(content_type))
+            .await
+    }
+
+    // Like add(), but with the given metadata instead of a new one
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError>;
+
+    // Returns true if the entry was deleted, false if there is no entry
+    async fn delete(&self, key: &str) -> Result<bool, CacheError>;
+
+    // Deletes the entries (including the aliases and the expired entries) with keys starting with
+    // the prefix, returns the number of deleted live entries
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError>;
+
+    // Deletes all the entries, returns the number of deleted live entries. Unlike delete_prefix(),
+    // it does not need the keys (e.g. with the DiskCache's drop_keys).
+    async fn clear(&self) -> Result<usize, CacheError> {
+        self.delete_prefix("").await
+    }
+
+    // Returns true if the entry was modified, false if there is no entry. The expiration time of the
+    // entry is kept.
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError>;
+
+    // Like modify(), but with the suffix appended to the current value (of the target for aliases).
+    // Callers hold the lock of the key, so the update is atomic.
+    async fn append(&self, key: String, suffix: Vec<u8>) -> Result<bool, CacheError> {
+        let Some(mut value) = self.get(&key).await? else {
+            return Ok(false);
+        };
+        value.extend(suffix);
+        self.modify(key, value).await
+    }
+
+    // Like modify(), but only if the current value (of the target for aliases) equals expected
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError>;
+
+    // Moves the value (of the target for aliases) of from to the key to, with its expiration time
+    // and metadata, and removes from. The aliases of from are left dangling, as with delete().
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool, // otherwise an existing entry to is left as it is
+    ) -> Result<RenameResult, CacheError>;
+
+    // Sets the expiration time of the entry, keeping its value and metadata. Returns true if the
+    // entry was touched, false if there is no entry. Aliases have no expiration time of their own,
+    // so touching them finds nothing.
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError>;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError>;
+
+    // Whether get() would find the key, the default implementation calls it
+    async fn contains(&self, key: &str) -> Result<bool, CacheError> {
+        Ok(self.get(key).await?.is_some())
+    }
+
+    // Like get(), but also returns the metadata and the expiration time (of the target for aliases)
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError>;
+
+    // The entries of list() with their metadata, in no particular order
+    async fn list_with_meta(&self) -> Result<Vec<(String, Vec<u8>, EntryMeta)>, CacheError> {
+        let keys = self
+            .iter()
+            .map_ok(|(key, _)| key)
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            // Unless deleted in the meantime
+            if let Some(stored) = self.get_with_meta(&key).await? {
+                entries.push((key, stored.value, stored.meta));
+            }
+        }
+        Ok(entries)
+    }
+
+    // Returns the value of the entry, if there is no entry it is created with the given value and
+    // None is returned. Callers hold the lock of the key, so the check and the insertion are atomic.
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError>;
+
+    // Like get_or_set(), but returns only whether the entry was created
+    async fn add_if_absent(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        Ok(self.get_or_set(key, value).await?.is_none())
+    }
+
+    // Adds delta to the value (a decimal integer) and returns the result. A missing entry counts as
+    // 0 and is created. Callers hold the lock of the key, so the update is atomic.
+    async fn increment(&self, key: String, delta: i64) -> Result<i64, IncrError> {
+        let value = self.get(&key).await?;
+        let new = incremented(value.as_deref(), delta)?;
+        if value.is_some() {
+            self.modify(key, new.to_string().into_bytes()).await?;
+        } else {
+            self.add(key, new.to_string().into_bytes(), None).await?;
+        }
+        Ok(new)
+    }
+
+    // Deletes the entries last modified more than max_age ago, returns the number of deleted
+    // entries
+    async fn remove_older_than(&self, max_age: Duration) -> Result<usize, CacheError>;
+
+    // Deletes up to (roughly, the grouped DiskCache goes group by group) max_count expired entries,
+    // returns the number of deleted entries. Called without any lock held: looking for the expired
+    // entries may take long, so only deleting them has to lock the keys (or all of them).
+    async fn remove_expired(&self, max_count: usize, locks: &KeyLocks)
+        -> Result<usize, CacheError>;
+
+    // Removes the leftovers of interrupted writes and the unreadable entries from the storage.
+    // Nothing to do for the storages that do not leave them behind.
+    async fn compact(&self) -> Result<Compaction, CacheError> {
+        Ok(Compaction::default())
+    }
+
+    // Makes reads of alias return the current value of target (following chains of aliases).
+    // Aliases are entries of their own: writing to or deleting the alias replaces or removes only
+    // the alias, while modifying the target is visible through the alias. Deleting the target
+    // leaves the alias dangling, it then behaves as a missing entry. Aliases to missing entries and
+    // cycles are rejected.
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError>;
+
+    // Returns true if any alias points directly at the entry
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError>;
+
+    // Returns the number of stored entries, including aliases and the expired entries that were not
+    // removed yet
+    async fn len(&self) -> Result<usize, CacheError>;
+
+    // Sizes of the entries of list(), the default implementation reads all of them
+    async fn stats(&self) -> Result<CacheStats, CacheError> {
+        CacheStats::of_entries(self.iter()).await
+    }
+
+    // Fails if the storage cannot take writes right now, e.g. for the readiness probe
+    async fn check_writable(&self) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    // Returns a cache of the same kind and configuration holding the entries of the namespace,
+    // separate from this one. Called once per namespace, the persistent caches reopen the entries
+    // stored before. The name is safe to use as a filename (see validate_namespace()).
+    async fn namespace(&self, name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError>;
+
+    // Whether namespace() would reopen entries stored before (e.g. by a previous run). Creates
+    // nothing, unlike namespace(). The in-memory caches keep nothing between the runs.
+    async fn has_namespace(&self, _name: &str) -> Result<bool, CacheError> {
+        Ok(false)
+    }
+
+    // The bulk methods are equivalent to calling the single-entry ones for every item in order,
+    // which the default implementations do
+
+    async fn bulk_add(
+        &self,
+        entries: Vec<(String, Vec<u8>, Option<SystemTime>)>,
+    ) -> Result<(), CacheError> {
+        for (key, value, expires_at) in entries {
+            self.add(key, value, expires_at).await?;
+        }
+        Ok(())
+    }
+
+    async fn bulk_get(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, CacheError> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.get(key).await?);
+        }
+        Ok(values)
+    }
+
+    // Returns whether each of the entries was deleted
+    async fn bulk_delete(&self, keys: &[String]) -> Result<Vec<bool>, CacheError> {
+        let mut deleted = Vec::with_capacity(keys.len());
+        for key in keys {
+            deleted.push(self.delete(key).await?);
+        }
+        Ok(deleted)
+    }
+}
+
+// The storage failed, the request fails with 500 but the server keeps serving the others
+#[derive(Debug)]
+enum CacheError {
+    Io(std::io::Error),
+    // The file cannot be parsed, e.g. it was damaged or not written by the cache
+    Malformed(PathBuf),
+    // See DiskCache::verify_writes
+    VerificationFailed(PathBuf),
+    // The value is encrypted with another key than DiskCache::cipher (or there is none), or the file
+    // was tampered with
+    Undecryptable(PathBuf),
+    // The file holds the entry of another key, whose truncated hash is the same (see
+    // DiskCache::hash_len)
+    KeyCollision(PathBuf),
+    Sqlite(rusqlite::Error),
+    Redis(redis::RedisError),
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(err: std::io::Error) -> Self {
+        CacheError::Io(err)
+    }
+}
+
+impl From<rusqlite::Error> for CacheError {
+    fn from(err: rusqlite::Error) -> Self {
+        CacheError::Sqlite(err)
+    }
+}
+
+impl From<redis::RedisError> for CacheError {
+    fn from(err: redis::RedisError) -> Self {
+        CacheError::Redis(err)
+    }
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::Io(err) => write!(f, "{}", err),
+            CacheError::Malformed(path) => write!(f, "{:?} is malformed", path),
+            CacheError::VerificationFailed(path) => {
+                write!(f, "verification of the write of {:?} failed", path)
+            }
+            CacheError::Undecryptable(path) => write!(f, "{:?} cannot be decrypted", path),
+            CacheError::KeyCollision(path) => {
+                write!(f, "{:?} holds the entry of another key", path)
+            }
+            CacheError::Sqlite(err) => write!(f, "{}", err),
+            CacheError::Redis(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+// The {"error": ...} body of all the failed requests
+fn error_response(status: StatusCode, error: impl std::fmt::Display) -> response::Response {
+    let body = serde_json::json!({ "error": error.to_string() });
+    (status, response::Json(body)).into_response()
+}
+
+impl IntoResponse for CacheError {
+    fn into_response(self) -> response::Response {
+        let status = match &self {
+            // ENOSPC, the cache can be written to again once some space is freed
+            CacheError::Io(err) if err.kind() == std::io::ErrorKind::StorageFull => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+            // See DiskCache::io()
+            CacheError::Io(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        error_response(status, self.to_string())
+    }
+}
+
+// A value in the JSON files and responses. Values are bytes, but JSON strings have to be UTF-8, so
+// only valid UTF-8 is kept as a plain string (which also keeps the files written before values
+// could be binary readable) and anything else becomes {"base64": "..."}.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "JsonBytesRepr", into = "JsonBytesRepr")]
+struct JsonBytes(Vec<u8>);
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum JsonBytesRepr {
+    Utf8(String),
+    Base64 { base64: String },
+}
+
+impl JsonBytes {
+    fn to_json(value: Vec<u8>) -> Value {
+        serde_json::to_value(JsonBytes(value)).unwrap()
+    }
+}
+
+impl TryFrom<JsonBytesRepr> for JsonBytes {
+    type Error = base64::DecodeError;
+
+    fn try_from(repr: JsonBytesRepr) -> Result<Self, Self::Error> {
+        use base64::Engine;
+        match repr {
+            JsonBytesRepr::Utf8(value) => Ok(JsonBytes(value.into_bytes())),
+            JsonBytesRepr::Base64 { base64 } => Ok(JsonBytes(
+                base64::engine::general_purpose::STANDARD.decode(base64)?,
+            )),
+        }
+    }
+}
+
+impl From<JsonBytes> for JsonBytesRepr {
+    fn from(value: JsonBytes) -> Self {
+        use base64::Engine;
+        match String::from_utf8(value.0) {
+            Ok(value) => JsonBytesRepr::Utf8(value),
+            Err(err) => JsonBytesRepr::Base64 {
+                base64: base64::engine::general_purpose::STANDARD.encode(err.into_bytes()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct Compaction {
+    temp_files: usize,      // removed temporary files of writes that did not complete
+    malformed_files: usize, // removed files that could not be parsed
+}
+
+struct ListPage {
+    entries: Vec<(String, Vec<u8>)>,
+    total: usize, // number of the entries with the prefix
+}
+
+impl ListPage {
+    fn new(
+        mut entries: Vec<(String, Vec<u8>)>,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Self {
+        entries.retain(|(key, _)| key.starts_with(prefix));
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let total = entries.len();
+        let entries = entries
+            .into_iter()
+            .skip(offset)
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        ListPage { entries, total }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct CacheStats {
+    entry_count: usize,
+    total_value_bytes: usize,
+    largest_value_bytes: usize,
+    // Total size of the files in the cache directory, only DiskCache sets it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disk_usage_bytes: Option<u64>,
+}
+
+// The body of /stats
+#[derive(Debug, Serialize, Deserialize)]
+struct Stats {
+    #[serde(flatten)]
+    cache: CacheStats,
+    // Mutating requests in progress in all namespaces (see WriteQueue)
+    write_queue_depth: usize,
+    // Only with --max-concurrent-lists (see ListLimiter)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lists_in_progress: Option<usize>,
+}
+
+impl CacheStats {
+    fn count(&mut self, value_bytes: usize) {
+        self.entry_count += 1;
+        self.total_value_bytes += value_bytes;
+        self.largest_value_bytes = self.largest_value_bytes.max(value_bytes);
+    }
+
+    async fn of_entries(
+        entries: BoxStream<'_, Result<(String, Vec<u8>), CacheError>>,
+    ) -> Result<Self, CacheError> {
+        entries
+            .try_fold(CacheStats::default(), |mut stats, (_, value)| async move {
+                stats.count(value.len());
+                Ok(stats)
+            })
+            .await
+    }
+
+    fn merge(self, other: CacheStats) -> Self {
+        CacheStats {
+            entry_count: self.entry_count + other.entry_count,
+            total_value_bytes: self.total_value_bytes + other.total_value_bytes,
+            largest_value_bytes: self.largest_value_bytes.max(other.largest_value_bytes),
+            disk_usage_bytes: match (self.disk_usage_bytes, other.disk_usage_bytes) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum RenameResult {
+    Renamed,
+    NotFound,
+    DestinationExists,
+}
+
+#[derive(Debug, PartialEq)]
+enum CasResult {
+    Swapped,
+    Mismatch,
+    NotFound,
+}
+
+fn is_expired(expires_at: Option<SystemTime>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now())
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// Stored with every value (besides the expiration time)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct EntryMeta {
+    // Served as the Content-Type of /get
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    // Milliseconds since the UNIX epoch, 0 for the entries stored before they were tracked
+    #[serde(default)]
+    created_at_ms: u64,
+    #[serde(default)]
+    updated_at_ms: u64, // the last add(), modify() or compare_and_swap()
+    // The previous values, newest first (see AppState::value_history)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    history: Vec<PastValue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PastValue {
+    value: JsonBytes,
+    updated_at_ms: u64, // when the value was set
+}
+
+impl EntryMeta {
+    fn new(content_type: Option<String>) -> Self {
+        let now = unix_ms(SystemTime::now());
+        EntryMeta {
+            content_type,
+            created_at_ms: now,
+            updated_at_ms: now,
+            history: vec![],
+        }
+    }
+
+    fn updated(self) -> Self {
+        EntryMeta {
+            updated_at_ms: unix_ms(SystemTime::now()),
+            ..self
+        }
+    }
+}
+
+// What Cache::get_with_meta() returns
+#[derive(Clone, Debug, PartialEq)]
+struct StoredValue {
+    value: Vec<u8>,
+    meta: EntryMeta,
+    expires_at: Option<SystemTime>,
+    aliased: bool, // read through an alias, so it changes with the target
+}
+
+#[derive(Debug)]
+enum IncrError {
+    NotAnInteger,
+    Overflow,
+    Cache(CacheError),
+}
+
+// The value /incr sets, a missing value counts as 0
+fn incremented(value: Option<&[u8]>, delta: i64) -> Result<i64, IncrError> {
+    let Some(value) = value else {
+        return Ok(delta);
+    };
+    let current = std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse::<i64>().ok())
+        .ok_or(IncrError::NotAnInteger)?;
+    current.checked_add(delta).ok_or(IncrError::Overflow)
+}
+
+impl From<CacheError> for IncrError {
+    fn from(err: CacheError) -> Self {
+        IncrError::Cache(err)
+    }
+}
+
+impl IntoResponse for IncrError {
+    fn into_response(self) -> response::Response {
+        match self {
+            IncrError::NotAnInteger => {
+                error_response(StatusCode::UNPROCESSABLE_ENTITY, "value is not an integer")
+            }
+            IncrError::Overflow => {
+                error_response(StatusCode::UNPROCESSABLE_ENTITY, "value would overflow")
+            }
+            IncrError::Cache(err) => err.into_response(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum AliasError {
+    TargetNotFound,
+    Cycle,
+    // HybridCache keeps the ephemeral and durable entries apart
+    AcrossPartitions,
+    Cache(CacheError),
+}
+
+impl From<CacheError> for AliasError {
+    fn from(err: CacheError) -> Self {
+        AliasError::Cache(err)
+    }
+}
+
+impl IntoResponse for AliasError {
+    fn into_response(self) -> response::Response {
+        match self {
+            AliasError::TargetNotFound => {
+                error_response(StatusCode::NOT_FOUND, "target does not exist")
+            }
+            AliasError::Cycle => error_response(StatusCode::CONFLICT, "alias would create a cycle"),
+            AliasError::AcrossPartitions => error_response(
+                StatusCode::BAD_REQUEST,
+                "alias and target must be both ephemeral or both durable",
+            ),
+            AliasError::Cache(err) => err.into_response(),
+        }
+    }
+}
+
+// Sharded maps, so that operations on different keys rarely contend
+struct MemCache {
+    cache: DashMap<String, MemCacheEntry>,
+    aliases: DashMap<String, String>, // alias -> target, the keys are disjoint with the cache keys
+    // Set in the LRU mode: the keys of cache in the order of use, once there are more of them than
+    // the capacity the least recently used one is evicted. Aliases are not counted nor evicted.
+    lru: Option<std::sync::Mutex<lru::LruCache<String, ()>>>,
+}
+
+struct MemCacheEntry {
+    value: Vec<u8>,
+    expires_at: Option<SystemTime>,
+    meta: EntryMeta,
+}
+
+// In memory cache - the simplest
+impl MemCache {
+    fn new() -> Self {
+        MemCache {
+            cache: DashMap::new(),
+            aliases: DashMap::new(),
+            lru: None,
+        }
+    }
+
+    fn with_lru(capacity: std::num::NonZeroUsize) -> Self {
+        MemCache {
+            lru: Some(std::sync::Mutex::new(lru::LruCache::new(capacity))),
+            ..MemCache::new()
+        }
+    }
+
+    // The LRU order is updated under its lock together with the map, so that they don't diverge
+    fn insert(&self, key: String, entry: MemCacheEntry) {
+        let Some(lru) = &self.lru else {
+            self.cache.insert(key, entry);
+            return;
+        };
+        let mut lru = lru.lock().unwrap();
+        self.cache.insert(key.clone(), entry);
+        if let Some((evicted, ())) = lru.push(key.clone(), ()) {
+            if evicted != key {
+                self.cache.remove(&evicted);
+            }
+        }
+    }
+
+    fn remove(&self, key: &str) -> Option<MemCacheEntry> {
+        let Some(lru) = &self.lru else {
+            return self.cache.remove(key).map(|(_, entry)| entry);
+        };
+        let mut lru = lru.lock().unwrap();
+        lru.pop(key);
+        self.cache.remove(key).map(|(_, entry)| entry)
+    }
+
+    fn touch(&self, key: &str) {
+        if let Some(lru) = &self.lru {
+            lru.lock().unwrap().promote(key);
+        }
+    }
+
+    // Expired entries are left in place until they are overwritten, deleted or swept
+    fn is_live(&self, key: &str) -> bool {
+        self.cache
+            .get(key)
+            .is_some_and(|entry| !is_expired(entry.expires_at))
+    }
+
+    // The references into the maps are never held across other lookups, as that could deadlock
+    // with a concurrent writer of the same shard
+    fn resolve(&self, key: &str) -> Option<Vec<u8>> {
+        self.resolve_with_meta(key).map(|stored| stored.value)
+    }
+
+    fn resolve_with_meta(&self, key: &str) -> Option<StoredValue> {
+        let mut key = key.to_string();
+        let mut aliased = false;
+        loop {
+            if let Some(entry) = self.cache.get(&key) {
+                let stored = StoredValue {
+                    value: entry.value.clone(),
+                    meta: entry.meta.clone(),
+                    expires_at: entry.expires_at,
+                    aliased,
+                };
+                return Some(stored).filter(|_| !is_expired(entry.expires_at));
+            }
+            key = self.aliases.get(&key)?.clone();
+            aliased = true;
+        }
+    }
+
+    fn resolved_entries(&self) -> Vec<(String, Vec<u8>)> {
+        let mut entries = self
+            .cache
+            .iter()
+            .filter(|entry| !is_expired(entry.expires_at))
+            .map(|entry| (entry.key().clone(), entry.value.clone()))
+            .collect::<Vec<_>>();
+        let aliases = self
+            .aliases
+            .iter()
+            .map(|alias| alias.key().clone())
+            .collect::<Vec<_>>();
+        for alias in aliases {
+            if let Some(value) = self.resolve(&alias) {
+                entries.push((alias, value));
+            }
+        }
+        entries
+    }
+}
+
+#[async_trait]
+impl Cache for MemCache {
+    async fn list(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.resolved_entries()
+                .into_iter()
+                .map(|(k, v)| (k, JsonBytes::to_json(v))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    async fn list_sizes(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.resolved_entries()
+                .into_iter()
+                .map(|(k, v)| (k, serde_json::json!({ "size": v.len() }))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError> {
+        Ok(ListPage::new(
+            self.resolved_entries(),
+            prefix,
+            limit,
+            offset,
+        ))
+    }
+
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        self.aliases.remove(&key);
+        let entry = MemCacheEntry {
+            value,
+            expires_at,
+            meta,
+        };
+        self.insert(key, entry);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        Ok(match self.remove(key) {
+            Some(entry) => !is_expired(entry.expires_at),
+            None => self.aliases.remove(key).is_some(),
+        })
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        let keys = self
+            .cache
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        let mut deleted = keys
+            .into_iter()
+            .filter_map(|key| self.remove(&key))
+            .filter(|entry| !is_expired(entry.expires_at))
+            .count();
+        self.aliases.retain(|alias, _| {
+            let matches = alias.starts_with(prefix);
+            deleted += matches as usize;
+            !matches
+        });
+        Ok(deleted)
+    }
+
+    async fn clear(&self) -> Result<usize, CacheError> {
+        let mut deleted = self.aliases.len();
+        self.aliases.clear();
+        let _lru = self.lru.as_ref().map(|lru| {
+            let mut lru = lru.lock().unwrap();
+            lru.clear();
+            lru
+        });
+        deleted += self
+            .cache
+            .iter()
+            .filter(|entry| !is_expired(entry.expires_at))
+            .count();
+        self.cache.clear();
+        Ok(deleted)
+    }
+
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        if self.aliases.remove(&key).is_some() {
+            self.add(key, value, None).await?;
+            return Ok(true);
+        }
+        Ok(match self.cache.get_mut(&key) {
+            Some(mut entry) if !is_expired(entry.expires_at) => {
+                entry.value = value;
+                entry.meta = std::mem::take(&mut entry.meta).updated();
+                true
+            }
+            _ => false,
+        })
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        if self.aliases.contains_key(&key) {
+            return Ok(match self.resolve(&key) {
+                Some(value) if value == expected => {
+                    self.add(key, new, None).await?;
+                    CasResult::Swapped
+                }
+                Some(_) => CasResult::Mismatch,
+                None => CasResult::NotFound,
+            });
+        }
+        // The entry stays locked from the comparison until the write
+        Ok(match self.cache.get_mut(&key) {
+            Some(mut entry) if !is_expired(entry.expires_at) => {
+                if entry.value != expected {
+                    return Ok(CasResult::Mismatch);
+                }
+                entry.value = new;
+                entry.meta = std::mem::take(&mut entry.meta).updated();
+                CasResult::Swapped
+            }
+            _ => CasResult::NotFound,
+        })
+    }
+
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> Result<RenameResult, CacheError> {
+        let Some(stored) = self.resolve_with_meta(&from) else {
+            return Ok(RenameResult::NotFound);
+        };
+        if !overwrite && self.resolve(&to).is_some() {
+            return Ok(RenameResult::DestinationExists);
+        }
+        if self.remove(&from).is_none() {
+            self.aliases.remove(&from);
+        }
+        self.aliases.remove(&to);
+        let entry = MemCacheEntry {
+            value: stored.value,
+            expires_at: stored.expires_at,
+            meta: stored.meta,
+        };
+        self.insert(to, entry);
+        Ok(RenameResult::Renamed)
+    }
+
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError> {
+        Ok(match self.cache.get_mut(key) {
+            Some(mut entry) if !is_expired(entry.expires_at) => {
+                entry.expires_at = Some(expires_at);
+                true
+            }
+            _ => false,
+        })
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        let val = self.resolve(key);
+        if val.is_some() {
+            self.touch(key);
+        }
+        Ok(val)
+    }
+
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        let val = self.resolve_with_meta(key);
+        if val.is_some() {
+            self.touch(key);
+        }
+        Ok(val)
+    }
+
+    // Without copying the value (or touching the LRU order)
+    async fn contains(&self, key: &str) -> Result<bool, CacheError> {
+        if let Some(entry) = self.cache.get(key) {
+            return Ok(!is_expired(entry.expires_at));
+        }
+        Ok(self.aliases.contains_key(key) && self.resolve(key).is_some())
+    }
+
+    // Without touching the entries, which would scramble the LRU order
+    async fn list_with_meta(&self) -> Result<Vec<(String, Vec<u8>, EntryMeta)>, CacheError> {
+        let keys = self.cache.iter().map(|entry| entry.key().clone());
+        let keys = keys
+            .chain(self.aliases.iter().map(|alias| alias.key().clone()))
+            .collect::<Vec<_>>();
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                let stored = self.resolve_with_meta(&key)?;
+                Some((key, stored.value, stored.meta))
+            })
+            .collect())
+    }
+
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        let val = self.resolve(&key);
+        match val {
+            Some(_) => self.touch(&key),
+            None => self.add(key, value, None).await?,
+        }
+        Ok(val)
+    }
+
+    // Entries don't outlive the process, so they are not tracked (--max-entry-age requires
+    // --cache-dir)
+    async fn remove_older_than(&self, _max_age: Duration) -> Result<usize, CacheError> {
+        Ok(0)
+    }
+
+    async fn remove_expired(
+        &self,
+        max_count: usize,
+        locks: &KeyLocks,
+    ) -> Result<usize, CacheError> {
+        let _lock = locks.write_all().await;
+        let expired = self
+            .cache
+            .iter()
+            .filter(|entry| is_expired(entry.expires_at))
+            .map(|entry| entry.key().clone())
+            .take(max_count)
+            .collect::<Vec<_>>();
+        for key in &expired {
+            self.remove(key);
+        }
+        Ok(expired.len())
+    }
+
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError> {
+        let mut key = target.clone();
+        loop {
+            if key == alias {
+                return Err(AliasError::Cycle);
+            }
+            if self.is_live(&key) {
+                break;
+            }
+            key = self
+                .aliases
+                .get(&key)
+                .ok_or(AliasError::TargetNotFound)?
+                .clone();
+        }
+        self.remove(&alias);
+        self.aliases.insert(alias, target);
+        Ok(())
+    }
+
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError> {
+        Ok(self.aliases.iter().any(|alias| alias.value() == key))
+    }
+
+    async fn len(&self) -> Result<usize, CacheError> {
+        Ok(self.cache.len() + self.aliases.len())
+    }
+
+    // Without copying the values (or touching the LRU order)
+    async fn stats(&self) -> Result<CacheStats, CacheError> {
+        let mut stats = CacheStats::default();
+        for entry in self.cache.iter() {
+            if !is_expired(entry.expires_at) {
+                stats.count(entry.value.len());
+            }
+        }
+        let aliases = self
+            .aliases
+            .iter()
+            .map(|alias| alias.key().clone())
+            .collect::<Vec<_>>();
+        for alias in aliases {
+            if let Some(value) = self.resolve(&alias) {
+                stats.count(value.len());
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn namespace(&self, _name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError> {
+        Ok(Box::new(match &self.lru {
+            Some(lru) => MemCache::with_lru(lru.lock().unwrap().cap()),
+            None => MemCache::new(),
+        }))
+    }
+}
+
+// In memory cache for read-mostly workloads: a read loads the current snapshot of the maps without
+// any locking, a write copies the maps, changes the copy and publishes it. The entries are shared
+// between the snapshots, still a write costs O(number of entries) and the writes are serialized.
+struct SnapshotMemCache {
+    snapshot: ArcSwap<MemSnapshot>,
+    writer: std::sync::Mutex<()>,
+}
+
+#[derive(Clone, Default)]
+struct MemSnapshot {
+    entries: HashMap<String, Arc<MemCacheEntry>>,
+    aliases: HashMap<String, String>, // alias -> target, the keys are disjoint with the entries
+}
+
+impl MemSnapshot {
+    // Expired entries are left in place until they are overwritten, deleted or swept
+    fn is_live(&self, key: &str) -> bool {
+        self.entries
+            .get(key)
+            .is_some_and(|entry| !is_expired(entry.expires_at))
+    }
+
+    fn resolve(&self, key: &str) -> Option<Vec<u8>> {
+        self.resolve_with_meta(key).map(|stored| stored.value)
+    }
+
+    fn resolve_with_meta(&self, key: &str) -> Option<StoredValue> {
+        let mut key = key;
+        let mut aliased = false;
+        loop {
+            if let Some(entry) = self.entries.get(key) {
+                let stored = StoredValue {
+                    value: entry.value.clone(),
+                    meta: entry.meta.clone(),
+                    expires_at: entry.expires_at,
+                    aliased,
+                };
+                return Some(stored).filter(|_| !is_expired(entry.expires_at));
+            }
+            key = self.aliases.get(key)?;
+            aliased = true;
+        }
+    }
+
+    fn resolved_entries(&self) -> Vec<(String, Vec<u8>)> {
+        let entries = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !is_expired(entry.expires_at))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()));
+        let aliases = self
+            .aliases
+            .keys()
+            .filter_map(|alias| Some((alias.clone(), self.resolve(alias)?)));
+        entries.chain(aliases).collect()
+    }
+
+    // The changed entries are replaced, as the old ones may be shared with older snapshots
+    fn replace(&mut self, key: String, value: Vec<u8>, expires_at: Option<SystemTime>) {
+        let meta = match self.entries.get(&key) {
+            Some(entry) => entry.meta.clone().updated(),
+            None => EntryMeta::new(None),
+        };
+        self.aliases.remove(&key);
+        let entry = MemCacheEntry {
+            value,
+            expires_at,
+            meta,
+        };
+        self.entries.insert(key, Arc::new(entry));
+    }
+}
+
+impl SnapshotMemCache {
+    fn new() -> Self {
+        SnapshotMemCache {
+            snapshot: ArcSwap::from_pointee(MemSnapshot::default()),
+            writer: std::sync::Mutex::new(()),
+        }
+    }
+
+    // A read never waits for a write, nor for other reads
+    fn load(&self) -> Arc<MemSnapshot> {
+        self.snapshot.load_full()
+    }
+
+    // The lock makes the writes see each other's changes, the readers never take it
+    fn update<T>(&self, f: impl FnOnce(&mut MemSnapshot) -> T) -> T {
+        let _writer = self.writer.lock().unwrap();
+        let mut snapshot = MemSnapshot::clone(&self.snapshot.load());
+        let res = f(&mut snapshot);
+        self.snapshot.store(Arc::new(snapshot));
+        res
+    }
+}
+
+#[async_trait]
+impl Cache for SnapshotMemCache {
+    async fn list(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.load()
+                .resolved_entries()
+                .into_iter()
+                .map(|(k, v)| (k, JsonBytes::to_json(v))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    async fn list_sizes(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.load()
+                .resolved_entries()
+                .into_iter()
+                .map(|(k, v)| (k, serde_json::json!({ "size": v.len() }))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError> {
+        Ok(ListPage::new(
+            self.load().resolved_entries(),
+            prefix,
+            limit,
+            offset,
+        ))
+    }
+
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        let entry = MemCacheEntry {
+            value,
+            expires_at,
+            meta,
+        };
+        self.update(|snapshot| {
+            snapshot.aliases.remove(&key);
+            snapshot.entries.insert(key, Arc::new(entry));
+        });
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        Ok(self.update(|snapshot| match snapshot.entries.remove(key) {
+            Some(entry) => !is_expired(entry.expires_at),
+            None => snapshot.aliases.remove(key).is_some(),
+        }))
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        Ok(self.update(|snapshot| {
+            let mut deleted = 0;
+            snapshot.entries.retain(|key, entry| {
+                let matches = key.starts_with(prefix);
+                deleted += (matches && !is_expired(entry.expires_at)) as usize;
+                !matches
+            });
+            snapshot.aliases.retain(|alias, _| {
+                let matches = alias.starts_with(prefix);
+                deleted += matches as usize;
+                !matches
+            });
+            deleted
+        }))
+    }
+
+    async fn clear(&self) -> Result<usize, CacheError> {
+        Ok(self.update(|snapshot| {
+            let deleted = snapshot.aliases.len()
+                + snapshot
+                    .entries
+                    .values()
+                    .filter(|entry| !is_expired(entry.expires_at))
+                    .count();
+            *snapshot = MemSnapshot::default();
+            deleted
+        }))
+    }
+
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        Ok(self.update(|snapshot| {
+            if snapshot.aliases.contains_key(&key) {
+                snapshot.replace(key, value, None);
+                return true;
+            }
+            match snapshot.entries.get(&key) {
+                Some(entry) if !is_expired(entry.expires_at) => {
+                    let expires_at = entry.expires_at;
+                    snapshot.replace(key, value, expires_at);
+                    true
+                }
+                _ => false,
+            }
+        }))
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        Ok(self.update(|snapshot| {
+            let aliased = snapshot.aliases.contains_key(&key);
+            let expires_at = match snapshot.resolve_with_meta(&key) {
+                Some(stored) if stored.value != expected => return CasResult::Mismatch,
+                Some(stored) => stored.expires_at.filter(|_| !aliased),
+                None => return CasResult::NotFound,
+            };
+            snapshot.replace(key, new, expires_at);
+            CasResult::Swapped
+        }))
+    }
+
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> Result<RenameResult, CacheError> {
+        Ok(self.update(|snapshot| {
+            let Some(stored) = snapshot.resolve_with_meta(&from) else {
+                return RenameResult::NotFound;
+            };
+            if !overwrite && snapshot.resolve(&to).is_some() {
+                return RenameResult::DestinationExists;
+            }
+            if snapshot.entries.remove(&from).is_none() {
+                snapshot.aliases.remove(&from);
+            }
+            snapshot.aliases.remove(&to);
+            let entry = MemCacheEntry {
+                value: stored.value,
+                expires_at: stored.expires_at,
+                meta: stored.meta,
+            };
+            snapshot.entries.insert(to, Arc::new(entry));
+            RenameResult::Renamed
+        }))
+    }
+
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError> {
+        if !self.load().is_live(key) {
+            return Ok(false);
+        }
+        Ok(self.update(|snapshot| match snapshot.entries.get(key) {
+            Some(entry) if !is_expired(entry.expires_at) => {
+                let entry = MemCacheEntry {
+                    value: entry.value.clone(),
+                    expires_at: Some(expires_at),
+                    meta: entry.meta.clone(),
+                };
+                snapshot.entries.insert(key.to_string(), Arc::new(entry));
+                true
+            }
+            _ => false,
+        }))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        Ok(self.load().resolve(key))
+    }
+
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        Ok(self.load().resolve_with_meta(key))
+    }
+
+    // Without copying the value
+    async fn contains(&self, key: &str) -> Result<bool, CacheError> {
+        let snapshot = self.load();
+        if let Some(entry) = snapshot.entries.get(key) {
+            return Ok(!is_expired(entry.expires_at));
+        }
+        Ok(snapshot.aliases.contains_key(key) && snapshot.resolve(key).is_some())
+    }
+
+    // From a single snapshot
+    async fn list_with_meta(&self) -> Result<Vec<(String, Vec<u8>, EntryMeta)>, CacheError> {
+        let snapshot = self.load();
+        let keys = snapshot.entries.keys().chain(snapshot.aliases.keys());
+        Ok(keys
+            .filter_map(|key| {
+                let stored = snapshot.resolve_with_meta(key)?;
+                Some((key.clone(), stored.value, stored.meta))
+            })
+            .collect())
+    }
+
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        if let Some(value) = self.load().resolve(&key) {
+            return Ok(Some(value));
+        }
+        Ok(self.update(|snapshot| {
+            let val = snapshot.resolve(&key);
+            if val.is_none() {
+                snapshot.aliases.remove(&key);
+                let entry = MemCacheEntry {
+                    value,
+                    expires_at: None,
+                    meta: EntryMeta::new(None),
+                };
+                snapshot.entries.insert(key, Arc::new(entry));
+            }
+            val
+        }))
+    }
+
+    // Entries don't outlive the process, so they are not tracked (--max-entry-age requires
+    // --cache-dir)
+    async fn remove_older_than(&self, _max_age: Duration) -> Result<usize, CacheError> {
+        Ok(0)
+    }
+
+    // The sweep runs periodically, so the maps are copied only if there is anything to remove
+    async fn remove_expired(
+        &self,
+        max_count: usize,
+        locks: &KeyLocks,
+    ) -> Result<usize, CacheError> {
+        let any_expired = self
+            .load()
+            .entries
+            .values()
+            .any(|entry| is_expired(entry.expires_at));
+        if !any_expired || max_count == 0 {
+            return Ok(0);
+        }
+        let _lock = locks.write_all().await;
+        Ok(self.update(|snapshot| {
+            let expired = snapshot
+                .entries
+                .iter()
+                .filter(|(_, entry)| is_expired(entry.expires_at))
+                .map(|(key, _)| key.clone())
+                .take(max_count)
+                .collect::<Vec<_>>();
+            for key in &expired {
+                snapshot.entries.remove(key);
+            }
+            expired.len()
+        }))
+    }
+
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError> {
+        self.update(|snapshot| {
+            let mut key = &target;
+            loop {
+                if *key == alias {
+                    return Err(AliasError::Cycle);
+                }
+                if snapshot.is_live(key) {
+                    break;
+                }
+                key = snapshot
+                    .aliases
+                    .get(key)
+                    .ok_or(AliasError::TargetNotFound)?;
+            }
+            snapshot.entries.remove(&alias);
+            snapshot.aliases.insert(alias, target);
+            Ok(())
+        })
+    }
+
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError> {
+        Ok(self.load().aliases.values().any(|target| target == key))
+    }
+
+    async fn len(&self) -> Result<usize, CacheError> {
+        let snapshot = self.load();
+        Ok(snapshot.entries.len() + snapshot.aliases.len())
+    }
+
+    // Without copying the values
+    async fn stats(&self) -> Result<CacheStats, CacheError> {
+        let snapshot = self.load();
+        let mut stats = CacheStats::default();
+        for entry in snapshot.entries.values() {
+            if !is_expired(entry.expires_at) {
+                stats.count(entry.value.len());
+            }
+        }
+        for alias in snapshot.aliases.keys() {
+            if let Some(value) = snapshot.resolve(alias) {
+                stats.count(value.len());
+            }
+        }
+        Ok(stats)
+    }
+
+    async fn namespace(&self, _name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError> {
+        Ok(Box::new(SnapshotMemCache::new()))
+    }
+}
+
+// Log of the DiskCache mutations for crash forensics: a record of each of them is synced to disk
+// before it is performed, so after a crash the log tells which mutations might have been torn. Only
+// the keys are recorded (unlike Journal, it cannot restore anything). The removals of expired and
+// old entries are not recorded.
+#[derive(Clone)]
+struct WriteAheadLog {
+    file: Arc<Mutex<File>>,
+    namespace: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct WalRecord {
+    timestamp_ms: u64,
+    op: MutationOp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+    key: String,
+}
+
+impl WriteAheadLog {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(WriteAheadLog {
+            file: Arc::new(Mutex::new(File::from_std(file))),
+            namespace: None,
+        })
+    }
+
+    // The same log, recording the namespace with the keys
+    fn for_namespace(&self, name: &str) -> Self {
+        WriteAheadLog {
+            file: self.file.clone(),
+            namespace: Some(name.to_string()),
+        }
+    }
+
+    async fn append(&self, op: MutationOp, key: &str) -> std::io::Result<()> {
+        let mut file = self.file.lock().await;
+        let record = WalRecord {
+            timestamp_ms: unix_ms(SystemTime::now()),
+            op,
+            namespace: self.namespace.clone(),
+            key: key.to_string(),
+        };
+        let mut line = serde_json::to_string(&record).unwrap();
+        line.push('\n');
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await
+    }
+}
+
+// The directories of the DiskCaches with FsyncMode::Interval that have renames or deletions not
+// synced yet. A sync of the directory covers all of them, so each gets synced once per interval.
+#[derive(Default)]
+struct PendingDirSyncs(std::sync::Mutex<BTreeSet<PathBuf>>);
+
+impl PendingDirSyncs {
+    const INTERVAL: Duration = Duration::from_millis(50);
+
+    fn add(&self, dir: &Path) {
+        let mut dirs = self.0.lock().unwrap();
+        if !dirs.contains(dir) {
+            dirs.insert(dir.to_path_buf());
+        }
+    }
+
+    // Failures are only logged, as the writes were already acknowledged
+    fn sync(&self) {
+        let dirs = std::mem::take(&mut *self.0.lock().unwrap());
+        for dir in dirs {
+            if let Err(err) = std::fs::File::open(&dir).and_then(|file| file.sync_data()) {
+                eprintln!("Failed to sync {:?}: {}", dir, err);
+            }
+        }
+    }
+
+    // Stops once the caches are gone
+    async fn sync_loop(pending: std::sync::Weak<PendingDirSyncs>) {
+        let mut interval = tokio::time::interval(Self::INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(pending) = pending.upgrade() else {
+                return;
+            };
+            tokio::task::spawn_blocking(move || pending.sync())
+                .await
+                .unwrap();
+        }
+    }
+}
+
+// The last syncs, e.g. on shutdown
+impl Drop for PendingDirSyncs {
+    fn drop(&mut self) {
+        self.sync();
+    }
+}
+
+// How DiskCache::sync_dir() works, see FsyncMode
+#[derive(Clone)]
+enum DirSync {
+    Always,
+    Interval(Arc<PendingDirSyncs>),
+    Never,
+}
+
+// On disk cache - a little trickier than in memory cache
+struct DiskCache {
+    cache_dir: PathBuf,
+    hash_len: usize, // number of bytes of the key hash used in the filename
+    // Catches faulty hardware or filesystem bugs at the cost of doubling the I/O of writes
+    verify_writes: bool,
+    // Store only the key hash (in the filename), the entries then cannot be listed
+    drop_keys: bool,
+    // Grouped mode: instead of a file per entry, the entries are packed into group files of up to
+    // group_size entries, which saves inodes and fsyncs with many tiny values. An entry belongs to
+    // the group whose name is a prefix of its key hash. A group that outgrows group_size is split
+    // into 16 groups with a one hex digit longer prefix.
+    group_size: Option<usize>,
+    // Prefixes of the existing groups, none of them is a prefix of another
+    groups: std::sync::Mutex<BTreeSet<String>>,
+    // Groups are rewritten as a whole, so writes of different keys of the same group (which the
+    // callers do not serialize) have to be
+    group_rewrites: Mutex<()>,
+    // Make the read-modify-write mutations of a key atomic on their own, without relying on the
+    // callers' KeyLocks (e.g. so that a modify() racing with a delete() cannot recreate the entry).
+    // Assigned like KeyLocks, so keys that map to the same file share the lock.
+    file_locks: Vec<Mutex<()>>,
+    wal: Option<WriteAheadLog>,
+    // Encrypts the values in the files (see seal())
+    cipher: Option<ChaCha20Poly1305>,
+    // Compresses the values in the files (see seal())
+    compress_values: bool,
+    // Set with set_fsync_mode()
+    dir_sync: DirSync,
+    // Filesystem operations taking longer fail with 504 (see io())
+    io_timeout: Option<Duration>,
+    #[cfg(test)]
+    fault: Option<DiskFault>,
+}
+
+// Simulated misbehavior of the disk
+#[cfg(test)]
+#[derive(Clone, Copy)]
+enum DiskFault {
+    // Written values land on the disk altered
+    CorruptWrites,
+    // The process dies after writing the new version of a file, but before renaming it into place
+    CrashBeforeRename,
+    // The process dies after writing the groups of a split, but before removing the split group
+    CrashMidSplit,
+    // The disk fills up in the middle of writing a file
+    StorageFull,
+    // Writing a file takes that long, e.g. a hung network filesystem
+    SlowWrites(Duration),
+}
+
+impl DiskCache {
+    const MIN_HASH_LEN: usize = 8;
+    // Remembers the hash length the directory was created with, so that it's not mixed
+    const HASH_LEN_FILENAME: &'static str = ".hash_len";
+    const GROUP_FILENAME_PREFIX: &'static str = "group-";
+    // Holds a directory per namespace, the name never clashes with the entry files
+    const NAMESPACES_DIRNAME: &'static str = "namespaces";
+    // Files read at once when going through all the entries
+    const PARALLEL_READS: usize = 16;
+
+    fn new(cache_dir: PathBuf) -> Self {
+        Self::with_hash_len(cache_dir, blake3::OUT_LEN)
+    }
+
+    fn namespace_dir(&self, name: &str) -> PathBuf {
+        self.cache_dir.join(Self::NAMESPACES_DIRNAME).join(name)
+    }
+
+    fn with_hash_len(cache_dir: PathBuf, hash_len: usize) -> Self {
+        assert!((Self::MIN_HASH_LEN..=blake3::OUT_LEN).contains(&hash_len));
+        DiskCache {
+            cache_dir,
+            hash_len,
+            verify_writes: false,
+            drop_keys: false,
+            group_size: None,
+            groups: std::sync::Mutex::new(BTreeSet::from([String::new()])),
+            group_rewrites: Mutex::new(()),
+            file_locks: (0..256).map(|_| Mutex::new(())).collect(),
+            wal: None,
+            cipher: None,
+            compress_values: false,
+            io_timeout: None,
+            dir_sync: DirSync::Always,
+            #[cfg(test)]
+            fault: None,
+        }
+    }
+
+    // Must be called within the tokio runtime, FsyncMode::Interval spawns the task syncing the
+    // directories
+    fn set_fsync_mode(&mut self, mode: FsyncMode) {
+        self.dir_sync = match mode {
+            FsyncMode::Always => DirSync::Always,
+            FsyncMode::Interval => {
+                let pending = Arc::new(PendingDirSyncs::default());
+                tokio::spawn(PendingDirSyncs::sync_loop(Arc::downgrade(&pending)));
+                DirSync::Interval(pending)
+            }
+            FsyncMode::Never => DirSync::Never,
+        };
+    }
+
+    // A stuck filesystem (e.g. a hung network mount) would hang the requests forever, so an
+    // operation exceeding io_timeout fails with ErrorKind::TimedOut instead. It may still complete
+    // in the background.
+    async fn io<T>(
+        &self,
+        op: impl std::future::Future<Output = std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        match self.io_timeout {
+            Some(io_timeout) => match tokio::time::timeout(io_timeout, op).await {
+                Ok(res) => res,
+                Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+            },
+            None => op.await,
+        }
+    }
+
+    // Makes the renames and deletions in the directory durable (or schedules it)
+    async fn sync_dir(&self) -> Result<(), CacheError> {
+        match &self.dir_sync {
+            DirSync::Always => {
+                self.io(async { File::open(&self.cache_dir).await?.sync_data().await })
+                    .await?
+            }
+            DirSync::Interval(pending) => pending.add(&self.cache_dir),
+            DirSync::Never => {}
+        }
+        Ok(())
+    }
+
+    // Makes sure the directory was not populated using a different hash length, as then existing
+    // entries would not be found. Directories created before the hash length was recorded use the
+    // full hash.
+    async fn check_hash_len(&self) -> Result<(), String> {
+        let path = self.cache_dir.join(Self::HASH_LEN_FILENAME);
+        let dir_hash_len = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents
+                .trim()
+                .parse()
+                .map_err(|_| format!("{:?} is malformed", path))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let mut entries = tokio::fs::read_dir(&self.cache_dir).await.unwrap();
+                let mut is_empty = true;
+                while let Some(entry) = entries.next_entry().await.unwrap() {
+                    is_empty &= entry.file_name().len() != blake3::OUT_LEN * 2;
+                }
+                if is_empty {
+                    tokio::fs::write(&path, self.hash_len.to_string())
+                        .await
+                        .unwrap();
+                    self.hash_len
+                } else {
+                    blake3::OUT_LEN
+                }
+            }
+            Err(err) => panic!("{:?}", err),
+        };
+        if dir_hash_len == self.hash_len {
+            Ok(())
+        } else {
+            Err(format!(
+                "{:?} uses {}-byte key hashes, not {}",
+                self.cache_dir, dir_hash_len, self.hash_len
+            ))
+        }
+    }
+
+    fn key_to_filename(&self, key: &str) -> String {
+        let hash = blake3::hash(key.as_bytes());
+        hash.to_hex()[..self.hash_len * 2].to_string()
+    }
+
+    fn key_to_path(&self, key: &str) -> PathBuf {
+        self.cache_dir.join(self.key_to_filename(key))
+    }
+
+    // A mismatch means the file was copied under a wrong name or tampered with. Without the stored
+    // key (drop_keys) there is nothing to check.
+    fn matches_filename(&self, entry: &DiskCacheEntry, filename: &str) -> bool {
+        entry
+            .key
+            .as_ref()
+            .is_none_or(|key| self.key_to_filename(key) == filename)
+    }
+
+    // Called right before the mutation is written, the mutation fails if logging it does
+    async fn log_to_wal(&self, op: MutationOp, key: &str) -> Result<(), CacheError> {
+        match &self.wal {
+            Some(wal) => Ok(wal.append(op, key).await?),
+            None => Ok(()),
+        }
+    }
+
+    async fn lock_file(&self, key: &str) -> tokio::sync::MutexGuard<'_, ()> {
+        let hash = blake3::hash(key.as_bytes());
+        self.file_locks[hash.as_bytes()[0] as usize].lock().await
+    }
+
+    fn new_entry(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at_ms: Option<u64>,
+        meta: EntryMeta,
+    ) -> DiskCacheEntry {
+        DiskCacheEntry {
+            key: Some(key).filter(|_| !self.drop_keys),
+            value: JsonBytes(value),
+            target: None,
+            expires_at_ms,
+            meta,
+            nonce: None,
+            sealed_history: None,
+            gzip: false,
+        }
+    }
+
+    // With the cipher the values (and the history of the previous ones) are stored encrypted, each
+    // with its own random nonce. The rest of the entry (including the key) is stored as it is. With
+    // compress_values the values are gzip-compressed first.
+    fn seal(&self, mut entry: DiskCacheEntry) -> DiskCacheEntry {
+        use chacha20poly1305::aead::{Aead, AeadCore, OsRng};
+        if self.compress_values && entry.target.is_none() {
+            use std::io::Write;
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&entry.value.0).unwrap();
+            let compressed = encoder.finish().unwrap();
+            // Stored as base64, unlike the UTF-8 values
+            if compressed.len().div_ceil(3) * 4 < entry.value.0.len() {
+                entry.value = JsonBytes(compressed);
+                entry.gzip = true;
+            }
+        }
+        if let Some(cipher) = self.cipher.as_ref().filter(|_| entry.target.is_none()) {
+            let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+            entry.value = JsonBytes(cipher.encrypt(&nonce, entry.value.0.as_slice()).unwrap());
+            entry.nonce = Some(JsonBytes(nonce.to_vec()));
+            if !entry.meta.history.is_empty() {
+                let history = serde_json::to_vec(&std::mem::take(&mut entry.meta.history)).unwrap();
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                let mut sealed = nonce.to_vec();
+                sealed.extend(cipher.encrypt(&nonce, history.as_slice()).unwrap());
+                entry.sealed_history = Some(JsonBytes(sealed));
+            }
+        }
+        entry
+    }
+
+    fn unseal(&self, path: &Path, entry: DiskCacheEntry) -> Result<DiskCacheEntry, CacheError> {
+        let mut entry = self.decrypt(path, entry)?;
+        if entry.gzip {
+            use std::io::Read;
+            let mut value = vec![];
+            flate2::read::GzDecoder::new(entry.value.0.as_slice())
+                .read_to_end(&mut value)
+                .map_err(|_| CacheError::Malformed(path.to_path_buf()))?;
+            entry.value = JsonBytes(value);
+            entry.gzip = false;
+        }
+        Ok(entry)
+    }
+
+    // The entries written before the cipher was set are returned as they are
+    fn decrypt(
+        &self,
+        path: &Path,
+        mut entry: DiskCacheEntry,
+    ) -> Result<DiskCacheEntry, CacheError> {
+        use chacha20poly1305::aead::Aead;
+        let Some(JsonBytes(nonce)) = entry.nonce.take() else {
+            return Ok(entry);
+        };
+        let undecryptable = || CacheError::Undecryptable(path.to_path_buf());
+        let cipher = self.cipher.as_ref().ok_or_else(undecryptable)?;
+        if nonce.len() != 12 {
+            return Err(undecryptable());
+        }
+        let value = cipher
+            .decrypt(
+                chacha20poly1305::Nonce::from_slice(&nonce),
+                entry.value.0.as_slice(),
+            )
+            .map_err(|_| undecryptable())?;
+        entry.value = JsonBytes(value);
+        if let Some(JsonBytes(sealed)) = entry.sealed_history.take() {
+            if sealed.len() < 12 {
+                return Err(undecryptable());
+            }
+            let (nonce, history) = sealed.split_at(12);
+            let history = cipher
+                .decrypt(chacha20poly1305::Nonce::from_slice(nonce), history)
+                .map_err(|_| undecryptable())?;
+            entry.meta.history = serde_json::from_slice(&history).map_err(|_| undecryptable())?;
+        }
+        Ok(entry)
+    }
+
+    fn serialize(&self, entry: DiskCacheEntry) -> String {
+        serde_json::to_string(&self.seal(entry)).unwrap()
+    }
+
+    fn serialize_group(&self, group: BTreeMap<String, DiskCacheEntry>) -> Vec<u8> {
+        let group = group
+            .into_iter()
+            .map(|(hash, entry)| (hash, self.seal(entry)))
+            .collect::<BTreeMap<_, _>>();
+        serde_json::to_vec(&group).unwrap()
+    }
+
+    // Without decrypting or decompressing the value
+    fn parse(path: &Path, entry: &[u8]) -> Result<DiskCacheEntry, CacheError> {
+        serde_json::from_slice(entry).map_err(|_| CacheError::Malformed(path.to_path_buf()))
+    }
+
+    fn deserialize(&self, path: &Path, entry: &[u8]) -> Result<DiskCacheEntry, CacheError> {
+        self.unseal(path, Self::parse(path, entry)?)
+    }
+
+    // Must be called before using the cache in the grouped mode
+    async fn load_groups(&self) {
+        let mut groups = BTreeSet::new();
+        let mut entries = tokio::fs::read_dir(&self.cache_dir).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let file_name = entry.file_name().into_string().unwrap_or_default();
+            if let Some(prefix) = file_name.strip_prefix(Self::GROUP_FILENAME_PREFIX) {
+                if prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+                    groups.insert(prefix.to_string());
+                }
+            }
+        }
+        // A group and its descendants both exist only if a split was interrupted. The split group
+        // still holds all of its entries then, so the descendants are dropped.
+        let stale = groups
+            .iter()
+            .filter(|prefix| (0..prefix.len()).any(|len| groups.contains(&prefix[..len])))
+            .cloned()
+            .collect::<Vec<_>>();
+        for prefix in stale {
+            tokio::fs::remove_file(self.cache_dir.join(Self::group_filename(&prefix)))
+                .await
+                .unwrap();
+            groups.remove(&prefix);
+        }
+        if groups.is_empty() {
+            groups.insert(String::new());
+        }
+        *self.groups.lock().unwrap() = groups;
+    }
+
+    fn group_filename(prefix: &str) -> String {
+        format!("{}{}", Self::GROUP_FILENAME_PREFIX, prefix)
+    }
+
+    fn group_of(&self, hash: &str) -> String {
+        let groups = self.groups.lock().unwrap();
+        (0..=hash.len())
+            .map(|len| &hash[..len])
+            .find(|prefix| groups.contains(*prefix))
+            .unwrap()
+            .to_string()
+    }
+
+    // Maps key hashes to the entries
+    async fn read_group(
+        &self,
+        prefix: &str,
+    ) -> Result<BTreeMap<String, DiskCacheEntry>, CacheError> {
+        let path = self.cache_dir.join(Self::group_filename(prefix));
+        match self.io(tokio::fs::read(&path)).await {
+            Ok(contents) => {
+                let group: BTreeMap<String, DiskCacheEntry> = serde_json::from_slice(&contents)
+                    .map_err(|_| CacheError::Malformed(path.clone()))?;
+                group
+                    .into_iter()
+                    .map(|(hash, entry)| Ok((hash, self.unseal(&path, entry)?)))
+                    .collect()
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Reads the group of the hash, which may be split concurrently
+    async fn read_group_of(
+        &self,
+        hash: &str,
+    ) -> Result<BTreeMap<String, DiskCacheEntry>, CacheError> {
+        loop {
+            let prefix = self.group_of(hash);
+            let group = self.read_group(&prefix).await?;
+            if self.group_of(hash) == prefix {
+                return Ok(group);
+            }
+        }
+    }
+
+    async fn write_group(
+        &self,
+        prefix: String,
+        group: BTreeMap<String, DiskCacheEntry>,
+    ) -> Result<(), CacheError> {
+        if group.len() <= self.group_size.unwrap() || prefix.len() == self.hash_len * 2 {
+            let contents = self.serialize_group(group);
+            return self
+                .write_file(&Self::group_filename(&prefix), &contents)
+                .await;
+        }
+        // The new groups may still be too big, they are split on their next write
+        let mut children = (0..16)
+            .map(|digit| (format!("{}{:x}", prefix, digit), BTreeMap::new()))
+            .collect::<BTreeMap<_, _>>();
+        for (hash, entry) in group {
+            children
+                .get_mut(&hash[..=prefix.len()])
+                .unwrap()
+                .insert(hash, entry);
+        }
+        let mut child_prefixes = Vec::with_capacity(children.len());
+        for (child, entries) in children {
+            let contents = self.serialize_group(entries);
+            self.write_file(&Self::group_filename(&child), &contents)
+                .await?;
+            child_prefixes.push(child);
+        }
+        #[cfg(test)]
+        if matches!(self.fault, Some(DiskFault::CrashMidSplit)) {
+            panic!("simulated crash");
+        }
+        // Before the removal, so that readers that missed the removed group retry with the new ones
+        {
+            let mut groups = self.groups.lock().unwrap();
+            groups.remove(&prefix);
+            groups.extend(child_prefixes);
+        }
+        match self
+            .io(tokio::fs::remove_file(
+                self.cache_dir.join(Self::group_filename(&prefix)),
+            ))
+            .await
+        {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        self.sync_dir().await?; // make deletion durable
+        Ok(())
+    }
+
+    // Never overwrites the entry of another key, see check_key()
+    async fn write(&self, key: &str, entry: DiskCacheEntry) -> Result<(), CacheError> {
+        let filename = self.key_to_filename(key);
+        if self.group_size.is_some() {
+            let _rewrite = self.group_rewrites.lock().await;
+            let prefix = self.group_of(&filename);
+            let mut group = self.read_group(&prefix).await?;
+            if let Some(stored) = group.get(&filename) {
+                let path = self.cache_dir.join(Self::group_filename(&prefix));
+                Self::check_key(&path, stored, key)?;
+            }
+            group.insert(filename, entry);
+            self.write_group(prefix, group).await
+        } else {
+            // Only a truncated hash can collide. A malformed file is overwritten as before.
+            if self.hash_len < blake3::OUT_LEN {
+                let path = self.cache_dir.join(&filename);
+                match self.io(tokio::fs::read(&path)).await {
+                    Ok(contents) => {
+                        if let Ok(stored) = Self::parse(&path, &contents) {
+                            Self::check_key(&path, &stored, key)?;
+                        }
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            self.write_file(&filename, self.serialize(entry).as_bytes())
+                .await
+        }
+    }
+
+    // Fails if the entry stored under the hash of the key belongs to another key. Without the
+    // stored key (drop_keys) there is nothing to check.
+    fn check_key(path: &Path, stored: &DiskCacheEntry, key: &str) -> Result<(), CacheError> {
+        match &stored.key {
+            Some(stored_key) if stored_key != key => Err(CacheError::KeyCollision(path.into())),
+            _ => Ok(()),
+        }
+    }
+
+    // The new version is written to a temporary file by a single blocking task, the timeout (see
+    // io()) only stops waiting for it. A write that outlives the timeout has its file removed once
+    // it finishes, and the unique name keeps it off the temporary file of a later write.
+    async fn write_file(&self, filename: &str, contents: &[u8]) -> Result<(), CacheError> {
+        static WRITES: AtomicU64 = AtomicU64::new(0);
+        let file_path = self.cache_dir.join(filename);
+        let tmp_file_path = self.cache_dir.join(format!(
+            "{}.{}.new",
+            filename,
+            WRITES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let sync = !matches!(self.dir_sync, DirSync::Never);
+        #[cfg(test)]
+        let fault = self.fault;
+        let mut written = tokio::task::spawn_blocking({
+            let tmp_file_path = tmp_file_path.clone();
+            let contents = contents.to_vec();
+            move || {
+                use std::io::Write;
+                let written = (|| {
+                    #[cfg(test)]
+                    if let Some(DiskFault::SlowWrites(delay)) = fault {
+                        std::thread::sleep(delay);
+                    }
+                    let mut file = std::fs::File::create(&tmp_file_path)?;
+                    #[cfg(test)]
+                    if matches!(fault, Some(DiskFault::StorageFull)) {
+                        file.write_all(&contents[..contents.len() / 2])?;
+                        return Err(std::io::ErrorKind::StorageFull.into());
+                    }
+                    file.write_all(&contents)?;
+                    #[cfg(test)]
+                    if matches!(fault, Some(DiskFault::CorruptWrites)) {
+                        file.write_all(b"corrupted")?;
+                    }
+                    // Make changes to disk durable
+                    if sync {
+                        file.sync_all()?;
+                    }
+                    Ok::<_, std::io::Error>(())
+                })();
+                // E.g. with a full disk, the partial file would only take the space that is left
+                if written.is_err() {
+                    Self::remove_temp_file(&tmp_file_path);
+                }
+                written
+            }
+        });
+        let written = match self.io_timeout {
+            Some(io_timeout) => match tokio::time::timeout(io_timeout, &mut written).await {
+                Ok(written) => written,
+                Err(_) => {
+                    tokio::spawn(async move {
+                        if let Ok(Ok(())) = written.await {
+                            let remove = move || Self::remove_temp_file(&tmp_file_path);
+                            let _ = tokio::task::spawn_blocking(remove).await;
+                        }
+                    });
+                    return Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+                }
+            },
+            None => written.await,
+        };
+        written.map_err(std::io::Error::other)??;
+        #[cfg(test)]
+        if matches!(self.fault, Some(DiskFault::CrashBeforeRename)) {
+            panic!("simulated crash");
+        }
+        self.io(tokio::fs::rename(tmp_file_path, &file_path))
+            .await?;
+        self.sync_dir().await?; // make rename durable
+        if self.verify_writes {
+            let readback = self.io(tokio::fs::read(&file_path)).await?;
+            // The request fails and the entry is left as is, as there is no telling which state is
+            // the right one to revert to
+            if readback != contents {
+                return Err(CacheError::VerificationFailed(file_path));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_temp_file(path: &Path) {
+        match std::fs::remove_file(path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => eprintln!("Failed to remove {:?}: {}", path, err),
+        }
+    }
+
+    // Returns None for expired entries, removing them on the way (except in the grouped mode, where
+    // it would require rewriting the group). Only for the keys locked by the caller, as the removal
+    // could otherwise race with a concurrent write of the key.
+    async fn read_entry(&self, key: &str) -> Result<Option<DiskCacheEntry>, CacheError> {
+        let Some(entry) = self.peek_entry(key).await? else {
+            return Ok(None);
+        };
+        if !entry.is_expired() {
+            return Ok(Some(entry));
+        }
+        if self.group_size.is_none() {
+            match self.io(tokio::fs::remove_file(self.key_to_path(key))).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    // Whether the entry file exists and holds an expired entry. Malformed files are skipped (with a
+    // warning), so that they don't stop the expiry of the other entries.
+    async fn is_expired_file(&self, path: &Path) -> Result<bool, CacheError> {
+        match self.io(tokio::fs::read(path)).await {
+            Ok(contents) => match Self::parse(path, &contents) {
+                Ok(entry) => Ok(entry.is_expired()),
+                Err(CacheError::Malformed(path)) => {
+                    eprintln!("Skipping {:?}: the file is malformed", path);
+                    Ok(false)
+                }
+                Err(err) => Err(err),
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Like read_entry(), but returns the expired entries too
+    async fn peek_entry(&self, key: &str) -> Result<Option<DiskCacheEntry>, CacheError> {
+        if self.group_size.is_some() {
+            let hash = self.key_to_filename(key);
+            let entry = self.read_group_of(&hash).await?.remove(&hash);
+            if let Some(entry) = &entry {
+                let path = self
+                    .cache_dir
+                    .join(Self::group_filename(&self.group_of(&hash)));
+                Self::check_key(&path, entry, key)?;
+            }
+            return Ok(entry);
+        }
+        let path = self.key_to_path(key);
+        match self.io(tokio::fs::read(&path)).await {
+            Ok(contents) => {
+                let entry = self.deserialize(&path, &contents)?;
+                Self::check_key(&path, &entry, key)?;
+                Ok(Some(entry))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Follows the aliases, without removing the expired entries on the way
+    async fn resolve(&self, entry: DiskCacheEntry) -> Result<Option<Vec<u8>>, CacheError> {
+        Ok(self.resolve_entry(entry).await?.map(|entry| entry.value.0))
+    }
+
+    // Like resolve(), but returns the whole entry of the final target
+    async fn resolve_entry(
+        &self,
+        mut entry: DiskCacheEntry,
+    ) -> Result<Option<DiskCacheEntry>, CacheError> {
+        while let Some(target) = entry.target {
+            match self.peek_entry(&target).await? {
+                Some(target_entry) if !target_entry.is_expired() => entry = target_entry,
+                _ => return Ok(None),
+            }
+        }
+        Ok(Some(entry))
+    }
+
+    // Returns (key, value) pairs with the aliases resolved, dangling aliases are skipped
+    async fn resolved_entries(&self) -> Result<Vec<(String, Vec<u8>)>, CacheError> {
+        self.iter().try_collect().await
+    }
+
+    // Like entries(), but reads the files (or groups) one by one as the stream is consumed
+    fn entries_stream(&self) -> BoxStream<'_, Result<DiskCacheEntry, CacheError>> {
+        if self.group_size.is_some() {
+            let groups = async move {
+                // No group can be split until the stream is dropped
+                let rewrite = self.group_rewrites.lock().await;
+                let groups = self.groups.lock().unwrap().clone();
+                (rewrite, groups)
+            };
+            return futures::stream::once(groups)
+                .flat_map(move |(rewrite, groups)| {
+                    futures::stream::iter(groups).then(move |prefix| {
+                        let _rewrite = &rewrite;
+                        async move {
+                            match self.read_group(&prefix).await {
+                                Ok(group) => Ok(group.into_values().collect()),
+                                Err(CacheError::Malformed(path)) => {
+                                    eprintln!("Skipping {:?}: the file is malformed", path);
+                                    Ok(vec![])
+                                }
+                                Err(err) => Err(err),
+                            }
+                        }
+                    })
+                })
+                .map_ok(|entries: Vec<DiskCacheEntry>| {
+                    futures::stream::iter(entries.into_iter().map(Ok))
+                })
+                .try_flatten()
+                .try_filter(|entry| futures::future::ready(!entry.is_expired()))
+                .boxed();
+        }
+        futures::stream::once(self.io(tokio::fs::read_dir(&self.cache_dir)))
+            .map_ok(move |dir| {
+                futures::stream::try_unfold(dir, move |mut dir| async move {
+                    let entry = self.io(dir.next_entry()).await?;
+                    Ok::<_, std::io::Error>(entry.map(|entry| (entry, dir)))
+                })
+            })
+            .try_flatten()
+            .map_err(CacheError::from)
+            .try_filter(|entry| {
+                futures::future::ready(entry.file_name().len() == self.hash_len * 2)
+            })
+            .map_ok(move |entry| async move {
+                let path = entry.path();
+                let entry_name = entry.file_name().into_string().unwrap_or_default();
+                let contents = match self.io(tokio::fs::read(&path)).await {
+                    Ok(contents) => contents,
+                    // Deleted concurrently
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(err) => return Err(err.into()),
+                };
+                match self.deserialize(&path, &contents) {
+                    Ok(entry) if !self.matches_filename(&entry, &entry_name) => {
+                        eprintln!("Skipping {:?}: the key does not hash to the filename", path);
+                        Ok(None)
+                    }
+                    Ok(entry) if !entry.is_expired() => Ok(Some(entry)),
+                    Ok(_) => Ok(None),
+                    Err(err) => {
+                        eprintln!("Skipping {}", err);
+                        Ok(None)
+                    }
+                }
+            })
+            // The reads run on the blocking threads, so they proceed in parallel
+            .try_buffer_unordered(Self::PARALLEL_READS)
+            .try_filter_map(|entry| futures::future::ready(Ok(entry)))
+            .boxed()
+    }
+
+    // Malformed files are skipped (with a warning), so that they don't make the listing fail
+    async fn entries(&self) -> Result<Vec<DiskCacheEntry>, CacheError> {
+        self.entries_stream().try_collect().await
+    }
+}
+
+// The filename is only a hash of the key, so the full key is stored alongside the value to allow
+// listing the entries
+#[derive(PartialEq, Serialize, Deserialize)]
+struct DiskCacheEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>, // None with drop_keys
+    value: JsonBytes, // empty for aliases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<String>, // set for aliases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at_ms: Option<u64>, // milliseconds since the UNIX epoch
+    #[serde(flatten)]
+    meta: EntryMeta,
+    // Set if the value is encrypted (see DiskCache::seal())
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<JsonBytes>,
+    // The encrypted meta.history prefixed with its nonce, set instead of it with the cipher
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed_history: Option<JsonBytes>,
+    // Set if the value is gzip-compressed (before the encryption)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    gzip: bool,
+}
+
+impl DiskCacheEntry {
+    fn is_expired(&self) -> bool {
+        is_expired(
+            self.expires_at_ms
+                .map(|ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms)),
+        )
+    }
+}
+
+#[async_trait]
+impl Cache for DiskCache {
+    async fn list(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.resolved_entries()
+                .await?
+                .into_iter()
+                .map(|(key, value)| (key, JsonBytes::to_json(value))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    // The key is stored only inside the file, so the files have to be read anyway
+    async fn list_sizes(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.resolved_entries()
+                .await?
+                .into_iter()
+                .map(|(key, value)| (key, serde_json::json!({ "size": value.len() }))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    // The keys are known only after reading the files, so all of them are read
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError> {
+        let entries = self.resolved_entries().await?;
+        Ok(ListPage::new(entries, prefix, limit, offset))
+    }
+
+    // Only the entry being read (and the targets of an alias) are held in memory
+    fn iter(&self) -> BoxStream<'_, Result<(String, Vec<u8>), CacheError>> {
+        self.entries_stream()
+            .try_filter_map(move |entry| async move {
+                let Some(key) = entry.key.clone() else {
+                    return Ok(None);
+                };
+                match self.resolve(entry).await {
+                    Ok(value) => Ok(value.map(|value| (key, value))),
+                    Err(CacheError::Malformed(path)) => {
+                        eprintln!(
+                            "Skipping {:?} of alias {:?}: the file is malformed",
+                            path, key
+                        );
+                        Ok(None)
+                    }
+                    Err(err) => Err(err),
+                }
+            })
+            .boxed()
+    }
+
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        let entry = self.new_entry(key.clone(), value, expires_at.map(unix_ms), meta);
+        let _lock = self.lock_file(&key).await;
+        self.log_to_wal(MutationOp::Add, &key).await?;
+        self.write(&key, entry).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        let _lock = self.lock_file(key).await;
+        if self.group_size.is_some() {
+            let hash = self.key_to_filename(key);
+            let _rewrite = self.group_rewrites.lock().await;
+            let prefix = self.group_of(&hash);
+            let mut group = self.read_group(&prefix).await?;
+            let Some(entry) = group.remove(&hash) else {
+                return Ok(false);
+            };
+            self.log_to_wal(MutationOp::Delete, key).await?;
+            self.write_group(prefix, group).await?;
+            return Ok(!entry.is_expired());
+        }
+        if self.read_entry(key).await?.is_none() {
+            return Ok(false);
+        }
+        self.log_to_wal(MutationOp::Delete, key).await?;
+        match self.io(tokio::fs::remove_file(self.key_to_path(key))).await {
+            Ok(()) => {
+                self.sync_dir().await?; // make deletion durable
+                Ok(true)
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Reads all the entries, so the keys have to be stored. The directory is synced once, after all
+    // the deletions.
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        let matches = |entry: &DiskCacheEntry| {
+            entry
+                .key
+                .as_ref()
+                .is_some_and(|key| key.starts_with(prefix))
+        };
+        let mut deleted = 0;
+        if self.group_size.is_some() {
+            let _rewrite = self.group_rewrites.lock().await;
+            let groups = self.groups.lock().unwrap().clone();
+            for group_prefix in groups {
+                let mut group = self.read_group(&group_prefix).await?;
+                let (removed, kept) = std::mem::take(&mut group)
+                    .into_iter()
+                    .partition::<BTreeMap<_, _>, _>(|(_, entry)| matches(entry));
+                if removed.is_empty() {
+                    continue;
+                }
+                for entry in removed.values() {
+                    self.log_to_wal(MutationOp::Delete, entry.key.as_ref().unwrap())
+                        .await?;
+                    deleted += !entry.is_expired() as usize;
+                }
+                self.write_group(group_prefix, kept).await?;
+            }
+            return Ok(deleted);
+        }
+        let mut removed_any = false;
+        let mut files = self.io(tokio::fs::read_dir(&self.cache_dir)).await?;
+        while let Some(file) = self.io(files.next_entry()).await? {
+            if file.file_name().len() != self.hash_len * 2 {
+                continue;
+            }
+            let path = file.path();
+            let contents = match self.io(tokio::fs::read(&path)).await {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            // The keys are not encrypted
+            let entry = Self::parse(&path, &contents)?;
+            if !matches(&entry) {
+                continue;
+            }
+            self.log_to_wal(MutationOp::Delete, entry.key.as_ref().unwrap())
+                .await?;
+            match self.io(tokio::fs::remove_file(&path)).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            }
+            removed_any = true;
+            deleted += !entry.is_expired() as usize;
+        }
+        if removed_any {
+            self.sync_dir().await?; // make deletions durable
+        }
+        Ok(deleted)
+    }
+
+    // Removes the entry (or group) files, the malformed ones too
+    async fn clear(&self) -> Result<usize, CacheError> {
+        let _rewrite = self.group_rewrites.lock().await;
+        self.log_to_wal(MutationOp::DeletePrefix, "").await?;
+        let mut deleted = 0;
+        let mut removed_any = false;
+        let mut files = self.io(tokio::fs::read_dir(&self.cache_dir)).await?;
+        while let Some(file) = self.io(files.next_entry()).await? {
+            let file_name = file.file_name().into_string().unwrap_or_default();
+            let holds_entries = match self.group_size {
+                Some(_) => file_name.starts_with(Self::GROUP_FILENAME_PREFIX),
+                None => file_name.len() == self.hash_len * 2,
+            };
+            if !holds_entries {
+                continue;
+            }
+            let path = file.path();
+            let contents = match self.io(tokio::fs::read(&path)).await {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            // Without decrypting the values
+            let entries = match self.group_size {
+                Some(_) => serde_json::from_slice::<BTreeMap<String, DiskCacheEntry>>(&contents)
+                    .map(|group| group.into_values().collect())
+                    .unwrap_or_default(),
+                None => Self::parse(&path, &contents)
+                    .into_iter()
+                    .collect::<Vec<_>>(),
+            };
+            self.io(tokio::fs::remove_file(&path)).await?;
+            removed_any = true;
+            deleted += entries.iter().filter(|entry| !entry.is_expired()).count();
+        }
+        if self.group_size.is_some() {
+            *self.groups.lock().unwrap() = BTreeSet::from([String::new()]);
+        }
+        if removed_any {
+            self.sync_dir().await?; // make deletions durable
+        }
+        Ok(deleted)
+    }
+
+    // The entry is written under the same lock as its existence is checked
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        let _lock = self.lock_file(&key).await;
+        match self.read_entry(&key).await? {
+            Some(entry) => {
+                let meta = match entry.target {
+                    Some(_) => EntryMeta::new(None), // replaces the alias
+                    None => entry.meta.updated(),
+                };
+                let entry = self.new_entry(key.clone(), value, entry.expires_at_ms, meta);
+                self.log_to_wal(MutationOp::Modify, &key).await?;
+                self.write(&key, entry).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // The entry is rewritten as a whole under the same lock as it is read
+    async fn append(&self, key: String, suffix: Vec<u8>) -> Result<bool, CacheError> {
+        let _lock = self.lock_file(&key).await;
+        let Some(entry) = self.read_entry(&key).await? else {
+            return Ok(false);
+        };
+        let expires_at_ms = entry.expires_at_ms;
+        let meta = match entry.target {
+            Some(_) => EntryMeta::new(None), // replaces the alias
+            None => entry.meta.clone().updated(),
+        };
+        let Some(mut value) = self.resolve(entry).await? else {
+            return Ok(false);
+        };
+        value.extend(suffix);
+        self.log_to_wal(MutationOp::Modify, &key).await?;
+        self.write(
+            &key,
+            self.new_entry(key.clone(), value, expires_at_ms, meta),
+        )
+        .await?;
+        Ok(true)
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        let _lock = self.lock_file(&key).await;
+        let Some(entry) = self.read_entry(&key).await? else {
+            return Ok(CasResult::NotFound);
+        };
+        let expires_at_ms = entry.expires_at_ms;
+        let meta = match entry.target {
+            Some(_) => EntryMeta::new(None), // replaces the alias
+            None => entry.meta.clone().updated(),
+        };
+        match self.resolve(entry).await? {
+            Some(value) if value == expected => {}
+            Some(_) => return Ok(CasResult::Mismatch),
+            None => return Ok(CasResult::NotFound),
+        }
+        self.log_to_wal(MutationOp::Modify, &key).await?;
+        self.write(&key, self.new_entry(key.clone(), new, expires_at_ms, meta))
+            .await?;
+        Ok(CasResult::Swapped)
+    }
+
+    // Without the keys in the files, the file of from is just moved. Otherwise the entry is written
+    // under the new name with the new key inside, before the old file is removed.
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> Result<RenameResult, CacheError> {
+        let Some(entry) = self.read_entry(&from).await? else {
+            return Ok(RenameResult::NotFound);
+        };
+        let aliased = entry.target.is_some();
+        let Some(mut entry) = self.resolve_entry(entry).await? else {
+            return Ok(RenameResult::NotFound);
+        };
+        if !overwrite && self.get(&to).await?.is_some() {
+            return Ok(RenameResult::DestinationExists);
+        }
+        self.log_to_wal(MutationOp::Add, &to).await?;
+        let same_file = self.key_to_filename(&from) == self.key_to_filename(&to);
+        if self.drop_keys && !aliased && self.group_size.is_none() {
+            self.log_to_wal(MutationOp::Delete, &from).await?;
+            self.io(tokio::fs::rename(
+                self.key_to_path(&from),
+                self.key_to_path(&to),
+            ))
+            .await?;
+            self.sync_dir().await?; // make rename durable
+            return Ok(RenameResult::Renamed);
+        }
+        entry.key = Some(to.clone()).filter(|_| !self.drop_keys);
+        self.write(&to, entry).await?;
+        // Keys with the same hash share the file, which now holds the renamed entry
+        if !same_file {
+            self.delete(&from).await?;
+        }
+        Ok(RenameResult::Renamed)
+    }
+
+    // The entry is rewritten as a whole under the same lock as it is read
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError> {
+        let _lock = self.lock_file(key).await;
+        let mut entry = match self.read_entry(key).await? {
+            Some(entry) if entry.target.is_none() => entry,
+            _ => return Ok(false),
+        };
+        entry.expires_at_ms = Some(unix_ms(expires_at));
+        self.log_to_wal(MutationOp::Touch, key).await?;
+        self.write(key, entry).await?;
+        Ok(true)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        match self.read_entry(key).await? {
+            Some(entry) => self.resolve(entry).await,
+            None => Ok(None),
+        }
+    }
+
+    // Missing files are recognized without reading anything. The existing ones have to be read, as
+    // the entry may have expired, be a dangling alias or belong to a key with the same hash.
+    async fn contains(&self, key: &str) -> Result<bool, CacheError> {
+        if self.group_size.is_none()
+            && !self
+                .io(tokio::fs::try_exists(self.key_to_path(key)))
+                .await?
+        {
+            return Ok(false);
+        }
+        Ok(self.get(key).await?.is_some())
+    }
+
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        let Some(entry) = self.read_entry(key).await? else {
+            return Ok(None);
+        };
+        let aliased = entry.target.is_some();
+        let entry = self.resolve_entry(entry).await?;
+        Ok(entry.map(|entry| StoredValue {
+            value: entry.value.0,
+            meta: entry.meta,
+            expires_at: entry
+                .expires_at_ms
+                .map(|ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms)),
+            aliased,
+        }))
+    }
+
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        let _lock = self.lock_file(&key).await;
+        let val = self.get(&key).await?;
+        if val.is_none() {
+            let entry = self.new_entry(key.clone(), value, None, EntryMeta::new(None));
+            self.log_to_wal(MutationOp::Add, &key).await?;
+            self.write(&key, entry).await?;
+        }
+        Ok(val)
+    }
+
+    // Uses the file mtimes, so the contents don't need to be read
+    async fn remove_older_than(&self, max_age: Duration) -> Result<usize, CacheError> {
+        let now = SystemTime::now();
+        let mut entries = self.io(tokio::fs::read_dir(&self.cache_dir)).await?;
+        let mut removed = 0;
+        while let Some(entry) = self.io(entries.next_entry()).await? {
+            if entry.file_name().len() != self.hash_len * 2 {
+                continue;
+            }
+            let modified = entry.metadata().await?.modified()?;
+            if now.duration_since(modified).unwrap_or_default() > max_age {
+                match self.io(tokio::fs::remove_file(entry.path())).await {
+                    Ok(()) => removed += 1,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        if removed > 0 {
+            self.sync_dir().await?; // make deletions durable
+        }
+        Ok(removed)
+    }
+
+    // A group holds the keys of many hashes, so it is swept with all the keys locked. The separate
+    // files are found without any lock and then deleted under the lock of their hash byte, unless
+    // they got rewritten in the meantime.
+    async fn remove_expired(
+        &self,
+        max_count: usize,
+        locks: &KeyLocks,
+    ) -> Result<usize, CacheError> {
+        let mut removed = 0;
+        if self.group_size.is_some() {
+            let _lock = locks.write_all().await;
+            let groups = self.groups.lock().unwrap().clone();
+            for prefix in groups {
+                if removed >= max_count {
+                    break;
+                }
+                let mut group = match self.read_group(&prefix).await {
+                    Ok(group) => group,
+                    Err(CacheError::Malformed(path)) => {
+                        eprintln!("Skipping {:?}: the file is malformed", path);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                let len = group.len();
+                group.retain(|_, entry| !entry.is_expired());
+                if group.len() < len {
+                    removed += len - group.len();
+                    self.write_group(prefix, group).await?;
+                }
+            }
+            return Ok(removed);
+        }
+        let mut entries = self.io(tokio::fs::read_dir(&self.cache_dir)).await?;
+        while let Some(entry) = self.io(entries.next_entry()).await? {
+            if removed >= max_count {
+                break;
+            }
+            let Some(hash_byte) = entry
+                .file_name()
+                .to_str()
+                .filter(|name| name.len() == self.hash_len * 2)
+                .and_then(|name| u8::from_str_radix(&name[..2], 16).ok())
+            else {
+                continue;
+            };
+            let path = entry.path();
+            if !self.is_expired_file(&path).await? {
+                continue;
+            }
+            let _lock = locks.write_hash_byte(hash_byte).await;
+            if self.is_expired_file(&path).await? {
+                self.io(tokio::fs::remove_file(&path)).await?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.sync_dir().await?; // make deletions durable
+        }
+        Ok(removed)
+    }
+
+    // Runs exclusively, so all the .new files are left by writes that were interrupted (e.g. by a
+    // crash). A removed malformed group leaves its prefix in groups, it is then empty.
+    async fn compact(&self) -> Result<Compaction, CacheError> {
+        let mut compaction = Compaction::default();
+        let mut entries = self.io(tokio::fs::read_dir(&self.cache_dir)).await?;
+        while let Some(entry) = self.io(entries.next_entry()).await? {
+            let file_name = entry.file_name().into_string().unwrap_or_default();
+            let path = entry.path();
+            if file_name.ends_with(".new") {
+                self.io(tokio::fs::remove_file(&path)).await?;
+                compaction.temp_files += 1;
+                continue;
+            }
+            let holds_entries = match self.group_size {
+                Some(_) => file_name.starts_with(Self::GROUP_FILENAME_PREFIX),
+                None => file_name.len() == self.hash_len * 2,
+            };
+            if !holds_entries {
+                continue;
+            }
+            let contents = self.io(tokio::fs::read(&path)).await?;
+            let parses = if self.group_size.is_some() {
+                serde_json::from_slice::<BTreeMap<String, DiskCacheEntry>>(&contents).is_ok()
+            } else {
+                Self::parse(&path, &contents).is_ok()
+            };
+            if !parses {
+                self.io(tokio::fs::remove_file(&path)).await?;
+                compaction.malformed_files += 1;
+            }
+        }
+        if compaction != Compaction::default() {
+            self.sync_dir().await?; // make deletions durable
+        }
+        Ok(compaction)
+    }
+
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError> {
+        let mut key = target.clone();
+        loop {
+            if key == alias {
+                return Err(AliasError::Cycle);
+            }
+            match self.read_entry(&key).await? {
+                Some(DiskCacheEntry {
+                    target: Some(next), ..
+                }) => key = next,
+                Some(_) => break,
+                None => return Err(AliasError::TargetNotFound),
+            }
+        }
+        let entry = DiskCacheEntry {
+            key: Some(alias.clone()).filter(|_| !self.drop_keys),
+            value: JsonBytes(vec![]),
+            target: Some(target),
+            expires_at_ms: None,
+            meta: EntryMeta::new(None),
+            nonce: None,
+            sealed_history: None,
+            gzip: false,
+        };
+        self.log_to_wal(MutationOp::Alias, &alias).await?;
+        self.write(&alias, entry).await?;
+        Ok(())
+    }
+
+    // Aliases are not indexed, so all the entries have to be read
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError> {
+        Ok(self
+            .entries()
+            .await?
+            .iter()
+            .any(|entry| entry.target.as_deref() == Some(key)))
+    }
+
+    // Counts the files (or the entries of the groups) without parsing the entries
+    async fn len(&self) -> Result<usize, CacheError> {
+        if self.group_size.is_some() {
+            let _rewrite = self.group_rewrites.lock().await;
+            let groups = self.groups.lock().unwrap().clone();
+            let mut len = 0;
+            for prefix in groups {
+                len += self.read_group(&prefix).await?.len();
+            }
+            return Ok(len);
+        }
+        let mut entries = self.io(tokio::fs::read_dir(&self.cache_dir)).await?;
+        let mut len = 0;
+        while let Some(entry) = self.io(entries.next_entry()).await? {
+            if entry.file_name().len() == self.hash_len * 2 {
+                len += 1;
+            }
+        }
+        Ok(len)
+    }
+
+    async fn stats(&self) -> Result<CacheStats, CacheError> {
+        let mut stats = CacheStats::of_entries(self.iter()).await?;
+        // The namespaces live in the subdirectories and have their own stats
+        let mut files = self.io(tokio::fs::read_dir(&self.cache_dir)).await?;
+        let mut disk_usage_bytes = 0;
+        while let Some(file) = self.io(files.next_entry()).await? {
+            let metadata = match file.metadata().await {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue, // just deleted
+                Err(err) => return Err(err.into()),
+            };
+            if metadata.is_file() {
+                disk_usage_bytes += metadata.len();
+            }
+        }
+        stats.disk_usage_bytes = Some(disk_usage_bytes);
+        Ok(stats)
+    }
+
+    // Creates and removes a file, named so that it is never taken for an entry or a group
+    async fn check_writable(&self) -> Result<(), CacheError> {
+        static CHECKS: AtomicU64 = AtomicU64::new(0);
+        let path = self.cache_dir.join(format!(
+            ".ready-{}-{}",
+            std::process::id(),
+            CHECKS.fetch_add(1, Ordering::Relaxed)
+        ));
+        self.io(tokio::fs::write(&path, b"")).await?;
+        self.io(tokio::fs::remove_file(&path)).await?;
+        Ok(())
+    }
+
+    async fn namespace(&self, name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError> {
+        let cache_dir = self.namespace_dir(name);
+        self.io(tokio::fs::create_dir_all(&cache_dir)).await?;
+        let mut cache = DiskCache::with_hash_len(cache_dir, self.hash_len);
+        cache
+            .check_hash_len()
+            .await
+            .map_err(std::io::Error::other)?;
+        cache.verify_writes = self.verify_writes;
+        cache.drop_keys = self.drop_keys;
+        cache.wal = self.wal.as_ref().map(|wal| wal.for_namespace(name));
+        cache.cipher = self.cipher.clone();
+        cache.compress_values = self.compress_values;
+        cache.dir_sync = self.dir_sync.clone();
+        cache.io_timeout = self.io_timeout;
+        if self.group_size.is_some() {
+            cache.group_size = self.group_size;
+            cache.load_groups().await;
+        }
+        Ok(Box::new(cache))
+    }
+
+    async fn has_namespace(&self, name: &str) -> Result<bool, CacheError> {
+        Ok(self
+            .io(tokio::fs::try_exists(self.namespace_dir(name)))
+            .await?)
+    }
+}
+
+// SQLite database cache - persistent like DiskCache, but in a single file. Aliases are rows with
+// the target set (and an empty value).
+struct SqliteCache {
+    path: PathBuf, // namespaces are stored in the <path>.namespaces directory
+    // rusqlite is blocking, so the queries run on the blocking threads of tokio
+    conn: Arc<std::sync::Mutex<rusqlite::Connection>>,
+}
+
+impl SqliteCache {
+    // The databases of the namespaces are next to the main one
+    fn namespace_path(&self, name: &str) -> PathBuf {
+        let mut dir = self.path.clone().into_os_string();
+        dir.push(".namespaces");
+        PathBuf::from(dir).join(format!("{}.db", name))
+    }
+
+    fn open(path: &Path) -> Result<Self, CacheError> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                key TEXT PRIMARY KEY,
+                value BLOB NOT NULL,
+                target TEXT,
+                expires_at_ms INTEGER,
+                content_type TEXT,
+                created_at_ms INTEGER NOT NULL DEFAULT 0,
+                updated_at_ms INTEGER NOT NULL DEFAULT 0,
+                history TEXT
+            )",
+        )?;
+        // Databases created before the EntryMeta was stored lack its columns
+        if conn.prepare("SELECT content_type FROM entries").is_err() {
+            conn.execute_batch(
+                "ALTER TABLE entries ADD COLUMN content_type TEXT;
+                ALTER TABLE entries ADD COLUMN created_at_ms INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE entries ADD COLUMN updated_at_ms INTEGER NOT NULL DEFAULT 0;",
+            )?;
+        }
+        if conn.prepare("SELECT history FROM entries").is_err() {
+            conn.execute_batch("ALTER TABLE entries ADD COLUMN history TEXT")?;
+        }
+        Ok(SqliteCache {
+            path: path.to_path_buf(),
+            conn: Arc::new(std::sync::Mutex::new(conn)),
+        })
+    }
+
+    async fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, CacheError> {
+        let conn = self.conn.clone();
+        Ok(
+            tokio::task::spawn_blocking(move || f(&conn.lock().unwrap()))
+                .await
+                .unwrap()?,
+        )
+    }
+
+    fn now_ms() -> i64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64
+    }
+
+    // Returns the live (value, target) of the key
+    fn lookup(
+        conn: &rusqlite::Connection,
+        key: &str,
+    ) -> rusqlite::Result<Option<(Vec<u8>, Option<String>)>> {
+        conn.query_row(
+            "SELECT value, target FROM entries
+                WHERE key = ?1 AND (expires_at_ms IS NULL OR expires_at_ms > ?2)",
+            rusqlite::params![key, Self::now_ms()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+    }
+
+    fn resolve_with_meta(
+        conn: &rusqlite::Connection,
+        key: &str,
+    ) -> rusqlite::Result<Option<StoredValue>> {
+        let mut key = key.to_string();
+        let mut aliased = false;
+        loop {
+            let row = conn
+                .query_row(
+                    "SELECT value, target, content_type, created_at_ms, updated_at_ms, expires_at_ms,
+                            history
+                        FROM entries
+                        WHERE key = ?1 AND (expires_at_ms IS NULL OR expires_at_ms > ?2)",
+                    rusqlite::params![key, Self::now_ms()],
+                    |row| {
+                        let stored = StoredValue {
+                            value: row.get(0)?,
+                            meta: EntryMeta {
+                                content_type: row.get(2)?,
+                                created_at_ms: row.get::<_, i64>(3)? as u64,
+                                updated_at_ms: row.get::<_, i64>(4)? as u64,
+                                history: Self::history_from_sql(row.get(6)?),
+                            },
+                            expires_at: row.get::<_, Option<i64>>(5)?.map(|ms| {
+                                SystemTime::UNIX_EPOCH + Duration::from_millis(ms as u64)
+                            }),
+                            aliased,
+                        };
+                        Ok((row.get(1)?, stored))
+                    },
+                )
+                .optional()?;
+            match row {
+                Some((Some(target), _)) => {
+                    key = target;
+                    aliased = true;
+                }
+                Some((None, stored)) => return Ok(Some(stored)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn resolve(conn: &rusqlite::Connection, key: &str) -> rusqlite::Result<Option<Vec<u8>>> {
+        let mut key = key.to_string();
+        loop {
+            match Self::lookup(conn, &key)? {
+                Some((_, Some(target))) => key = target,
+                Some((value, None)) => return Ok(Some(value)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn resolved_entries(conn: &rusqlite::Connection) -> rusqlite::Result<Vec<(String, Vec<u8>)>> {
+        let mut stmt = conn.prepare(
+            "SELECT key, value, target FROM entries
+                WHERE expires_at_ms IS NULL OR expires_at_ms > ?1",
+        )?;
+        let rows = stmt
+            .query_map([Self::now_ms()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, Vec<u8>, Option<String>)>>>()?;
+        let mut entries = vec![];
+        for (key, value, target) in rows {
+            match target {
+                Some(target) => {
+                    if let Some(value) = Self::resolve(conn, &target)? {
+                        entries.push((key, value));
+                    }
+                }
+                None => entries.push((key, value)),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn insert(
+        conn: &rusqlite::Connection,
+        key: &str,
+        value: &[u8],
+        target: Option<&str>,
+        expires_at_ms: Option<i64>,
+        meta: &EntryMeta,
+    ) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT OR REPLACE INTO entries
+                (key, value, target, expires_at_ms, content_type, created_at_ms, updated_at_ms,
+                    history)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![
+                key,
+                value,
+                target,
+                expires_at_ms,
+                meta.content_type,
+                meta.created_at_ms as i64,
+                meta.updated_at_ms as i64,
+                Self::history_to_sql(&meta.history),
+            ],
+        )?;
+        Ok(())
+    }
+
+    // The history column holds the JSON array, NULL if it is empty
+    fn history_to_sql(history: &[PastValue]) -> Option<String> {
+        Some(history)
+            .filter(|history| !history.is_empty())
+            .map(|history| serde_json::to_string(history).unwrap())
+    }
+
+    fn history_from_sql(history: Option<String>) -> Vec<PastValue> {
+        history
+            .and_then(|history| serde_json::from_str(&history).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[async_trait]
+impl Cache for SqliteCache {
+    async fn list(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.call(Self::resolved_entries)
+                .await?
+                .into_iter()
+                .map(|(key, value)| (key, JsonBytes::to_json(value))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    async fn list_sizes(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.call(Self::resolved_entries)
+                .await?
+                .into_iter()
+                .map(|(key, value)| (key, serde_json::json!({ "size": value.len() }))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError> {
+        let entries = self.call(Self::resolved_entries).await?;
+        Ok(ListPage::new(entries, prefix, limit, offset))
+    }
+
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        let expires_at_ms = expires_at.map(|expires_at| unix_ms(expires_at) as i64);
+        self.call(move |conn| Self::insert(conn, &key, &value, None, expires_at_ms, &meta))
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        let key = key.to_string();
+        self.call(move |conn| {
+            let live = Self::lookup(conn, &key)?.is_some();
+            conn.execute("DELETE FROM entries WHERE key = ?1", [&key])?;
+            Ok(live)
+        })
+        .await
+    }
+
+    // substr() counts the characters of TEXT
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        let prefix = prefix.to_string();
+        self.call(move |conn| {
+            let prefix_len = prefix.chars().count();
+            let transaction = conn.unchecked_transaction()?;
+            let deleted = transaction.execute(
+                "DELETE FROM entries WHERE substr(key, 1, ?2) = ?1
+                    AND (expires_at_ms IS NULL OR expires_at_ms > ?3)",
+                rusqlite::params![prefix, prefix_len, Self::now_ms()],
+            )?;
+            // The expired ones
+            transaction.execute(
+                "DELETE FROM entries WHERE substr(key, 1, ?2) = ?1",
+                rusqlite::params![prefix, prefix_len],
+            )?;
+            transaction.commit()?;
+            Ok(deleted)
+        })
+        .await
+    }
+
+    // Modifying an alias replaces it with the value
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        self.call(move |conn| {
+            let modified = conn.execute(
+                "UPDATE entries SET value = ?2, target = NULL, updated_at_ms = ?3
+                    WHERE key = ?1 AND (expires_at_ms IS NULL OR expires_at_ms > ?3)",
+                rusqlite::params![key, value, Self::now_ms()],
+            )?;
+            Ok(modified > 0)
+        })
+        .await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        self.call(move |conn| {
+            match Self::resolve(conn, &key)? {
+                Some(value) if value == expected => {}
+                Some(_) => return Ok(CasResult::Mismatch),
+                None => return Ok(CasResult::NotFound),
+            }
+            conn.execute(
+                "UPDATE entries SET value = ?2, target = NULL, updated_at_ms = ?3 WHERE key = ?1",
+                rusqlite::params![key, new, Self::now_ms()],
+            )?;
+            Ok(CasResult::Swapped)
+        })
+        .await
+    }
+
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> Result<RenameResult, CacheError> {
+        self.call(move |conn| {
+            let Some(stored) = Self::resolve_with_meta(conn, &from)? else {
+                return Ok(RenameResult::NotFound);
+            };
+            if !overwrite && Self::resolve(conn, &to)?.is_some() {
+                return Ok(RenameResult::DestinationExists);
+            }
+            let transaction = conn.unchecked_transaction()?;
+            transaction.execute(
+                "DELETE FROM entries WHERE key = ?1 OR key = ?2",
+                rusqlite::params![from, to],
+            )?;
+            transaction.execute(
+                "INSERT INTO entries
+                    (key, value, expires_at_ms, content_type, created_at_ms, updated_at_ms, history)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                rusqlite::params![
+                    to,
+                    stored.value,
+                    stored.expires_at.map(|time| unix_ms(time) as i64),
+                    stored.meta.content_type,
+                    stored.meta.created_at_ms as i64,
+                    stored.meta.updated_at_ms as i64,
+                    Self::history_to_sql(&stored.meta.history),
+                ],
+            )?;
+            transaction.commit()?;
+            Ok(RenameResult::Renamed)
+        })
+        .await
+    }
+
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError> {
+        let key = key.to_string();
+        self.call(move |conn| {
+            let touched = conn.execute(
+                "UPDATE entries SET expires_at_ms = ?2
+                    WHERE key = ?1 AND target IS NULL
+                        AND (expires_at_ms IS NULL OR expires_at_ms > ?3)",
+                rusqlite::params![key, unix_ms(expires_at) as i64, Self::now_ms()],
+            )?;
+            Ok(touched > 0)
+        })
+        .await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        let key = key.to_string();
+        self.call(move |conn| Self::resolve(conn, &key)).await
+    }
+
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        let key = key.to_string();
+        self.call(move |conn| Self::resolve_with_meta(conn, &key))
+            .await
+    }
+
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        self.call(move |conn| {
+            let val = Self::resolve(conn, &key)?;
+            if val.is_none() {
+                Self::insert(conn, &key, &value, None, None, &EntryMeta::new(None))?;
+            }
+            Ok(val)
+        })
+        .await
+    }
+
+    // The modification times are not tracked (--max-entry-age requires --cache-dir)
+    async fn remove_older_than(&self, _max_age: Duration) -> Result<usize, CacheError> {
+        Ok(0)
+    }
+
+    async fn remove_expired(
+        &self,
+        max_count: usize,
+        locks: &KeyLocks,
+    ) -> Result<usize, CacheError> {
+        let _lock = locks.write_all().await;
+        self.call(move |conn| {
+            conn.execute(
+                "DELETE FROM entries WHERE key IN
+                    (SELECT key FROM entries WHERE expires_at_ms <= ?1 LIMIT ?2)",
+                rusqlite::params![Self::now_ms(), max_count as i64],
+            )
+        })
+        .await
+    }
+
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError> {
+        self.call(move |conn| {
+            let mut key = target.clone();
+            loop {
+                if key == alias {
+                    return Ok(Err(AliasError::Cycle));
+                }
+                match Self::lookup(conn, &key)? {
+                    Some((_, Some(next))) => key = next,
+                    Some((_, None)) => break,
+                    None => return Ok(Err(AliasError::TargetNotFound)),
+                }
+            }
+            Self::insert(
+                conn,
+                &alias,
+                &[],
+                Some(&target),
+                None,
+                &EntryMeta::new(None),
+            )?;
+            Ok(Ok(()))
+        })
+        .await?
+    }
+
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError> {
+        let key = key.to_string();
+        self.call(move |conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM entries WHERE target = ?1)",
+                [&key],
+                |row| row.get(0),
+            )
+        })
+        .await
+    }
+
+    async fn len(&self) -> Result<usize, CacheError> {
+        self.call(|conn| conn.query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0)))
+            .await
+    }
+
+    async fn namespace(&self, name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError> {
+        let path = self.namespace_path(name);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        // Opening creates the file and the table, which blocks
+        Ok(Box::new(
+            tokio::task::spawn_blocking(move || SqliteCache::open(&path))
+                .await
+                .unwrap()?,
+        ))
+    }
+
+    async fn has_namespace(&self, name: &str) -> Result<bool, CacheError> {
+        Ok(tokio::fs::try_exists(self.namespace_path(name)).await?)
+    }
+}
+
+// Delegates to a Redis server, e.g. one that is already run for other purposes. The entries are
+// plain Redis keys under the prefix: the value in v:<key> (so other Redis clients can GET it), its
+// EntryMeta as JSON in m:<key> and the target of an alias in a:<key>. The expiration times are
+// Redis' own, so Redis removes the expired entries.
+#[derive(Clone)]
+struct RedisCache {
+    conn: redis::aio::ConnectionManager, // reconnects if the connection is lost
+    prefix: String,                      // empty, or ns:<name>: in a namespace
+}
+
+impl RedisCache {
+    // Number of keys fetched with one MGET
+    const BATCH_SIZE: usize = 1000;
+
+    // Fails if the server cannot be reached
+    async fn connect(url: &str) -> Result<Self, CacheError> {
+        let client = redis::Client::open(url)?;
+        Ok(RedisCache {
+            conn: redis::aio::ConnectionManager::new(client).await?,
+            prefix: String::new(),
+        })
+    }
+
+    fn value_key(&self, key: &str) -> String {
+        format!("{}v:{}", self.prefix, key)
+    }
+
+    fn meta_key(&self, key: &str) -> String {
+        format!("{}m:{}", self.prefix, key)
+    }
+
+    fn alias_key(&self, key: &str) -> String {
+        format!("{}a:{}", self.prefix, key)
+    }
+
+    // Set of the names of the created namespaces, so that telling whether one exists doesn't take
+    // scanning the keys
+    fn namespaces_key(&self) -> String {
+        format!("{}namespaces", self.prefix)
+    }
+
+    // SCAN matches the keys against a glob pattern
+    fn escape_glob(s: &str) -> String {
+        let mut escaped = String::with_capacity(s.len());
+        for c in s.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    // Returns the keys of the values ("v") or the aliases ("a") that start with key_prefix
+    async fn scan(&self, kind: &str, key_prefix: &str) -> Result<Vec<String>, CacheError> {
+        let pattern = format!(
+            "{}*",
+            Self::escape_glob(&format!("{}{}:{}", self.prefix, kind, key_prefix))
+        );
+        let mut conn = self.conn.clone();
+        let mut iter = conn.scan_match::<_, String>(pattern).await?;
+        let stripped = self.prefix.len() + kind.len() + 1;
+        let mut keys = vec![];
+        while let Some(key) = iter.next_item().await {
+            keys.push(key[stripped..].to_string());
+        }
+        Ok(keys)
+    }
+
+    // Adds the commands replacing the entry (or alias) of the key with the value
+    fn write(
+        &self,
+        pipe: &mut redis::Pipeline,
+        key: &str,
+        value: Vec<u8>,
+        meta: &EntryMeta,
+        expires_at: Option<SystemTime>,
+    ) {
+        pipe.del(self.alias_key(key))
+            .ignore()
+            .set(self.value_key(key), value)
+            .ignore()
+            .set(self.meta_key(key), serde_json::to_string(meta).unwrap())
+            .ignore();
+        if let Some(expires_at) = expires_at {
+            for redis_key in [self.value_key(key), self.meta_key(key)] {
+                pipe.cmd("PEXPIREAT")
+                    .arg(redis_key)
+                    .arg(unix_ms(expires_at))
+                    .ignore();
+            }
+        }
+    }
+
+    async fn resolve_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        let mut conn = self.conn.clone();
+        let mut key = key.to_string();
+        let mut aliased = false;
+        loop {
+            let (value, meta, ttl_ms, target): (
+                Option<Vec<u8>>,
+                Option<String>,
+                i64,
+                Option<String>,
+            ) = redis::pipe()
+                .atomic()
+                .get(self.value_key(&key))
+                .get(self.meta_key(&key))
+                .pttl(self.value_key(&key))
+                .get(self.alias_key(&key))
+                .query_async(&mut conn)
+                .await?;
+            if let Some(value) = value {
+                let meta = meta
+                    .and_then(|meta| serde_json::from_str(&meta).ok())
+                    .unwrap_or_default();
+                // Negative if the value does not expire
+                let expires_at = u64::try_from(ttl_ms)
+                    .ok()
+                    .map(|ttl_ms| SystemTime::now() + Duration::from_millis(ttl_ms));
+                return Ok(Some(StoredValue {
+                    value,
+                    meta,
+                    expires_at,
+                    aliased,
+                }));
+            }
+            let Some(target) = target else {
+                return Ok(None);
+            };
+            key = target;
+            aliased = true;
+        }
+    }
+
+    async fn resolved_entries(
+        &self,
+        key_prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, CacheError> {
+        let mut conn = self.conn.clone();
+        let mut entries = vec![];
+        for keys in self.scan("v", key_prefix).await?.chunks(Self::BATCH_SIZE) {
+            let redis_keys = keys
+                .iter()
+                .map(|key| self.value_key(key))
+                .collect::<Vec<_>>();
+            let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+                .arg(redis_keys)
+                .query_async(&mut conn)
+                .await?;
+            // Unless deleted or expired in the meantime
+            entries.extend(
+                keys.iter()
+                    .zip(values)
+                    .filter_map(|(key, value)| Some((key.clone(), value?))),
+            );
+        }
+        for alias in self.scan("a", key_prefix).await? {
+            if let Some(stored) = self.resolve_with_meta(&alias).await? {
+                entries.push((alias, stored.value));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl Cache for RedisCache {
+    async fn list(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.resolved_entries("")
+                .await?
+                .into_iter()
+                .map(|(key, value)| (key, JsonBytes::to_json(value))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    async fn list_sizes(&self) -> Result<Value, CacheError> {
+        let map = serde_json::Map::from_iter(
+            self.resolved_entries("")
+                .await?
+                .into_iter()
+                .map(|(key, value)| (key, serde_json::json!({ "size": value.len() }))),
+        );
+        Ok(Value::Object(map))
+    }
+
+    // Only the keys with the prefix are scanned
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError> {
+        let entries = self.resolved_entries(prefix).await?;
+        Ok(ListPage::new(entries, prefix, limit, offset))
+    }
+
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        self.write(&mut pipe, &key, value, &meta, expires_at);
+        Ok(pipe.query_async(&mut self.conn.clone()).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        let (value, alias): (bool, bool) = redis::pipe()
+            .atomic()
+            .del(self.value_key(key))
+            .del(self.meta_key(key))
+            .ignore()
+            .del(self.alias_key(key))
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(value || alias)
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        let keys = self.scan("v", prefix).await?;
+        let aliases = self.scan("a", prefix).await?;
+        if keys.is_empty() && aliases.is_empty() {
+            return Ok(0);
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for key in &keys {
+            pipe.del(self.value_key(key))
+                .ignore()
+                .del(self.meta_key(key))
+                .ignore();
+        }
+        for alias in &aliases {
+            pipe.del(self.alias_key(alias)).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut self.conn.clone()).await?;
+        Ok(keys.len() + aliases.len())
+    }
+
+    // Modifying an alias replaces it with the value
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        let mut conn = self.conn.clone();
+        let (is_alias, meta): (bool, Option<String>) = redis::pipe()
+            .exists(self.alias_key(&key))
+            .get(self.meta_key(&key))
+            .query_async(&mut conn)
+            .await?;
+        if is_alias {
+            self.add(key, value, None).await?;
+            return Ok(true);
+        }
+        let meta = meta
+            .and_then(|meta| serde_json::from_str::<EntryMeta>(&meta).ok())
+            .unwrap_or_default()
+            .updated();
+        // XX, so that an entry that expired in the meantime is not recreated
+        let (modified,): (redis::Value,) = redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(self.value_key(&key))
+            .arg(value)
+            .arg("XX")
+            .arg("KEEPTTL")
+            .cmd("SET")
+            .arg(self.meta_key(&key))
+            .arg(serde_json::to_string(&meta).unwrap())
+            .arg("XX")
+            .arg("KEEPTTL")
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(modified != redis::Value::Nil)
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        match self.get(&key).await? {
+            Some(value) if value == expected => {}
+            Some(_) => return Ok(CasResult::Mismatch),
+            None => return Ok(CasResult::NotFound),
+        }
+        Ok(match self.modify(key, new).await? {
+            true => CasResult::Swapped,
+            false => CasResult::NotFound,
+        })
+    }
+
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> Result<RenameResult, CacheError> {
+        let Some(stored) = self.resolve_with_meta(&from).await? else {
+            return Ok(RenameResult::NotFound);
+        };
+        if !overwrite && self.get(&to).await?.is_some() {
+            return Ok(RenameResult::DestinationExists);
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .del(&[
+                self.value_key(&from),
+                self.meta_key(&from),
+                self.alias_key(&from),
+            ])
+            .ignore();
+        self.write(
+            &mut pipe,
+            &to,
+            stored.value,
+            &stored.meta,
+            stored.expires_at,
+        );
+        pipe.query_async::<_, ()>(&mut self.conn.clone()).await?;
+        Ok(RenameResult::Renamed)
+    }
+
+    // The aliases are under other keys, so they are not found
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError> {
+        let (touched,): (bool,) = redis::pipe()
+            .atomic()
+            .cmd("PEXPIREAT")
+            .arg(self.value_key(key))
+            .arg(unix_ms(expires_at))
+            .cmd("PEXPIREAT")
+            .arg(self.meta_key(key))
+            .arg(unix_ms(expires_at))
+            .ignore()
+            .query_async(&mut self.conn.clone())
+            .await?;
+        Ok(touched)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        Ok(self
+            .resolve_with_meta(key)
+            .await?
+            .map(|stored| stored.value))
+    }
+
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        self.resolve_with_meta(key).await
+    }
+
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        let val = self.get(&key).await?;
+        if val.is_none() {
+            self.add(key, value, None).await?;
+        }
+        Ok(val)
+    }
+
+    // The modification times are not tracked (--max-entry-age requires --cache-dir)
+    async fn remove_older_than(&self, _max_age: Duration) -> Result<usize, CacheError> {
+        Ok(0)
+    }
+
+    // Redis removes the expired entries itself
+    async fn remove_expired(
+        &self,
+        _max_count: usize,
+        _locks: &KeyLocks,
+    ) -> Result<usize, CacheError> {
+        Ok(0)
+    }
+
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError> {
+        let mut conn = self.conn.clone();
+        let mut key = target.clone();
+        loop {
+            if key == alias {
+                return Err(AliasError::Cycle);
+            }
+            let (exists, next): (bool, Option<String>) = redis::pipe()
+                .exists(self.value_key(&key))
+                .get(self.alias_key(&key))
+                .query_async(&mut conn)
+                .await
+                .map_err(CacheError::from)?;
+            if exists {
+                break;
+            }
+            key = next.ok_or(AliasError::TargetNotFound)?;
+        }
+        redis::pipe()
+            .atomic()
+            .del(&[self.value_key(&alias), self.meta_key(&alias)])
+            .ignore()
+            .set(self.alias_key(&alias), target)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(CacheError::from)?;
+        Ok(())
+    }
+
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError> {
+        let mut conn = self.conn.clone();
+        for aliases in self.scan("a", "").await?.chunks(Self::BATCH_SIZE) {
+            let redis_keys = aliases
+                .iter()
+                .map(|alias| self.alias_key(alias))
+                .collect::<Vec<_>>();
+            let targets: Vec<Option<String>> = redis::cmd("MGET")
+                .arg(redis_keys)
+                .query_async(&mut conn)
+                .await?;
+            if targets.iter().flatten().any(|target| target == key) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn len(&self) -> Result<usize, CacheError> {
+        Ok(self.scan("v", "").await?.len() + self.scan("a", "").await?.len())
+    }
+
+    // Sets and deletes a probe key rather than sending PING, which e.g. a read-only replica or a
+    // server refusing writes (out of memory, failing to persist) answers as well
+    async fn check_writable(&self) -> Result<(), CacheError> {
+        let key = format!("{}ready", self.prefix);
+        redis::pipe()
+            .atomic()
+            .set(&key, "")
+            .ignore()
+            .del(&key)
+            .ignore()
+            .query_async::<_, ()>(&mut self.conn.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn namespace(&self, name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError> {
+        let mut conn = self.conn.clone();
+        conn.sadd::<_, _, ()>(self.namespaces_key(), name).await?;
+        Ok(Box::new(RedisCache {
+            conn: self.conn.clone(),
+            prefix: format!("ns:{}:", name),
+        }))
+    }
+
+    async fn has_namespace(&self, name: &str) -> Result<bool, CacheError> {
+        let mut conn = self.conn.clone();
+        Ok(conn.sismember(self.namespaces_key(), name).await?)
+    }
+}
+
+// Keeps the keys with the ephemeral prefix (e.g. locks, sessions) in memory and all the others in
+// the durable cache, so that high-churn transient keys don't pay for the fsyncs. Ephemeral keys
+// are lost on restart.
+struct HybridCache {
+    ephemeral_prefix: String,
+    ephemeral: MemCache,
+    durable: Box<dyn Cache + Send + Sync>,
+}
+
+impl HybridCache {
+    fn new(ephemeral_prefix: String, durable: Box<dyn Cache + Send + Sync>) -> Self {
+        HybridCache {
+            ephemeral_prefix,
+            ephemeral: MemCache::new(),
+            durable,
+        }
+    }
+
+    fn cache_for(&self, key: &str) -> &(dyn Cache + Send + Sync) {
+        if key.starts_with(&self.ephemeral_prefix) {
+            &self.ephemeral
+        } else {
+            self.durable.as_ref()
+        }
+    }
+
+    fn merge(mut durable: Value, ephemeral: Value) -> Value {
+        if let (Value::Object(durable), Value::Object(ephemeral)) = (&mut durable, ephemeral) {
+            durable.extend(ephemeral);
+        }
+        durable
+    }
+}
+
+#[async_trait]
+impl Cache for HybridCache {
+    async fn list(&self) -> Result<Value, CacheError> {
+        Ok(Self::merge(
+            self.durable.list().await?,
+            self.ephemeral.list().await?,
+        ))
+    }
+
+    async fn list_sizes(&self) -> Result<Value, CacheError> {
+        Ok(Self::merge(
+            self.durable.list_sizes().await?,
+            self.ephemeral.list_sizes().await?,
+        ))
+    }
+
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError> {
+        let mut entries = self.durable.list_paged(prefix, None, 0).await?.entries;
+        entries.extend(self.ephemeral.list_paged(prefix, None, 0).await?.entries);
+        Ok(ListPage::new(entries, prefix, limit, offset))
+    }
+
+    // Streams the durable entries, as the wrapped cache does, instead of collecting them
+    fn iter(&self) -> BoxStream<'_, Result<(String, Vec<u8>), CacheError>> {
+        self.durable.iter().chain(self.ephemeral.iter()).boxed()
+    }
+
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        self.cache_for(&key)
+            .add_with_meta(key, value, expires_at, meta)
+            .await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        self.cache_for(key).delete(key).await
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        Ok(
+            self.ephemeral.delete_prefix(prefix).await?
+                + self.durable.delete_prefix(prefix).await?,
+        )
+    }
+
+    async fn clear(&self) -> Result<usize, CacheError> {
+        Ok(self.ephemeral.clear().await? + self.durable.clear().await?)
+    }
+
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        self.cache_for(&key).modify(key, value).await
+    }
+
+    async fn append(&self, key: String, suffix: Vec<u8>) -> Result<bool, CacheError> {
+        self.cache_for(&key).append(key, suffix).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        self.cache_for(&key)
+            .compare_and_swap(key, expected, new)
+            .await
+    }
+
+    // Across the partitions, the entry is added to the other cache, which resets its metadata
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> Result<RenameResult, CacheError> {
+        if from.starts_with(&self.ephemeral_prefix) == to.starts_with(&self.ephemeral_prefix) {
+            return self.cache_for(&from).rename(from, to, overwrite).await;
+        }
+        let (source, destination) = (self.cache_for(&from), self.cache_for(&to));
+        let Some(stored) = source.get_with_meta(&from).await? else {
+            return Ok(RenameResult::NotFound);
+        };
+        if !overwrite && destination.contains(&to).await? {
+            return Ok(RenameResult::DestinationExists);
+        }
+        destination
+            .add_with_content_type(
+                to,
+                stored.value,
+                stored.expires_at,
+                stored.meta.content_type,
+            )
+            .await?;
+        source.delete(&from).await?;
+        Ok(RenameResult::Renamed)
+    }
+
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError> {
+        self.cache_for(key).touch(key, expires_at).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        self.cache_for(key).get(key).await
+    }
+
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        self.cache_for(key).get_with_meta(key).await
+    }
+
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        self.cache_for(&key).get_or_set(key, value).await
+    }
+
+    async fn remove_older_than(&self, max_age: Duration) -> Result<usize, CacheError> {
+        Ok(self.ephemeral.remove_older_than(max_age).await?
+            + self.durable.remove_older_than(max_age).await?)
+    }
+
+    async fn remove_expired(
+        &self,
+        max_count: usize,
+        locks: &KeyLocks,
+    ) -> Result<usize, CacheError> {
+        let removed = self.ephemeral.remove_expired(max_count, locks).await?;
+        Ok(removed
+            + self
+                .durable
+                .remove_expired(max_count - removed, locks)
+                .await?)
+    }
+
+    async fn compact(&self) -> Result<Compaction, CacheError> {
+        let ephemeral = self.ephemeral.compact().await?;
+        let durable = self.durable.compact().await?;
+        Ok(Compaction {
+            temp_files: ephemeral.temp_files + durable.temp_files,
+            malformed_files: ephemeral.malformed_files + durable.malformed_files,
+        })
+    }
+
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError> {
+        if alias.starts_with(&self.ephemeral_prefix) != target.starts_with(&self.ephemeral_prefix) {
+            return Err(AliasError::AcrossPartitions);
+        }
+        self.cache_for(&alias).alias(alias, target).await
+    }
+
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError> {
+        self.cache_for(key).has_aliases(key).await
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, CacheError> {
+        self.cache_for(key).contains(key).await
+    }
+
+    async fn len(&self) -> Result<usize, CacheError> {
+        Ok(self.durable.len().await? + self.ephemeral.len().await?)
+    }
+
+    async fn stats(&self) -> Result<CacheStats, CacheError> {
+        Ok(self
+            .durable
+            .stats()
+            .await?
+            .merge(self.ephemeral.stats().await?))
+    }
+
+    async fn check_writable(&self) -> Result<(), CacheError> {
+        self.durable.check_writable().await
+    }
+
+    async fn namespace(&self, name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError> {
+        Ok(Box::new(HybridCache::new(
+            self.ephemeral_prefix.clone(),
+            self.durable.namespace(name).await?,
+        )))
+    }
+
+    async fn has_namespace(&self, name: &str) -> Result<bool, CacheError> {
+        self.durable.has_namespace(name).await
+    }
+}
+
+// Keeps the recently read entries of the wrapped cache (meant for DiskCache, but any works) in
+// memory, so that the hot keys are not read from the storage every time. Writes go to the wrapped
+// cache and drop the copy of the key. The values read through aliases are not kept, as they change
+// with their targets.
+struct CachedDiskCache {
+    memory: std::sync::Mutex<lru::LruCache<String, StoredValue>>,
+    inner: Box<dyn Cache + Send + Sync>,
+}
+
+impl CachedDiskCache {
+    fn new(inner: Box<dyn Cache + Send + Sync>, capacity: std::num::NonZeroUsize) -> Self {
+        CachedDiskCache {
+            memory: std::sync::Mutex::new(lru::LruCache::new(capacity)),
+            inner,
+        }
+    }
+
+    fn forget(&self, key: &str) {
+        self.memory.lock().unwrap().pop(key);
+    }
+
+    fn remembered(&self, key: &str) -> Option<StoredValue> {
+        let mut memory = self.memory.lock().unwrap();
+        match memory.get(key) {
+            Some(stored) if is_expired(stored.expires_at) => {
+                memory.pop(key);
+                None
+            }
+            stored => stored.cloned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for CachedDiskCache {
+    async fn list(&self) -> Result<Value, CacheError> {
+        self.inner.list().await
+    }
+
+    async fn list_sizes(&self) -> Result<Value, CacheError> {
+        self.inner.list_sizes().await
+    }
+
+    async fn list_paged(
+        &self,
+        prefix: &str,
+        limit: Option<usize>,
+        offset: usize,
+    ) -> Result<ListPage, CacheError> {
+        self.inner.list_paged(prefix, limit, offset).await
+    }
+
+    fn iter(&self) -> BoxStream<'_, Result<(String, Vec<u8>), CacheError>> {
+        self.inner.iter()
+    }
+
+    async fn add_with_meta(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<SystemTime>,
+        meta: EntryMeta,
+    ) -> Result<(), CacheError> {
+        self.forget(&key);
+        self.inner.add_with_meta(key, value, expires_at, meta).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        self.forget(key);
+        self.inner.delete(key).await
+    }
+
+    async fn delete_prefix(&self, prefix: &str) -> Result<usize, CacheError> {
+        {
+            let mut memory = self.memory.lock().unwrap();
+            let keys = memory
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in keys {
+                memory.pop(&key);
+            }
+        }
+        self.inner.delete_prefix(prefix).await
+    }
+
+    async fn clear(&self) -> Result<usize, CacheError> {
+        self.memory.lock().unwrap().clear();
+        self.inner.clear().await
+    }
+
+    async fn modify(&self, key: String, value: Vec<u8>) -> Result<bool, CacheError> {
+        self.forget(&key);
+        self.inner.modify(key, value).await
+    }
+
+    async fn append(&self, key: String, suffix: Vec<u8>) -> Result<bool, CacheError> {
+        self.forget(&key);
+        self.inner.append(key, suffix).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Vec<u8>,
+        new: Vec<u8>,
+    ) -> Result<CasResult, CacheError> {
+        self.forget(&key);
+        self.inner.compare_and_swap(key, expected, new).await
+    }
+
+    async fn rename(
+        &self,
+        from: String,
+        to: String,
+        overwrite: bool,
+    ) -> Result<RenameResult, CacheError> {
+        self.forget(&from);
+        self.forget(&to);
+        self.inner.rename(from, to, overwrite).await
+    }
+
+    async fn touch(&self, key: &str, expires_at: SystemTime) -> Result<bool, CacheError> {
+        self.forget(key);
+        self.inner.touch(key, expires_at).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        Ok(self.get_with_meta(key).await?.map(|stored| stored.value))
+    }
+
+    async fn contains(&self, key: &str) -> Result<bool, CacheError> {
+        if self.remembered(key).is_some() {
+            return Ok(true);
+        }
+        self.inner.contains(key).await
+    }
+
+    async fn get_with_meta(&self, key: &str) -> Result<Option<StoredValue>, CacheError> {
+        if let Some(stored) = self.remembered(key) {
+            return Ok(Some(stored));
+        }
+        let stored = self.inner.get_with_meta(key).await?;
+        if let Some(stored) = stored.as_ref().filter(|stored| !stored.aliased) {
+            self.memory
+                .lock()
+                .unwrap()
+                .put(key.to_string(), stored.clone());
+        }
+        Ok(stored)
+    }
+
+    async fn list_with_meta(&self) -> Result<Vec<(String, Vec<u8>, EntryMeta)>, CacheError> {
+        self.inner.list_with_meta().await
+    }
+
+    async fn get_or_set(&self, key: String, value: Vec<u8>) -> Result<Option<Vec<u8>>, CacheError> {
+        self.forget(&key);
+        self.inner.get_or_set(key, value).await
+    }
+
+    async fn increment(&self, key: String, delta: i64) -> Result<i64, IncrError> {
+        self.forget(&key);
+        self.inner.increment(key, delta).await
+    }
+
+    async fn remove_older_than(&self, max_age: Duration) -> Result<usize, CacheError> {
+        let removed = self.inner.remove_older_than(max_age).await?;
+        if removed > 0 {
+            self.memory.lock().unwrap().clear();
+        }
+        Ok(removed)
+    }
+
+    // The expired copies are dropped when read
+    async fn remove_expired(
+        &self,
+        max_count: usize,
+        locks: &KeyLocks,
+    ) -> Result<usize, CacheError> {
+        self.inner.remove_expired(max_count, locks).await
+    }
+
+    // The malformed entries failed to be read, so they are not in the memory
+    async fn compact(&self) -> Result<Compaction, CacheError> {
+        self.inner.compact().await
+    }
+
+    async fn alias(&self, alias: String, target: String) -> Result<(), AliasError> {
+        self.forget(&alias);
+        self.inner.alias(alias, target).await
+    }
+
+    async fn has_aliases(&self, key: &str) -> Result<bool, CacheError> {
+        self.inner.has_aliases(key).await
+    }
+
+    async fn len(&self) -> Result<usize, CacheError> {
+        self.inner.len().await
+    }
+
+    async fn stats(&self) -> Result<CacheStats, CacheError> {
+        self.inner.stats().await
+    }
+
+    async fn check_writable(&self) -> Result<(), CacheError> {
+        self.inner.check_writable().await
+    }
+
+    async fn namespace(&self, name: &str) -> Result<Box<dyn Cache + Send + Sync>, CacheError> {
+        let capacity = self.memory.lock().unwrap().cap();
+        Ok(Box::new(CachedDiskCache::new(
+            self.inner.namespace(name).await?,
+            capacity,
+        )))
+    }
+
+    async fn has_namespace(&self, name: &str) -> Result<bool, CacheError> {
+        self.inner.has_namespace(name).await
+    }
+
+    async fn bulk_add(
+        &self,
+        entries: Vec<(String, Vec<u8>, Option<SystemTime>)>,
+    ) -> Result<(), CacheError> {
+        for (key, _, _) in &entries {
+            self.forget(key);
+        }
+        self.inner.bulk_add(entries).await
+    }
+
+    async fn bulk_delete(&self, keys: &[String]) -> Result<Vec<bool>, CacheError> {
+        for key in keys {
+            self.forget(key);
+        }
+        self.inner.bulk_delete(keys).await
+    }
+}
+
+#[derive(Debug)]
+enum InvalidKey {
+    Empty,
+    ContainsNul,
+    TooLong { max_bytes: usize },
+    PatternMismatch(regex::Regex), // AppState::key_pattern
+}
+
+impl std::fmt::Display for InvalidKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidKey::Empty => write!(f, "key must not be empty"),
+            InvalidKey::ContainsNul => write!(f, "key must not contain NUL characters"),
+            InvalidKey::TooLong { max_bytes } => {
+                write!(f, "key must not be longer than {} bytes", max_bytes)
+            }
+            InvalidKey::PatternMismatch(pattern) => {
+                write!(f, "key must match {}", pattern.as_str())
+            }
+        }
+    }
+}
+
+impl IntoResponse for InvalidKey {
+    fn into_response(self) -> response::Response {
+        let status = match self {
+            InvalidKey::TooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        };
+        error_response(status, self.to_string())
+    }
+}
+
+#[derive(Debug)]
+enum InvalidValue {
+    TooLarge { max_bytes: usize },
+    SchemaViolation(Vec<String>), // the errors of the validation against AppState::value_schema
+}
+
+impl IntoResponse for InvalidValue {
+    fn into_response(self) -> response::Response {
+        match self {
+            InvalidValue::TooLarge { max_bytes } => error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("value must not be longer than {} bytes", max_bytes),
+            ),
+            InvalidValue::SchemaViolation(errors) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                response::Json(serde_json::json!({ "errors": errors })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+// Every value received from a client has to pass through here
+fn validate_value(value: &[u8], state: &AppState) -> Result<(), InvalidValue> {
+    if let Some(max_bytes) = state.max_value_bytes {
+        if value.len() > max_bytes {
+            return Err(InvalidValue::TooLarge { max_bytes });
+        }
+    }
+    let Some(schema) = &state.value_schema else {
+        return Ok(());
+    };
+    let value = serde_json::from_slice::<Value>(value).map_err(|err| {
+        InvalidValue::SchemaViolation(vec![format!("value is not valid JSON: {}", err)])
+    })?;
+    schema.validate(&value).map_err(|errors| {
+        let errors = errors.map(|err| match err.instance_path.to_string().as_str() {
+            "" => err.to_string(),
+            path => format!("{}: {}", path, err),
+        });
+        InvalidValue::SchemaViolation(errors.collect())
+    })
+}
+
+// See AppState::max_entries
+#[derive(Debug)]
+struct CacheFull;
+
+impl IntoResponse for CacheFull {
+    fn into_response(self) -> response::Response {
+        error_response(StatusCode::INSUFFICIENT_STORAGE, "the cache is full")
+    }
+}
+
+// The limits of a namespace (see --quota-file), the values count in bytes
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Quota {
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+struct QuotaExceeded {
+    namespace: String,
+}
+
+impl IntoResponse for QuotaExceeded {
+    fn into_response(self) -> response::Response {
+        let msg = match self.namespace.as_str() {
+            "" => "the quota of the default namespace is exceeded".to_string(),
+            namespace => format!("the quota of namespace {} is exceeded", namespace),
+        };
+        error_response(StatusCode::INSUFFICIENT_STORAGE, msg)
+    }
+}
+
+#[derive(Debug)]
+enum InvalidNamespace {
+    Malformed,
+    Reserved,
+}
+
+impl IntoResponse for InvalidNamespace {
+    fn into_response(self) -> response::Response {
+        let msg = match self {
+            InvalidNamespace::Malformed => {
+                "namespace must consist of 1 to 64 ASCII letters, digits, '-' and '_'"
+            }
+            InvalidNamespace::Reserved => "namespace must not be named like a route",
+        };
+        error_response(StatusCode::BAD_REQUEST, msg)
+    }
+}
+
+// Namespaces become filenames, so the allowed characters are restricted to the safe ones
+fn validate_namespace(name: &str, reserved: &BTreeSet<&str>) -> Result<(), InvalidNamespace> {
+    let is_allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || name.len() > 64 || !name.chars().all(is_allowed) {
+        return Err(InvalidNamespace::Malformed);
+    }
+    if reserved.contains(name) {
+        return Err(InvalidNamespace::Reserved);
+    }
+    Ok(())
+}
+
+// Every key received from a client has to pass through here. Keys never become paths (DiskCache
+// hashes them), so path-like keys such as "../x" are fine, but NUL characters break too much
+// tooling (C strings, some file formats) to be worth supporting. Anything that ever maps client
+// supplied names onto paths has to additionally reject "", ".", ".." and "/". The length limit is
+// checked here so that a huge key is neither hashed nor stored.
+fn validate_key(key: &str, state: &AppState) -> Result<(), InvalidKey> {
+    if key.is_empty() {
+        return Err(InvalidKey::Empty);
+    }
+    if key.contains('\0') {
+        return Err(InvalidKey::ContainsNul);
+    }
+    if let Some(max_bytes) = state.max_key_bytes {
+        if key.len() > max_bytes {
+            return Err(InvalidKey::TooLong { max_bytes });
+        }
+    }
+    if let Some(pattern) = &state.key_pattern {
+        if !pattern.is_match(key) {
+            return Err(InvalidKey::PatternMismatch(pattern.clone()));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ListQuery {
+    // Return the sizes of the values instead of the values themselves
+    #[serde(default)]
+    with_sizes: bool,
+    // Return {"value": ..., "truncated": bool} with the values cut to at most that many bytes
+    // (together with with_sizes the full size is included as "size")
+    max_value_bytes: Option<usize>,
+    // With any of the below the entries are ordered by the key and returned as
+    // {"entries": {...}, "total": N, "next_offset": M}, where total counts all the entries with the
+    // prefix and next_offset is present only if more of them remain
+    prefix: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    // Return {"value": ..., "content_type": ..., "created_at_ms": ..., "updated_at_ms": ...} (see
+    // EntryMeta), not combinable with the above
+    #[serde(default)]
+    verbose: bool,
+    // Return the values that are valid JSON as nested JSON instead of strings, not combinable with
+    // max_value_bytes
+    #[serde(default)]
+    parse_json: bool,
+    // Return the keys in lexicographic order (the entries are not collected in memory otherwise)
+    #[serde(default)]
+    sorted: bool,
+}
+
+// A BTreeMap, so that the order does not depend on the features of serde_json
+fn sorted_by_key(list: Value) -> BTreeMap<String, Value> {
+    match list {
+        Value::Object(entries) => entries.into_iter().collect(),
+        _ => BTreeMap::new(),
+    }
+}
+
+// Only changes how the value is presented, see ListQuery::parse_json
+fn embed_json(value: Value) -> Value {
+    match &value {
+        Value::String(s) => serde_json::from_str(s).unwrap_or(value),
+        _ => value,
+    }
+}
+
+fn truncate_values(list: Value, max_value_bytes: usize, with_sizes: bool) -> Value {
+    let Value::Object(entries) = list else {
+        return list;
+    };
+    let entries = entries.into_iter().map(|(key, value)| {
+        let Value::String(mut value) = value else {
+            return (key, value);
+        };
+        let size = value.len();
+        let mut end = max_value_bytes.min(size);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
+        let mut entry = serde_json::json!({ "value": value, "truncated": end < size });
+        if with_sizes {
+            entry["size"] = size.into();
+        }
+        (key, entry)
+    });
+    Value::Object(entries.collect())
+}
+
+// Bounds the concurrent requests reading the whole cache (/list, /snapshot and /dump.csv)
+struct ListLimiter {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+}
+
+impl ListLimiter {
+    fn new(max: usize) -> Self {
+        ListLimiter {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max,
+        }
+    }
+
+    fn in_use(&self) -> usize {
+        self.max - self.semaphore.available_permits()
+    }
+}
+
+#[derive(Debug)]
+struct TooManyListings;
+
+impl IntoResponse for TooManyListings {
+    fn into_response(self) -> response::Response {
+        error_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too many listings in progress",
+        )
+    }
+}
+
+impl AppState {
+    // The permit is to be held until the response body is sent
+    fn list_permit(&self) -> Result<Option<OwnedSemaphorePermit>, TooManyListings> {
+        let Some(list_limiter) = &self.list_limiter else {
+            return Ok(None);
+        };
+        match list_limiter.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(TooManyListings),
+        }
+    }
+}
+
+async fn list(
+    NsState(state): NsState,
+    extract::Query(query): extract::Query<ListQuery>,
+    headers: HeaderMap,
+) -> Result<response::Response, CacheError> {
+    let format = ResponseFormat::negotiate(&headers);
+    if state.list_disabled {
+        return Ok(error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "listing is unavailable as the keys are not stored",
+        ));
+    }
+    let permit = match state.list_permit() {
+        Ok(permit) => permit,
+        Err(err) => return Ok(err.into_response()),
+    };
+    if query.parse_json && query.max_value_bytes.is_some() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "parse_json cannot be combined with max_value_bytes",
+        ));
+    }
+    if query.verbose {
+        if query.with_sizes
+            || query.max_value_bytes.is_some()
+            || query.prefix.is_some()
+            || query.limit.is_some()
+            || query.offset.is_some()
+        {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "verbose cannot be combined with other parameters",
+            ));
+        }
+        let entries = state.cache.list_with_meta().await?;
+        let entries = entries.into_iter().map(|(key, value, meta)| {
+            let mut value = JsonBytes::to_json(value);
+            if query.parse_json {
+                value = embed_json(value);
+            }
+            let entry = serde_json::json!({
+                "value": value,
+                "content_type": meta.content_type,
+                "created_at_ms": meta.created_at_ms,
+                "updated_at_ms": meta.updated_at_ms,
+            });
+            (key, entry)
+        });
+        let entries = Value::Object(entries.collect());
+        if query.sorted {
+            return Ok(format.respond(&sorted_by_key(entries)));
+        }
+        return Ok(format.respond(&entries));
+    }
+    if query.prefix.is_some() || query.limit.is_some() || query.offset.is_some() {
+        let offset = query.offset.unwrap_or(0);
+        let page = state
+            .cache
+            .list_paged(query.prefix.as_deref().unwrap_or(""), query.limit, offset)
+            .await?;
+        let next_offset = offset + page.entries.len();
+        let only_sizes = query.with_sizes && query.max_value_bytes.is_none();
+        let entries = page
+            .entries
+            .into_iter()
+            .map(|(key, value)| match only_sizes {
+                true => (key, serde_json::json!({ "size": value.len() })),
+                false if query.parse_json => (key, embed_json(JsonBytes::to_json(value))),
+                false => (key, JsonBytes::to_json(value)),
+            });
+        let mut entries = Value::Object(entries.collect());
+        if let Some(max_value_bytes) = query.max_value_bytes {
+            entries = truncate_values(entries, max_value_bytes, query.with_sizes);
+        }
+        let mut body = serde_json::json!({ "entries": entries, "total": page.total });
+        if next_offset < page.total {
+            body["next_offset"] = next_offset.into();
+        }
+        return Ok(format.respond(&body));
+    }
+    let list = if let Some(max_value_bytes) = query.max_value_bytes {
+        let list = state.cache.list().await?;
+        truncate_values(list, max_value_bytes, query.with_sizes)
+    } else if query.with_sizes {
+        state.cache.list_sizes().await?
+    } else if format == ResponseFormat::MessagePack || query.sorted {
+        let mut list = state.cache.list().await?;
+        if let (true, Value::Object(entries)) = (query.parse_json, &mut list) {
+            entries
+                .values_mut()
+                .for_each(|value| *value = embed_json(value.take()));
+        }
+        list
+    } else {
+        let format_entry: fn(String, Vec<u8>) -> String = match query.parse_json {
+            true => |key, value| {
+                let value = embed_json(JsonBytes::to_json(value));
+                format!("{}:{}", Value::String(key), value)
+            },
+            false => |key, value| format!("{}:{}", Value::String(key), JsonBytes::to_json(value)),
+        };
+        // Streamed, so that the listing of a large cache is never held in memory as a whole
+        let body = stream_entries(
+            state,
+            permit,
+            ["{", ",", "}"],
+            |state| state.cache.iter(),
+            format_entry,
+        )
+        .await?;
+        return Ok((
+            [(header::CONTENT_TYPE, "application/json")],
+            body::boxed(body),
+        )
+            .into_response());
+    };
+    if query.sorted {
+        return Ok(format.respond(&sorted_by_key(list)));
+    }
+    Ok(format.respond(&list))
+}
+
+const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+// The encoding of the responses of /list and /get, chosen by the client with the Accept header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResponseFormat {
+    Json,
+    MessagePack,
+}
+
+impl ResponseFormat {
+    // JSON unless MessagePack is accepted explicitly (q=0 refuses it), the q-values of the media
+    // types are not weighed against each other
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let accepts_msgpack = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|media_range| {
+                let mut params = media_range.split(';').map(str::trim);
+                let media_type = params.next().unwrap_or("");
+                (media_type.eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE)
+                    || media_type.eq_ignore_ascii_case("application/x-msgpack"))
+                    && !params.any(|param| {
+                        let q = param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok());
+                        q == Some(0.0)
+                    })
+            });
+        match accepts_msgpack {
+            true => ResponseFormat::MessagePack,
+            false => ResponseFormat::Json,
+        }
+    }
+
+    fn respond(self, value: &impl Serialize) -> response::Response {
+        match self {
+            ResponseFormat::Json => response::Json(value).into_response(),
+            ResponseFormat::MessagePack => (
+                [(header::CONTENT_TYPE, MSGPACK_CONTENT_TYPE)],
+                rmp_serde::to_vec_named(value).unwrap(),
+            )
+                .into_response(),
+        }
+    }
+}
+
+// A value as MessagePack: a str if it is valid UTF-8 and bin otherwise
+struct MsgpackValue(Vec<u8>);
+
+impl Serialize for MsgpackValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&self.0) {
+            Ok(value) => serializer.serialize_str(value),
+            Err(_) => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+
+type EntryStream<'a, T> = BoxStream<'a, Result<(String, T), CacheError>>;
+
+// Returns a response body with the entries of the cache (e.g. Cache::iter()), sent as they are
+// read. The entries are formatted with format_entry, joined with the separator and enclosed in open
+// and close. Fails if reading the first entry fails, later failures abort the body (so that a
+// truncated body is not mistaken for a complete one). The guard (e.g. a semaphore permit) is held
+// until the body is sent.
+async fn stream_entries<T: Send + 'static>(
+    state: Arc<AppState>,
+    guard: impl Send + 'static,
+    [open, separator, close]: [&'static str; 3],
+    entries: fn(&AppState) -> EntryStream<'_, T>,
+    format_entry: fn(String, T) -> String,
+) -> Result<Body, CacheError> {
+    let (mut sender, body) = Body::channel();
+    let (started, start_result) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let _guard = guard;
+        let mut entries = entries(&state);
+        let mut entry = match entries.next().await.transpose() {
+            Ok(entry) => {
+                let _ = started.send(Ok(()));
+                entry
+            }
+            Err(err) => {
+                let _ = started.send(Err(err));
+                return;
+            }
+        };
+        let mut chunk = open.to_string();
+        let mut is_first = true;
+        while let Some((key, value)) = entry {
+            if !is_first {
+                chunk.push_str(separator);
+            }
+            is_first = false;
+            chunk.push_str(&format_entry(key, value));
+            if sender
+                .send_data(std::mem::take(&mut chunk).into())
+                .await
+                .is_err()
+            {
+                return; // the client went away
+            }
+            entry = match entries.next().await.transpose() {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("Failed to read the entries: {}", err);
+                    sender.abort();
+                    return;
+                }
+            };
+        }
+        chunk.push_str(close);
+        let _ = sender.send_data(chunk.into()).await;
+    });
+    start_result.await.unwrap()?;
+    Ok(body)
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AddPayload {
+    key: String,
+    value: String,
+    // The entry expires after that many seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_seconds: Option<u64>,
+    // Served as the Content-Type of /get
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+}
+
+// With Content-Type: application/octet-stream the body is the raw value and the rest of
+// AddPayload is in the query, as JSON cannot hold arbitrary bytes
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AddQuery {
+    key: Option<String>,
+    ttl_seconds: Option<u64>,
+    content_type: Option<String>,
+}
+
+async fn add(
+    NsState(state): NsState,
+    extract::Query(query): extract::Query<AddQuery>,
+    request: Request<Body>,
+) -> Result<response::Response, CacheError> {
+    let is_raw = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/octet-stream");
+    let (key, value, ttl_seconds, content_type) = if is_raw {
+        let Some(key) = query.key else {
+            return Ok(error_response(
+                StatusCode::BAD_REQUEST,
+                "missing key query parameter",
+            ));
+        };
+        match Bytes::from_request(request, &()).await {
+            Ok(value) => (key, value.to_vec(), query.ttl_seconds, query.content_type),
+            Err(rejection) => return Ok(rejection.into_response()),
+        }
+    } else {
+        match JsonBody::<AddPayload>::from_request(request, &()).await {
+            Ok(JsonBody(payload)) => (
+                payload.key,
+                payload.value.into_bytes(),
+                payload.ttl_seconds,
+                payload.content_type,
+            ),
+            Err(rejection) => return Ok(rejection.into_response()),
+        }
+    };
+    if let Err(err) = validate_key(&key, &state) {
+        return Ok(err.into_response());
+    }
+    // It has to be a valid header value
+    if content_type
+        .as_deref()
+        .is_some_and(|content_type| HeaderValue::from_str(content_type).is_err())
+    {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid content_type",
+        ));
+    }
+    if let Err(err) = validate_value(&value, &state) {
+        return Ok(err.into_response());
+    }
+    let expires_at = match ttl_seconds.map(expiry_time) {
+        Some(Err(err)) => return Ok(err.into_response()),
+        Some(Ok(expires_at)) => Some(expires_at),
+        None => None,
+    };
+    add_validated(&state, key, value, expires_at, content_type).await
+}
+
+// The write of /add (and /add-from-url), the key, value and content type are already validated
+async fn add_validated(
+    state: &AppState,
+    key: String,
+    value: Vec<u8>,
+    expires_at: Option<SystemTime>,
+    content_type: Option<String>,
+) -> Result<response::Response, CacheError> {
+    let _lock = state.lock_for_write(&key).await;
+    if !state.has_room_for(&[&key]).await? {
+        return Ok(CacheFull.into_response());
+    }
+    if let Some(exceeded) = state.check_quota(&key, value.len()).await? {
+        return Ok(exceeded.into_response());
+    }
+    state
+        .add_entry(key.clone(), value.clone(), expires_at, content_type.clone())
+        .await?;
+    state
+        .record_mutation_with_content_type(
+            MutationOp::Add,
+            &key,
+            Some(&value),
+            expires_at,
+            content_type.as_deref(),
+        )
+        .await?;
+    let location = match &state.namespace {
+        Some(namespace) => format!("/{}/get/{}", namespace, percent_encode(&key)),
+        None => format!("/get/{}", percent_encode(&key)),
+    };
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)]).into_response())
+}
+
+#[derive(Debug)]
+struct InvalidTtl;
+
+impl IntoResponse for InvalidTtl {
+    fn into_response(self) -> response::Response {
+        error_response(StatusCode::BAD_REQUEST, "ttl_seconds is too large")
+    }
+}
+
+// When an entry with the given TTL set now expires, a TTL past the range of SystemTime is rejected
+fn expiry_time(ttl_seconds: u64) -> Result<SystemTime, InvalidTtl> {
+    SystemTime::now()
+        .checked_add(Duration::from_secs(ttl_seconds))
+        .ok_or(InvalidTtl)
+}
+
+// Encodes all the bytes except the unreserved characters of RFC 3986, so that the result is a
+// single path segment
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AddFromUrlPayload {
+    key: String,
+    url: String,
+}
+
+async fn add_from_url(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<AddFromUrlPayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    let url = match reqwest::Url::parse(&payload.url) {
+        Ok(url) => url,
+        Err(err) => {
+            let error = format!("invalid url: {}", err);
+            return Ok(error_response(StatusCode::BAD_REQUEST, error));
+        }
+    };
+    if !state.fetcher.is_allowed(&url) {
+        return Ok(error_response(
+            StatusCode::FORBIDDEN,
+            "the host of the url is not allowed",
+        ));
+    }
+    // The key is not locked while fetching
+    let (value, content_type) = match state.fetcher.fetch(url, state.max_value_bytes).await {
+        Ok(fetched) => fetched,
+        Err(FetchError::Request(err)) => return Ok(error_response(StatusCode::BAD_GATEWAY, err)),
+        Err(FetchError::TooLarge) => {
+            return Ok(error_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "the fetched value is too large",
+            ))
+        }
+        Err(FetchError::NotUtf8) => {
+            return Ok(error_response(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "the fetched value is not valid UTF-8",
+            ))
+        }
+    };
+    if let Err(err) = validate_value(value.as_bytes(), &state) {
+        return Ok(err.into_response());
+    }
+    add_validated(&state, payload.key, value.into_bytes(), None, content_type).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AddIfAbsentPayload {
+    key: String,
+    value: String,
+}
+
+// Like /add, but an existing entry (or alias) is left as it is and the response is 409
+async fn add_if_absent(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<AddIfAbsentPayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    if let Err(err) = validate_value(payload.value.as_bytes(), &state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.lock_for_write(&payload.key).await;
+    if !state.has_room_for(&[&payload.key]).await? {
+        return Ok(CacheFull.into_response());
+    }
+    // An existing entry is left as it is, so only a new one counts
+    if state.quota().is_some() && !state.cache.contains(&payload.key).await? {
+        if let Some(exceeded) = state.check_quota(&payload.key, payload.value.len()).await? {
+            return Ok(exceeded.into_response());
+        }
+    }
+    if !state
+        .cache
+        .add_if_absent(payload.key.clone(), payload.value.clone().into_bytes())
+        .await?
+    {
+        return Ok(error_response(StatusCode::CONFLICT, "entry already exists"));
+    }
+    state
+        .record_mutation(
+            MutationOp::Add,
+            &payload.key,
+            Some(payload.value.as_bytes()),
+            None,
+        )
+        .await?;
+    let location = match &state.namespace {
+        Some(namespace) => format!("/{}/get/{}", namespace, percent_encode(&payload.key)),
+        None => format!("/get/{}", percent_encode(&payload.key)),
+    };
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)]).into_response())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeletePayload {
+    key: String,
+}
+
+async fn delete(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<DeletePayload>,
+) -> Result<response::Response, CacheError> {
+    delete_key(&state, payload).await
+}
+
+// The key of /get/<key> and /delete/<key>
+#[derive(Deserialize)]
+struct KeyPath {
+    key: String,
+}
+
+// DELETE /delete/<key>, with the key percent-encoded (it may contain slashes)
+async fn delete_by_path(
+    NsState(state): NsState,
+    extract::Path(KeyPath { key }): extract::Path<KeyPath>,
+) -> Result<response::Response, CacheError> {
+    delete_key(&state, DeletePayload { key }).await
+}
+
+async fn delete_key(
+    state: &AppState,
+    payload: DeletePayload,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.key_locks.write(&payload.key).await;
+    if state.refuse_delete_aliased && state.cache.has_aliases(&payload.key).await? {
+        return Ok(error_response(
+            StatusCode::CONFLICT,
+            "entry is the target of an alias",
+        ));
+    }
+    if state.cache.delete(&payload.key).await? {
+        state
+            .record_mutation(MutationOp::Delete, &payload.key, None, None)
+            .await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(StatusCode::NOT_FOUND.into_response())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ModifyPayload {
+    key: String,
+    value: String,
+}
+
+async fn modify(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<ModifyPayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    if let Err(err) = validate_value(payload.value.as_bytes(), &state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.key_locks.write(&payload.key).await;
+    if state
+        .modify_entry(payload.key.clone(), payload.value.clone().into_bytes())
+        .await?
+    {
+        state
+            .record_mutation(
+                MutationOp::Modify,
+                &payload.key,
+                Some(payload.value.as_bytes()),
+                None,
+            )
+            .await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(StatusCode::NOT_FOUND.into_response())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendPayload {
+    key: String,
+    value: String, // appended to the current value
+}
+
+async fn append(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<AppendPayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.lock_for_write(&payload.key).await;
+    // The resulting value is what has to be valid and fit in the quota
+    if state.max_value_bytes.is_some() || state.value_schema.is_some() || state.quota().is_some() {
+        if let Some(mut value) = state.cache.get(&payload.key).await? {
+            value.extend(payload.value.as_bytes());
+            if let Err(err) = validate_value(&value, &state) {
+                return Ok(err.into_response());
+            }
+            if let Some(exceeded) = state.check_quota(&payload.key, value.len()).await? {
+                return Ok(exceeded.into_response());
+            }
+        }
+    }
+    if !state
+        .append_entry(payload.key.clone(), payload.value.into_bytes())
+        .await?
+    {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    // Recorded as /modify of the whole value, so that the journal needs no new operation
+    let value = state.cache.get(&payload.key).await?.unwrap_or_default();
+    state
+        .record_mutation(MutationOp::Modify, &payload.key, Some(&value), None)
+        .await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MergePayload {
+    key: String,
+    patch: Value,
+}
+
+// RFC 7386 JSON Merge Patch: the members of the patch replace the ones of the target, recursively
+// for objects, and the null ones are removed
+fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (name, value) in patch {
+        if value.is_null() {
+            target.remove(&name);
+        } else {
+            merge_patch(target.entry(name).or_insert(Value::Null), value);
+        }
+    }
+}
+
+async fn merge(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<MergePayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.lock_for_write(&payload.key).await;
+    let Some(value) = state.cache.get(&payload.key).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let Ok(mut document) = serde_json::from_slice::<Value>(&value) else {
+        return Ok(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "value is not JSON",
+        ));
+    };
+    merge_patch(&mut document, payload.patch);
+    let value = serde_json::to_vec(&document).unwrap();
+    if let Err(err) = validate_value(&value, &state) {
+        return Ok(err.into_response());
+    }
+    if let Some(exceeded) = state.check_quota(&payload.key, value.len()).await? {
+        return Ok(exceeded.into_response());
+    }
+    if !state
+        .modify_entry(payload.key.clone(), value.clone())
+        .await?
+    {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    state
+        .record_mutation(MutationOp::Modify, &payload.key, Some(&value), None)
+        .await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CasPayload {
+    key: String,
+    expected: String,
+    new: String,
+}
+
+// Compare-and-swap: sets the new value only if the current one is the expected one
+async fn cas(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<CasPayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    if let Err(err) = validate_value(payload.new.as_bytes(), &state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.lock_for_write(&payload.key).await;
+    // Only the value that would be swapped counts
+    if state.quota().is_some()
+        && state.cache.get(&payload.key).await?.as_deref() == Some(payload.expected.as_bytes())
+    {
+        if let Some(exceeded) = state.check_quota(&payload.key, payload.new.len()).await? {
+            return Ok(exceeded.into_response());
+        }
+    }
+    let result = state
+        .compare_and_swap_entry(
+            payload.key.clone(),
+            payload.expected.into_bytes(),
+            payload.new.clone().into_bytes(),
+        )
+        .await?;
+    Ok(match result {
+        CasResult::Swapped => {
+            state
+                .record_mutation(
+                    MutationOp::Modify,
+                    &payload.key,
+                    Some(payload.new.as_bytes()),
+                    None,
+                )
+                .await?;
+            StatusCode::OK.into_response()
+        }
+        CasResult::Mismatch => error_response(
+            StatusCode::CONFLICT,
+            "the current value is not the expected one",
+        ),
+        CasResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IncrPayload {
+    key: String,
+    #[serde(default = "IncrPayload::default_by")]
+    by: i64,
+}
+
+impl IncrPayload {
+    fn default_by() -> i64 {
+        1
+    }
+}
+
+// Returns the new value as a JSON number
+async fn incr(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<IncrPayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.lock_for_write(&payload.key).await;
+    let current = state.cache.get(&payload.key).await?;
+    let existed = current.is_some();
+    if !existed && !state.has_room_for(&[&payload.key]).await? {
+        return Ok(CacheFull.into_response());
+    }
+    if state.quota().is_some() {
+        let value = match incremented(current.as_deref(), payload.by) {
+            Ok(value) => value,
+            Err(err) => return Ok(err.into_response()),
+        };
+        let value_bytes = value.to_string().len();
+        if let Some(exceeded) = state.check_quota(&payload.key, value_bytes).await? {
+            return Ok(exceeded.into_response());
+        }
+    }
+    let value = match state.increment_entry(payload.key.clone(), payload.by).await {
+        Ok(value) => value,
+        Err(IncrError::Cache(err)) => return Err(err),
+        Err(err) => return Ok(err.into_response()),
+    };
+    // Recorded like /add or /modify would do it, so that the journal needs no new operation
+    let op = if existed {
+        MutationOp::Modify
+    } else {
+        MutationOp::Add
+    };
+    state
+        .record_mutation(op, &payload.key, Some(value.to_string().as_bytes()), None)
+        .await?;
+    Ok(response::Json(value).into_response())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GetPayload {
+    key: String,
+}
+
+// Controls what /get returns if there is no entry (by default it is 404):
+// - default: the given value is returned with 200, but the cache is left untouched,
+// - default_and_set: the given value is stored (atomically with the lookup) and returned with 200,
+//   so subsequent /get calls will see it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct GetQuery {
+    default: Option<String>,
+    default_and_set: Option<String>,
+    #[serde(default)]
+    format: GetFormat,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GetFormat {
+    // The value as it is
+    #[default]
+    Raw,
+    // {"key": ..., "value": ...}, with the value as in /list
+    Json,
+}
+
+#[derive(Serialize)]
+struct KeyValue<'a, V> {
+    key: &'a str,
+    value: V,
+}
+
+async fn get(
+    NsState(state): NsState,
+    extract::Query(query): extract::Query<GetQuery>,
+    headers: HeaderMap,
+    JsonBody(payload): JsonBody<GetPayload>,
+) -> Result<response::Response, CacheError> {
+    get_key(&state, query, &headers, payload).await
+}
+
+// GET /get/<key>, with the key percent-encoded (it may contain slashes)
+async fn get_by_path(
+    NsState(state): NsState,
+    extract::Query(query): extract::Query<GetQuery>,
+    headers: HeaderMap,
+    extract::Path(KeyPath { key }): extract::Path<KeyPath>,
+) -> Result<response::Response, CacheError> {
+    get_key(&state, query, &headers, GetPayload { key }).await
+}
+
+// 200 if the key is in the cache and 404 otherwise, without the value
+async fn exists(
+    NsState(state): NsState,
+    extract::Path(KeyPath { key }): extract::Path<KeyPath>,
+) -> Result<StatusCode, CacheError> {
+    if validate_key(&key, &state).is_err() {
+        return Ok(StatusCode::NOT_FOUND);
+    }
+    let _lock = state.key_locks.read(&key).await;
+    Ok(match state.cache.contains(&key).await? {
+        true => StatusCode::OK,
+        false => StatusCode::NOT_FOUND,
+    })
+}
+
+// GET /history/<key>: the previous values of the entry (of the target for aliases) with the times
+// they were set, newest first. Kept only with --value-history.
+async fn history(
+    NsState(state): NsState,
+    extract::Path(KeyPath { key }): extract::Path<KeyPath>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&key, &state) {
+        return Ok(err.into_response());
+    }
+    let _lock = state.key_locks.read(&key).await;
+    Ok(match state.cache.get_with_meta(&key).await? {
+        Some(stored) => response::Json(stored.meta.history).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+// Found entries are returned with an ETag, and with If-None-Match matching it the response is 304
+// without the value
+async fn get_key(
+    state: &AppState,
+    query: GetQuery,
+    headers: &HeaderMap,
+    payload: GetPayload,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, state) {
+        return Ok(err.into_response());
+    }
+    let format = ResponseFormat::negotiate(headers);
+    let value_response = |value: Vec<u8>| match (query.format, format) {
+        (GetFormat::Raw, _) => value_response(value, format),
+        (GetFormat::Json, ResponseFormat::Json) => format.respond(&KeyValue {
+            key: &payload.key,
+            value: JsonBytes(value),
+        }),
+        (GetFormat::Json, ResponseFormat::MessagePack) => format.respond(&KeyValue {
+            key: &payload.key,
+            value: MsgpackValue(value),
+        }),
+    };
+    Ok(match query {
+        GetQuery {
+            default: Some(_),
+            default_and_set: Some(_),
+            ..
+        } => error_response(
+            StatusCode::BAD_REQUEST,
+            "default and default_and_set are mutually exclusive",
+        ),
+        GetQuery {
+            default_and_set: Some(value),
+            ..
+        } => {
+            if let Err(err) = validate_value(value.as_bytes(), state) {
+                return Ok(err.into_response());
+            }
+            let _lock = state.lock_for_write(&payload.key).await;
+            if !state.has_room_for(&[&payload.key]).await? {
+                return Ok(CacheFull.into_response());
+            }
+            // An existing entry is returned as it is, so only a new one counts
+            if state.quota().is_some() && !state.cache.contains(&payload.key).await? {
+                if let Some(exceeded) = state.check_quota(&payload.key, value.len()).await? {
+                    return Ok(exceeded.into_response());
+                }
+            }
+            let current = state
+                .cache
+                .get_or_set(payload.key.clone(), value.clone().into_bytes())
+                .await?;
+            state.lookups.record(current.is_some());
+            match current {
+                Some(val) => value_response(val),
+                None => {
+                    state
+                        .record_mutation(
+                            MutationOp::Add,
+                            &payload.key,
+                            Some(value.as_bytes()),
+                            None,
+                        )
+                        .await?;
+                    value_response(value.into_bytes())
+                }
+            }
+        }
+        GetQuery { default, .. } => {
+            let _lock = state.key_locks.read(&payload.key).await;
+            let value = state.cache.get_with_meta(&payload.key).await?;
+            state.lookups.record(value.is_some());
+            let value = value.map(|stored| {
+                let etag = etag_of(&stored.value);
+                (stored, etag)
+            });
+            match value {
+                Some((_, etag)) if etag_matches(headers.get(header::IF_NONE_MATCH), &etag) => {
+                    (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response()
+                }
+                Some((stored, etag)) => {
+                    let mut response = value_response(stored.value);
+                    let headers = response.headers_mut();
+                    headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+                    if let (Some(content_type), ResponseFormat::Json, GetFormat::Raw) =
+                        (stored.meta.content_type, format, query.format)
+                    {
+                        // Validated by add()
+                        let content_type = HeaderValue::from_str(&content_type).unwrap();
+                        headers.insert(header::CONTENT_TYPE, content_type);
+                    }
+                    if let Some(expires_at) = stored.expires_at {
+                        // Whole seconds the value is still valid for
+                        let ttl = expires_at
+                            .duration_since(SystemTime::now())
+                            .unwrap_or_default();
+                        headers.insert(TTL_HEADER, HeaderValue::from(ttl.as_secs()));
+                    }
+                    response
+                }
+                None => match (default, &state.miss_body) {
+                    (Some(val), _) => value_response(val.into_bytes()),
+                    (None, MissBody::Empty) => StatusCode::NOT_FOUND.into_response(),
+                    (None, MissBody::Json) => (
+                        StatusCode::NOT_FOUND,
+                        response::Json(serde_json::json!({
+                            "error": "not found",
+                            "key": payload.key,
+                        })),
+                    )
+                        .into_response(),
+                    (None, MissBody::Default(val)) => value_response(val.clone().into_bytes()),
+                },
+            }
+        }
+    })
+}
+
+// The remaining lifetime of the entries with a TTL returned by /get
+const TTL_HEADER: &str = "x-cache-ttl-seconds";
+
+// If-None-Match is a list of ETags or "*", weak ETags (W/"...") match the same strong ones
+fn etag_matches(if_none_match: Option<&HeaderValue>, etag: &str) -> bool {
+    let Some(if_none_match) = if_none_match.and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    if_none_match.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+// Values that are valid UTF-8 are returned as text, the others as raw bytes
+// A quoted hash of the value, so that equal values have the same ETag whatever the key and the
+// backend
+fn etag_of(value: &[u8]) -> String {
+    format!("\"{}\"", blake3::hash(value).to_hex())
+}
+
+fn value_response(value: Vec<u8>, format: ResponseFormat) -> response::Response {
+    if format == ResponseFormat::MessagePack {
+        return format.respond(&MsgpackValue(value));
+    }
+    match String::from_utf8(value) {
+        Ok(value) => (StatusCode::OK, value).into_response(),
+        Err(err) => (StatusCode::OK, err.into_bytes()).into_response(),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TxnCondition {
+    key: String,
+    // None means the entry is expected to not exist
+    value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TxnWrite {
+    key: String,
+    // None means the entry is deleted
+    value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TxnPayload {
+    #[serde(default)]
+    watch: Vec<TxnCondition>,
+    #[serde(default)]
+    writes: Vec<TxnWrite>,
+}
+
+// Applies the writes only if all the watched entries have the expected values, all with every key
+// locked so that nothing can interleave. The writes are applied one by one, so a crash in the
+// middle of them (e.g. with DiskCache) may leave only some of them applied.
+async fn txn(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<TxnPayload>,
+) -> Result<response::Response, CacheError> {
+    let keys = payload.watch.iter().map(|condition| &condition.key);
+    for key in keys.chain(payload.writes.iter().map(|write| &write.key)) {
+        if let Err(err) = validate_key(key, &state) {
+            return Ok(err.into_response());
+        }
+    }
+    let values = payload
+        .writes
+        .iter()
+        .filter_map(|write| write.value.as_ref());
+    for value in values {
+        if let Err(err) = validate_value(value.as_bytes(), &state) {
+            return Ok(err.into_response());
+        }
+    }
+    let _lock = state.key_locks.write_all().await;
+    for condition in &payload.watch {
+        let expected = condition.value.as_ref().map(|value| value.as_bytes());
+        if state.cache.get(&condition.key).await?.as_deref() != expected {
+            let error = format!("the watched entry {} has changed", condition.key);
+            return Ok(error_response(StatusCode::CONFLICT, error));
+        }
+    }
+    if state.refuse_delete_aliased {
+        for write in payload.writes.iter().filter(|write| write.value.is_none()) {
+            if state.cache.has_aliases(&write.key).await? {
+                return Ok(error_response(
+                    StatusCode::CONFLICT,
+                    "entry is the target of an alias",
+                ));
+            }
+        }
+    }
+    let added_keys = payload
+        .writes
+        .iter()
+        .filter(|write| write.value.is_some())
+        .map(|write| write.key.as_str())
+        .collect::<Vec<_>>();
+    if !state.has_room_for(&added_keys).await? {
+        return Ok(CacheFull.into_response());
+    }
+    let writes = payload
+        .writes
+        .iter()
+        .map(|write| (write.key.as_str(), write.value.as_ref().map(String::len)))
+        .collect::<Vec<_>>();
+    if let Some(exceeded) = state.check_quota_of_writes(&writes).await? {
+        return Ok(exceeded.into_response());
+    }
+    for write in payload.writes {
+        match write.value {
+            Some(value) => {
+                state
+                    .add_entry(write.key.clone(), value.clone().into_bytes(), None, None)
+                    .await?;
+                state
+                    .record_mutation(MutationOp::Add, &write.key, Some(value.as_bytes()), None)
+                    .await?;
+            }
+            None => {
+                if state.cache.delete(&write.key).await? {
+                    state
+                        .record_mutation(MutationOp::Delete, &write.key, None, None)
+                        .await?;
+                }
+            }
+        }
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+// The bulk routes do many keys in one request, like the transactions they hold all the keys locked
+async fn bulk_add(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<Vec<AddPayload>>,
+) -> Result<response::Response, CacheError> {
+    let mut expiry_times = Vec::with_capacity(payload.len());
+    for entry in &payload {
+        if let Err(err) = validate_key(&entry.key, &state) {
+            return Ok(err.into_response());
+        }
+        if let Err(err) = validate_value(entry.value.as_bytes(), &state) {
+            return Ok(err.into_response());
+        }
+        match entry.ttl_seconds.map(expiry_time) {
+            Some(Err(err)) => return Ok(err.into_response()),
+            Some(Ok(expires_at)) => expiry_times.push(Some(expires_at)),
+            None => expiry_times.push(None),
+        }
+    }
+    let _lock = state.key_locks.write_all().await;
+    let keys = payload
+        .iter()
+        .map(|entry| entry.key.as_str())
+        .collect::<Vec<_>>();
+    if !state.has_room_for(&keys).await? {
+        return Ok(CacheFull.into_response());
+    }
+    let writes = payload
+        .iter()
+        .map(|entry| (entry.key.as_str(), Some(entry.value.len())))
+        .collect::<Vec<_>>();
+    if let Some(exceeded) = state.check_quota_of_writes(&writes).await? {
+        return Ok(exceeded.into_response());
+    }
+    let entries = payload
+        .into_iter()
+        .zip(expiry_times)
+        .map(|(entry, expires_at)| (entry.key, entry.value.into_bytes(), expires_at))
+        .collect::<Vec<_>>();
+    if state.value_history == 0 {
+        state.cache.bulk_add(entries.clone()).await?;
+    } else {
+        // One by one, so that the replaced values go to the history
+        for (key, value, expires_at) in &entries {
+            state
+                .add_entry(key.clone(), value.clone(), *expires_at, None)
+                .await?;
+        }
+    }
+    for (key, value, expires_at) in &entries {
+        state
+            .record_mutation(MutationOp::Add, key, Some(value), *expires_at)
+            .await?;
+    }
+    Ok(StatusCode::CREATED.into_response())
+}
+
+// Returns {key: value} for the keys that exist
+async fn bulk_get(
+    NsState(state): NsState,
+    JsonBody(keys): JsonBody<Vec<String>>,
+) -> Result<response::Response, CacheError> {
+    for key in &keys {
+        if let Err(err) = validate_key(key, &state) {
+            return Ok(err.into_response());
+        }
+    }
+    let _lock = state.key_locks.read_many(&keys).await;
+    let values = state.cache.bulk_get(&keys).await?;
+    for value in &values {
+        state.lookups.record(value.is_some());
+    }
+    let entries = keys
+        .into_iter()
+        .zip(values)
+        .filter_map(|(key, value)| Some((key, JsonBytes::to_json(value?))));
+    Ok(response::Json(Value::Object(entries.collect())).into_response())
+}
+
+// GET /mget?key=a&key=b..., /bulk/get for the clients that prefer not to send a body
+async fn mget(
+    ns_state: NsState,
+    extract::Query(params): extract::Query<Vec<(String, String)>>,
+) -> Result<response::Response, CacheError> {
+    let keys = params
+        .into_iter()
+        .filter(|(name, _)| name == "key")
+        .map(|(_, key)| key)
+        .collect();
+    bulk_get(ns_state, JsonBody(keys)).await
+}
+
+// Returns {"deleted": N, "missing": M}
+async fn bulk_delete(
+    NsState(state): NsState,
+    JsonBody(keys): JsonBody<Vec<String>>,
+) -> Result<response::Response, CacheError> {
+    for key in &keys {
+        if let Err(err) = validate_key(key, &state) {
+            return Ok(err.into_response());
+        }
+    }
+    let _lock = state.key_locks.write_all().await;
+    if state.refuse_delete_aliased {
+        for key in &keys {
+            if state.cache.has_aliases(key).await? {
+                return Ok(error_response(
+                    StatusCode::CONFLICT,
+                    "entry is the target of an alias",
+                ));
+            }
+        }
+    }
+    let deleted = state.cache.bulk_delete(&keys).await?;
+    for (key, _) in keys.iter().zip(&deleted).filter(|(_, deleted)| **deleted) {
+        state
+            .record_mutation(MutationOp::Delete, key, None, None)
+            .await?;
+    }
+    let deleted_count = deleted.iter().filter(|deleted| **deleted).count();
+    Ok(response::Json(serde_json::json!({
+        "deleted": deleted_count,
+        "missing": deleted.len() - deleted_count,
+    }))
+    .into_response())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DeletePrefixPayload {
+    prefix: String,
+}
+
+// Returns {"deleted": N}. The empty prefix is rejected, as it is more likely a mistake than a way to
+// delete everything.
+async fn delete_prefix(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<DeletePrefixPayload>,
+) -> Result<response::Response, CacheError> {
+    if state.list_disabled {
+        return Ok(error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "deleting by prefix is unavailable as the keys are not stored",
+        ));
+    }
+    if payload.prefix.is_empty() {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "prefix must not be empty",
+        ));
+    }
+    // Exclusively, as any number of keys may be involved
+    let _lock = state.key_locks.write_all().await;
+    if state.refuse_delete_aliased {
+        let page = state.cache.list_paged(&payload.prefix, None, 0).await?;
+        for (key, _) in &page.entries {
+            if state.cache.has_aliases(key).await? {
+                return Ok(error_response(
+                    StatusCode::CONFLICT,
+                    "entry is the target of an alias",
+                ));
+            }
+        }
+    }
+    let deleted = state.cache.delete_prefix(&payload.prefix).await?;
+    if deleted > 0 {
+        state
+            .record_mutation(MutationOp::DeletePrefix, &payload.prefix, None, None)
+            .await?;
+    }
+    Ok(response::Json(serde_json::json!({ "deleted": deleted })).into_response())
+}
+
+// Deletes all the entries of the namespace, returns {"deleted": N}
+async fn flush(NsState(state): NsState) -> Result<response::Json<Value>, CacheError> {
+    let _lock = state.key_locks.write_all().await;
+    let deleted = state.cache.clear().await?;
+    // Replayed as the deletion of all the keys
+    state
+        .record_mutation(MutationOp::DeletePrefix, "", None, None)
+        .await?;
+    Ok(response::Json(serde_json::json!({ "deleted": deleted })))
+}
+
+// Cleans up the storage, see Cache::compact()
+async fn compact(NsState(state): NsState) -> Result<response::Json<Compaction>, CacheError> {
+    let _lock = state.key_locks.write_all().await;
+    Ok(response::Json(state.cache.compact().await?))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AliasPayload {
+    alias: String,
+    target: String,
+}
+
+// See Cache::alias() for the semantics
+async fn alias(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<AliasPayload>,
+) -> response::Response {
+    for key in [&payload.alias, &payload.target] {
+        if let Err(err) = validate_key(key, &state) {
+            return err.into_response();
+        }
+    }
+    // Exclusively, as concurrently created aliases could form a cycle
+    let _lock = state.key_locks.write_all().await;
+    // The alias counts as an entry with the value of the target
+    if state.quota().is_some() {
+        let value_bytes = match state.cache.get(&payload.target).await {
+            Ok(value) => value.map(|value| value.len()),
+            Err(err) => return err.into_response(),
+        };
+        // A missing target is rejected by alias()
+        if let Some(value_bytes) = value_bytes {
+            match state.check_quota(&payload.alias, value_bytes).await {
+                Ok(Some(exceeded)) => return exceeded.into_response(),
+                Ok(None) => {}
+                Err(err) => return err.into_response(),
+            }
+        }
+    }
+    if let Err(err) = state
+        .cache
+        .alias(payload.alias.clone(), payload.target.clone())
+        .await
+    {
+        return err.into_response();
+    }
+    if let Err(err) = state
+        .record_mutation(
+            MutationOp::Alias,
+            &payload.alias,
+            Some(payload.target.as_bytes()),
+            None,
+        )
+        .await
+    {
+        return err.into_response();
+    }
+    StatusCode::CREATED.into_response()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RenamePayload {
+    from: String,
+    to: String,
+    #[serde(default)]
+    overwrite: bool, // otherwise an existing entry to is a conflict
+}
+
+async fn rename(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<RenamePayload>,
+) -> Result<response::Response, CacheError> {
+    for key in [&payload.from, &payload.to] {
+        if let Err(err) = validate_key(key, &state) {
+            return Ok(err.into_response());
+        }
+    }
+    if payload.from == payload.to {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            "from and to must differ",
+        ));
+    }
+    // Exclusively, as two keys are involved
+    let _lock = state.key_locks.write_all().await;
+    if state.refuse_delete_aliased && state.cache.has_aliases(&payload.from).await? {
+        return Ok(error_response(
+            StatusCode::CONFLICT,
+            "entry is the target of an alias",
+        ));
+    }
+    let result = state
+        .cache
+        .rename(payload.from.clone(), payload.to.clone(), payload.overwrite)
+        .await?;
+    Ok(match result {
+        RenameResult::Renamed => {
+            state
+                .record_mutation(
+                    MutationOp::Rename,
+                    &payload.from,
+                    Some(payload.to.as_bytes()),
+                    None,
+                )
+                .await?;
+            StatusCode::NO_CONTENT.into_response()
+        }
+        RenameResult::NotFound => StatusCode::NOT_FOUND.into_response(),
+        RenameResult::DestinationExists => {
+            error_response(StatusCode::CONFLICT, "destination already exists")
+        }
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TouchPayload {
+    key: String,
+    ttl_seconds: u64, // counted from now, replacing the current expiration time
+}
+
+async fn touch(
+    NsState(state): NsState,
+    JsonBody(payload): JsonBody<TouchPayload>,
+) -> Result<response::Response, CacheError> {
+    if let Err(err) = validate_key(&payload.key, &state) {
+        return Ok(err.into_response());
+    }
+    let expires_at = match expiry_time(payload.ttl_seconds) {
+        Ok(expires_at) => expires_at,
+        Err(err) => return Ok(err.into_response()),
+    };
+    let _lock = state.key_locks.write(&payload.key).await;
+    if state.cache.touch(&payload.key, expires_at).await? {
+        state
+            .record_mutation(MutationOp::Touch, &payload.key, None, Some(expires_at))
+            .await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(StatusCode::NOT_FOUND.into_response())
+    }
+}
+
+// JSON-RPC 2.0 (https://www.jsonrpc.org/specification) on top of the REST handlers: the methods add,
+// get, delete, modify and list take the JSON payloads of their REST counterparts (the ListQuery for
+// list) as named params. The statuses other than 2xx become errors with the status in data.
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    outcome: RpcOutcome,
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RpcOutcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    const PARSE_ERROR: i64 = -32700;
+    const INVALID_REQUEST: i64 = -32600;
+    const METHOD_NOT_FOUND: i64 = -32601;
+    const INVALID_PARAMS: i64 = -32602;
+    const INTERNAL_ERROR: i64 = -32603;
+    // Any other failure of the method e.g. 404
+    const SERVER_ERROR: i64 = -32000;
+
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl RpcResponse {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        RpcResponse {
+            jsonrpc: "2.0",
+            outcome: match outcome {
+                Ok(result) => RpcOutcome::Result(result),
+                Err(err) => RpcOutcome::Error(err),
+            },
+            id,
+        }
+    }
+}
+
+// The calls of a batch are made in order. Notifications (calls without an id) get no response, so
+// a request of only them gets 204.
+async fn rpc(NsState(state): NsState, body: Bytes) -> response::Response {
+    let request = match serde_json::from_slice::<Value>(&body) {
+        Ok(request) => request,
+        Err(err) => {
+            let err = RpcError::new(RpcError::PARSE_ERROR, err.to_string());
+            return extract::Json(RpcResponse::new(Value::Null, Err(err))).into_response();
+        }
+    };
+    match request {
+        Value::Array(calls) if !calls.is_empty() => {
+            let mut responses = Vec::with_capacity(calls.len());
+            for call in calls {
+                responses.extend(rpc_call(&state, call).await);
+            }
+            if responses.is_empty() {
+                return StatusCode::NO_CONTENT.into_response();
+            }
+            extract::Json(responses).into_response()
+        }
+        call => match rpc_call(&state, call).await {
+            Some(response) => extract::Json(response).into_response(),
+            None => StatusCode::NO_CONTENT.into_response(),
+        },
+    }
+}
+
+async fn rpc_call(state: &Arc<AppState>, call: Value) -> Option<RpcResponse> {
+    let id = call.get("id").cloned();
+    let valid_id = id
+        .as_ref()
+        .is_none_or(|id| id.is_string() || id.is_number() || id.is_null());
+    let request = match serde_json::from_value::<RpcRequest>(call) {
+        Ok(request) if request.jsonrpc == "2.0" && valid_id => request,
+        _ => {
+            let err = RpcError::new(RpcError::INVALID_REQUEST, "invalid request");
+            return Some(RpcResponse::new(Value::Null, Err(err)));
+        }
+    };
+    let outcome = rpc_method(state, &request.method, request.params).await;
+    Some(RpcResponse::new(id?, outcome))
+}
+
+fn rpc_params<T: serde::de::DeserializeOwned>(params: Option<Value>) -> Result<T, RpcError> {
+    serde_json::from_value(params.unwrap_or(Value::Null))
+        .map_err(|err| RpcError::new(RpcError::INVALID_PARAMS, err.to_string()))
+}
+
+async fn rpc_method(
+    state: &Arc<AppState>,
+    method: &str,
+    params: Option<Value>,
+) -> Result<Value, RpcError> {
+    let response = match method {
+        "add" => {
+            let payload: AddPayload = rpc_params(params)?;
+            let request = Request::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+                .unwrap();
+            add(
+                NsState(state.clone()),
+                extract::Query(AddQuery::default()),
+                request,
+            )
+            .await
+        }
+        "get" => {
+            get_key(
+                state,
+                GetQuery::default(),
+                &HeaderMap::new(),
+                rpc_params(params)?,
+            )
+            .await
+        }
+        "delete" => delete_key(state, rpc_params(params)?).await,
+        "modify" => modify(NsState(state.clone()), JsonBody(rpc_params(params)?)).await,
+        "list" => {
+            let query = params.map(|params| rpc_params(Some(params))).transpose()?;
+            list(
+                NsState(state.clone()),
+                extract::Query(query.unwrap_or_default()),
+                HeaderMap::new(),
+            )
+            .await
+        }
+        _ => {
+            let message = format!("unknown method {:?}", method);
+            return Err(RpcError::new(RpcError::METHOD_NOT_FOUND, message));
+        }
+    }
+    .into_response();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| RpcError::new(RpcError::INTERNAL_ERROR, err.to_string()))?;
+    if !status.is_success() {
+        let code = match status {
+            StatusCode::BAD_REQUEST
+            | StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::UNPROCESSABLE_ENTITY => RpcError::INVALID_PARAMS,
+            status if status.is_server_error() => RpcError::INTERNAL_ERROR,
+            _ => RpcError::SERVER_ERROR,
+        };
+        let message = if body.is_empty() {
+            status.canonical_reason().unwrap_or_default().to_string()
+        } else {
+            String::from_utf8_lossy(&body).into_owned()
+        };
+        return Err(RpcError {
+            data: Some(serde_json::json!({ "status": status.as_u16() })),
+            ..RpcError::new(code, message)
+        });
+    }
+    Ok(match method {
+        "get" => JsonBytes::to_json(body.to_vec()),
+        "list" => serde_json::from_slice(&body).unwrap(),
+        _ => Value::Null,
+    })
+}
+
+// Server-sent events: a "message" event with the MutationEvent (without the value) as data for every
+// mutation of the namespace from now on, and a "lagged" event with {"missed": N} if the client reads
+// too slowly to keep up
+async fn subscribe(NsState(state): NsState) -> response::Response {
+    let receiver = state.changes.sender.subscribe();
+    let closing = state.changes.closing.subscribe();
+    let namespace = state.namespace.clone();
+    let events =
+        futures::stream::unfold((receiver, closing), move |(mut receiver, mut closing)| {
+            let namespace = namespace.clone();
+            async move {
+                loop {
+                    let event = tokio::select! {
+                        event = receiver.recv() => event,
+                        _ = closing.wait_for(|closing| *closing) => return None,
+                    };
+                    let event = match event {
+                        Ok(event) if event.namespace == namespace => {
+                            Event::default().json_data(event).unwrap()
+                        }
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => Event::default()
+                            .event("lagged")
+                            .json_data(serde_json::json!({ "missed": missed }))
+                            .unwrap(),
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    };
+                    return Some((
+                        Ok::<_, std::convert::Infallible>(event),
+                        (receiver, closing),
+                    ));
+                }
+            }
+        });
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+// A line of /snapshot and /restore. The snapshots taken before the metadata was included have only
+// the key and the value, the restored entries are created anew then.
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotRecord {
+    key: String,
+    value: JsonBytes,
+    // Milliseconds since the UNIX epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at_ms: Option<u64>,
+}
+
+// Streams all the entries as newline-delimited JSON SnapshotRecords. Like /list it is not atomic:
+// the mutations made during it may or may not be included.
+async fn snapshot(NsState(state): NsState) -> Result<response::Response, CacheError> {
+    if state.list_disabled {
+        return Ok(error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "snapshots are unavailable as the keys are not stored",
+        ));
+    }
+    let permit = match state.list_permit() {
+        Ok(permit) => permit,
+        Err(err) => return Ok(err.into_response()),
+    };
+    let body = stream_entries(
+        state,
+        permit,
+        ["", "", ""],
+        |state| state.cache.iter_with_meta(),
+        |key, stored| {
+            let record = SnapshotRecord {
+                key,
+                value: JsonBytes(stored.value),
+                expires_at_ms: stored.expires_at.map(unix_ms),
+                content_type: stored.meta.content_type,
+                created_at_ms: Some(stored.meta.created_at_ms),
+            };
+            serde_json::to_string(&record).unwrap() + "\n"
+        },
+    )
+    .await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        body::boxed(body),
+    )
+        .into_response())
+}
+
+// Streams all the entries as CSV with the key,value columns, e.g. for spreadsheets. Binary values
+// have no place there, their invalid UTF-8 is replaced with U+FFFD.
+async fn dump_csv(NsState(state): NsState) -> Result<response::Response, CacheError> {
+    if state.list_disabled {
+        return Ok(error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "dumps are unavailable as the keys are not stored",
+        ));
+    }
+    let permit = match state.list_permit() {
+        Ok(permit) => permit,
+        Err(err) => return Ok(err.into_response()),
+    };
+    let body = stream_entries(
+        state,
+        permit,
+        ["key,value\n", "", ""],
+        |state| state.cache.iter(),
+        |key, value| {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer
+                .write_record([key.as_str(), &String::from_utf8_lossy(&value)])
+                .unwrap();
+            String::from_utf8(writer.into_inner().unwrap()).unwrap()
+        },
+    )
+    .await?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+        body::boxed(body),
+    )
+        .into_response())
+}
+
+// Adds the entries of a /snapshot body (existing entries with other keys are kept), returns
+// {"restored": N}. The records are applied as they arrive, so if one is invalid, the ones before it
+// stay applied.
+async fn restore(
+    NsState(state): NsState,
+    request: Request<Body>,
+) -> Result<response::Response, CacheError> {
+    let mut body = request.into_body();
+    // The line being received, which (unlike the whole body) is limited by max_body_bytes
+    let mut line = Vec::new();
+    let mut restored = 0;
+    while let Some(chunk) = body::HttpBody::data(&mut body).await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => return Ok(error_response(StatusCode::BAD_REQUEST, err)),
+        };
+        let mut rest = &chunk[..];
+        loop {
+            let end = rest.iter().position(|&b| b == b'\n');
+            line.extend_from_slice(&rest[..end.unwrap_or(rest.len())]);
+            if let Some(max_bytes) = state.max_body_bytes.filter(|&max| line.len() > max) {
+                let error = format!("record {} exceeds {} bytes", restored + 1, max_bytes);
+                return Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE, error));
+            }
+            let Some(end) = end else {
+                break;
+            };
+            rest = &rest[end + 1..];
+            if !line.is_empty() {
+                if let Some(rejection) = restore_record(&state, &line, restored).await? {
+                    return Ok(rejection);
+                }
+                restored += 1;
+            }
+            line.clear();
+        }
+    }
+    // The last line does not need to end with a newline
+    if !line.is_empty() {
+        if let Some(rejection) = restore_record(&state, &line, restored).await? {
+            return Ok(rejection);
+        }
+        restored += 1;
+    }
+    Ok(response::Json(serde_json::json!({ "restored": restored })).into_response())
+}
+
+// Applies a (non-empty) line of the /restore body, returns the response rejecting the record if it
+// is invalid
+async fn restore_record(
+    state: &AppState,
+    line: &[u8],
+    restored: usize,
+) -> Result<Option<response::Response>, CacheError> {
+    let record = match serde_json::from_slice::<SnapshotRecord>(line) {
+        Ok(record) => record,
+        Err(err) => {
+            let msg = format!("record {}: {}", restored + 1, err);
+            return Ok(Some(error_response(StatusCode::BAD_REQUEST, msg)));
+        }
+    };
+    if let Err(err) = validate_key(&record.key, state) {
+        return Ok(Some(err.into_response()));
+    }
+    if let Err(err) = validate_value(&record.value.0, state) {
+        return Ok(Some(err.into_response()));
+    }
+    // It has to be a valid header value, as in /add
+    if record
+        .content_type
+        .as_deref()
+        .is_some_and(|content_type| HeaderValue::from_str(content_type).is_err())
+    {
+        let msg = format!("record {}: invalid content_type", restored + 1);
+        return Ok(Some(error_response(StatusCode::BAD_REQUEST, msg)));
+    }
+    let expires_at = record
+        .expires_at_ms
+        .map(|ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms));
+    let mut meta = EntryMeta::new(record.content_type.clone());
+    if let Some(created_at_ms) = record.created_at_ms {
+        meta.created_at_ms = created_at_ms;
+    }
+    let _lock = state.lock_for_write(&record.key).await;
+    if !state.has_room_for(&[&record.key]).await? {
+        return Ok(Some(CacheFull.into_response()));
+    }
+    if let Some(exceeded) = state.check_quota(&record.key, record.value.0.len()).await? {
+        return Ok(Some(exceeded.into_response()));
+    }
+    state
+        .add_entry_with_meta(record.key.clone(), record.value.0.clone(), expires_at, meta)
+        .await?;
+    state
+        .record_mutation_with_content_type(
+            MutationOp::Add,
+            &record.key,
+            Some(&record.value.0),
+            expires_at,
+            record.content_type.as_deref(),
+        )
+        .await?;
+    Ok(None)
+}
+
+// Returns the CacheStats of the namespace
+async fn stats(NsState(state): NsState) -> Result<response::Response, CacheError> {
+    if state.list_disabled {
+        return Ok(error_response(
+            StatusCode::NOT_IMPLEMENTED,
+            "stats are unavailable as the keys are not stored",
+        ));
+    }
+    Ok(response::Json(Stats {
+        cache: state.cache.stats().await?,
+        write_queue_depth: state.write_queue.depth(),
+        lists_in_progress: state.list_limiter.as_ref().map(|limiter| limiter.in_use()),
+    })
+    .into_response())
+}
+
+async fn counters(State(state): State<Arc<AppState>>) -> response::Json<Value> {
+    response::Json(state.counters.to_json())
+}
+
+// Returns {"window_seconds": N, "requests_per_second": R, "by_method": {"GET": {"requests": M,
+// "requests_per_second": R}, ...}} of the last minute (see Throughput)
+async fn throughput(State(state): State<Arc<AppState>>) -> response::Json<Value> {
+    response::Json(state.throughput.to_json())
+}
+
+async fn reset_counters(State(state): State<Arc<AppState>>) -> StatusCode {
+    state.counters.reset();
+    StatusCode::NO_CONTENT
+}
+
+// Liveness probe: the server is able to respond
+async fn health() -> response::Response {
+    response::Json(serde_json::json!({ "status": "ok" })).into_response()
+}
+
+// Which build is running: the crate version, the git commit (if built from a repository) and the
+// build time (seconds since the UNIX epoch)
+async fn version() -> response::Response {
+    response::Json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("GIT_COMMIT"),
+        "built_at": env!("BUILD_TIMESTAMP").parse::<u64>().unwrap(),
+    }))
+    .into_response()
+}
+
+// Readiness probe: the cache can take writes
+async fn ready(State(state): State<Arc<AppState>>) -> response::Response {
+    match state.cache.check_writable().await {
+        Ok(()) => response::Json(serde_json::json!({ "status": "ok" })).into_response(),
+        Err(err) => {
+            eprintln!("Not ready: {}", err);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                response::Json(serde_json::json!({ "status": "unavailable" })),
+            )
+                .into_response()
+        }
+    }
+}
+
+// Prometheus text exposition format
+async fn metrics(State(state): State<Arc<AppState>>) -> Result<response::Response, CacheError> {
+    let mut out = String::new();
+    state.counters.to_prometheus(&mut out);
+    out.push_str(&format!(
+        "# TYPE rest_server_get_hits_total counter\nrest_server_get_hits_total {}\n",
+        state.lookups.hits.load(Ordering::Relaxed)
+    ));
+    out.push_str(&format!(
+        "# TYPE rest_server_get_misses_total counter\nrest_server_get_misses_total {}\n",
+        state.lookups.misses.load(Ordering::Relaxed)
+    ));
+    out.push_str(&format!(
+        "# TYPE rest_server_entries gauge\nrest_server_entries {}\n",
+        state.cache.len().await?
+    ));
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response())
+}
+
+#[cfg(test)]
+mod app_tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use axum_test::TestServer;
+    use tmpdir::TmpDir;
+
+    struct Apps {
+        _tmp_dir: TmpDir, // guards temporary directory and removes it after testing
+        apps: [App; 4],
+    }
+
+    impl Apps {
+        async fn new() -> Self {
+            Self::new_with(app).await
+        }
+
+        // Allows a test to customize the state or the router of the apps
+        async fn new_with(make_app: impl Fn(AppState) -> App) -> Self {
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let tmp_dir_path = tmp_dir.to_path_buf();
+            Self {
+                _tmp_dir: tmp_dir,
+                apps: [
+                    make_app(AppState::new(Box::new(MemCache::new()))),
+                    make_app(AppState::new(Box::new(SnapshotMemCache::new()))),
+                    make_app(AppState::new(Box::new(DiskCache::new(
+                        tmp_dir_path.clone(),
+                    )))),
+                    make_app(AppState::new(Box::new(
+                        SqliteCache::open(&tmp_dir_path.join("cache.db")).unwrap(),
+                    ))),
+                ],
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_list() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn add() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"a value"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn add_two_values() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "b".to_string(),
+                value: "y".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"a": "x", "b": "y"})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn add_overrides_previous_entry() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"another value"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn deleting_nonexistent_entry() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "some key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn delete() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "some key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn modifying_nonexistent_entry() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn modify() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"another value"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn get_nonexistent_entry() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.get("/get").json(&GetPayload {
+                key: "some key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn get() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.get("/get").json(&GetPayload {
+                key: "some key".to_string(),
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "a value");
+        }
+    }
+
+    #[tokio::test]
+    async fn get_default_does_not_store() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server
+                .get("/get")
+                .add_query_param("default", "fallback")
+                .json(&GetPayload {
+                    key: "some key".to_string(),
+                });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "fallback");
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn get_default_of_existing_entry() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            for param in ["default", "default_and_set"] {
+                let request =
+                    server
+                        .get("/get")
+                        .add_query_param(param, "fallback")
+                        .json(&GetPayload {
+                            key: "some key".to_string(),
+                        });
+                let response = request.await;
+                assert_eq!(response.status_code(), StatusCode::OK);
+                assert_eq!(response.text(), "a value");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn get_default_and_set() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server
+                .get("/get")
+                .add_query_param("default_and_set", "fallback")
+                .json(&GetPayload {
+                    key: "some key".to_string(),
+                });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "fallback");
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"fallback"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_panic_is_internal_server_error() {
+        async fn panicking() -> StatusCode {
+            panic!("a bug")
+        }
+        let apps = Apps::new_with(|app_state| {
+            let router = routes(app_state).route("/panic", routing::get(panicking));
+            with_middleware(router).into_make_service_with_connect_info::<SocketAddr>()
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server.get("/panic").await;
+            assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"error": "internal error"})
+            );
+
+            // The server is still up
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn list_with_sizes() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "xyz".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "b".to_string(),
+                value: String::new(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server
+                .get("/list")
+                .add_query_param("with_sizes", true)
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"a":{"size":3},"b":{"size":0}}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn txn() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.post("/txn").json(&TxnPayload {
+                watch: vec![
+                    TxnCondition {
+                        key: "a".to_string(),
+                        value: Some("x".to_string()),
+                    },
+                    TxnCondition {
+                        key: "b".to_string(),
+                        value: None,
+                    },
+                ],
+                writes: vec![
+                    TxnWrite {
+                        key: "a".to_string(),
+                        value: None,
+                    },
+                    TxnWrite {
+                        key: "b".to_string(),
+                        value: Some("y".to_string()),
+                    },
+                ],
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"b":"y"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn txn_conflict() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            for watch in [
+                TxnCondition {
+                    key: "a".to_string(),
+                    value: Some("other".to_string()),
+                },
+                TxnCondition {
+                    key: "a".to_string(),
+                    value: None,
+                },
+                TxnCondition {
+                    key: "b".to_string(),
+                    value: Some("x".to_string()),
+                },
+            ] {
+                let request = server.post("/txn").json(&TxnPayload {
+                    watch: vec![watch],
+                    writes: vec![TxnWrite {
+                        key: "c".to_string(),
+                        value: Some("z".to_string()),
+                    }],
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CONFLICT);
+            }
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"a":"x"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn dedup_window() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.dedup = Some(Arc::new(Deduplicator::new(Duration::from_secs(60))));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let add_payload = AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            };
+            let request = server.put("/add").json(&add_payload);
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "some key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            // Duplicate is answered with the previous response without being executed
+            let request = server.put("/add").json(&add_payload);
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+
+            // A different request is executed
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"another value"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn dedup_window_expires() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.dedup = Some(Arc::new(Deduplicator::new(Duration::from_millis(1))));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let add_payload = AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            };
+            let request = server.put("/add").json(&add_payload);
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "some key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let request = server.put("/add").json(&add_payload);
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"a value"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_queue_soft_limit() {
+        let write_queue = Arc::new(WriteQueue::new(Some(1), None));
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.write_queue = write_queue.clone();
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            // Pretend there is a write in progress
+            write_queue.depth.fetch_add(1, Ordering::Relaxed);
+            let response = server.get("/stats").await;
+            assert_eq!(response.json::<Stats>().write_queue_depth, 1);
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+                ..Default::default()
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.header(header::RETRY_AFTER), "1");
+            assert_eq!(
+                response.json::<serde_json::Value>(),
+                serde_json::json!({"error": "too many writes in progress"})
+            );
+
+            // Reads are unaffected
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"a value"}"#);
+
+            write_queue.depth.fetch_sub(1, Ordering::Relaxed);
+            assert_eq!(write_queue.depth.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_queue_hard_limit() {
+        let write_queue = Arc::new(WriteQueue::new(Some(1), Some(2)));
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.write_queue = write_queue.clone();
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            // Past the soft limit the writes are executed, but the clients are asked to slow down
+            write_queue.depth.fetch_add(1, Ordering::Relaxed);
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::CREATED);
+            assert_eq!(response.header(header::RETRY_AFTER), "1");
+            assert_eq!(
+                get_value(&server, "some key").await.as_deref(),
+                Some("a value")
+            );
+
+            // Past the hard limit they are rejected
+            write_queue.depth.fetch_add(1, Ordering::Relaxed);
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+                ..Default::default()
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.header(header::RETRY_AFTER), "1");
+            assert_eq!(
+                get_value(&server, "some key").await.as_deref(),
+                Some("a value")
+            );
+
+            write_queue.depth.fetch_sub(2, Ordering::Relaxed);
+            assert_eq!(write_queue.depth.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    fn origin_server() -> TestServer {
+        TestServer::new(
+            Router::new()
+                .route("/value", routing::get(|| async { "fetched value" }))
+                .into_make_service(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn add_from_url() {
+        let origin = origin_server();
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.fetcher = Arc::new(Fetcher::new(vec!["127.0.0.1".to_string()]));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.post("/add-from-url").json(&AddFromUrlPayload {
+                key: "some key".to_string(),
+                url: format!("{}value", origin.server_address()),
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::CREATED);
+            assert_eq!(response.header(header::LOCATION), "/get/some%20key");
+            let response = server.get("/get/some%20key").await;
+            assert_eq!(
+                response.header(header::CONTENT_TYPE),
+                "text/plain; charset=utf-8"
+            );
+
+            let request = server.post("/add-from-url").json(&AddFromUrlPayload {
+                key: "other key".to_string(),
+                url: format!("{}nonexistent", origin.server_address()),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::BAD_GATEWAY);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), r#"{"some key":"fetched value"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn add_from_url_disallowed() {
+        let origin = origin_server();
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.fetcher = Arc::new(Fetcher::new(vec!["example.com".to_string()]));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            for url in [
+                format!("{}value", origin.server_address()),
+                "file:///etc/passwd".to_string(),
+                "ftp://example.com/value".to_string(),
+                "not a url".to_string(),
+            ] {
+                let request = server.post("/add-from-url").json(&AddFromUrlPayload {
+                    key: "some key".to_string(),
+                    url,
+                });
+                assert!(request.await.status_code().is_client_error());
+            }
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn add_from_url_too_large() {
+        let origin = origin_server();
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.fetcher = Arc::new(Fetcher::new(vec!["127.0.0.1".to_string()]));
+            app_state.max_value_bytes = Some(5);
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.post("/add-from-url").json(&AddFromUrlPayload {
+                key: "some key".to_string(),
+                url: format!("{}value", origin.server_address()),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        }
+    }
+
+    type Events = Arc<std::sync::Mutex<Vec<Value>>>;
+
+    fn webhook_receiver(events: Events) -> TestServer {
+        async fn record(
+            State(events): State<Events>,
+            extract::Json(event): extract::Json<Value>,
+        ) -> StatusCode {
+            events.lock().unwrap().push(event);
+            StatusCode::OK
+        }
+        TestServer::new(
+            Router::new()
+                .route("/hook", routing::post(record))
+                .with_state(events)
+                .into_make_service(),
+        )
+        .unwrap()
+    }
+
+    async fn wait_for_events(events: &Events, count: usize) -> Vec<Value> {
+        for _ in 0..100 {
+            if events.lock().unwrap().len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::mem::take(&mut events.lock().unwrap())
+    }
+
+    #[tokio::test]
+    async fn webhook() {
+        let events = Events::default();
+        let receiver = webhook_receiver(events.clone());
+        let url = format!("{}hook", receiver.server_address());
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.webhook = Some(Webhook::start(url.clone(), false));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            // Unsuccessful mutations are not reported
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "other key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "some key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            let events = wait_for_events(&events, 3).await;
+            assert_eq!(
+                events
+                    .iter()
+                    .map(|event| (
+                        event["op"].as_str().unwrap(),
+                        event["key"].as_str().unwrap()
+                    ))
+                    .collect::<Vec<_>>(),
+                [
+                    ("add", "some key"),
+                    ("modify", "some key"),
+                    ("delete", "some key")
+                ]
+            );
+            assert!(events.iter().all(|event| event["timestamp"].is_u64()));
+            assert!(events.iter().all(|event| event.get("value").is_none()));
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_with_value() {
+        let events = Events::default();
+        let receiver = webhook_receiver(events.clone());
+        let url = format!("{}hook", receiver.server_address());
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.webhook = Some(Webhook::start(url.clone(), true));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let events = wait_for_events(&events, 1).await;
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0]["value"], "a value");
+        }
+    }
+
+    async fn wait_for_list(server: &TestServer, expected: &str) -> String {
+        for _ in 0..100 {
+            if server.get("/list").await.text() == expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        server.get("/list").await.text()
+    }
+
+    #[tokio::test]
+    async fn replica() {
+        let replica = TestServer::new(app(AppState::new(Box::new(MemCache::new())))).unwrap();
+        let url = replica.server_address().to_string();
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.replica = Some(Replica::start(url.clone()));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ttl_seconds: Some(100),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.put("/add").json(&AddPayload {
+                key: "other key".to_string(),
+                value: "other value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "other key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            let expected = r#"{"some key":"another value"}"#;
+            assert_eq!(wait_for_list(&replica, expected).await, expected);
+            // The expiration time is replicated too
+            let response = replica.get("/get/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let ttl = response.header("x-cache-ttl-seconds");
+            let ttl = ttl.to_str().unwrap().parse::<u64>().unwrap();
+            assert!((95..=100).contains(&ttl), "{}", ttl);
+
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "some key".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            assert_eq!(wait_for_list(&replica, "{}").await, "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_lists() {
+        let list_limiter = Arc::new(ListLimiter::new(2));
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.list_limiter = Some(list_limiter.clone());
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            // Pretend there are two listings in progress
+            let permits = list_limiter.semaphore.try_acquire_many(2).unwrap();
+            let response = server.get("/stats").await;
+            assert_eq!(response.json::<Stats>().lists_in_progress, Some(2));
+            for path in ["/list", "/snapshot", "/dump.csv"] {
+                let response = server.get(path).await;
+                assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(
+                    response.json::<serde_json::Value>(),
+                    serde_json::json!({"error": "too many listings in progress"})
+                );
+            }
+
+            drop(permits);
+            let response = server.get("/stats").await;
+            assert_eq!(response.json::<Stats>().lists_in_progress, Some(0));
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk_reads_share_the_locks() {
+        let locks = KeyLocks::new();
+        let _read = locks.read("a").await;
+        let keys = ["a".to_string(), "b".to_string()];
+        let lock = tokio::time::timeout(Duration::from_secs(5), locks.read_many(&keys)).await;
+        let _lock = lock.unwrap();
+
+        // But the writes of the keys wait
+        let write = tokio::time::timeout(Duration::from_millis(50), locks.write("b")).await;
+        assert!(write.is_err());
+    }
+
+    #[tokio::test]
+    async fn value_history() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.value_history = 3;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server.get("/history/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "v1".to_string(),
+                ttl_seconds: Some(100),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let response = server.get("/history/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "[]");
+
+            for value in ["v2", "v3", "v4", "v5"] {
+                tokio::time::sleep(Duration::from_millis(2)).await;
+                let request = server.patch("/modify").json(&ModifyPayload {
+                    key: "some key".to_string(),
+                    value: value.to_string(),
+                });
+                assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            }
+
+            let history = server
+                .get("/history/some%20key")
+                .await
+                .json::<Vec<PastValue>>();
+            assert_eq!(
+                history
+                    .iter()
+                    .map(|past| past.value.0.as_slice())
+                    .collect::<Vec<_>>(),
+                [b"v4", b"v3", b"v2"]
+            );
+            assert!(history
+                .windows(2)
+                .all(|pair| pair[0].updated_at_ms > pair[1].updated_at_ms));
+            // modify() keeps the expiration time
+            let response = server.get("/get/some%20key").await;
+            assert_eq!(response.text(), "v5");
+            assert!(response.maybe_header("x-cache-ttl-seconds").is_some());
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "v6".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let history = server
+                .get("/history/some%20key")
+                .await
+                .json::<Vec<PastValue>>();
+            assert_eq!(history.len(), 3);
+            assert_eq!(history[0].value, JsonBytes(b"v5".to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn value_history_of_every_write() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.value_history = 10;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "k".to_string(),
+                value: "1".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let requests = [
+                server.post("/append").json(&AppendPayload {
+                    key: "k".to_string(),
+                    value: "0".to_string(),
+                }),
+                server.post("/incr").json(&serde_json::json!({"key": "k"})),
+                server.patch("/cas").json(&CasPayload {
+                    key: "k".to_string(),
+                    expected: "11".to_string(),
+                    new: "{}".to_string(),
+                }),
+                server.patch("/merge").json(&MergePayload {
+                    key: "k".to_string(),
+                    patch: serde_json::json!({"a": 1}),
+                }),
+                server.post("/txn").json(&serde_json::json!({
+                    "writes": [{"key": "k", "value": "txn"}],
+                })),
+                server.put("/bulk/add").json(&[AddPayload {
+                    key: "k".to_string(),
+                    value: "bulk".to_string(),
+                    ..Default::default()
+                }]),
+                server
+                    .post("/restore")
+                    .bytes(r#"{"key":"k","value":"restored"}"#.into()),
+            ];
+            for request in requests {
+                assert!(request.await.status_code().is_success());
+            }
+            let history = server.get("/history/k").await.json::<Vec<PastValue>>();
+            assert_eq!(
+                history
+                    .iter()
+                    .map(|past| String::from_utf8(past.value.0.clone()).unwrap())
+                    .collect::<Vec<_>>(),
+                ["bulk", "txn", r#"{"a":1}"#, "{}", "11", "10", "1"]
+            );
+            assert_eq!(get_value(&server, "k").await.as_deref(), Some("restored"));
+        }
+    }
+
+    #[tokio::test]
+    async fn no_value_history_by_default() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "v1".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "some key".to_string(),
+                value: "v2".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+            let response = server.get("/history/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "[]");
+        }
+    }
+
+    #[tokio::test]
+    async fn get_json_envelope() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                content_type: Some("text/html".to_string()),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server
+                .put("/add")
+                .add_query_param("key", "binary")
+                .content_type("application/octet-stream")
+                .bytes(Bytes::from_static(b"\xff\xfe"));
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            // Raw by default
+            let response = server.get("/get/some%20key").await;
+            assert_eq!(response.text(), "a value");
+            assert_eq!(response.header(header::CONTENT_TYPE), "text/html");
+            let response = server
+                .get("/get/some%20key")
+                .add_query_param("format", "raw")
+                .await;
+            assert_eq!(response.text(), "a value");
+
+            let response = server
+                .get("/get/some%20key")
+                .add_query_param("format", "json")
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.header(header::CONTENT_TYPE), "application/json");
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"key": "some key", "value": "a value"})
+            );
+            let response = server
+                .get("/get")
+                .add_query_param("format", "json")
+                .json(&GetPayload {
+                    key: "binary".to_string(),
+                })
+                .await;
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"key": "binary", "value": {"base64": "//4="}})
+            );
+            let response = server
+                .get("/get/some%20key")
+                .add_query_param("format", "json")
+                .add_header(header::ACCEPT, "application/msgpack".parse().unwrap())
+                .await;
+            assert_eq!(response.header(header::CONTENT_TYPE), "application/msgpack");
+            assert_eq!(
+                rmp_serde::from_slice::<Value>(response.as_bytes()).unwrap(),
+                serde_json::json!({"key": "some key", "value": "a value"})
+            );
+
+            // The defaults are enveloped too
+            let response = server
+                .get("/get/other%20key")
+                .add_query_param("format", "json")
+                .add_query_param("default", "fallback")
+                .await;
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"key": "other key", "value": "fallback"})
+            );
+            let response = server
+                .get("/get/other%20key")
+                .add_query_param("format", "json")
+                .await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+
+            let response = server
+                .get("/get/some%20key")
+                .add_query_param("format", "xml")
+                .await;
+            assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn namespace_quotas() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.quotas = Arc::new(HashMap::from([
+                (
+                    "small".to_string(),
+                    Quota {
+                        max_entries: Some(2),
+                        max_bytes: None,
+                    },
+                ),
+                (
+                    "tiny".to_string(),
+                    Quota {
+                        max_entries: None,
+                        max_bytes: Some(10),
+                    },
+                ),
+            ]));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let add = |namespace: &str, key: &str, value: &str| {
+                server
+                    .put(&format!("/{}/add", namespace))
+                    .json(&AddPayload {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                        ..Default::default()
+                    })
+            };
+
+            assert_eq!(
+                add("small", "a", "x").await.status_code(),
+                StatusCode::CREATED
+            );
+            assert_eq!(
+                add("small", "b", "x").await.status_code(),
+                StatusCode::CREATED
+            );
+            let response = add("small", "c", "x").await;
+            assert_eq!(response.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"error": "the quota of namespace small is exceeded"})
+            );
+            // Overwriting does not add an entry
+            assert_eq!(
+                add("small", "b", "y").await.status_code(),
+                StatusCode::CREATED
+            );
+
+            assert_eq!(
+                add("tiny", "a", "12345").await.status_code(),
+                StatusCode::CREATED
+            );
+            let response = add("tiny", "b", "123456").await;
+            assert_eq!(response.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"error": "the quota of namespace tiny is exceeded"})
+            );
+            assert_eq!(
+                add("tiny", "a", "1").await.status_code(),
+                StatusCode::CREATED
+            );
+            assert_eq!(
+                add("tiny", "b", "123456").await.status_code(),
+                StatusCode::CREATED
+            );
+
+            // The other namespaces are unaffected
+            for i in 0..5 {
+                let key = i.to_string();
+                let value = "x".repeat(100);
+                assert_eq!(
+                    add("other", &key, &value).await.status_code(),
+                    StatusCode::CREATED
+                );
+                let request = server.put("/add").json(&AddPayload {
+                    key,
+                    value,
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let response = server.get("/small/list").await;
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"a": "x", "b": "y"})
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_adds_respect_quota() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.quotas = Arc::new(HashMap::from([(
+                "small".to_string(),
+                Quota {
+                    max_entries: Some(3),
+                    max_bytes: None,
+                },
+            )]));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let responses = futures::future::join_all((0..20).map(|i| {
+                let request = server.put("/small/add").json(&AddPayload {
+                    key: i.to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                });
+                std::future::IntoFuture::into_future(request)
+            }))
+            .await;
+            let created = responses
+                .iter()
+                .filter(|response| response.status_code() == StatusCode::CREATED)
+                .count();
+            assert_eq!(created, 3);
+            let response = server.get("/small/list").await;
+            assert_eq!(response.json::<Value>().as_object().unwrap().len(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn every_write_respects_quota() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.quotas = Arc::new(HashMap::from([(
+                "small".to_string(),
+                Quota {
+                    max_entries: Some(1),
+                    max_bytes: Some(10),
+                },
+            )]));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/small/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "12345".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            // Each of them would add a second entry
+            let requests = [
+                server.put("/small/bulk/add").json(&[AddPayload {
+                    key: "b".to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                }]),
+                server
+                    .put("/small/add-if-absent")
+                    .json(&AddIfAbsentPayload {
+                        key: "b".to_string(),
+                        value: "x".to_string(),
+                    }),
+                server
+                    .post("/small/incr")
+                    .json(&serde_json::json!({"key": "b"})),
+                server.post("/small/txn").json(&serde_json::json!({
+                    "writes": [{"key": "b", "value": "x"}],
+                })),
+                server
+                    .post("/small/restore")
+                    .bytes(r#"{"key":"b","value":"x"}"#.into()),
+                server
+                    .get("/small/get")
+                    .add_query_param("default_and_set", "x")
+                    .json(&GetPayload {
+                        key: "b".to_string(),
+                    }),
+                server.post("/small/alias").json(&AliasPayload {
+                    alias: "b".to_string(),
+                    target: "a".to_string(),
+                }),
+                // And these would exceed max_bytes
+                server.post("/small/append").json(&AppendPayload {
+                    key: "a".to_string(),
+                    value: "678901".to_string(),
+                }),
+                server.patch("/small/merge").json(&MergePayload {
+                    key: "a".to_string(),
+                    patch: serde_json::json!({"some": "object"}),
+                }),
+                server.patch("/small/cas").json(&CasPayload {
+                    key: "a".to_string(),
+                    expected: "12345".to_string(),
+                    new: "12345678901".to_string(),
+                }),
+            ];
+            for request in requests {
+                let response = request.await;
+                assert_eq!(response.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+            }
+            let response = server.get("/small/list").await;
+            assert_eq!(response.text(), r#"{"a":"12345"}"#);
+
+            // Replacing the entry within the quota is fine
+            let request = server.post("/small/txn").json(&serde_json::json!({
+                "writes": [{"key": "a"}, {"key": "b", "value": "1234567890"}],
+            }));
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            let response = server.get("/small/list").await;
+            assert_eq!(response.text(), r#"{"b":"1234567890"}"#);
+        }
+    }
+
+    #[tokio::test]
+    async fn add_if_absent() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add-if-absent").json(&AddIfAbsentPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::CREATED);
+            assert_eq!(response.header(header::LOCATION), "/get/some%20key");
+
+            let request = server.put("/add-if-absent").json(&AddIfAbsentPayload {
+                key: "some key".to_string(),
+                value: "another value".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CONFLICT);
+            let response = server.get("/list").await;
+            assert_eq!(response.text(), r#"{"some key":"a value"}"#);
+
+            // An expired entry counts as absent
+            let request = server.put("/add").json(&AddPayload {
+                key: "other key".to_string(),
+                value: "old value".to_string(),
+                ttl_seconds: Some(0),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.put("/add-if-absent").json(&AddIfAbsentPayload {
+                key: "other key".to_string(),
+                value: "new value".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let response = server.get("/get/other%20key").await;
+            assert_eq!(response.text(), "new value");
+        }
+    }
+
+    #[tokio::test]
+    async fn version() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server.get("/version").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let version = response.json::<Value>();
+            assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+            assert!(!version["commit"].as_str().unwrap().is_empty());
+            assert!(version["built_at"].as_u64().unwrap() > 0);
+            // Not namespaced
+            let response = server.get("/ns/version").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn trailing_slash() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add/").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.put("/add").json(&AddPayload {
+                key: "dir/".to_string(),
+                value: "another value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            for path in ["/list", "/list/", "/list//"] {
+                let response = server.get(path).await;
+                assert_eq!(response.status_code(), StatusCode::OK);
+                assert_eq!(
+                    response.json::<Value>(),
+                    serde_json::json!({"some key": "a value", "dir/": "another value"})
+                );
+            }
+            // The query is kept
+            let response = server
+                .get("/list/")
+                .add_query_param("with_sizes", true)
+                .await;
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"some key": {"size": 7}, "dir/": {"size": 13}})
+            );
+            // The slash is a part of the key
+            let response = server.get("/get/dir/").await;
+            assert_eq!(response.text(), "another value");
+            let response = server.get("/get/dir").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            // Routes are case-sensitive by default
+            let response = server.get("/List").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn case_insensitive_routes() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.case_insensitive_routes = true;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/Add").json(&AddPayload {
+                key: "Some Key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.put("/NS/ADD/").json(&AddPayload {
+                key: "Some Key".to_string(),
+                value: "another value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/LIST/").await;
+            assert_eq!(response.text(), r#"{"Some Key":"a value"}"#);
+            // Only the routes are case-insensitive
+            let response = server.get("/GET/Some%20Key").await;
+            assert_eq!(response.text(), "a value");
+            let response = server.get("/get/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            let response = server.get("/NS/List").await;
+            assert_eq!(response.text(), r#"{"Some Key":"another value"}"#);
+            let response = server.get("/ns/list").await;
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_requests() {
+        let request_limiter = Arc::new(Semaphore::new(2));
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.request_limiter = Some(request_limiter.clone());
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            // Pretend there are two requests in progress
+            let permits = request_limiter.try_acquire_many(2).unwrap();
+            let response = server.get("/get/key").await;
+            assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(
+                response.json::<serde_json::Value>(),
+                serde_json::json!({"error": "too many requests in progress"})
+            );
+
+            drop(permits);
+            let response = server.get("/get/key").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            assert_eq!(request_limiter.available_permits(), 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn key_with_nul_is_rejected() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some\0key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "some\0key".to_string(),
+                value: "a value".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+
+            let request = server.get("/get").json(&GetPayload {
+                key: "\0".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "\0".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+
+            let request = server.post("/txn").json(&TxnPayload {
+                watch: vec![],
+                writes: vec![TxnWrite {
+                    key: "some\0key".to_string(),
+                    value: Some("a value".to_string()),
+                }],
+            });
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn path_like_keys_stay_inside_the_cache() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache_dir = tmp_dir.to_path_buf().join("cache");
+        tokio::fs::create_dir(&cache_dir).await.unwrap();
+        let server = TestServer::new(app(AppState::new(Box::new(DiskCache::new(
+            cache_dir.clone(),
+        )))))
+        .unwrap();
+
+        for key in ["../escaped", "/etc/passwd", ".", "..", "a/../../b"] {
+            let request = server.put("/add").json(&AddPayload {
+                key: key.to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.get("/get").json(&GetPayload {
+                key: key.to_string(),
+            });
+            assert_eq!(request.await.text(), "a value");
+        }
+        assert!(
+            !tokio::fs::try_exists(tmp_dir.to_path_buf().join("escaped"))
+                .await
+                .unwrap()
+        );
+        let mut entries = tokio::fs::read_dir(tmp_dir.as_ref()).await.unwrap();
+        assert_eq!(
+            entries.next_entry().await.unwrap().unwrap().file_name(),
+            "cache"
+        );
+        assert!(entries.next_entry().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn static_token_auth() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.authenticator = Some(Arc::new(StaticTokenAuth::new("secret")));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let add = || {
+                server.put("/add").json(&AddPayload {
+                    key: "a".to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                })
+            };
+
+            let response = add().await;
+            assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.header(header::WWW_AUTHENTICATE), "Bearer");
+
+            let response = add()
+                .add_header(header::AUTHORIZATION, "Bearer wrong".parse().unwrap())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+
+            let response = add()
+                .add_header(header::AUTHORIZATION, "Bearer secret".parse().unwrap())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::CREATED);
+
+            let response = server.delete("/delete/a").await;
+            assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+
+            // Reads are open by default
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let response = server.get("/get/a").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            // Except for the ones that write
+            let response = server
+                .get("/get/b")
+                .add_query_param("default_and_set", "y")
+                .await;
+            assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+            assert_eq!(server.get("/list").await.text(), r#"{"a":"x"}"#);
+            let response = server
+                .get("/get/b")
+                .add_query_param("default_and_set", "y")
+                .add_header(header::AUTHORIZATION, "Bearer secret".parse().unwrap())
+                .await;
+            assert_eq!(response.text(), "y");
+        }
+
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.authenticator = Some(Arc::new(StaticTokenAuth::new("secret")));
+            app_state.require_auth_for_reads = true;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+
+            let response = server
+                .get("/list")
+                .add_header(header::AUTHORIZATION, "Bearer secret".parse().unwrap())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+        }
+    }
+
+    fn jwks_server() -> TestServer {
+        use base64::Engine;
+        let jwks = serde_json::json!({
+            "keys": [{
+                "kty": "oct",
+                "kid": "key1",
+                "alg": "HS256",
+                "k": base64::engine::general_purpose::URL_SAFE_NO_PAD.encode("jwt secret"),
+            }]
+        });
+        TestServer::new(
+            Router::new()
+                .route(
+                    "/jwks",
+                    routing::get(move || async move { response::Json(jwks) }),
+                )
+                .into_make_service(),
+        )
+        .unwrap()
+    }
+
+    fn jwt(kid: &str, secret: &str, sub: &str, exp_offset: i64) -> String {
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256);
+        header.kid = Some(kid.to_string());
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        jsonwebtoken::encode(
+            &header,
+            &serde_json::json!({"sub": sub, "exp": now + exp_offset}),
+            &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn jwt_auth() {
+        let jwks = jwks_server();
+        let jwks_url = format!("{}jwks", jwks.server_address());
+        let auth = JwtAuth::new(jwks_url.clone(), None);
+        let headers = |token: String| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::AUTHORIZATION,
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+            headers
+        };
+
+        assert_eq!(
+            auth.authenticate(&headers(jwt("key1", "jwt secret", "alice", 60)))
+                .await
+                .unwrap(),
+            Identity {
+                name: "alice".to_string()
+            }
+        );
+        for token in [
+            jwt("key1", "other secret", "alice", 60),
+            jwt("key1", "jwt secret", "alice", -3600), // expired
+            jwt("key2", "jwt secret", "alice", 60),
+            "garbage".to_string(),
+        ] {
+            assert!(auth.authenticate(&headers(token)).await.is_err());
+        }
+        assert!(auth.authenticate(&HeaderMap::new()).await.is_err());
+
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.authenticator = Some(Arc::new(JwtAuth::new(jwks_url.clone(), None)));
+            app_state.require_auth_for_reads = true;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::UNAUTHORIZED);
+
+            let response = server
+                .get("/list")
+                .add_header(
+                    header::AUTHORIZATION,
+                    format!("Bearer {}", jwt("key1", "jwt secret", "bob", 60))
+                        .parse()
+                        .unwrap(),
+                )
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn cors() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.cors_allow_origins = vec![HeaderValue::from_static("https://example.com")];
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server
+                .get("/list")
+                .add_header(
+                    header::ORIGIN,
+                    HeaderValue::from_static("https://example.com"),
+                )
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                "https://example.com"
+            );
+            let response = server
+                .get("/list")
+                .add_header(
+                    header::ORIGIN,
+                    HeaderValue::from_static("https://other.com"),
+                )
+                .await;
+            assert!(!response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+            let response = server
+                .method(Method::OPTIONS, "/add")
+                .add_header(
+                    header::ORIGIN,
+                    HeaderValue::from_static("https://example.com"),
+                )
+                .add_header(
+                    header::ACCESS_CONTROL_REQUEST_METHOD,
+                    HeaderValue::from_static("PUT"),
+                )
+                .add_header(
+                    header::ACCESS_CONTROL_REQUEST_HEADERS,
+                    HeaderValue::from_static("content-type"),
+                )
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.header(header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                "https://example.com"
+            );
+            let allowed_methods = response.header(header::ACCESS_CONTROL_ALLOW_METHODS);
+            assert!(allowed_methods.to_str().unwrap().contains("PUT"));
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_control() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.default_cache_control = Some(HeaderValue::from_static("max-age=60"));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::CREATED);
+            assert_eq!(response.header(header::CACHE_CONTROL), "no-store");
+
+            for path in ["/get/a", "/list", "/ns/list"] {
+                let response = server.get(path).await;
+                assert_eq!(response.header(header::CACHE_CONTROL), "max-age=60");
+            }
+            let response = server
+                .get("/get/a")
+                .add_query_param("cache_control", "private, max-age=5")
+                .await;
+            assert_eq!(response.text(), "x");
+            assert_eq!(response.header(header::CACHE_CONTROL), "private, max-age=5");
+            let response = server
+                .get("/get/a")
+                .add_query_param("cache_control", "max-age=5\u{1}")
+                .await;
+            assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+            assert_eq!(response.json::<Value>()["error"], "invalid cache_control");
+            let response = server.get("/get/b").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            assert!(!response.headers().contains_key(header::CACHE_CONTROL));
+            // Writes
+            let response = server
+                .get("/get/b")
+                .add_query_param("default_and_set", "y")
+                .await;
+            assert_eq!(response.text(), "y");
+            assert_eq!(response.header(header::CACHE_CONTROL), "no-store");
+
+            let response = server.delete("/delete/a").await;
+            assert_eq!(response.header(header::CACHE_CONTROL), "no-store");
+        }
+    }
+
+    #[tokio::test]
+    async fn response_headers() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.response_headers = vec![
+                parse_response_header("X-Content-Type-Options: nosniff").unwrap(),
+                parse_response_header("X-Custom:some value").unwrap(),
+            ];
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            for response in [
+                server.get("/list").await,
+                server
+                    .get("/get")
+                    .json(&GetPayload {
+                        key: "missing".to_string(),
+                    })
+                    .await,
+            ] {
+                assert_eq!(response.header("x-content-type-options"), "nosniff");
+                assert_eq!(response.header("x-custom"), "some value");
+            }
+        }
+
+        assert!(parse_response_header("X-Content-Type-Options").is_err());
+        assert!(parse_response_header("Bad Name: value").is_err());
+        assert!(parse_response_header("X-Custom: bad\nvalue").is_err());
+    }
+
+    #[tokio::test]
+    async fn too_long_key_is_rejected() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.max_key_bytes = Some(4);
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "abcd".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "abcde".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            let request = server.get("/get").json(&GetPayload {
+                key: "abcde".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            let request = server.post("/txn").json(&TxnPayload {
+                watch: vec![],
+                writes: vec![TxnWrite {
+                    key: "abcde".to_string(),
+                    value: None,
+                }],
+            });
+            assert_eq!(request.await.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            let response = server.get("/list").await;
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"abcd": "a value"})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn list_with_truncated_values() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            for (key, value) in [("big", "x".repeat(10_000)), ("small", "abc".to_string())] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value,
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            // Multi-byte characters are not cut in half
+            let request = server.put("/add").json(&AddPayload {
+                key: "utf8".to_string(),
+                value: "ąąą".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server
+                .get("/list")
+                .add_query_param("max_value_bytes", 5)
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({
+                    "big": {"value": "xxxxx", "truncated": true},
+                    "small": {"value": "abc", "truncated": false},
+                    "utf8": {"value": "ąą", "truncated": true},
+                })
+            );
+
+            let response = server
+                .get("/list")
+                .add_query_param("max_value_bytes", 3)
+                .add_query_param("with_sizes", true)
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({
+                    "big": {"value": "xxx", "truncated": true, "size": 10_000},
+                    "small": {"value": "abc", "truncated": false, "size": 3},
+                    "utf8": {"value": "ą", "truncated": true, "size": 6},
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn counters() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            for key in ["a", "missing"] {
+                server
+                    .get("/get")
+                    .json(&GetPayload {
+                        key: key.to_string(),
+                    })
+                    .await;
+            }
+            server.get("/no-such-route").await;
+
+            let response = server.get("/counters").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let counters = response.json::<Value>();
+            assert_eq!(
+                counters["/add"],
+                serde_json::json!({"requests": 1, "client_errors": 0, "server_errors": 0})
+            );
+            assert_eq!(
+                counters["/get"],
+                serde_json::json!({"requests": 2, "client_errors": 1, "server_errors": 0})
+            );
+            assert_eq!(counters["/list"]["requests"], 0);
+            assert!(counters.get("/no-such-route").is_none());
+
+            let response = server.post("/counters/reset").await;
+            assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+
+            let counters = server.get("/counters").await.json::<Value>();
+            assert_eq!(counters["/add"]["requests"], 0);
+            assert_eq!(counters["/get"]["requests"], 0);
+            assert_eq!(counters["/get"]["client_errors"], 0);
+            // The reset itself finished after the counters were zeroed
+            assert_eq!(counters["/counters/reset"]["requests"], 1);
+        }
+    }
+
+    async fn add_alias(server: &TestServer, alias: &str, target: &str) -> StatusCode {
+        let request = server.post("/alias").json(&AliasPayload {
+            alias: alias.to_string(),
+            target: target.to_string(),
+        });
+        request.await.status_code()
+    }
+
+    async fn get_value(server: &TestServer, key: &str) -> Option<String> {
+        let response = server
+            .get("/get")
+            .json(&GetPayload {
+                key: key.to_string(),
+            })
+            .await;
+        match response.status_code() {
+            StatusCode::OK => Some(response.text()),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn alias_resolution() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "target".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            assert_eq!(add_alias(&server, "a", "target").await, StatusCode::CREATED);
+            assert_eq!(add_alias(&server, "b", "a").await, StatusCode::CREATED);
+            assert_eq!(get_value(&server, "a").await.as_deref(), Some("x"));
+            assert_eq!(get_value(&server, "b").await.as_deref(), Some("x"));
+
+            // Modifications of the target are visible through the aliases
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "target".to_string(),
+                value: "y".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            assert_eq!(get_value(&server, "b").await.as_deref(), Some("y"));
+            assert_eq!(
+                server.get("/list").await.json::<Value>(),
+                serde_json::json!({"target": "y", "a": "y", "b": "y"})
+            );
+
+            // Writing to an alias replaces the alias, not the target
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "a".to_string(),
+                value: "z".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            assert_eq!(get_value(&server, "a").await.as_deref(), Some("z"));
+            assert_eq!(get_value(&server, "b").await.as_deref(), Some("z"));
+            assert_eq!(get_value(&server, "target").await.as_deref(), Some("y"));
+
+            assert_eq!(
+                add_alias(&server, "c", "missing").await,
+                StatusCode::NOT_FOUND
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn alias_cycles_are_rejected() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "target".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            assert_eq!(add_alias(&server, "a", "target").await, StatusCode::CREATED);
+            assert_eq!(add_alias(&server, "b", "a").await, StatusCode::CREATED);
+
+            assert_eq!(add_alias(&server, "a", "a").await, StatusCode::CONFLICT);
+            assert_eq!(add_alias(&server, "a", "b").await, StatusCode::CONFLICT);
+            assert_eq!(
+                add_alias(&server, "target", "b").await,
+                StatusCode::CONFLICT
+            );
+            assert_eq!(get_value(&server, "b").await.as_deref(), Some("x"));
+        }
+    }
+
+    #[tokio::test]
+    async fn deleting_alias_target() {
+        for refuse_delete_aliased in [false, true] {
+            let apps = Apps::new_with(|mut app_state| {
+                app_state.refuse_delete_aliased = refuse_delete_aliased;
+                app(app_state)
+            });
+            for app in apps.await.apps {
+                let server = TestServer::new(app).unwrap();
+
+                let request = server.put("/add").json(&AddPayload {
+                    key: "target".to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+                assert_eq!(add_alias(&server, "a", "target").await, StatusCode::CREATED);
+
+                let request = server.delete("/delete").json(&DeletePayload {
+                    key: "target".to_string(),
+                });
+                if refuse_delete_aliased {
+                    assert_eq!(request.await.status_code(), StatusCode::CONFLICT);
+                    assert_eq!(get_value(&server, "a").await.as_deref(), Some("x"));
+
+                    // Once the alias is gone, the target can be deleted
+                    let request = server.delete("/delete").json(&DeletePayload {
+                        key: "a".to_string(),
+                    });
+                    assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+                    let request = server.delete("/delete").json(&DeletePayload {
+                        key: "target".to_string(),
+                    });
+                    assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+                } else {
+                    assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+                    assert_eq!(get_value(&server, "a").await, None);
+                    assert_eq!(server.get("/list").await.text(), "{}");
+
+                    // Recreating the target revives the alias
+                    let request = server.put("/add").json(&AddPayload {
+                        key: "target".to_string(),
+                        value: "y".to_string(),
+                        ..Default::default()
+                    });
+                    assert_eq!(request.await.status_code(), StatusCode::CREATED);
+                    assert_eq!(get_value(&server, "a").await.as_deref(), Some("y"));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_absent() {
+        let apps = Apps::new().await;
+        let servers = apps.apps.map(|app| TestServer::new(app).unwrap());
+        for server in &servers {
+            for (key, ttl_seconds) in [("short", Some(1)), ("long", Some(3600)), ("forever", None)]
+            {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ttl_seconds,
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            assert_eq!(get_value(server, "short").await.as_deref(), Some("x"));
+            // Modification keeps the expiration time
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "short".to_string(),
+                value: "y".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+        }
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        for server in &servers {
+            assert_eq!(get_value(server, "short").await, None);
+            assert_eq!(
+                server.get("/list").await.json::<Value>(),
+                serde_json::json!({"long": "x", "forever": "x"})
+            );
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "short".to_string(),
+                value: "z".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+            let request = server.delete("/delete").json(&DeletePayload {
+                key: "short".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+
+            // The key can be reused
+            let request = server.put("/add").json(&AddPayload {
+                key: "short".to_string(),
+                value: "new".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            assert_eq!(get_value(server, "short").await.as_deref(), Some("new"));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_writers() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let url = format!("{}add", server.server_address());
+
+            let client = reqwest::Client::new();
+            let mut writers = tokio::task::JoinSet::new();
+            for i in 0..100 {
+                let request = client.put(&url).json(&AddPayload {
+                    key: format!("key {}", i),
+                    value: format!("value {}", i),
+                    ..Default::default()
+                });
+                writers.spawn(async move { request.send().await.unwrap().status() });
+            }
+            while let Some(status) = writers.join_next().await {
+                assert_eq!(status.unwrap(), StatusCode::CREATED);
+            }
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let list = response.json::<Value>();
+            assert_eq!(list.as_object().unwrap().len(), 100);
+            assert_eq!(list["key 42"], "value 42");
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_files() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        tokio::fs::write(cache.key_to_path("broken"), "not an entry")
+            .await
+            .unwrap();
+        let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+
+        let request = server.put("/add").json(&AddPayload {
+            key: "some key".to_string(),
+            value: "a value".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+        // The listing skips the malformed file
+        let response = server.get("/list").await;
+        assert_eq!(response.status_code(), StatusCode::OK);
+        assert_eq!(response.text(), r#"{"some key":"a value"}"#);
+
+        let response = server
+            .get("/get")
+            .json(&GetPayload {
+                key: "broken".to_string(),
+            })
+            .await;
+        assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        let error = response.json::<Value>()["error"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(error.ends_with("is malformed"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn binary_values() {
+        let value = b"\0binary\xff\xfe\0value";
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server
+                .put("/add")
+                .add_query_param("key", "some key")
+                .content_type("application/octet-stream")
+                .bytes(Bytes::from_static(value));
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server
+                .get("/get")
+                .json(&GetPayload {
+                    key: "some key".to_string(),
+                })
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.as_bytes().as_ref(), value);
+
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"some key": {"base64": "AGJpbmFyef/+AHZhbHVl"}})
+            );
+
+            // Raw values need the key
+            let request = server
+                .put("/add")
+                .content_type("application/octet-stream")
+                .bytes(Bytes::from_static(value));
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn list_paged() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for key in ["user:3", "user:1", "session:1", "user:2", "user:10"] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: format!("value of {}", key),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+
+            let list = |query: &'static [(&'static str, &'static str)]| {
+                let mut request = server.get("/list");
+                for (name, value) in query {
+                    request = request.add_query_param(name, value);
+                }
+                async move {
+                    let response = request.await;
+                    assert_eq!(response.status_code(), StatusCode::OK);
+                    response.json::<Value>()
+                }
+            };
+            assert_eq!(
+                list(&[("prefix", "user:"), ("limit", "2")]).await,
+                serde_json::json!({
+                    "entries": {"user:1": "value of user:1", "user:10": "value of user:10"},
+                    "total": 4,
+                    "next_offset": 2,
+                })
+            );
+            assert_eq!(
+                list(&[("prefix", "user:"), ("limit", "2"), ("offset", "2")]).await,
+                serde_json::json!({
+                    "entries": {"user:2": "value of user:2", "user:3": "value of user:3"},
+                    "total": 4,
+                })
+            );
+            assert_eq!(
+                list(&[("prefix", "user:"), ("offset", "4")]).await,
+                serde_json::json!({"entries": {}, "total": 4})
+            );
+            assert_eq!(
+                list(&[("offset", "4")]).await,
+                serde_json::json!({"entries": {"user:3": "value of user:3"}, "total": 5})
+            );
+            assert_eq!(
+                list(&[("prefix", "session:"), ("with_sizes", "true")]).await,
+                serde_json::json!({"entries": {"session:1": {"size": 18}}, "total": 1})
+            );
+            assert_eq!(
+                list(&[("prefix", "nobody:")]).await,
+                serde_json::json!({"entries": {}, "total": 0})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn key_in_path() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for key in ["some key", "dir/file%"] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: format!("value of {}", key),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+
+            let response = server.get("/get/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "value of some key");
+            // The slash may be encoded or not
+            for path in ["/get/dir/file%25", "/get/dir%2Ffile%25"] {
+                let response = server.get(path).await;
+                assert_eq!(response.status_code(), StatusCode::OK, "{}", path);
+                assert_eq!(response.text(), "value of dir/file%");
+            }
+            let response = server.get("/get/nonexistent").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            let response = server
+                .get("/get/nonexistent")
+                .add_query_param("default", "x")
+                .await;
+            assert_eq!(response.text(), "x");
+
+            let response = server.delete("/delete/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+            let response = server.delete("/delete/some%20key").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            assert_eq!(get_value(&server, "some key").await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn cas() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let cas = |key: &str, expected: &str, new: &str| {
+                server.patch("/cas").json(&CasPayload {
+                    key: key.to_string(),
+                    expected: expected.to_string(),
+                    new: new.to_string(),
+                })
+            };
+            let response = cas("some key", "a value", "new value").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                get_value(&server, "some key").await,
+                Some("new value".to_string())
+            );
+
+            let response = cas("some key", "a value", "other value").await;
+            assert_eq!(response.status_code(), StatusCode::CONFLICT);
+            assert_eq!(
+                get_value(&server, "some key").await,
+                Some("new value".to_string())
+            );
+
+            let response = cas("other key", "a value", "other value").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            assert_eq!(get_value(&server, "other key").await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let entries = ["a", "b", "c"].map(|key| AddPayload {
+                key: key.to_string(),
+                value: format!("value of {}", key),
+                ..Default::default()
+            });
+            let request = server.put("/bulk/add").json(&entries);
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server
+                .post("/bulk/get")
+                .json(&["a", "b", "c", "missing"])
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"a": "value of a", "b": "value of b", "c": "value of c"})
+            );
+
+            let response = server
+                .delete("/bulk/delete")
+                .json(&["a", "c", "missing"])
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"deleted": 2, "missing": 1})
+            );
+            assert_eq!(
+                server.get("/list").await.json::<Value>(),
+                serde_json::json!({"b": "value of b"})
+            );
+
+            // Nothing is added when any of the entries is invalid
+            let entries =
+                [("d", None), ("e", Some(u64::MAX))].map(|(key, ttl_seconds)| AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ttl_seconds,
+                    ..Default::default()
+                });
+            let request = server.put("/bulk/add").json(&entries);
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                server.get("/list").await.json::<Value>(),
+                serde_json::json!({"b": "value of b"})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            for key in ["a", "missing", "a", "missing", "missing"] {
+                server
+                    .get("/get")
+                    .json(&GetPayload {
+                        key: key.to_string(),
+                    })
+                    .await;
+            }
+
+            let response = server.get("/metrics").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let metrics = response.text();
+            let lines = metrics.lines().collect::<Vec<_>>();
+            assert!(lines.contains(&"rest_server_get_hits_total 2"));
+            assert!(lines.contains(&"rest_server_get_misses_total 3"));
+            assert!(lines.contains(&"rest_server_entries 1"));
+            assert!(lines.contains(&"rest_server_requests_total{route=\"/get\"} 5"));
+            assert!(lines.contains(&"rest_server_requests_total{route=\"/add\"} 1"));
+            assert!(lines.contains(&"# TYPE rest_server_entries gauge"));
+        }
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            app(AppState::new(Box::new(MemCache::new()))),
+            async {
+                shutdown_rx.await.unwrap();
+            },
+        ));
+
+        let response = reqwest::Client::new()
+            .put(format!("http://{}/add", address))
+            .json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            })
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        shutdown.send(()).unwrap();
+        server.await.unwrap();
+        assert!(tokio::net::TcpStream::connect(address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn too_large_value_is_rejected() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.max_value_bytes = Some(4);
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            for (value, status) in [
+                ("12345", StatusCode::PAYLOAD_TOO_LARGE),
+                ("1234", StatusCode::CREATED),
+            ] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: "a".to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), status);
+            }
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "a".to_string(),
+                value: "12345".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+
+            let response = server.get("/get/a").await;
+            assert_eq!(response.text(), "1234");
+        }
+    }
+
+    #[tokio::test]
+    async fn value_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}},
+            "required": ["name"],
+        });
+        let schema = Arc::new(jsonschema::JSONSchema::compile(&schema).unwrap());
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.value_schema = Some(schema.clone());
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            for (value, status) in [
+                ("{\"name\": 42}", StatusCode::UNPROCESSABLE_ENTITY),
+                ("{}", StatusCode::UNPROCESSABLE_ENTITY),
+                ("not json", StatusCode::UNPROCESSABLE_ENTITY),
+                ("{\"name\": \"x\"}", StatusCode::CREATED),
+            ] {
+                let response = server
+                    .put("/add")
+                    .json(&AddPayload {
+                        key: "a".to_string(),
+                        value: value.to_string(),
+                        ..Default::default()
+                    })
+                    .await;
+                assert_eq!(response.status_code(), status);
+                if status == StatusCode::UNPROCESSABLE_ENTITY {
+                    let errors = &response.json::<Value>()["errors"];
+                    assert!(!errors.as_array().unwrap().is_empty());
+                }
+            }
+            let response = server
+                .patch("/modify")
+                .json(&ModifyPayload {
+                    key: "a".to_string(),
+                    value: "{\"name\": null}".to_string(),
+                })
+                .await;
+            assert_eq!(response.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(
+                response.json::<Value>()["errors"][0].as_str().unwrap(),
+                "/name: null is not of type \"string\""
+            );
+
+            let response = server.get("/get/a").await;
+            assert_eq!(response.text(), "{\"name\": \"x\"}");
+        }
+    }
+
+    #[tokio::test]
+    async fn merge() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let merge = |key: &str, patch: Value| {
+                server.patch("/merge").json(&MergePayload {
+                    key: key.to_string(),
+                    patch,
+                })
+            };
+            assert_eq!(
+                merge("a", serde_json::json!({})).await.status_code(),
+                StatusCode::NOT_FOUND
+            );
+
+            for (key, value) in [
+                (
+                    "a",
+                    r#"{"name": "x", "tags": {"t1": 1, "t2": 2}, "gone": true}"#,
+                ),
+                ("b", "not json"),
+            ] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let patch = serde_json::json!({"tags": {"t1": null, "t3": 3}, "gone": null, "n": 1});
+            assert_eq!(
+                merge("a", patch).await.status_code(),
+                StatusCode::NO_CONTENT
+            );
+            assert_eq!(
+                server.get("/get/a").await.json::<Value>(),
+                serde_json::json!({"name": "x", "tags": {"t2": 2, "t3": 3}, "n": 1})
+            );
+
+            let response = merge("b", serde_json::json!({"n": 1})).await;
+            assert_eq!(response.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(server.get("/get/b").await.text(), "not json");
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.read_only = true;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+            assert_eq!(response.json::<Value>()["error"], "the server is read-only");
+            let response = server.delete("/delete/a").await;
+            assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+            let response = server.patch("/ns/modify").json(&ModifyPayload {
+                key: "a".to_string(),
+                value: "y".to_string(),
+            });
+            assert_eq!(response.await.status_code(), StatusCode::FORBIDDEN);
+
+            assert_eq!(server.get("/list").await.text(), "{}");
+        }
+
+        let cache = MemCache::new();
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        let mut app_state = AppState::new(Box::new(cache));
+        app_state.read_only = true;
+        let server = TestServer::new(app(app_state)).unwrap();
+        assert_eq!(server.get("/get/a").await.text(), "x");
+        assert_eq!(
+            server.delete("/delete/a").await.status_code(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(server.get("/get/a").await.text(), "x");
+    }
+
+    #[tokio::test]
+    async fn read_only_rejects_default_and_set() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.read_only = true;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            for path in ["/get/a", "/ns/get/a"] {
+                let response = server
+                    .get(path)
+                    .add_query_param("default_and_set", "x")
+                    .await;
+                assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+                // Only reads
+                let response = server.get(path).add_query_param("default", "x").await;
+                assert_eq!(response.status_code(), StatusCode::OK);
+            }
+            assert_eq!(server.get("/list").await.text(), "{}");
+            assert_eq!(server.get("/ns/list").await.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn add_returns_location() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for (path, key, location) in [
+                ("/add", "a/b c?ż", "/get/a%2Fb%20c%3F%C5%BC"),
+                ("/ns/add", "a", "/ns/get/a"),
+            ] {
+                let request = server.put(path).json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                });
+                let response = request.await;
+                assert_eq!(response.status_code(), StatusCode::CREATED);
+                assert_eq!(response.header(header::LOCATION), location);
+                assert_eq!(server.get(location).await.text(), "x");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let rename = |from: &str, to: &str, overwrite: bool| {
+                server.post("/rename").json(&RenamePayload {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    overwrite,
+                })
+            };
+            for (key, value) in [("a", "x"), ("c", "z")] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ttl_seconds: Some(3600),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+
+            assert_eq!(
+                rename("a", "b", false).await.status_code(),
+                StatusCode::NO_CONTENT
+            );
+            assert_eq!(
+                server.get("/get/a").await.status_code(),
+                StatusCode::NOT_FOUND
+            );
+            let response = server.get("/get/b").await;
+            assert_eq!(response.text(), "x");
+            assert!(response.headers().contains_key(TTL_HEADER));
+
+            assert_eq!(
+                rename("a", "d", false).await.status_code(),
+                StatusCode::NOT_FOUND
+            );
+            assert_eq!(
+                rename("b", "c", false).await.status_code(),
+                StatusCode::CONFLICT
+            );
+            assert_eq!(server.get("/get/c").await.text(), "z");
+            assert_eq!(
+                rename("b", "c", true).await.status_code(),
+                StatusCode::NO_CONTENT
+            );
+            assert_eq!(
+                server.get("/list").await.json::<Value>(),
+                serde_json::json!({"c": "x"})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn exists() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "a b".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let response = server.get("/exists/a%20b").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "");
+            let response = server.get("/exists/b").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            assert_eq!(response.text(), "");
+        }
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let stats = server.get("/stats").await.json::<CacheStats>();
+            assert_eq!((stats.entry_count, stats.total_value_bytes), (0, 0));
+
+            for (key, value) in [("a", "12345"), ("b", "123")] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let stats = server.get("/stats").await.json::<CacheStats>();
+            assert_eq!(stats.entry_count, 2);
+            assert_eq!(stats.total_value_bytes, 8);
+            assert_eq!(stats.largest_value_bytes, 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn seed() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let seed_path = tmp_dir.to_path_buf().join("seed.json");
+        std::fs::write(&seed_path, r#"{"a": "x", "b": "y"}"#).unwrap();
+
+        for (mode, expected_a, expected_added) in
+            [(SeedMode::Overwrite, "x", 2), (SeedMode::Skip, "old", 1)]
+        {
+            let cache = MemCache::new();
+            cache
+                .add("a".to_string(), b"old".to_vec(), None)
+                .await
+                .unwrap();
+            cache
+                .add("c".to_string(), b"z".to_vec(), None)
+                .await
+                .unwrap();
+            let added = seed_cache(&cache, &seed_path, mode).await.unwrap();
+            assert_eq!(added, expected_added);
+
+            let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+            let response = server.get("/list").await;
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"a": expected_a, "b": "y", "c": "z"})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn max_entries() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.max_entries = Some(2);
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let add = |key: &str, value: &str| {
+                server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                })
+            };
+
+            assert_eq!(add("a", "1").await.status_code(), StatusCode::CREATED);
+            assert_eq!(add("b", "1").await.status_code(), StatusCode::CREATED);
+            assert_eq!(
+                add("c", "1").await.status_code(),
+                StatusCode::INSUFFICIENT_STORAGE
+            );
+            // Updates don't need room
+            assert_eq!(add("b", "2").await.status_code(), StatusCode::CREATED);
+            assert_eq!(server.get("/get/b").await.text(), "2");
+            assert_eq!(
+                server.get("/get/c").await.status_code(),
+                StatusCode::NOT_FOUND
+            );
+
+            assert_eq!(
+                server.delete("/delete/a").await.status_code(),
+                StatusCode::NO_CONTENT
+            );
+            assert_eq!(add("c", "1").await.status_code(), StatusCode::CREATED);
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_traced() {
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+
+        struct SpanRecorder(Arc<std::sync::Mutex<Vec<String>>>);
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanRecorder {
+            fn on_new_span(
+                &self,
+                attrs: &tracing::span::Attributes<'_>,
+                _id: &tracing::span::Id,
+                _ctx: Context<'_, S>,
+            ) {
+                let span = format!("{} {:?}", attrs.metadata().name(), attrs.values());
+                self.0.lock().unwrap().push(span);
+            }
+        }
+        let spans = Arc::new(std::sync::Mutex::new(vec![]));
+        // The test runtime is single-threaded, so the server runs with this subscriber too
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(SpanRecorder(spans.clone())),
+        );
+
+        let server = TestServer::new(app(AppState::new(Box::new(MemCache::new())))).unwrap();
+        assert_eq!(server.get("/list").await.status_code(), StatusCode::OK);
+
+        // hyper has spans of its own
+        let spans = spans.lock().unwrap();
+        let requests = spans
+            .iter()
+            .filter(|span| span.starts_with("request "))
+            .collect::<Vec<_>>();
+        assert_eq!(requests.len(), 1, "{:?}", spans);
+        assert!(
+            requests[0].contains("method: GET, uri: /list"),
+            "{:?}",
+            spans
+        );
+    }
+
+    #[tokio::test]
+    async fn incr() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+
+            let response = server
+                .post("/incr")
+                .json(&serde_json::json!({"key": "n"}))
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.json::<i64>(), 1);
+            for (by, expected) in [(1, 2), (10, 12), (-20, -8)] {
+                let response = server.post("/incr").json(&IncrPayload {
+                    key: "n".to_string(),
+                    by,
+                });
+                assert_eq!(response.await.json::<i64>(), expected);
+            }
+            assert_eq!(server.get("/get/n").await.text(), "-8");
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "s".to_string(),
+                value: "abc".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let response = server.post("/incr").json(&IncrPayload {
+                key: "s".to_string(),
+                by: 1,
+            });
+            assert_eq!(
+                response.await.status_code(),
+                StatusCode::UNPROCESSABLE_ENTITY
+            );
+            assert_eq!(server.get("/get/s").await.text(), "abc");
+
+            let response = server.post("/incr").json(&IncrPayload {
+                key: "n".to_string(),
+                by: i64::MIN,
+            });
+            assert_eq!(
+                response.await.status_code(),
+                StatusCode::UNPROCESSABLE_ENTITY
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn namespaces() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let add = |path: &str, value: &str| {
+                server.put(path).json(&AddPayload {
+                    key: "k".to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                })
+            };
+
+            assert_eq!(add("/add", "0").await.status_code(), StatusCode::CREATED);
+            assert_eq!(add("/a/add", "1").await.status_code(), StatusCode::CREATED);
+            assert_eq!(add("/b/add", "2").await.status_code(), StatusCode::CREATED);
+            assert_eq!(server.get("/get/k").await.text(), "0");
+            assert_eq!(server.get("/a/get/k").await.text(), "1");
+            assert_eq!(server.get("/b/get/k").await.text(), "2");
+
+            let response = server.get("/a/list").await;
+            assert_eq!(response.json::<Value>(), serde_json::json!({"k": "1"}));
+            let response = server.get("/c/list").await;
+            assert_eq!(response.json::<Value>(), serde_json::json!({}));
+
+            let response = server.delete("/a/delete/k").await;
+            assert_eq!(response.status_code(), StatusCode::NO_CONTENT);
+            let response = server.get("/a/get/k").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            assert_eq!(server.get("/b/get/k").await.text(), "2");
+            assert_eq!(server.get("/get/k").await.text(), "0");
+
+            for path in ["/bad.name/list", "/list/list"] {
+                let response = server.get(path).await;
+                assert_eq!(response.status_code(), StatusCode::BAD_REQUEST, "{}", path);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_do_not_create_namespaces() {
+        for sqlite in [false, true] {
+            let open = |dir: &Path| -> Box<dyn Cache + Send + Sync> {
+                match sqlite {
+                    false => Box::new(DiskCache::new(dir.to_path_buf())),
+                    true => Box::new(SqliteCache::open(&dir.join("cache.db")).unwrap()),
+                }
+            };
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let namespace_path = tmp_dir.to_path_buf().join(match sqlite {
+                false => "namespaces/ns",
+                true => "cache.db.namespaces/ns.db",
+            });
+            let server = TestServer::new(app(AppState::new(open(tmp_dir.as_ref())))).unwrap();
+
+            assert_eq!(server.get("/ns/list").await.text(), "{}");
+            let response = server.get("/ns/get/k").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+            assert!(!tokio::fs::try_exists(&namespace_path).await.unwrap());
+
+            let request = server.put("/ns/add").json(&AddPayload {
+                key: "k".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            assert!(tokio::fs::try_exists(&namespace_path).await.unwrap());
+
+            // After a restart the reads find the namespace stored before
+            let server = TestServer::new(app(AppState::new(open(tmp_dir.as_ref())))).unwrap();
+            assert_eq!(server.get("/ns/get/k").await.text(), "x");
+        }
+    }
+
+    #[tokio::test]
+    async fn max_namespaces() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.namespaces.max_count = Some(2);
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let add = |path: &str| {
+                server.put(path).json(&AddPayload {
+                    key: "k".to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                })
+            };
+
+            assert_eq!(add("/a/add").await.status_code(), StatusCode::CREATED);
+            assert_eq!(add("/b/add").await.status_code(), StatusCode::CREATED);
+            let response = add("/c/add").await;
+            assert_eq!(response.status_code(), StatusCode::FORBIDDEN);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"error": "there are already 2 namespaces"})
+            );
+            // The existing ones are unaffected
+            assert_eq!(add("/a/add").await.status_code(), StatusCode::CREATED);
+            assert_eq!(add("/add").await.status_code(), StatusCode::CREATED);
+            // Reads don't count
+            assert_eq!(server.get("/c/list").await.text(), "{}");
+        }
+    }
+
+    #[tokio::test]
+    async fn etag() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let add = |value: &str| {
+                server.put("/add").json(&AddPayload {
+                    key: "k".to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                })
+            };
+
+            assert_eq!(add("x").await.status_code(), StatusCode::CREATED);
+            let response = server.get("/get/k").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let etag = response.header(header::ETAG);
+
+            let response = server
+                .get("/get/k")
+                .add_header(header::IF_NONE_MATCH, etag.clone())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response.header(header::ETAG), etag);
+            assert!(response.as_bytes().is_empty());
+
+            let response = server
+                .get("/get/k")
+                .add_header(header::IF_NONE_MATCH, "\"other\"".parse().unwrap())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+
+            assert_eq!(add("y").await.status_code(), StatusCode::CREATED);
+            let response = server
+                .get("/get/k")
+                .add_header(header::IF_NONE_MATCH, etag.clone())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "y");
+            assert_ne!(response.header(header::ETAG), etag);
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_and_restore() {
+        let fresh_apps = Apps::new().await;
+        for (app, fresh_app) in Apps::new().await.apps.into_iter().zip(fresh_apps.apps) {
+            let server = TestServer::new(app).unwrap();
+            for i in 0..10 {
+                let request = server.put("/add").json(&AddPayload {
+                    key: format!("key {}", i),
+                    value: format!("value {}", i),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let request = server
+                .put("/add")
+                .add_query_param("key", "binary")
+                .content_type("application/octet-stream")
+                .bytes(vec![0xff, 0x00].into());
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.put("/add").json(&AddPayload {
+                key: "expiring".to_string(),
+                value: "x".to_string(),
+                ttl_seconds: Some(1000),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let list = server.get("/list").await.json::<Value>();
+            let verbose_list = server
+                .get("/list")
+                .add_query_param("verbose", true)
+                .await
+                .json::<Value>();
+
+            let response = server.get("/snapshot").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.header(header::CONTENT_TYPE),
+                "application/x-ndjson"
+            );
+            let snapshot = response.as_bytes().clone();
+            assert_eq!(snapshot.split(|&b| b == b'\n').count(), 13); // with the final empty one
+
+            let fresh_server = TestServer::new(fresh_app).unwrap();
+            let response = fresh_server.post("/restore").bytes(snapshot).await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"restored": 12})
+            );
+            assert_eq!(fresh_server.get("/list").await.json::<Value>(), list);
+            // With the metadata and the expiration times
+            let response = fresh_server.get("/get/binary").await;
+            assert_eq!(
+                response.header(header::CONTENT_TYPE),
+                "application/octet-stream"
+            );
+            let response = fresh_server.get("/get/expiring").await;
+            let ttl = response.header(TTL_HEADER).to_str().unwrap().parse::<u64>();
+            assert!((990..=1000).contains(&ttl.unwrap()));
+            let restored_verbose_list = fresh_server
+                .get("/list")
+                .add_query_param("verbose", true)
+                .await
+                .json::<Value>();
+            for (key, entry) in verbose_list.as_object().unwrap() {
+                let restored_entry = &restored_verbose_list[key];
+                assert!(entry["created_at_ms"].is_u64());
+                assert_eq!(restored_entry["content_type"], entry["content_type"]);
+                assert_eq!(restored_entry["created_at_ms"], entry["created_at_ms"]);
+            }
+
+            let response = fresh_server
+                .post("/restore")
+                .bytes("{\"key\": \"a\", \"value\": \"x\"}\nnot json\n".into())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+            assert_eq!(fresh_server.get("/get/a").await.text(), "x");
+        }
+    }
+
+    #[tokio::test]
+    async fn compression() {
+        use std::io::Read;
+
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for i in 0..20 {
+                let request = server.put("/add").json(&AddPayload {
+                    key: format!("key {}", i),
+                    value: "value ".repeat(10),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let list = server.get("/list").await;
+            assert!(list.maybe_header(header::CONTENT_ENCODING).is_none());
+
+            for path in ["/list", "/get/key%201"] {
+                let expected = server.get(path).await.text();
+                let response = server
+                    .get(path)
+                    .add_header(header::ACCEPT_ENCODING, "gzip".parse().unwrap())
+                    .await;
+                assert_eq!(response.status_code(), StatusCode::OK);
+                assert_eq!(response.header(header::CONTENT_ENCODING), "gzip");
+                let mut body = String::new();
+                flate2::read::GzDecoder::new(response.as_bytes().as_ref())
+                    .read_to_string(&mut body)
+                    .unwrap();
+                if path == "/list" {
+                    // The order of the listed entries may differ
+                    assert_eq!(
+                        serde_json::from_str::<Value>(&body).unwrap(),
+                        serde_json::from_str::<Value>(&expected).unwrap()
+                    );
+                } else {
+                    assert_eq!(body, expected);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn list_many_entries() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let mut expected = serde_json::Map::new();
+            for i in 0..300 {
+                let request = server.put("/add").json(&AddPayload {
+                    key: format!("key {}", i),
+                    value: format!("value \"{}\"", i),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+                expected.insert(
+                    format!("key {}", i),
+                    Value::String(format!("value \"{}\"", i)),
+                );
+            }
+            let response = server.get("/list").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.header(header::CONTENT_TYPE), "application/json");
+            assert_eq!(response.json::<Value>(), Value::Object(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn msgpack() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for i in 0..3 {
+                let request = server.put("/add").json(&AddPayload {
+                    key: format!("key {}", i),
+                    value: format!("value {}", i),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let expected = serde_json::json!({
+                "key 0": "value 0",
+                "key 1": "value 1",
+                "key 2": "value 2",
+            });
+
+            for accept in [
+                None,
+                Some("application/json"),
+                Some("application/msgpack;q=0"),
+            ] {
+                let mut request = server.get("/list");
+                if let Some(accept) = accept {
+                    request = request.add_header(header::ACCEPT, accept.parse().unwrap());
+                }
+                let response = request.await;
+                assert_eq!(response.header(header::CONTENT_TYPE), "application/json");
+                assert_eq!(response.json::<Value>(), expected);
+            }
+
+            for accept in [
+                "application/msgpack",
+                "text/plain, application/msgpack;q=0.5",
+            ] {
+                let response = server
+                    .get("/list")
+                    .add_header(header::ACCEPT, accept.parse().unwrap())
+                    .await;
+                assert_eq!(response.status_code(), StatusCode::OK);
+                assert_eq!(response.header(header::CONTENT_TYPE), "application/msgpack");
+                let list = rmp_serde::from_slice::<Value>(response.as_bytes()).unwrap();
+                assert_eq!(list, expected);
+            }
+
+            let response = server
+                .get("/get/key%201")
+                .add_header(header::ACCEPT, "application/msgpack".parse().unwrap())
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.header(header::CONTENT_TYPE), "application/msgpack");
+            let value = rmp_serde::from_slice::<String>(response.as_bytes()).unwrap();
+            assert_eq!(value, "value 1");
+            assert_eq!(server.get("/get/key%201").await.text(), "value 1");
+        }
+    }
+
+    #[tokio::test]
+    async fn probes() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.authenticator = Some(Arc::new(StaticTokenAuth::new("secret")));
+            app_state.require_auth_for_reads = true;
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            assert_eq!(
+                server.get("/list").await.status_code(),
+                StatusCode::UNAUTHORIZED
+            );
+            for path in ["/health", "/ready"] {
+                let response = server.get(path).await;
+                assert_eq!(response.status_code(), StatusCode::OK);
+                assert_eq!(
+                    response.json::<Value>(),
+                    serde_json::json!({"status": "ok"})
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn entry_metadata() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "{}".to_string(),
+                content_type: Some("application/json".to_string()),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server
+                .put("/add")
+                .add_query_param("key", "b")
+                .add_query_param("content_type", "image/png")
+                .content_type("application/octet-stream")
+                .bytes(vec![0x89, b'P', b'N', b'G'].into());
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let request = server.put("/add").json(&AddPayload {
+                key: "c".to_string(),
+                value: "x".to_string(),
+                content_type: Some("bad\nvalue".to_string()),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::BAD_REQUEST);
+
+            let response = server.get("/get/a").await;
+            assert_eq!(response.header(header::CONTENT_TYPE), "application/json");
+            assert_eq!(response.text(), "{}");
+            let response = server.get("/get/b").await;
+            assert_eq!(response.header(header::CONTENT_TYPE), "image/png");
+            assert_eq!(response.as_bytes().as_ref(), b"\x89PNG");
+
+            let list = server
+                .get("/list")
+                .add_query_param("verbose", true)
+                .await
+                .json::<Value>();
+            let created_at_ms = list["a"]["created_at_ms"].as_u64().unwrap();
+            assert!(created_at_ms > 0);
+            assert_eq!(list["a"]["updated_at_ms"], created_at_ms);
+            assert_eq!(list["a"]["value"], "{}");
+            assert_eq!(list["a"]["content_type"], "application/json");
+            assert_eq!(list["b"]["content_type"], "image/png");
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let request = server.patch("/modify").json(&ModifyPayload {
+                key: "a".to_string(),
+                value: "[]".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            let list = server
+                .get("/list")
+                .add_query_param("verbose", true)
+                .await
+                .json::<Value>();
+            assert_eq!(list["a"]["created_at_ms"], created_at_ms);
+            assert!(list["a"]["updated_at_ms"].as_u64().unwrap() > created_at_ms);
+            assert_eq!(list["a"]["content_type"], "application/json");
+            let response = server.get("/get/a").await;
+            assert_eq!(response.header(header::CONTENT_TYPE), "application/json");
+            assert_eq!(response.text(), "[]");
+
+            let response = server
+                .get("/list")
+                .add_query_param("verbose", true)
+                .add_query_param("limit", 1)
+                .await;
+            assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.rate_limiter = Some(Arc::new(RateLimiter::new(5)));
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            // All the requests come from 127.0.0.1
+            let server = TestServer::new(app).unwrap();
+            let mut rejected = 0;
+            for _ in 0..20 {
+                let response = server.get("/get/a").await;
+                if response.status_code() == StatusCode::TOO_MANY_REQUESTS {
+                    assert_eq!(response.header(header::RETRY_AFTER), "1");
+                    rejected += 1;
+                } else {
+                    assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+                }
+            }
+            assert!(rejected >= 10);
+
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            let response = server.get("/get/a").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn append() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "log".to_string(),
+                value: "a".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            for suffix in ["b", "cd"] {
+                let request = server.post("/append").json(&AppendPayload {
+                    key: "log".to_string(),
+                    value: suffix.to_string(),
+                });
+                assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            }
+            assert_eq!(server.get("/get/log").await.text(), "abcd");
+
+            let request = server.post("/append").json(&AppendPayload {
+                key: "missing".to_string(),
+                value: "x".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+            let response = server.get("/get/missing").await;
+            assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn ttl_header() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for (key, ttl_seconds) in [("a", Some(60)), ("b", None)] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ttl_seconds,
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+
+            let response = server.get("/get/a").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            let ttl = response.header("x-cache-ttl-seconds");
+            let ttl = ttl.to_str().unwrap().parse::<u64>().unwrap();
+            assert!((55..=60).contains(&ttl), "{}", ttl);
+
+            let response = server.get("/get/b").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert!(response.maybe_header("x-cache-ttl-seconds").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn ttl_out_of_range() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let response = server
+                .put("/add")
+                .json(&AddPayload {
+                    key: "a".to_string(),
+                    value: "x".to_string(),
+                    ttl_seconds: Some(u64::MAX),
+                    ..Default::default()
+                })
+                .await;
+            assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+            assert!(response.json::<Value>()["error"].is_string());
+            assert_eq!(get_value(&server, "a").await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn unix_socket() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let path = tmp_dir.to_path_buf().join("socket");
+        // Left by a server that is gone
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = bind_unix_socket(&path).await.unwrap();
+        assert!(bind_unix_socket(&path).await.is_err());
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve_unix(
+            listener,
+            with_middleware(routes(AppState::new(Box::new(MemCache::new())))),
+            async {
+                shutdown_rx.await.unwrap();
+            },
+        ));
+
+        let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let payload = AddPayload {
+            key: "a".to_string(),
+            value: "x".to_string(),
+            ..Default::default()
+        };
+        let request = Request::put("/add")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&payload).unwrap()))
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let request = Request::get("/get/a").body(Body::empty()).unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "x");
+
+        drop(sender);
+        shutdown.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn touch() {
+        let apps = Apps::new().await;
+        let servers = apps.apps.map(|app| TestServer::new(app).unwrap());
+        let touch = |server: &TestServer, key: &str, ttl_seconds: u64| {
+            server.post("/touch").json(&TouchPayload {
+                key: key.to_string(),
+                ttl_seconds,
+            })
+        };
+        for server in &servers {
+            for key in ["touched", "untouched"] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ttl_seconds: Some(1),
+                    content_type: Some("text/plain".to_string()),
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let request = server.post("/alias").json(&AliasPayload {
+                alias: "alias".to_string(),
+                target: "touched".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            assert_eq!(
+                touch(server, "touched", 3600).await.status_code(),
+                StatusCode::NO_CONTENT
+            );
+            assert_eq!(
+                touch(server, "missing", 3600).await.status_code(),
+                StatusCode::NOT_FOUND
+            );
+            assert_eq!(
+                touch(server, "untouched", u64::MAX).await.status_code(),
+                StatusCode::BAD_REQUEST
+            );
+            // Aliases have no expiration time of their own
+            assert_eq!(
+                touch(server, "alias", 3600).await.status_code(),
+                StatusCode::NOT_FOUND
+            );
+        }
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        for server in &servers {
+            let response = server.get("/get/touched").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.text(), "x");
+            assert_eq!(response.header(header::CONTENT_TYPE), "text/plain");
+            assert_eq!(get_value(server, "alias").await.as_deref(), Some("x"));
+            assert_eq!(get_value(server, "untouched").await, None);
+            assert_eq!(
+                touch(server, "untouched", 3600).await.status_code(),
+                StatusCode::NOT_FOUND
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rpc() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.post("/rpc").json(&serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "add",
+                "params": {"key": "a", "value": "x"},
+                "id": 1,
+            }));
+            assert_eq!(
+                request.await.json::<Value>(),
+                serde_json::json!({"jsonrpc": "2.0", "result": null, "id": 1})
+            );
+            assert_eq!(get_value(&server, "a").await.as_deref(), Some("x"));
+
+            let request = server.post("/rpc").json(&serde_json::json!([
+                {"jsonrpc": "2.0", "method": "modify", "params": {"key": "a", "value": "y"}, "id": "m"},
+                // A notification
+                {"jsonrpc": "2.0", "method": "add", "params": {"key": "b", "value": "z"}},
+                {"jsonrpc": "2.0", "method": "get", "params": {"key": "a"}, "id": 2},
+                {"jsonrpc": "2.0", "method": "delete", "params": {"key": "c"}, "id": 3},
+                {"jsonrpc": "2.0", "method": "get", "params": {"value": "a"}, "id": 4},
+                {"jsonrpc": "2.0", "method": "list", "id": 5},
+                {"method": "list", "id": 6},
+            ]));
+            let response = request.await.json::<Value>();
+            assert_eq!(
+                response[0],
+                serde_json::json!({"jsonrpc": "2.0", "result": null, "id": "m"})
+            );
+            assert_eq!(
+                response[1],
+                serde_json::json!({"jsonrpc": "2.0", "result": "y", "id": 2})
+            );
+            assert_eq!(response[2]["error"]["code"], RpcError::SERVER_ERROR);
+            assert_eq!(response[2]["error"]["data"]["status"], 404);
+            assert_eq!(response[2]["id"], 3);
+            assert_eq!(response[3]["error"]["code"], RpcError::INVALID_PARAMS);
+            assert_eq!(
+                response[4],
+                serde_json::json!({"jsonrpc": "2.0", "result": {"a": "y", "b": "z"}, "id": 5})
+            );
+            assert_eq!(response[5]["error"]["code"], RpcError::INVALID_REQUEST);
+            assert_eq!(response[5]["id"], Value::Null);
+            assert_eq!(response.as_array().unwrap().len(), 6);
+
+            let request = server.post("/rpc").json(&serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "increment",
+                "id": 7,
+            }));
+            let response = request.await.json::<Value>();
+            assert_eq!(response["error"]["code"], RpcError::METHOD_NOT_FOUND);
+            assert_eq!(response["id"], 7);
+
+            let response = server.post("/rpc").text("{").await;
+            assert_eq!(
+                response.json::<Value>()["error"]["code"],
+                RpcError::PARSE_ERROR
+            );
+            let request = server.post("/rpc").json(&serde_json::json!([
+                {"jsonrpc": "2.0", "method": "delete", "params": {"key": "b"}},
+            ]));
+            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+            assert_eq!(get_value(&server, "b").await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn key_validation() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.max_key_bytes = Some(8);
+            app_state.key_pattern = Some(parse_key_pattern("[a-z:]+").unwrap());
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "user:a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            for (key, expected_status, expected_error) in [
+                (
+                    "user:abcdef",
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "key must not be longer than 8 bytes",
+                ),
+                // Only whole keys match
+                (
+                    "user:A",
+                    StatusCode::BAD_REQUEST,
+                    "key must match ^(?:[a-z:]+)$",
+                ),
+                ("", StatusCode::BAD_REQUEST, "key must not be empty"),
+            ] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                });
+                let response = request.await;
+                assert_eq!(response.status_code(), expected_status);
+                assert_eq!(response.json::<Value>()["error"], expected_error);
+
+                let request = server.patch("/modify").json(&ModifyPayload {
+                    key: key.to_string(),
+                    value: "y".to_string(),
+                });
+                let response = request.await;
+                assert_eq!(response.status_code(), expected_status);
+                assert_eq!(response.json::<Value>()["error"], expected_error);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app_state = AppState::new(Box::new(MemCache::new()));
+        let changes = app_state.changes.clone();
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app(app_state), async move {
+            shutdown_rx.await.unwrap();
+            changes.close();
+        }));
+        let client = reqwest::Client::new();
+        let add = |path: &str, key: &str| {
+            client
+                .put(format!("http://{}{}", address, path))
+                .json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                })
+                .send()
+        };
+
+        // Late subscribers don't get the past events
+        assert_eq!(
+            add("/add", "before").await.unwrap().status(),
+            StatusCode::CREATED
+        );
+        let mut subscription = client
+            .get(format!("http://{}/subscribe", address))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(subscription.status(), StatusCode::OK);
+        assert_eq!(
+            subscription.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        // Nor the ones of other namespaces
+        assert_eq!(
+            add("/ns/add", "other").await.unwrap().status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            add("/add", "after").await.unwrap().status(),
+            StatusCode::CREATED
+        );
+
+        let mut received = String::new();
+        while !received.contains("\n\n") {
+            let chunk = subscription.chunk().await.unwrap().unwrap();
+            received.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let (event, _) = received.split_once("\n\n").unwrap();
+        let data = event.strip_prefix("data:").unwrap();
+        let event = serde_json::from_str::<Value>(data).unwrap();
+        assert_eq!(event["op"], "add");
+        assert_eq!(event["key"], "after");
+        assert!(event.get("value").is_none());
+
+        // The subscription does not hold up the shutdown
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_prefix() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for key in ["user:1", "user:2", "user:3", "users", "other"] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let request = server.post("/alias").json(&AliasPayload {
+                alias: "user:alias".to_string(),
+                target: "other".to_string(),
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            let delete_prefix = |prefix: &str| {
+                server.delete("/delete-prefix").json(&DeletePrefixPayload {
+                    prefix: prefix.to_string(),
+                })
+            };
+            let response = delete_prefix("user:").await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(response.json::<Value>(), serde_json::json!({"deleted": 4}));
+            assert_eq!(
+                server.get("/list").await.json::<Value>(),
+                serde_json::json!({"users": "x", "other": "x"})
+            );
+
+            let response = delete_prefix("user:").await;
+            assert_eq!(response.json::<Value>(), serde_json::json!({"deleted": 0}));
+            assert_eq!(
+                delete_prefix("").await.status_code(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn miss_body() {
+        for (miss_body, expected_status, expected_body) in [
+            (MissBody::Empty, StatusCode::NOT_FOUND, ""),
+            (
+                MissBody::Json,
+                StatusCode::NOT_FOUND,
+                r#"{"error":"not found","key":"a/b"}"#,
+            ),
+            (
+                MissBody::Default("fallback".to_string()),
+                StatusCode::OK,
+                "fallback",
+            ),
+        ] {
+            let apps = Apps::new_with(|mut app_state| {
+                app_state.miss_body = miss_body.clone();
+                app(app_state)
+            });
+            for app in apps.await.apps {
+                let server = TestServer::new(app).unwrap();
+                for request in [
+                    server.get("/get").json(&GetPayload {
+                        key: "a/b".to_string(),
+                    }),
+                    server.get("/get/a%2Fb"),
+                ] {
+                    let response = request.await;
+                    assert_eq!(response.status_code(), expected_status);
+                    assert_eq!(response.text(), expected_body);
+                }
+                // The default query parameter takes precedence
+                let response = server.get("/get/a").add_query_param("default", "x").await;
+                assert_eq!(response.text(), "x");
+            }
+        }
+
+        assert_eq!(parse_miss_body("json"), Ok(MissBody::Json));
+        assert_eq!(
+            parse_miss_body("default:a:b"),
+            Ok(MissBody::Default("a:b".to_string()))
+        );
+        assert!(parse_miss_body("default").is_err());
+    }
+
+    #[tokio::test]
+    async fn throughput() {
+        let server = TestServer::new(app(AppState::new(Box::new(MemCache::new())))).unwrap();
+        for i in 0..10 {
+            let request = server.put("/add").json(&AddPayload {
+                key: i.to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            assert_eq!(
+                server.get(&format!("/get/{}", i)).await.status_code(),
+                StatusCode::OK
+            );
+            server.get("/get/missing").await;
+        }
+
+        let response = server.get("/throughput").await.json::<Value>();
+        // Including this request
+        assert_eq!(response["by_method"]["GET"]["requests"], 21);
+        assert_eq!(response["by_method"]["PUT"]["requests"], 10);
+        let window_seconds = response["window_seconds"].as_f64().unwrap();
+        assert!((1.0..=60.0).contains(&window_seconds));
+        assert_eq!(
+            response["requests_per_second"].as_f64().unwrap(),
+            31.0 / window_seconds
+        );
+    }
+
+    #[tokio::test]
+    async fn mget() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for (key, value) in [("a", "x"), ("b&c", "y"), ("d", "z")] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+
+            let response = server
+                .get("/mget")
+                .add_query_params([("key", "a"), ("key", "b&c"), ("key", "missing")])
+                .await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"a": "x", "b&c": "y"})
+            );
+            assert_eq!(
+                server.get("/mget").await.json::<Value>(),
+                serde_json::json!({})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_json_body() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for (path, body) in [
+                ("/add", r#"{"key": "a", "value": "#),
+                ("/add", r#"{"key": "a"}"#),
+                ("/add", r#"{"key": 1, "value": "x"}"#),
+                ("/delete", "not json"),
+                ("/delete", "{}"),
+            ] {
+                let request = match path {
+                    "/add" => server.put(path),
+                    _ => server.delete(path),
+                };
+                let response = request
+                    .bytes(Bytes::from(body))
+                    .content_type("application/json")
+                    .await;
+                assert_eq!(response.status_code(), StatusCode::BAD_REQUEST, "{}", body);
+                let error = response.json::<Value>()["error"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                assert!(!error.is_empty());
+            }
+
+            let response = server
+                .put("/add")
+                .text(r#"{"key": "a", "value": "x"}"#)
+                .await;
+            assert_eq!(response.status_code(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            assert!(response.json::<Value>()["error"].is_string());
+            assert_eq!(get_value(&server, "a").await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn error_bodies() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let request = server.put("/add").json(&AddPayload {
+                key: "a".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            });
+            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+
+            for (response, status, error) in [
+                (
+                    server
+                        .put("/add-if-absent")
+                        .json(&AddIfAbsentPayload {
+                            key: "a".to_string(),
+                            value: "y".to_string(),
+                        })
+                        .await,
+                    StatusCode::CONFLICT,
+                    "entry already exists",
+                ),
+                (
+                    server
+                        .post("/incr")
+                        .json(&IncrPayload {
+                            key: "a".to_string(),
+                            by: 1,
+                        })
+                        .await,
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "value is not an integer",
+                ),
+                (
+                    server
+                        .post("/alias")
+                        .json(&AliasPayload {
+                            alias: "a".to_string(),
+                            target: "missing".to_string(),
+                        })
+                        .await,
+                    StatusCode::NOT_FOUND,
+                    "target does not exist",
+                ),
+                (
+                    server
+                        .patch("/merge")
+                        .json(&MergePayload {
+                            key: "a".to_string(),
+                            patch: serde_json::json!({"b": 1}),
+                        })
+                        .await,
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "value is not JSON",
+                ),
+                (
+                    server
+                        .delete("/delete-prefix")
+                        .json(&DeletePrefixPayload {
+                            prefix: String::new(),
+                        })
+                        .await,
+                    StatusCode::BAD_REQUEST,
+                    "prefix must not be empty",
+                ),
+                (
+                    server
+                        .post("/rename")
+                        .json(&RenamePayload {
+                            from: "a".to_string(),
+                            to: "a".to_string(),
+                            overwrite: false,
+                        })
+                        .await,
+                    StatusCode::BAD_REQUEST,
+                    "from and to must differ",
+                ),
+                (
+                    server
+                        .patch("/cas")
+                        .json(&CasPayload {
+                            key: "a".to_string(),
+                            expected: "y".to_string(),
+                            new: "z".to_string(),
+                        })
+                        .await,
+                    StatusCode::CONFLICT,
+                    "the current value is not the expected one",
+                ),
+                (
+                    server
+                        .post("/txn")
+                        .json(&serde_json::json!({"watch": [{"key": "a", "value": "y"}]}))
+                        .await,
+                    StatusCode::CONFLICT,
+                    "the watched entry a has changed",
+                ),
+                (
+                    server
+                        .post("/add-from-url")
+                        .json(&AddFromUrlPayload {
+                            key: "b".to_string(),
+                            url: "http://example.com/value".to_string(),
+                        })
+                        .await,
+                    StatusCode::FORBIDDEN,
+                    "the host of the url is not allowed",
+                ),
+            ] {
+                assert_eq!(response.status_code(), status, "{}", error);
+                assert_eq!(
+                    response.json::<Value>(),
+                    serde_json::json!({ "error": error })
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn list_parse_json() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for (key, value) in [
+                ("a", r#"{"n": 1, "tags": ["x"]}"#),
+                ("b", "plain text"),
+                ("c", "[1, 2]"),
+                ("d", "{not json"),
+            ] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let expected = serde_json::json!({
+                "a": {"n": 1, "tags": ["x"]},
+                "b": "plain text",
+                "c": [1, 2],
+                "d": "{not json",
+            });
+            let response = server
+                .get("/list")
+                .add_query_param("parse_json", true)
+                .await;
+            assert_eq!(response.json::<Value>(), expected);
+            let response = server
+                .get("/list")
+                .add_query_params([("parse_json", "true"), ("prefix", "")])
+                .await;
+            assert_eq!(response.json::<Value>()["entries"], expected);
+            let response = server
+                .get("/list")
+                .add_query_params([("parse_json", "true"), ("verbose", "true")])
+                .await;
+            assert_eq!(response.json::<Value>()["a"]["value"], expected["a"]);
+
+            // The values are stored as they were
+            assert_eq!(server.get("/list").await.json::<Value>()["c"], "[1, 2]");
+            let response = server
+                .get("/list")
+                .add_query_params([("parse_json", "true"), ("max_value_bytes", "3")])
+                .await;
+            assert_eq!(response.status_code(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_body_bytes() {
+        let apps = Apps::new_with(|mut app_state| {
+            app_state.max_body_bytes = Some(100);
+            app(app_state)
+        });
+        for app in apps.await.apps {
+            let server = TestServer::new(app).unwrap();
+            let add = |value: &str| {
+                server.put("/add").json(&AddPayload {
+                    key: "a".to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                })
+            };
+            let response = add(&"x".repeat(100)).await;
+            assert_eq!(response.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(
+                response.json::<Value>()["error"],
+                "the request body exceeds 100 bytes"
+            );
+            assert_eq!(get_value(&server, "a").await, None);
+            assert_eq!(add("x").await.status_code(), StatusCode::CREATED);
+
+            // Only the mutations are limited
+            let keys = (0..50).map(|i| format!("k{}", i)).collect::<Vec<_>>();
+            let response = server.post("/bulk/get").json(&keys).await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            // /restore streams its body, only a record must not exceed the limit
+            let snapshot = (0..10)
+                .map(|i| format!("{{\"key\":\"b{}\",\"value\":\"{}\"}}\n", i, "y".repeat(20)))
+                .collect::<String>();
+            let response = server.post("/restore").text(snapshot).await;
+            assert_eq!(response.status_code(), StatusCode::OK);
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"restored": 10})
+            );
+            // Not even without a newline
+            let snapshot = format!("{{\"key\":\"c\",\"value\":\"{}\"}}", "y".repeat(200));
+            let response = server.post("/restore").text(snapshot).await;
+            assert_eq!(response.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+            assert_eq!(
+                response.json::<Value>()["error"],
+                "record 1 exceeds 100 bytes"
+            );
+            assert_eq!(get_value(&server, "c").await, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn flush() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for key in ["a", "b", "c"] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: "x".to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let request = server.post("/alias").json(&AliasPayload {
+                alias: "d".to_string(),
+                target: "a".to_string(),
+            });
+            assert!(request.await.status_code().is_success());
+
+            let response = server.post("/flush").await;
+            assert_eq!(response.json::<Value>(), serde_json::json!({"deleted": 4}));
+            assert_eq!(server.get("/list").await.text(), "{}");
+            assert_eq!(get_value(&server, "a").await, None);
+
+            let response = server.post("/flush").await;
+            assert_eq!(response.json::<Value>(), serde_json::json!({"deleted": 0}));
+        }
+    }
+
+    #[tokio::test]
+    async fn list_sorted() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            for key in ["c", "a", "b2", "b10", "B"] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: key.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let response = server.get("/list").add_query_param("sorted", true).await;
+            assert_eq!(
+                response.text(),
+                r#"{"B":"B","a":"a","b10":"b10","b2":"b2","c":"c"}"#
+            );
+            let response = server
+                .get("/list")
+                .add_query_params([("sorted", "true"), ("with_sizes", "true")])
+                .await;
+            let keys = response
+                .json::<Value>()
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(keys, ["B", "a", "b10", "b2", "c"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn dump_csv() {
+        for app in Apps::new().await.apps {
+            let server = TestServer::new(app).unwrap();
+            let entries = [
+                ("a", "1,5"),
+                ("b", "say \"hi\""),
+                ("c", "two\nlines"),
+                ("d", "plain"),
+            ];
+            for (key, value) in entries {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+
+            let response = server.get("/dump.csv").await;
+            assert_eq!(response.header("content-type"), "text/csv; charset=utf-8");
+            let text = response.text();
+            assert!(text.starts_with("key,value\n"));
+            assert!(text.contains("a,\"1,5\"\n"));
+            assert!(text.contains("b,\"say \"\"hi\"\"\"\n"));
+            let mut reader = csv::Reader::from_reader(text.as_bytes());
+            let mut records = reader
+                .records()
+                .map(|record| {
+                    let record = record.unwrap();
+                    (record[0].to_string(), record[1].to_string())
+                })
+                .collect::<Vec<_>>();
+            records.sort();
+            let expected = entries.map(|(key, value)| (key.to_string(), value.to_string()));
+            assert_eq!(records, expected);
+        }
+    }
+}
+
+#[cfg(test)]
+mod discovery_tests {
+    use super::*;
+    use axum_test::TestServer;
+
+    type Requests = Arc<std::sync::Mutex<Vec<(String, Value)>>>;
+
+    // Pretends to be a Consul agent, recording the received requests
+    fn consul_mock(requests: Requests) -> axum::routing::IntoMakeService<Router> {
+        async fn record(
+            State(requests): State<Requests>,
+            uri: axum::http::Uri,
+            body: String,
+        ) -> StatusCode {
+            let body = serde_json::from_str(&body).unwrap_or(Value::Null);
+            requests
+                .lock()
+                .unwrap()
+                .push((uri.path().to_string(), body));
+            StatusCode::OK
+        }
+        Router::new()
+            .fallback(record)
+            .with_state(requests)
+            .into_make_service()
+    }
+
+    #[tokio::test]
+    async fn register_heartbeat_deregister() {
+        let requests = Requests::default();
+        let consul = TestServer::new(consul_mock(requests.clone())).unwrap();
+        let discovery = Discovery::new(
+            consul.server_address().to_string(),
+            "cache".to_string(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+
+        discovery.register().await.unwrap();
+        discovery.heartbeat().await.unwrap();
+        discovery.deregister().await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(
+            requests.iter().map(|(path, _)| path).collect::<Vec<_>>(),
+            [
+                "/v1/agent/service/register",
+                "/v1/agent/check/pass/service:cache-127.0.0.1:8080",
+                "/v1/agent/service/deregister/cache-127.0.0.1:8080",
+            ]
+        );
+        assert_eq!(
+            requests[0].1,
+            serde_json::json!({
+                "ID": "cache-127.0.0.1:8080",
+                "Name": "cache",
+                "Address": "127.0.0.1",
+                "Port": 8080,
+                "Check": {"TTL": "30s"},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_registration_is_an_error() {
+        let consul = TestServer::new(
+            Router::new()
+                .fallback(|| async { StatusCode::INTERNAL_SERVER_ERROR })
+                .into_make_service(),
+        )
+        .unwrap();
+        let discovery = Discovery::new(
+            consul.server_address().to_string(),
+            "cache".to_string(),
+            "127.0.0.1:8080".parse().unwrap(),
+        );
+        assert!(discovery.register().await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod disk_cache_tests {
+    use super::*;
+    use axum_test::TestServer;
+    use tmpdir::TmpDir;
+
+    #[tokio::test]
+    async fn truncated_hash() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::with_hash_len(tmp_dir.to_path_buf(), 16);
+        cache.check_hash_len().await.unwrap();
+
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache
+            .add("b".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "x", "b": "y"})
+        );
+
+        let mut filenames = vec![];
+        let mut entries = tokio::fs::read_dir(tmp_dir.as_ref()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            filenames.push(entry.file_name().into_string().unwrap());
+        }
+        filenames.sort();
+        let mut expected = vec![
+            DiskCache::HASH_LEN_FILENAME.to_string(),
+            blake3::hash(b"a").to_hex()[..32].to_string(),
+            blake3::hash(b"b").to_hex()[..32].to_string(),
+        ];
+        expected.sort();
+        assert_eq!(filenames, expected);
+
+        // Entries with a different hash length are not part of the cache
+        let full_hash_cache = DiskCache::new(tmp_dir.to_path_buf());
+        full_hash_cache
+            .add("c".to_string(), b"z".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "x", "b": "y"})
+        );
+    }
+
+    #[tokio::test]
+    async fn colliding_keys_fail() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::with_hash_len(tmp_dir.to_path_buf(), 16);
+        cache.check_hash_len().await.unwrap();
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        // As if the truncated hashes of a and b were the same
+        tokio::fs::rename(cache.key_to_path("a"), cache.key_to_path("b"))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            cache.get("b").await,
+            Err(CacheError::KeyCollision(_))
+        ));
+        assert!(matches!(
+            cache.add("b".to_string(), b"y".to_vec(), None).await,
+            Err(CacheError::KeyCollision(_))
+        ));
+        let stored = tokio::fs::read_to_string(cache.key_to_path("b"))
+            .await
+            .unwrap();
+        assert!(stored.contains(r#""key":"a""#));
+    }
+
+    #[tokio::test]
+    async fn mixing_hash_lengths_is_rejected() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        // Directory populated before the hash length was recorded
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+
+        let truncated = DiskCache::with_hash_len(tmp_dir.to_path_buf(), 16);
+        assert!(truncated.check_hash_len().await.is_err());
+        cache.check_hash_len().await.unwrap();
+
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let truncated = DiskCache::with_hash_len(tmp_dir.to_path_buf(), 16);
+        truncated.check_hash_len().await.unwrap();
+        truncated.check_hash_len().await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        assert!(cache.check_hash_len().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_writes() {
+        for (verify_writes, fault, expected_status) in [
+            (true, None, StatusCode::CREATED),
+            (
+                true,
+                Some(DiskFault::CorruptWrites),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            // The corruption goes unnoticed
+            (false, Some(DiskFault::CorruptWrites), StatusCode::CREATED),
+        ] {
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+            cache.verify_writes = verify_writes;
+            cache.fault = fault;
+            let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+
+            let request = server.put("/add").json(&AddPayload {
+                key: "some key".to_string(),
+                value: "a value".to_string(),
+                ..Default::default()
             });
-            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            assert_eq!(request.await.status_code(), expected_status);
+        }
+    }
+
+    #[tokio::test]
+    async fn remove_older_than() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache.check_hash_len().await.unwrap();
+        cache
+            .add("old".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache
+            .add("new".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        std::fs::File::options()
+            .write(true)
+            .open(cache.key_to_path("old"))
+            .unwrap()
+            .set_modified(SystemTime::now() - 2 * day)
+            .unwrap();
+
+        assert_eq!(cache.remove_older_than(day).await.unwrap(), 1);
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({"new": "y"}));
+        assert_eq!(cache.remove_older_than(day).await.unwrap(), 0);
+        // Bookkeeping files are not entries
+        assert!(
+            tokio::fs::try_exists(tmp_dir.to_path_buf().join(DiskCache::HASH_LEN_FILENAME))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_max_entry_age() {
+        assert_eq!(parse_duration("45s"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::from_secs(90 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_duration("30d"),
+            Ok(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("30w").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("0d").is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX / 60)).is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_keys() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache.drop_keys = true;
+        let key = "a rather long key ".repeat(100);
+        cache.add(key.clone(), b"x".to_vec(), None).await.unwrap();
+
+        let contents = tokio::fs::read_to_string(cache.key_to_path(&key))
+            .await
+            .unwrap();
+        assert!(!contents.contains("rather long key"));
+        assert_eq!(cache.get(&key).await.unwrap(), Some(b"x".to_vec()));
+        assert!(cache.modify(key.clone(), b"y".to_vec()).await.unwrap());
+        assert_eq!(cache.get(&key).await.unwrap(), Some(b"y".to_vec()));
+        assert!(cache.delete(&key).await.unwrap());
+
+        let mut app_state = AppState::new(Box::new(cache));
+        app_state.list_disabled = true;
+        let server = TestServer::new(app(app_state)).unwrap();
+        let response = server.get("/list").await;
+        assert_eq!(response.status_code(), StatusCode::NOT_IMPLEMENTED);
+    }
+
+    async fn group_files(dir: &TmpDir) -> Vec<String> {
+        let mut filenames = vec![];
+        let mut entries = tokio::fs::read_dir(dir.as_ref()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let filename = entry.file_name().into_string().unwrap();
+            if filename.starts_with(DiskCache::GROUP_FILENAME_PREFIX) {
+                filenames.push(filename);
+            }
+        }
+        filenames.sort();
+        filenames
+    }
+
+    async fn grouped_cache(dir: &TmpDir, group_size: usize) -> DiskCache {
+        let mut cache = DiskCache::new(dir.to_path_buf());
+        cache.group_size = Some(group_size);
+        cache.load_groups().await;
+        cache
+    }
+
+    #[tokio::test]
+    async fn grouped_entries() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = grouped_cache(&tmp_dir, 16).await;
+
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache
+            .add("b".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(group_files(&tmp_dir).await, ["group-"]);
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+        assert!(cache.modify("b".to_string(), b"z".to_vec()).await.unwrap());
+        assert!(!cache.modify("c".to_string(), b"z".to_vec()).await.unwrap());
+        assert!(cache.delete("a").await.unwrap());
+        assert!(!cache.delete("a").await.unwrap());
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({"b": "z"}));
+
+        let mut expected = serde_json::Map::new();
+        expected.insert("b".to_string(), "z".into());
+        for i in 0..100 {
+            cache
+                .add(i.to_string(), format!("value {}", i).into_bytes(), None)
+                .await
+                .unwrap();
+            expected.insert(i.to_string(), format!("value {}", i).into());
+        }
+        let files = group_files(&tmp_dir).await;
+        assert!(files.len() >= 16 && files.len() < 50, "{:?}", files);
+        assert!(!files.contains(&"group-".to_string()));
+        assert_eq!(cache.get("42").await.unwrap(), Some(b"value 42".to_vec()));
+        assert_eq!(cache.list().await.unwrap(), Value::Object(expected.clone()));
+
+        // The groups are found after a restart
+        let cache = grouped_cache(&tmp_dir, 16).await;
+        assert_eq!(cache.list().await.unwrap(), Value::Object(expected));
+        assert!(cache.delete("42").await.unwrap());
+        assert_eq!(cache.get("42").await.unwrap(), None);
+        assert_eq!(cache.get("43").await.unwrap(), Some(b"value 43".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn interrupted_group_rewrite() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = grouped_cache(&tmp_dir, 2).await;
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+
+        let mut crashing = grouped_cache(&tmp_dir, 2).await;
+        crashing.fault = Some(DiskFault::CrashBeforeRename);
+        let crash =
+            tokio::spawn(async move { crashing.add("b".to_string(), b"y".to_vec(), None).await });
+        assert!(crash.await.unwrap_err().is_panic());
+
+        let cache = grouped_cache(&tmp_dir, 2).await;
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({"a": "x"}));
+        cache
+            .add("b".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "x", "b": "y"})
+        );
+    }
+
+    #[tokio::test]
+    async fn interrupted_group_split() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = grouped_cache(&tmp_dir, 2).await;
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache
+            .add("b".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+
+        let mut crashing = grouped_cache(&tmp_dir, 2).await;
+        crashing.fault = Some(DiskFault::CrashMidSplit);
+        let crash =
+            tokio::spawn(async move { crashing.add("c".to_string(), b"z".to_vec(), None).await });
+        assert!(crash.await.unwrap_err().is_panic());
+        assert_eq!(group_files(&tmp_dir).await.len(), 17);
+
+        // The split group wins over the leftovers of the split
+        let cache = grouped_cache(&tmp_dir, 2).await;
+        assert_eq!(group_files(&tmp_dir).await, ["group-"]);
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "x", "b": "y"})
+        );
+        cache
+            .add("c".to_string(), b"z".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(group_files(&tmp_dir).await.len(), 16);
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "x", "b": "y", "c": "z"})
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_entry_is_removed_on_access() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        let past = SystemTime::now() - Duration::from_secs(1);
+        cache
+            .add("a".to_string(), b"x".to_vec(), Some(past))
+            .await
+            .unwrap();
+        assert!(tokio::fs::try_exists(cache.key_to_path("a")).await.unwrap());
+
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert!(!tokio::fs::try_exists(cache.key_to_path("a")).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_are_swept() {
+        for group_size in [None, Some(16)] {
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+            cache.group_size = group_size;
+            let past = SystemTime::now() - Duration::from_secs(1);
+            cache
+                .add("a".to_string(), b"x".to_vec(), Some(past))
+                .await
+                .unwrap();
+            cache
+                .add("b".to_string(), b"y".to_vec(), None)
+                .await
+                .unwrap();
+            let path = cache.key_to_path("a");
+            let group_path = tmp_dir.to_path_buf().join(DiskCache::group_filename(""));
+            let files_contain_a = || async {
+                match group_size {
+                    Some(_) => tokio::fs::read_to_string(&group_path)
+                        .await
+                        .unwrap()
+                        .contains(r#""key":"a""#),
+                    None => tokio::fs::try_exists(&path).await.unwrap(),
+                }
+            };
+            assert!(files_contain_a().await);
+
+            let mut app_state = AppState::new(Box::new(cache));
+            app_state.sweep_interval = Some(Duration::from_millis(50));
+            let server = TestServer::new(app(app_state)).unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            assert!(!files_contain_a().await);
+            let response = server.get("/list").await;
+            assert_eq!(response.json::<Value>(), serde_json::json!({"b": "y"}));
+        }
+    }
+
+    #[tokio::test]
+    async fn sweep_locks_only_the_deleted_keys() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        let past = SystemTime::now() - Duration::from_secs(1);
+        cache
+            .add("a".to_string(), b"x".to_vec(), Some(past))
+            .await
+            .unwrap();
+        let hash_byte = |key: &str| blake3::hash(key.as_bytes()).as_bytes()[0];
+        let other = (0..)
+            .map(|i| i.to_string())
+            .find(|key| hash_byte(key) != hash_byte("a"))
+            .unwrap();
+        cache.add(other.clone(), b"y".to_vec(), None).await.unwrap();
+
+        // A request in progress on the other key does not hold the sweep up
+        let locks = KeyLocks::new();
+        let _lock = locks.write(&other).await;
+        let removed =
+            tokio::time::timeout(Duration::from_secs(5), cache.remove_expired(10, &locks))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(removed, 1);
+        assert!(!tokio::fs::try_exists(cache.key_to_path("a")).await.unwrap());
+        assert_eq!(cache.get(&other).await.unwrap(), Some(b"y".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn sweep_skips_malformed_files() {
+        for group_size in [None, Some(16)] {
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+            cache.group_size = group_size;
+            // Split into the groups of the first hex digit, the malformed one goes first
+            *cache.groups.lock().unwrap() = (0..16).map(|i| format!("{:x}", i)).collect();
+            let malformed = match group_size {
+                None => cache.key_to_path("malformed"),
+                Some(_) => tmp_dir.to_path_buf().join(DiskCache::group_filename("0")),
+            };
+            tokio::fs::write(&malformed, "not JSON").await.unwrap();
+            let past = SystemTime::now() - Duration::from_secs(1);
+            let key = (0..)
+                .map(|i| i.to_string())
+                .find(|key| !cache.key_to_filename(key).starts_with('0'))
+                .unwrap();
+            cache
+                .add(key.clone(), b"x".to_vec(), Some(past))
+                .await
+                .unwrap();
+
+            let removed = cache.remove_expired(10, &KeyLocks::new()).await.unwrap();
+            assert_eq!(removed, 1, "{:?}", group_size);
+            assert!(tokio::fs::try_exists(&malformed).await.unwrap());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_grouped_writers() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = Arc::new(grouped_cache(&tmp_dir, 4).await);
+
+        // Different keys of the same groups, with splits on the way
+        let mut writers = tokio::task::JoinSet::new();
+        for i in 0..100 {
+            let cache = cache.clone();
+            writers
+                .spawn(async move { cache.add(i.to_string(), b"x".to_vec(), None).await.unwrap() });
+        }
+        while let Some(res) = writers.join_next().await {
+            res.unwrap();
+        }
+        for i in 0..100 {
+            assert_eq!(
+                cache.get(&i.to_string()).await.unwrap(),
+                Some(b"x".to_vec())
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delete_racing_with_modify() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let grouped_dir = TmpDir::new("rest_server").await.unwrap();
+        let caches = [
+            Arc::new(DiskCache::new(tmp_dir.to_path_buf())),
+            Arc::new(grouped_cache(&grouped_dir, 4).await),
+        ];
+        for cache in caches {
+            for i in 0..50 {
+                let key = format!("key {}", i);
+                cache.add(key.clone(), b"x".to_vec(), None).await.unwrap();
+                let deleter = tokio::spawn({
+                    let (cache, key) = (cache.clone(), key.clone());
+                    async move { cache.delete(&key).await.unwrap() }
+                });
+                let modifier = tokio::spawn({
+                    let (cache, key) = (cache.clone(), key.clone());
+                    async move { cache.modify(key, b"y".to_vec()).await.unwrap() }
+                });
+                assert!(deleter.await.unwrap());
+                modifier.await.unwrap();
+                // Whichever came first, the deleted entry must not be recreated
+                assert_eq!(cache.get(&key).await.unwrap(), None);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn not_ready_when_read_only() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache_dir = tmp_dir.to_path_buf().join("cache");
+        std::fs::create_dir(&cache_dir).unwrap();
+        let server = TestServer::new(app(AppState::new(Box::new(DiskCache::new(
+            cache_dir.clone(),
+        )))))
+        .unwrap();
+        assert_eq!(server.get("/ready").await.status_code(), StatusCode::OK);
+
+        let mut permissions = std::fs::metadata(&cache_dir).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&cache_dir, permissions).unwrap();
+        // Permissions do not restrict root, then the directory is made unwritable by removing it
+        if std::fs::write(cache_dir.join("probe"), "").is_ok() {
+            std::fs::remove_dir_all(&cache_dir).unwrap();
+        }
+        let response = server.get("/ready").await;
+        assert_eq!(response.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(server.get("/health").await.status_code(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn write_ahead_log() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache_dir = tmp_dir.to_path_buf().join("cache");
+        std::fs::create_dir(&cache_dir).unwrap();
+        let wal_path = tmp_dir.to_path_buf().join("wal");
+        let mut cache = DiskCache::new(cache_dir);
+        cache.wal = Some(WriteAheadLog::open(&wal_path).unwrap());
+
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        assert!(cache.modify("a".to_string(), b"y".to_vec()).await.unwrap());
+        assert!(!cache.modify("b".to_string(), b"y".to_vec()).await.unwrap());
+        assert!(cache.delete("a").await.unwrap());
+        assert!(!cache.delete("a").await.unwrap());
+        let ns_cache = cache.namespace("ns").await.unwrap();
+        ns_cache
+            .add("b".to_string(), b"z".to_vec(), None)
+            .await
+            .unwrap();
+
+        let wal = tokio::fs::read_to_string(&wal_path).await.unwrap();
+        let records = wal
+            .lines()
+            .map(|line| serde_json::from_str::<WalRecord>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].timestamp_ms <= pair[1].timestamp_ms));
+        let records = records
+            .into_iter()
+            .map(|record| (record.op, record.namespace, record.key))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [
+                (MutationOp::Add, None, "a".to_string()),
+                (MutationOp::Modify, None, "a".to_string()),
+                (MutationOp::Delete, None, "a".to_string()),
+                (MutationOp::Add, Some("ns".to_string()), "b".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_include_disk_usage() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        assert_eq!(cache.stats().await.unwrap().disk_usage_bytes, Some(0));
+
+        cache
+            .add("a".to_string(), vec![b'x'; 1000], None)
+            .await
+            .unwrap();
+        let stats = cache.stats().await.unwrap();
+        assert_eq!(stats.entry_count, 1);
+        assert_eq!(stats.total_value_bytes, 1000);
+        // The entry file holds the value and the metadata
+        assert!(stats.disk_usage_bytes.unwrap() > 1000);
+    }
+
+    #[tokio::test]
+    async fn rename() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache.alias("b".to_string(), "a".to_string()).await.unwrap();
+
+        // The alias becomes an entry of its own
+        let result = cache
+            .rename("b".to_string(), "c".to_string(), false)
+            .await
+            .unwrap();
+        assert_eq!(result, RenameResult::Renamed);
+        let result = cache
+            .rename("a".to_string(), "d".to_string(), false)
+            .await
+            .unwrap();
+        assert_eq!(result, RenameResult::Renamed);
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"c": "x", "d": "x"})
+        );
+        assert!(!cache.key_to_path("a").exists());
+        assert!(!cache.key_to_path("b").exists());
+
+        // Without the keys in the files, the file is moved as it is
+        let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache.drop_keys = true;
+        let contents = std::fs::read(cache.key_to_path("c")).unwrap();
+        let result = cache
+            .rename("c".to_string(), "e".to_string(), false)
+            .await
+            .unwrap();
+        assert_eq!(result, RenameResult::Renamed);
+        assert!(!cache.key_to_path("c").exists());
+        assert_eq!(std::fs::read(cache.key_to_path("e")).unwrap(), contents);
+    }
+
+    fn cipher(byte: u8) -> Option<ChaCha20Poly1305> {
+        Some(ChaCha20Poly1305::new(&[byte; 32].into()))
+    }
+
+    #[tokio::test]
+    async fn encryption() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache.cipher = cipher(1);
+        let path = cache.key_to_path("some key");
+        let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+
+        let request = server.put("/add").json(&AddPayload {
+            key: "some key".to_string(),
+            value: "a secret value".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(request.await.status_code(), StatusCode::CREATED);
+        let contents = String::from_utf8(tokio::fs::read(&path).await.unwrap()).unwrap();
+        assert!(!contents.contains("secret"));
+        assert!(contents.contains("some key"));
+
+        let response = server.get("/get").json(&GetPayload {
+            key: "some key".to_string(),
+        });
+        assert_eq!(response.await.text(), "a secret value");
+        let response = server.get("/list").await;
+        assert_eq!(response.text(), r#"{"some key":"a secret value"}"#);
+    }
 
+    #[tokio::test]
+    async fn compressed_values() {
+        for cipher in [None, cipher(1)] {
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+            // Written before enabling the compression
+            cache
+                .add("plain".to_string(), b"x".repeat(1000), None)
+                .await
+                .unwrap();
+            cache.cipher = cipher;
+            cache.compress_values = true;
+            let path = cache.key_to_path("some key");
+            let small_path = cache.key_to_path("small");
+            let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+
+            let value = "a compressible value ".repeat(10_000);
+            for (key, value) in [("some key", value.as_str()), ("small", "tiny")] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            let size = tokio::fs::metadata(&path).await.unwrap().len();
+            assert!(size < value.len() as u64 / 10, "{}", size);
+            // Would not get smaller
+            let contents = tokio::fs::read(&small_path).await.unwrap();
+            assert!(!String::from_utf8(contents).unwrap().contains("gzip"));
+
+            let response = server.get("/get/some%20key").await;
+            assert_eq!(response.text(), value);
+            assert_eq!(
+                response.header(header::CONTENT_LENGTH),
+                value.len().to_string()
+            );
             let response = server.get("/list").await;
+            assert_eq!(
+                response.json::<Value>(),
+                serde_json::json!({"some key": value, "small": "tiny", "plain": "x".repeat(1000)})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn history() {
+        for cipher in [None, cipher(1)] {
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+            cache.cipher = cipher.clone();
+            let path = cache.key_to_path("some key");
+            let mut app_state = AppState::new(Box::new(cache));
+            app_state.value_history = 2;
+            let server = TestServer::new(app(app_state)).unwrap();
+
+            for value in ["first secret", "second secret"] {
+                let request = server.put("/add").json(&AddPayload {
+                    key: "some key".to_string(),
+                    value: value.to_string(),
+                    ..Default::default()
+                });
+                assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            }
+            // Stored in the entry file, encrypted with the cipher
+            let contents = String::from_utf8(tokio::fs::read(&path).await.unwrap()).unwrap();
+            assert_eq!(contents.contains("first secret"), cipher.is_none());
+
+            let response = server.get("/history/some%20key").await;
             assert_eq!(response.status_code(), StatusCode::OK);
-            assert_eq!(response.text(), r#"{"a":"x","b":"y"}"#);
+            let history = response.json::<Vec<PastValue>>();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history[0].value, JsonBytes(b"first secret".to_vec()));
         }
     }
 
     #[tokio::test]
-    async fn add_overrides_previous_entry() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+    async fn decryption_with_another_key_fails() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache
+            .add("plain".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache.cipher = cipher(1);
+        cache
+            .add("secret".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+        // The entries written before enabling the encryption stay readable
+        assert_eq!(cache.get("plain").await.unwrap(), Some(b"x".to_vec()));
+        assert_eq!(cache.get("secret").await.unwrap(), Some(b"y".to_vec()));
+
+        for cipher in [None, cipher(2)] {
+            cache.cipher = cipher;
+            assert!(matches!(
+                cache.get("secret").await,
+                Err(CacheError::Undecryptable(_))
+            ));
+            assert_eq!(cache.get("plain").await.unwrap(), Some(b"x".to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn grouped_encryption() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let mut cache = grouped_cache(&tmp_dir, 4).await;
+        cache.cipher = cipher(1);
+        let mut expected = serde_json::Map::new();
+        for i in 0..20 {
+            let value = format!("secret {}", i);
+            cache
+                .add(i.to_string(), value.clone().into_bytes(), None)
+                .await
+                .unwrap();
+            expected.insert(i.to_string(), value.into());
+        }
+        assert!(group_files(&tmp_dir).await.len() > 1);
+        for filename in group_files(&tmp_dir).await {
+            let contents = tokio::fs::read(tmp_dir.to_path_buf().join(filename))
+                .await
+                .unwrap();
+            assert!(!String::from_utf8(contents).unwrap().contains("secret"));
+        }
+        assert_eq!(cache.get("7").await.unwrap(), Some(b"secret 7".to_vec()));
+        assert_eq!(cache.list().await.unwrap(), Value::Object(expected));
+    }
+
+    #[test]
+    fn parse_encryption_key() {
+        let key = (0..32).collect::<Vec<u8>>().try_into().unwrap();
+        assert_eq!(
+            super::parse_encryption_key(
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            ),
+            Ok(key)
+        );
+        assert_eq!(
+            super::parse_encryption_key("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8="),
+            Ok(key)
+        );
+        assert!(super::parse_encryption_key("00").is_err());
+        assert!(super::parse_encryption_key("not a key").is_err());
+    }
+
+    // Timing the writes instead would be flaky, fsync costs next to nothing on e.g. tmpfs
+    #[tokio::test]
+    async fn fsync_interval_batches_dir_syncs() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+        // Without the sync_loop(), so that nothing is synced in the meantime
+        let pending = Arc::new(PendingDirSyncs::default());
+        cache.dir_sync = DirSync::Interval(pending.clone());
+        for i in 0..100 {
+            cache.add(i.to_string(), b"x".to_vec(), None).await.unwrap();
+        }
+        // The 100 renames get one sync
+        let dirs = pending.0.lock().unwrap().clone();
+        assert_eq!(dirs, BTreeSet::from([tmp_dir.to_path_buf()]));
+    }
+
+    #[tokio::test]
+    async fn fsync_modes_keep_entries_readable() {
+        for mode in [FsyncMode::Always, FsyncMode::Interval, FsyncMode::Never] {
+            let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+            let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+            cache.set_fsync_mode(mode);
+            for (key, value) in [("a", "x"), ("b", "y"), ("c", "z")] {
+                cache
+                    .add(key.to_string(), value.as_bytes().to_vec(), None)
+                    .await
+                    .unwrap();
+            }
+            assert!(cache.delete("b").await.unwrap());
+            if let DirSync::Interval(pending) = &cache.dir_sync {
+                assert!(!pending.0.lock().unwrap().is_empty());
+                tokio::time::sleep(PendingDirSyncs::INTERVAL * 3).await;
+                assert!(pending.0.lock().unwrap().is_empty(), "{:?}", mode);
+            }
+            drop(cache);
+
+            let cache = DiskCache::new(tmp_dir.to_path_buf());
+            assert_eq!(
+                cache.list().await.unwrap(),
+                serde_json::json!({"a": "x", "c": "z"}),
+                "{:?}",
+                mode
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn grouped_delete_prefix() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = grouped_cache(&tmp_dir, 4).await;
+        let mut expected = serde_json::Map::new();
+        for i in 0..20 {
+            cache
+                .add(format!("a{}", i), b"x".to_vec(), None)
+                .await
+                .unwrap();
+            cache
+                .add(format!("b{}", i), b"y".to_vec(), None)
+                .await
+                .unwrap();
+            expected.insert(format!("b{}", i), "y".into());
+        }
+        assert_eq!(cache.delete_prefix("a").await.unwrap(), 20);
+        assert_eq!(cache.list().await.unwrap(), Value::Object(expected));
+        assert_eq!(cache.delete_prefix("a").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn compact() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        let path = cache.key_to_path("broken");
+        let stray = format!("{}.new", cache.key_to_path("stray").display());
+        let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+
+        let request = server.put("/add").json(&AddPayload {
+            key: "kept".to_string(),
+            value: "value".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(request.await.status_code(), StatusCode::CREATED);
+        tokio::fs::write(&stray, "{\"key\":").await.unwrap();
+        tokio::fs::write(&path, "not json").await.unwrap();
+
+        let response = server.post("/compact").await;
+        assert_eq!(
+            response.json::<Compaction>(),
+            Compaction {
+                temp_files: 1,
+                malformed_files: 1,
+            }
+        );
+        assert!(!tokio::fs::try_exists(&stray).await.unwrap());
+        assert!(!tokio::fs::try_exists(&path).await.unwrap());
+        let response = server.get("/list").await;
+        assert_eq!(response.text(), r#"{"kept":"value"}"#);
+
+        let response = server.post("/compact").await;
+        assert_eq!(response.json::<Compaction>(), Compaction::default());
+    }
+
+    #[tokio::test]
+    async fn misnamed_entry_is_not_served() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        tokio::fs::copy(cache.key_to_path("a"), cache.key_to_path("b"))
+            .await
+            .unwrap();
+
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({"a": "x"}));
+        assert!(matches!(
+            cache.get("b").await,
+            Err(CacheError::KeyCollision(_))
+        ));
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn expiration_survives_restart() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        // As written before a restart, the expiration time is absolute
+        let expired_at_ms = unix_ms(SystemTime::now() - Duration::from_secs(1));
+        let entry = serde_json::json!({"key": "a", "value": "x", "expires_at_ms": expired_at_ms});
+        tokio::fs::write(cache.key_to_path("a"), entry.to_string())
+            .await
+            .unwrap();
+        let entry = serde_json::json!({"key": "b", "value": "y"});
+        tokio::fs::write(cache.key_to_path("b"), entry.to_string())
+            .await
+            .unwrap();
+        drop(cache);
+
+        let cache = DiskCache::new(tmp_dir.to_path_buf());
+        let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+        let response = server.get("/get/a").await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+        let response = server.get("/list").await;
+        assert_eq!(response.text(), r#"{"b":"y"}"#);
+    }
+
+    #[tokio::test]
+    async fn grouped_clear() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = grouped_cache(&tmp_dir, 4).await;
+        for i in 0..20 {
+            cache
+                .add(format!("k{}", i), b"x".to_vec(), None)
+                .await
+                .unwrap();
+        }
+        assert_eq!(cache.clear().await.unwrap(), 20);
+        assert!(group_files(&tmp_dir).await.is_empty());
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({}));
+        cache
+            .add("a".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"y".to_vec()));
+    }
 
+    #[tokio::test]
+    async fn disk_full() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        DiskCache::new(tmp_dir.to_path_buf())
+            .add("kept".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache.fault = Some(DiskFault::StorageFull);
+        let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
+
+        for _ in 0..2 {
             let request = server.put("/add").json(&AddPayload {
                 key: "some key".to_string(),
                 value: "a value".to_string(),
+                ..Default::default()
             });
-            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            let response = request.await;
+            assert_eq!(response.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+            assert!(response.json::<Value>()["error"].is_string());
+        }
+        let mut entries = tokio::fs::read_dir(tmp_dir.as_ref()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let file_name = entry.file_name().into_string().unwrap();
+            assert!(!file_name.ends_with(".new"), "{}", file_name);
+        }
+        assert_eq!(server.get("/get/kept").await.text(), "x");
+        assert_eq!(server.get("/list").await.text(), r#"{"kept":"x"}"#);
+    }
 
-            let request = server.put("/add").json(&AddPayload {
-                key: "some key".to_string(),
-                value: "another value".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+    #[tokio::test]
+    async fn hung_disk_times_out() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        DiskCache::new(tmp_dir.to_path_buf())
+            .add("kept".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        let mut cache = DiskCache::new(tmp_dir.to_path_buf());
+        cache.io_timeout = Some(Duration::from_millis(100));
+        // Even creating the file hangs past the timeout
+        cache.fault = Some(DiskFault::SlowWrites(Duration::from_millis(500)));
+        let server = TestServer::new(app(AppState::new(Box::new(cache)))).unwrap();
 
-            let response = server.get("/list").await;
-            assert_eq!(response.status_code(), StatusCode::OK);
-            assert_eq!(response.text(), r#"{"some key":"another value"}"#);
+        let start = Instant::now();
+        let request = server.put("/add").json(&AddPayload {
+            key: "some key".to_string(),
+            value: "a value".to_string(),
+            ..Default::default()
+        });
+        let response = request.await;
+        assert_eq!(response.status_code(), StatusCode::GATEWAY_TIMEOUT);
+        assert!(response.json::<Value>()["error"].is_string());
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // The file created after the timeout is removed once the write is done
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut entries = tokio::fs::read_dir(tmp_dir.as_ref()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let file_name = entry.file_name().into_string().unwrap();
+            assert!(!file_name.ends_with(".new"), "{}", file_name);
+        }
+        // The reads are not delayed
+        assert_eq!(server.get("/get/kept").await.text(), "x");
+        assert_eq!(server.get("/list").await.text(), r#"{"kept":"x"}"#);
+    }
+}
+
+#[cfg(test)]
+mod hybrid_cache_tests {
+    use super::*;
+    use tmpdir::TmpDir;
+
+    async fn entry_files(dir: &TmpDir) -> usize {
+        let mut count = 0;
+        let mut entries = tokio::fs::read_dir(dir.as_ref()).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            if entry.file_name().len() == blake3::OUT_LEN * 2 {
+                count += 1;
+            }
         }
+        count
     }
 
     #[tokio::test]
-    async fn deleting_nonexistent_entry() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+    async fn ephemeral_keys_are_not_written_to_disk() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = HybridCache::new(
+            "session:".to_string(),
+            Box::new(DiskCache::new(tmp_dir.to_path_buf())),
+        );
 
-            let request = server.delete("/delete").json(&DeletePayload {
-                key: "some key".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+        cache
+            .add("session:1".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(entry_files(&tmp_dir).await, 0);
+        assert!(cache
+            .modify("session:1".to_string(), b"y".to_vec())
+            .await
+            .unwrap());
+        assert_eq!(entry_files(&tmp_dir).await, 0);
+
+        cache
+            .add("user:1".to_string(), b"z".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(entry_files(&tmp_dir).await, 1);
+
+        assert_eq!(cache.get("session:1").await.unwrap(), Some(b"y".to_vec()));
+        assert_eq!(cache.get("user:1").await.unwrap(), Some(b"z".to_vec()));
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"session:1": "y", "user:1": "z"})
+        );
+        let mut entries = cache.iter().try_collect::<Vec<_>>().await.unwrap();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                ("session:1".to_string(), b"y".to_vec()),
+                ("user:1".to_string(), b"z".to_vec()),
+            ]
+        );
+
+        assert!(cache.delete("session:1").await.unwrap());
+        assert!(!cache.delete("session:1").await.unwrap());
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"user:1": "z"})
+        );
+
+        // Ephemeral keys do not survive a restart
+        cache
+            .add("session:2".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        let cache = HybridCache::new(
+            "session:".to_string(),
+            Box::new(DiskCache::new(tmp_dir.to_path_buf())),
+        );
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"user:1": "z"})
+        );
+    }
+}
+
+#[cfg(test)]
+mod cached_disk_cache_tests {
+    use super::*;
+    use tmpdir::TmpDir;
+
+    // The entries are changed behind the back of the memory layer through another DiskCache of the
+    // same directory, so that the reads show where they come from
+    #[tokio::test]
+    async fn reads_are_served_from_memory() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let disk = DiskCache::new(tmp_dir.to_path_buf());
+        let cache = CachedDiskCache::new(
+            Box::new(DiskCache::new(tmp_dir.to_path_buf())),
+            std::num::NonZeroUsize::new(2).unwrap(),
+        );
+        for key in ["a", "b", "c"] {
+            cache
+                .add(key.to_string(), b"x".to_vec(), None)
+                .await
+                .unwrap();
         }
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+        disk.modify("a".to_string(), b"disk".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+
+        // Writes go through and drop the copy
+        cache.modify("a".to_string(), b"y".to_vec()).await.unwrap();
+        assert_eq!(disk.get("a").await.unwrap(), Some(b"y".to_vec()));
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"y".to_vec()));
+
+        // The least recently used one is evicted
+        cache.get("b").await.unwrap();
+        cache.get("c").await.unwrap();
+        disk.modify("a".to_string(), b"disk".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"disk".to_vec()));
     }
 
     #[tokio::test]
-    async fn delete() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+    async fn values_of_aliases_are_not_kept() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = CachedDiskCache::new(
+            Box::new(DiskCache::new(tmp_dir.to_path_buf())),
+            std::num::NonZeroUsize::new(10).unwrap(),
+        );
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache.alias("b".to_string(), "a".to_string()).await.unwrap();
+        assert_eq!(cache.get("b").await.unwrap(), Some(b"x".to_vec()));
+        cache.modify("a".to_string(), b"y".to_vec()).await.unwrap();
+        assert_eq!(cache.get("b").await.unwrap(), Some(b"y".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn expired_copies_are_not_served() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let cache = CachedDiskCache::new(
+            Box::new(DiskCache::new(tmp_dir.to_path_buf())),
+            std::num::NonZeroUsize::new(10).unwrap(),
+        );
+        let expires_at = SystemTime::now() + Duration::from_millis(50);
+        cache
+            .add("a".to_string(), b"x".to_vec(), Some(expires_at))
+            .await
+            .unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(cache.get("a").await.unwrap(), None);
+    }
+}
+
+#[cfg(test)]
+mod sqlite_cache_tests {
+    use super::*;
+    use tmpdir::TmpDir;
+
+    #[tokio::test]
+    async fn entries_survive_reopening() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let db_path = tmp_dir.to_path_buf().join("cache.db");
+        let cache = SqliteCache::open(&db_path).unwrap();
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache
+            .add("b".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+        cache.alias("c".to_string(), "a".to_string()).await.unwrap();
+        drop(cache);
+
+        let cache = SqliteCache::open(&db_path).unwrap();
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "x", "b": "y", "c": "x"})
+        );
+        assert!(cache.modify("a".to_string(), b"z".to_vec()).await.unwrap());
+        assert_eq!(cache.get("c").await.unwrap(), Some(b"z".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn opens_database_without_metadata() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let db_path = tmp_dir.to_path_buf().join("cache.db");
+        let conn = rusqlite::Connection::open(&db_path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE entries (
+                key TEXT PRIMARY KEY,
+                value BLOB NOT NULL,
+                target TEXT,
+                expires_at_ms INTEGER
+            );
+            INSERT INTO entries (key, value) VALUES ('a', X'78');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let cache = SqliteCache::open(&db_path).unwrap();
+        assert_eq!(
+            cache.get_with_meta("a").await.unwrap(),
+            Some(StoredValue {
+                value: b"x".to_vec(),
+                meta: EntryMeta::default(),
+                expires_at: None,
+                aliased: false,
+            })
+        );
+        cache
+            .add_with_content_type("b".to_string(), b"y".to_vec(), None, Some("a/b".into()))
+            .await
+            .unwrap();
+        let stored = cache.get_with_meta("b").await.unwrap().unwrap();
+        assert_eq!(stored.meta.content_type.as_deref(), Some("a/b"));
+    }
+}
+
+#[cfg(test)]
+mod redis_cache_tests {
+    use super::*;
+
+    #[test]
+    fn escape_glob() {
+        assert_eq!(RedisCache::escape_glob("a:b"), "a:b");
+        assert_eq!(RedisCache::escape_glob(r"*?[x]\"), r"\*\?\[x\]\\");
+    }
+
+    // Without a Redis server: a fake one answers the connection setup and then goes away
+    #[tokio::test]
+    async fn unreachable_server_is_not_writable() {
+        use tokio::io::AsyncReadExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // CLIENT SETINFO of LIB-NAME and LIB-VER
+            let mut request = vec![];
+            while request.windows(7).filter(|w| w == b"SETINFO").count() < 2 {
+                let mut buf = [0; 256];
+                let len = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..len]);
+            }
+            stream.write_all(b"+OK\r\n+OK\r\n").await.unwrap();
+        });
+        let cache = RedisCache::connect(&url).await.unwrap();
+        server.await.unwrap();
+        assert!(cache.check_writable().await.is_err());
+    }
+
+    // The entries are put in a namespace of their own, so that the other keys of the server are
+    // left alone. Without a Redis server at $REDIS_URL (redis://127.0.0.1/ by default) the tests
+    // pass without checking anything.
+    #[cfg(feature = "redis-tests")]
+    async fn redis_cache(test: &str) -> Option<Box<dyn Cache + Send + Sync>> {
+        let url = std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string());
+        let cache = match RedisCache::connect(&url).await {
+            Ok(cache) => cache,
+            Err(err) => {
+                eprintln!("Skipping, Redis is unavailable: {}", err);
+                return None;
+            }
+        };
+        let name = format!("rest_server-test-{}-{}", test, std::process::id());
+        let cache = cache.namespace(&name).await.unwrap();
+        cache.delete_prefix("").await.unwrap();
+        Some(cache)
+    }
+
+    #[cfg(feature = "redis-tests")]
+    #[tokio::test]
+    async fn writable() {
+        let Some(cache) = redis_cache("writable").await else {
+            return;
+        };
+        cache.check_writable().await.unwrap();
+        assert_eq!(cache.len().await.unwrap(), 0);
+    }
+
+    #[cfg(feature = "redis-tests")]
+    #[tokio::test]
+    async fn entries() {
+        let Some(cache) = redis_cache("entries").await else {
+            return;
+        };
+        cache
+            .add("a".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        cache
+            .add_with_content_type("b".to_string(), b"y".to_vec(), None, Some("a/b".into()))
+            .await
+            .unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+        assert_eq!(cache.get("c").await.unwrap(), None);
+        let stored = cache.get_with_meta("b").await.unwrap().unwrap();
+        assert_eq!(stored.meta.content_type.as_deref(), Some("a/b"));
+
+        assert!(cache.modify("a".to_string(), b"z".to_vec()).await.unwrap());
+        assert!(!cache.modify("c".to_string(), b"z".to_vec()).await.unwrap());
+        cache.alias("c".to_string(), "a".to_string()).await.unwrap();
+        assert!(cache.has_aliases("a").await.unwrap());
+        assert!(matches!(
+            cache.alias("a".to_string(), "c".to_string()).await,
+            Err(AliasError::Cycle)
+        ));
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "z", "b": "y", "c": "z"})
+        );
+        assert_eq!(cache.len().await.unwrap(), 3);
+
+        assert_eq!(
+            cache
+                .rename("b".to_string(), "d".to_string(), false)
+                .await
+                .unwrap(),
+            RenameResult::Renamed
+        );
+        let stored = cache.get_with_meta("d").await.unwrap().unwrap();
+        assert_eq!(stored.meta.content_type.as_deref(), Some("a/b"));
+        assert!(cache.delete("a").await.unwrap());
+        assert!(!cache.delete("a").await.unwrap());
+        assert_eq!(cache.get("c").await.unwrap(), None);
+        assert_eq!(cache.delete_prefix("").await.unwrap(), 2);
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({}));
+    }
+
+    #[cfg(feature = "redis-tests")]
+    #[tokio::test]
+    async fn expiration() {
+        let Some(cache) = redis_cache("expiration").await else {
+            return;
+        };
+        let expires_at = SystemTime::now() + Duration::from_millis(200);
+        cache
+            .add("a".to_string(), b"x".to_vec(), Some(expires_at))
+            .await
+            .unwrap();
+        cache
+            .add("b".to_string(), b"y".to_vec(), Some(expires_at))
+            .await
+            .unwrap();
+        let stored = cache.get_with_meta("a").await.unwrap().unwrap();
+        assert!(stored.expires_at.unwrap() <= expires_at + Duration::from_millis(10));
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(cache.touch("b", later).await.unwrap());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert_eq!(cache.get("b").await.unwrap(), Some(b"y".to_vec()));
+        assert!(!cache.touch("a", later).await.unwrap());
+        cache.delete_prefix("").await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+    use axum_test::TestServer;
+    use tmpdir::TmpDir;
 
+    #[tokio::test]
+    async fn replay_to_intermediate_point() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let journal_path = tmp_dir.to_path_buf().join("journal");
+        let mut app_state = AppState::new(Box::new(MemCache::new()));
+        app_state.journal = Some(Arc::new(Journal::open(&journal_path).unwrap()));
+        let server = TestServer::new(app(app_state)).unwrap();
+
+        for (key, value) in [("a", "x"), ("b", "y"), ("a", "z")] {
             let request = server.put("/add").json(&AddPayload {
-                key: "some key".to_string(),
-                value: "a value".to_string(),
+                key: key.to_string(),
+                value: value.to_string(),
+                ..Default::default()
             });
             assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        let request = server.patch("/modify").json(&ModifyPayload {
+            key: "b".to_string(),
+            value: "w".to_string(),
+        });
+        assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        let request = server.delete("/delete").json(&DeletePayload {
+            key: "a".to_string(),
+        });
+        assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
 
-            let request = server.delete("/delete").json(&DeletePayload {
-                key: "some key".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+        let records = tokio::fs::read_to_string(&journal_path)
+            .await
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<JournalRecord>>();
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| {
+                    let value = record.value.as_ref().map(|value| value.0.as_slice());
+                    (record.op, record.key.as_str(), value)
+                })
+                .collect::<Vec<_>>(),
+            [
+                (MutationOp::Add, "a", Some(b"x".as_slice())),
+                (MutationOp::Add, "b", Some(b"y".as_slice())),
+                (MutationOp::Add, "a", Some(b"z".as_slice())),
+                (MutationOp::Modify, "b", Some(b"w".as_slice())),
+                (MutationOp::Delete, "a", None),
+            ]
+        );
 
-            let response = server.get("/list").await;
-            assert_eq!(response.status_code(), StatusCode::OK);
-            assert_eq!(response.text(), "{}");
-        }
+        let cache = MemCache::new();
+        let applied = Journal::replay(&journal_path, Some(records[1].timestamp_ms), &cache)
+            .await
+            .unwrap();
+        assert_eq!(applied, 2);
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "x", "b": "y"})
+        );
+
+        let cache = MemCache::new();
+        let applied = Journal::replay(&journal_path, Some(records[3].timestamp_ms), &cache)
+            .await
+            .unwrap();
+        assert_eq!(applied, 4);
+        assert_eq!(
+            cache.list().await.unwrap(),
+            serde_json::json!({"a": "z", "b": "w"})
+        );
+
+        let cache = MemCache::new();
+        let applied = Journal::replay(&journal_path, None, &cache).await.unwrap();
+        assert_eq!(applied, 5);
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({"b": "w"}));
     }
 
     #[tokio::test]
-    async fn modifying_nonexistent_entry() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+    async fn get_default_and_set_is_journaled() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let journal_path = tmp_dir.to_path_buf().join("journal");
+        let mut app_state = AppState::new(Box::new(MemCache::new()));
+        app_state.journal = Some(Arc::new(Journal::open(&journal_path).unwrap()));
+        let server = TestServer::new(app(app_state)).unwrap();
 
-            let request = server.patch("/modify").json(&ModifyPayload {
-                key: "some key".to_string(),
-                value: "a value".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+        for _ in 0..2 {
+            let request = server
+                .get("/get")
+                .add_query_param("default_and_set", "x")
+                .json(&GetPayload {
+                    key: "a".to_string(),
+                });
+            assert_eq!(request.await.status_code(), StatusCode::OK);
         }
+
+        let cache = MemCache::new();
+        let applied = Journal::replay(&journal_path, None, &cache).await.unwrap();
+        assert_eq!(applied, 1);
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({"a": "x"}));
     }
 
     #[tokio::test]
-    async fn modify() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
-
-            let request = server.put("/add").json(&AddPayload {
-                key: "some key".to_string(),
-                value: "a value".to_string(),
+    async fn replay_namespaces() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let journal_path = tmp_dir.to_path_buf().join("journal");
+        let mut app_state = AppState::new(Box::new(MemCache::new()));
+        app_state.journal = Some(Arc::new(Journal::open(&journal_path).unwrap()));
+        let server = TestServer::new(app(app_state)).unwrap();
+        for (path, value) in [("/add", "x"), ("/ns/add", "y")] {
+            let request = server.put(path).json(&AddPayload {
+                key: "k".to_string(),
+                value: value.to_string(),
+                ..Default::default()
             });
             assert_eq!(request.await.status_code(), StatusCode::CREATED);
+        }
 
-            let request = server.patch("/modify").json(&ModifyPayload {
-                key: "some key".to_string(),
-                value: "another value".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+        let cache_dir = tmp_dir.to_path_buf().join("cache");
+        tokio::fs::create_dir(&cache_dir).await.unwrap();
+        let cache = DiskCache::new(cache_dir);
+        let applied = Journal::replay(&journal_path, None, &cache).await.unwrap();
+        assert_eq!(applied, 2);
+        assert_eq!(cache.list().await.unwrap(), serde_json::json!({"k": "x"}));
+        let namespace = cache.namespace("ns").await.unwrap();
+        assert_eq!(
+            namespace.list().await.unwrap(),
+            serde_json::json!({"k": "y"})
+        );
+    }
 
-            let response = server.get("/list").await;
-            assert_eq!(response.status_code(), StatusCode::OK);
-            assert_eq!(response.text(), r#"{"some key":"another value"}"#);
-        }
+    #[tokio::test]
+    async fn replay_touch() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let journal_path = tmp_dir.to_path_buf().join("journal");
+        let mut app_state = AppState::new(Box::new(MemCache::new()));
+        app_state.journal = Some(Arc::new(Journal::open(&journal_path).unwrap()));
+        let server = TestServer::new(app(app_state)).unwrap();
+        let request = server.put("/add").json(&AddPayload {
+            key: "k".to_string(),
+            value: "x".to_string(),
+            ttl_seconds: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(request.await.status_code(), StatusCode::CREATED);
+        let request = server.post("/touch").json(&TouchPayload {
+            key: "k".to_string(),
+            ttl_seconds: 3600,
+        });
+        assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
+
+        let cache = MemCache::new();
+        let applied = Journal::replay(&journal_path, None, &cache).await.unwrap();
+        assert_eq!(applied, 2);
+        let stored = cache.get_with_meta("k").await.unwrap().unwrap();
+        assert_eq!(stored.value, b"x");
+        assert!(stored.expires_at.unwrap() > SystemTime::now() + Duration::from_secs(3000));
     }
 
     #[tokio::test]
-    async fn get_nonexistent_entry() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+    async fn replay_content_type() {
+        let tmp_dir = TmpDir::new("rest_server").await.unwrap();
+        let journal_path = tmp_dir.to_path_buf().join("journal");
+        let mut app_state = AppState::new(Box::new(MemCache::new()));
+        app_state.journal = Some(Arc::new(Journal::open(&journal_path).unwrap()));
+        let server = TestServer::new(app(app_state)).unwrap();
+        let request = server.put("/add").json(&AddPayload {
+            key: "k".to_string(),
+            value: "{}".to_string(),
+            content_type: Some("application/json".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(request.await.status_code(), StatusCode::CREATED);
+        let request = server.patch("/modify").json(&ModifyPayload {
+            key: "k".to_string(),
+            value: "[]".to_string(),
+        });
+        assert_eq!(request.await.status_code(), StatusCode::NO_CONTENT);
 
-            let request = server.get("/get").json(&GetPayload {
-                key: "some key".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::NOT_FOUND);
+        let cache = MemCache::new();
+        let applied = Journal::replay(&journal_path, None, &cache).await.unwrap();
+        assert_eq!(applied, 2);
+        let stored = cache.get_with_meta("k").await.unwrap().unwrap();
+        assert_eq!(stored.value, b"[]");
+        assert_eq!(
+            stored.meta.content_type.as_deref(),
+            Some("application/json")
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_journal() {
+        let mut app_state = AppState::new(Box::new(MemCache::new()));
+        // Every write fails with ENOSPC
+        app_state.journal = Some(Arc::new(Journal::open(Path::new("/dev/full")).unwrap()));
+        let server = TestServer::new(app(app_state)).unwrap();
+        let response = server
+            .put("/add")
+            .json(&AddPayload {
+                key: "k".to_string(),
+                value: "x".to_string(),
+                ..Default::default()
+            })
+            .await;
+        assert_eq!(response.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(response.json::<Value>()["error"].is_string());
+    }
+}
+
+#[cfg(test)]
+mod mem_cache_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lru_evicts_least_recently_used() {
+        let cache = MemCache::with_lru(std::num::NonZeroUsize::new(3).unwrap());
+        for key in ["a", "b", "c"] {
+            cache
+                .add(key.to_string(), b"x".to_vec(), None)
+                .await
+                .unwrap();
+        }
+        // "a" is the oldest, but reading it makes "b" the least recently used
+        assert!(cache.get("a").await.unwrap().is_some());
+        cache
+            .add("d".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+
+        assert_eq!(cache.len().await.unwrap(), 3);
+        assert!(cache.get("b").await.unwrap().is_none());
+        for key in ["a", "c", "d"] {
+            assert_eq!(
+                cache.get(key).await.unwrap().as_deref(),
+                Some(b"x".as_slice())
+            );
         }
+
+        // Overwriting doesn't evict anything
+        cache
+            .add("a".to_string(), b"y".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.len().await.unwrap(), 3);
+        // Deleted entries free their place
+        assert!(cache.delete("c").await.unwrap());
+        cache
+            .add("e".to_string(), b"x".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.len().await.unwrap(), 3);
+        assert!(cache.get("d").await.unwrap().is_some());
     }
 
     #[tokio::test]
-    async fn get() {
-        for app in Apps::new().await.apps {
-            let server = TestServer::new(app).unwrap();
+    async fn contains_skips_expired_entries_and_dangling_aliases() {
+        let caches: [Box<dyn Cache + Send + Sync>; 2] =
+            [Box::new(MemCache::new()), Box::new(SnapshotMemCache::new())];
+        for cache in caches {
+            let expired = SystemTime::now() - Duration::from_secs(1);
+            cache
+                .add("a".to_string(), b"x".to_vec(), None)
+                .await
+                .unwrap();
+            cache
+                .add("b".to_string(), b"y".to_vec(), Some(expired))
+                .await
+                .unwrap();
+            cache.alias("c".to_string(), "a".to_string()).await.unwrap();
+            assert!(cache.contains("a").await.unwrap());
+            assert!(!cache.contains("b").await.unwrap());
+            assert!(cache.contains("c").await.unwrap());
 
-            let request = server.put("/add").json(&AddPayload {
-                key: "some key".to_string(),
-                value: "a value".to_string(),
-            });
-            assert_eq!(request.await.status_code(), StatusCode::CREATED);
+            cache.delete("a").await.unwrap();
+            assert!(!cache.contains("c").await.unwrap());
+        }
+    }
 
-            let request = server.get("/get").json(&GetPayload {
-                key: "some key".to_string(),
-            });
-            let response = request.await;
-            assert_eq!(response.status_code(), StatusCode::OK);
-            assert_eq!(response.text(), "a value");
+    #[tokio::test(flavor = "multi_thread")]
+    async fn snapshot_reads_during_writes() {
+        const WRITES: usize = 2000;
+        let cache = Arc::new(SnapshotMemCache::new());
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                let done = done.clone();
+                tokio::spawn(async move {
+                    let mut last = 0;
+                    let mut reads = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        if let Some(value) = cache.get("counter").await.unwrap() {
+                            let value = String::from_utf8(value).unwrap();
+                            let (a, b) = value.split_once(':').unwrap();
+                            assert_eq!(a, b, "torn value {value:?}");
+                            let counter = a.parse::<usize>().unwrap();
+                            assert!(counter >= last, "{counter} read after {last}");
+                            last = counter;
+                            // Each "key i" is written before the counter reaches i
+                            assert!(cache.len().await.unwrap() > counter);
+                            let key = format!("key {counter}");
+                            assert!(counter == 0 || cache.contains(&key).await.unwrap());
+                        }
+                        reads += 1;
+                        tokio::task::yield_now().await;
+                    }
+                    reads
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 1..=WRITES {
+            cache
+                .add(format!("key {i}"), b"x".to_vec(), None)
+                .await
+                .unwrap();
+            let value = format!("{i}:{i}").into_bytes();
+            cache.add("counter".to_string(), value, None).await.unwrap();
+            if i % 100 == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            assert!(reader.await.unwrap() > 0);
+        }
+        assert_eq!(cache.len().await.unwrap(), WRITES + 1);
+        assert_eq!(
+            cache.get("counter").await.unwrap(),
+            Some(format!("{WRITES}:{WRITES}").into_bytes())
+        );
+    }
+
+    // Micro-benchmark of the reads from an increasing number of threads while one thread keeps
+    // writing, run with: cargo test --release read_scalability -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn read_scalability() {
+        const ENTRIES: usize = 1000;
+        const READS_PER_THREAD: usize = 1_000_000;
+        let caches: [(&str, Box<dyn Cache + Send + Sync>); 2] = [
+            ("dashmap", Box::new(MemCache::new())),
+            ("arcswap", Box::new(SnapshotMemCache::new())),
+        ];
+        for (name, cache) in caches {
+            let cache = cache.as_ref();
+            for i in 0..ENTRIES {
+                let add = cache.add(format!("key {i}"), b"value".to_vec(), None);
+                futures::executor::block_on(add).unwrap();
+            }
+            for threads in [1, 2, 4, 8] {
+                let done = std::sync::atomic::AtomicBool::new(false);
+                let elapsed = std::thread::scope(|scope| {
+                    scope.spawn(|| {
+                        while !done.load(Ordering::Relaxed) {
+                            let modify = cache.modify("key 0".to_string(), b"new".to_vec());
+                            futures::executor::block_on(modify).unwrap();
+                        }
+                    });
+                    let start = Instant::now();
+                    let readers = (0..threads)
+                        .map(|thread| {
+                            scope.spawn(move || {
+                                for i in 0..READS_PER_THREAD {
+                                    let key = format!("key {}", (i + thread) % ENTRIES);
+                                    let get = cache.get(&key);
+                                    assert!(futures::executor::block_on(get).unwrap().is_some());
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    for reader in readers {
+                        reader.join().unwrap();
+                    }
+                    let elapsed = start.elapsed();
+                    done.store(true, Ordering::Relaxed);
+                    elapsed
+                });
+                let reads_per_second = (threads * READS_PER_THREAD) as f64 / elapsed.as_secs_f64();
+                println!("{name}: {threads} threads: {reads_per_second:.0} reads/s");
+            }
         }
     }
 }